@@ -1,8 +1,18 @@
-use crate::cart::{Cart, EXT_RAM_START, EXT_RAM_STOP, ROM_START, ROM_STOP};
-use crate::io::{Buttons, IO, IO_START, IO_STOP};
-use crate::ppu::{Ppu, PpuUpdateResult, LCD_REG_START, LCD_REG_STOP, OAM_START, OAM_STOP, VRAM_START, VRAM_STOP};
+use crate::cart::camera::CameraSource;
+use crate::config::RamInit;
+use crate::error::GbError;
+use crate::hooks::{HookId, MemAccess, MemoryHook};
+use crate::cart::{Cart, CartInfo, GlobalChecksum, HardwareMode, EXT_RAM_START, EXT_RAM_STOP, ROM_START, ROM_STOP};
+use crate::io::{Buttons, IO, IO_START, IO_STOP, JOYPAD_ADDR};
+use crate::serial::{Disconnected, SerialDevice, SB, SC};
+use crate::state::bess::{BessState, INFO_SIZE, IO_REGS_SIZE};
+use crate::state::{Region, StateError, StateReader, StateWriter};
+use crate::timer::{DIV, TAC, TIMA, TMA};
+use crate::ppu::compat::CompatPalette;
+use crate::ppu::sprite::Sprite;
+use crate::ppu::{Layer, Ppu, PpuUpdateResult, Renderer, NUM_OAM_SPRITES, TILEMAP_PIXELS, BCPS, CGB_PALETTE_START, CGB_PALETTE_STOP, LCD_REG_START, LCD_REG_STOP, OCPS, OAM_START, OAM_STOP, VBK, VRAM_START, VRAM_STOP};
 use crate::utils::*;
-use crate::wram::{WRAM, ECHO_STOP, WRAM_START};
+use crate::wram::{WRAM, ECHO_STOP, WRAM_START, WRAM_STOP};
 
 /*
  * RAM Map
@@ -51,6 +61,12 @@ use crate::wram::{WRAM, ECHO_STOP, WRAM_START};
 **/
 
 const OAM_DMA: u16      = 0xFF46;
+// Writing any non-zero value here unmaps the boot ROM for good
+const BOOT_ROM_OFF: u16 = 0xFF50;
+
+// The DMG boot ROM only covers $0000-$00FF, while the CGB's carries on from $0200-$08FF
+const DMG_BOOT_ROM_SIZE: usize = 0x100;
+const CGB_BOOT_ROM_SIZE: usize = 0x900;
 
 const HRAM_START: u16   = 0xFF80;
 const HRAM_STOP: u16    = 0xFFFF;
@@ -62,6 +78,19 @@ pub struct Bus {
     io: IO,
     wram: WRAM,
     hram: [u8; HRAM_SIZE],
+    mode_override: Option<HardwareMode>,
+    boot_rom: Option<Vec<u8>>,
+    // Cleared once the boot ROM hands over to the game, but it's kept to run again after a reset
+    boot_rom_mapped: bool,
+    // Belong to the frontend, so are kept through resets and aren't part of the state
+    hooks: Vec<(HookId, Box<dyn MemoryHook>)>,
+    next_hook_id: u32,
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Bus {
@@ -72,6 +101,11 @@ impl Bus {
             io: IO::new(),
             wram: WRAM::new(),
             hram: [0; HRAM_SIZE],
+            mode_override: None,
+            boot_rom: None,
+            boot_rom_mapped: false,
+            hooks: Vec::new(),
+            next_hook_id: 0,
         }
     }
 
@@ -83,10 +117,22 @@ impl Bus {
         }
     }
 
-    pub fn get_battery_data(&self) -> &[u8] {
+    pub fn get_cart_info(&self) -> CartInfo {
+        self.rom.get_info()
+    }
+
+    pub fn get_battery_data(&self) -> Vec<u8> {
         self.rom.get_battery_data()
     }
 
+    pub fn get_global_checksum(&self) -> GlobalChecksum {
+        self.rom.get_global_checksum()
+    }
+
+    pub fn get_hardware_mode(&self) -> HardwareMode {
+        self.mode_override.unwrap_or_else(|| self.rom.get_hardware_mode())
+    }
+
     pub fn get_title(&self) -> &str {
         self.rom.get_title()
     }
@@ -95,14 +141,101 @@ impl Bus {
         self.rom.has_battery()
     }
 
-    pub fn load_rom(&mut self, data: &[u8]) {
-        self.rom.load_cart(data);
+    pub fn get_rom_bank(&self, addr: u16) -> u16 {
+        self.rom.get_rom_bank(addr)
+    }
+
+    pub fn is_rumbling(&self) -> bool {
+        self.rom.is_rumbling()
+    }
+
+    /// Puts the hardware back how it is at power on, keeping the cartridge's saved data and the frontend's settings
+    pub fn reset(&mut self) {
+        self.rom.reset();
+        self.ppu.reset();
+        self.io.reset();
+        self.wram = WRAM::new();
+        self.hram = [0; HRAM_SIZE];
+        self.boot_rom_mapped = self.boot_rom.is_some();
+        self.update_hardware_mode();
+    }
+
+    /// Registers a hook to be told about every memory access the CPU makes, returning an ID to remove it with
+    pub fn add_hook(&mut self, hook: Box<dyn MemoryHook>) -> HookId {
+        let id = HookId(self.next_hook_id);
+        self.next_hook_id += 1;
+        self.hooks.push((id, hook));
+        id
+    }
+
+    /// Removes a hook, returning false if it had already been removed
+    pub fn remove_hook(&mut self, id: HookId) -> bool {
+        let len = self.hooks.len();
+        self.hooks.retain(|(hook_id, _)| *hook_id != id);
+        self.hooks.len() != len
+    }
+
+    pub fn has_hooks(&self) -> bool {
+        !self.hooks.is_empty()
+    }
+
+    /// Tells every hook about an access, in the order they were added
+    pub fn notify_hooks(&mut self, access: MemAccess) {
+        for (_, hook) in &mut self.hooks {
+            hook.on_access(&access);
+        }
+    }
+
+    pub fn load_rom(&mut self, data: &[u8]) -> Result<(), GbError> {
+        self.rom.load_cart(data)?;
+        self.update_hardware_mode();
+        Ok(())
+    }
+
+    /// Maps a boot ROM over the cartridge, returning false if it isn't the size of one
+    pub fn load_boot_rom(&mut self, data: &[u8]) -> bool {
+        if data.len() != DMG_BOOT_ROM_SIZE && data.len() != CGB_BOOT_ROM_SIZE {
+            return false;
+        }
+        self.boot_rom = Some(data.to_vec());
+        self.boot_rom_mapped = true;
+        true
+    }
+
+    pub fn is_boot_rom_mapped(&self) -> bool {
+        self.boot_rom_mapped
+    }
+
+    // The cartridge header shows through the boot ROM, as it's what the boot ROM checks
+    fn read_boot_rom(&self, addr: u16) -> Option<u8> {
+        let boot_rom = self.boot_rom.as_ref()?;
+        if !self.boot_rom_mapped || (0x0100..0x0200).contains(&addr) {
+            return None;
+        }
+        boot_rom.get(addr as usize).copied()
+    }
+
+    /// Fills the memory which isn't cleared at power on
+    pub fn init_ram(&mut self, init: RamInit) {
+        for (addr, val) in (VRAM_START..=VRAM_STOP).zip(init.pattern(true)) {
+            self.ppu.write_vram(addr, val);
+        }
+        for (addr, val) in (WRAM_START..=WRAM_STOP).zip(init.pattern(false)) {
+            self.wram.write_u8(addr, val);
+        }
+        // The last byte of this range is IE, which is always cleared
+        for (byte, val) in self.hram.iter_mut().take(HRAM_SIZE - 1).zip(init.pattern(false)) {
+            *byte = val;
+        }
     }
 
     pub fn read_ram(&self, addr: u16) -> u8 {
         match addr {
             ROM_START..=ROM_STOP => {
-                self.rom.read_cart(addr)
+                match self.read_boot_rom(addr) {
+                    Some(val) => { val },
+                    None => { self.rom.read_cart(addr) },
+                }
             },
             VRAM_START..=VRAM_STOP => {
                 self.ppu.read_vram(addr)
@@ -122,6 +255,9 @@ impl Bus {
             LCD_REG_START..=LCD_REG_STOP => {
                 self.ppu.read_lcd_reg(addr)
             },
+            VBK | CGB_PALETTE_START..=CGB_PALETTE_STOP => {
+                self.ppu.read_cgb_reg(addr)
+            },
             HRAM_START..=HRAM_STOP => {
                 let relative_addr = addr - HRAM_START;
                 self.hram[relative_addr as usize]
@@ -136,18 +272,112 @@ impl Bus {
         self.io.set_button(button, pressed);
     }
 
-    pub fn render(&self) -> [u8; DISPLAY_BUFFER] {
+    pub fn is_button_pressed(&self, button: Buttons) -> bool {
+        self.io.is_button_pressed(button)
+    }
+
+    pub fn render(&self) -> &[u8; DISPLAY_BUFFER] {
         self.ppu.render()
     }
 
+    pub fn take_frame_changed(&mut self) -> bool {
+        self.ppu.take_frame_changed()
+    }
+
+    pub fn render_indexed(&self) -> &[u8; INDEXED_BUFFER] {
+        self.ppu.render_indexed()
+    }
+
+    pub fn render_tileset(&self) -> Vec<u8> {
+        self.ppu.render_tileset()
+    }
+
+    pub fn render_tilemap(&self, map_index: u8) -> [u8; TILEMAP_PIXELS * TILEMAP_PIXELS * 4] {
+        self.ppu.render_tilemap(map_index)
+    }
+
+    pub fn get_viewport(&self) -> Point {
+        self.ppu.get_viewport()
+    }
+
+    pub fn sprites(&self) -> &[Sprite; NUM_OAM_SPRITES] {
+        self.ppu.sprites()
+    }
+
     pub fn render_scanline(&mut self) {
         self.ppu.render_scanline();
     }
 
+    pub fn verify_global_checksum(&self) -> bool {
+        self.rom.verify_global_checksum()
+    }
+
+    pub fn verify_header_checksum(&self) -> bool {
+        self.rom.verify_header_checksum()
+    }
+
     pub fn set_battery_data(&mut self, data: &[u8]) {
         self.rom.set_battery_data(data);
     }
 
+    pub fn set_camera_source(&mut self, source: Option<Box<dyn CameraSource>>) {
+        self.rom.set_camera_source(source);
+    }
+
+    /// Plugs a device into the link port, or unplugs whatever was there if None
+    pub fn set_serial_device(&mut self, device: Option<Box<dyn SerialDevice>>) {
+        self.io.set_serial_device(device.unwrap_or_else(|| Box::new(Disconnected)));
+    }
+
+    pub fn set_stat_write_bug(&mut self, enabled: bool) {
+        self.ppu.set_stat_write_bug(enabled);
+    }
+
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.ppu.set_renderer(renderer);
+    }
+
+    pub fn set_layer_enabled(&mut self, layer: Layer, enabled: bool) {
+        self.ppu.set_layer_enabled(layer, enabled);
+    }
+
+    pub fn is_layer_enabled(&self, layer: Layer) -> bool {
+        self.ppu.is_layer_enabled(layer)
+    }
+
+    pub fn set_palette(&mut self, colors: [[u8; 4]; 4]) {
+        self.ppu.set_palette(colors);
+    }
+
+    pub fn set_compat_palette(&mut self, palette: Option<CompatPalette>) {
+        self.ppu.set_compat_palette(palette);
+    }
+
+    /// Feeds the accelerometer of tilt sensing carts, in g along each axis
+    pub fn set_tilt(&mut self, x: f32, y: f32) {
+        self.rom.set_tilt(x, y);
+    }
+
+    /// Forces the given hardware mode rather than the one requested by the cartridge, or restores it if None
+    pub fn set_hardware_mode(&mut self, mode: Option<HardwareMode>) {
+        self.mode_override = mode;
+        self.update_hardware_mode();
+    }
+
+    fn update_hardware_mode(&mut self) {
+        let mode = self.get_hardware_mode();
+        self.ppu.set_cgb_mode(mode.is_cgb());
+        self.io.set_cgb_mode(mode.is_cgb());
+        self.io.set_sgb_enabled(self.rom.supports_sgb() && !mode.is_cgb());
+        self.sync_sgb_colors();
+    }
+
+    fn sync_sgb_colors(&mut self) {
+        if let Some(colors) = self.io.take_sgb_colors() {
+            self.ppu.set_sgb_colors(colors);
+        }
+    }
+
     pub fn write_ram(&mut self, addr: u16, val: u8) -> bool {
         let mut battery_write = false;
         match addr {
@@ -169,6 +399,7 @@ impl Bus {
             },
             IO_START..=IO_STOP => {
                 self.io.write_u8(addr, val);
+                self.sync_sgb_colors();
             },
             LCD_REG_START..=LCD_REG_STOP => {
                 if addr == OAM_DMA {
@@ -176,19 +407,127 @@ impl Bus {
                 }
                 self.ppu.write_lcd_reg(addr, val)
             },
+            VBK | CGB_PALETTE_START..=CGB_PALETTE_STOP => {
+                self.ppu.write_cgb_reg(addr, val);
+            },
             HRAM_START..=HRAM_STOP => {
                 let relative_addr = addr - HRAM_START;
                 self.hram[relative_addr as usize] = val;
             },
+            BOOT_ROM_OFF if val != 0 => {
+                self.boot_rom_mapped = false;
+            },
             _ => {}
         }
         battery_write
     }
 
+    // The cartridge comes first, as the rest can't be loaded into the wrong game
+    pub fn save_state(&self, state: &mut StateWriter) {
+        self.rom.save_state(state);
+        self.wram.save_state(state);
+        // BESS stores HRAM without IE, which directly follows it
+        state.write_region(Region::Hram, &self.hram[..(HRAM_SIZE - 1)]);
+        state.write_u8(self.hram[HRAM_SIZE - 1]);
+        self.ppu.save_state(state);
+        self.io.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader, version: u16) -> Result<(), StateError> {
+        self.rom.load_state(state)?;
+        self.wram.load_state(state)?;
+        self.hram = state.read_array()?;
+        self.ppu.load_state(state, version)?;
+        self.io.load_state(state)?;
+        self.sync_sgb_colors();
+        // States don't record the boot ROM, so it's assumed to have already finished
+        self.boot_rom_mapped = false;
+        Ok(())
+    }
+
+    pub fn get_bess_info(&self) -> [u8; INFO_SIZE] {
+        self.rom.get_bess_info()
+    }
+
+    pub fn get_mbc_writes(&self) -> Vec<(u16, u8)> {
+        self.rom.get_mbc_writes()
+    }
+
+    pub fn get_rtc_data(&self) -> Option<Vec<u8>> {
+        self.rom.get_rtc_data()
+    }
+
+    /// Returns $FF00-$FF7F as the CPU would read them
+    pub fn read_io_regs(&self) -> [u8; IO_REGS_SIZE] {
+        let mut regs = [0; IO_REGS_SIZE];
+        for (addr, reg) in (IO_START..).zip(regs.iter_mut()) {
+            *reg = self.read_ram(addr);
+        }
+        regs
+    }
+
+    /*
+     * BESS Import
+     *
+     * States from other emulators only describe the hardware as the CPU
+     * sees it. Memory is copied in directly and the mapper's writes are
+     * replayed, but registers with side effects are set one by one:
+     * - DIV can only be cleared by the CPU, so it's set on the timer directly
+     * - The LCD picks up from the start of the mode STAT was in on line LY
+     * - OAM DMA, the boot ROM mapping, and other unemulated registers are skipped
+     */
+    pub fn load_bess(&mut self, bess: &BessState) {
+        self.rom.load_ram(bess.get_region(Region::CartRam));
+        for &(addr, val) in &bess.mbc_writes {
+            if addr <= ROM_STOP {
+                self.rom.write_cart(addr, val);
+            }
+        }
+        if let Some(rtc) = bess.rtc {
+            self.rom.load_rtc(rtc);
+        }
+
+        self.wram.load_ram(bess.get_region(Region::Wram));
+        let hram = bess.get_region(Region::Hram);
+        let hram_len = hram.len().min(HRAM_SIZE - 1);
+        self.hram[..hram_len].copy_from_slice(&hram[..hram_len]);
+        self.hram[HRAM_SIZE - 1] = bess.core.ie;
+
+        self.ppu.load_vram(bess.get_region(Region::Vram));
+        self.ppu.load_oam(bess.get_region(Region::Oam));
+        self.ppu.load_cgb_palettes(bess.get_region(Region::BgPalettes), bess.get_region(Region::ObjPalettes));
+
+        let regs = &bess.core.io_regs;
+        let reg = |addr: u16| regs[(addr - IO_START) as usize];
+        for addr in [JOYPAD_ADDR, SB, SC, TIMA, TMA, TAC] {
+            self.io.write_u8(addr, reg(addr));
+        }
+        self.io.set_div(reg(DIV));
+        // IF and the sound registers have no side effects
+        for addr in (TAC + 1)..=IO_STOP {
+            self.io.write_u8(addr, reg(addr));
+        }
+
+        let lcd_regs: Vec<u8> = (LCD_REG_START..=LCD_REG_STOP).map(reg).collect();
+        self.ppu.restore_lcd_regs(&lcd_regs);
+        for addr in [VBK, BCPS, OCPS] {
+            self.ppu.write_cgb_reg(addr, reg(addr));
+        }
+        self.sync_sgb_colors();
+    }
+
     pub fn update_timer(&mut self, cycles: u8) -> bool {
         self.io.update_timer(cycles)
     }
 
+    pub fn take_serial_output(&mut self) -> Vec<u8> {
+        self.io.take_serial_output()
+    }
+
+    pub fn update_serial(&mut self) -> bool {
+        self.io.update_serial()
+    }
+
     pub fn update_ppu(&mut self, cycles: u8) -> PpuUpdateResult {
         self.ppu.update(cycles)
     }
@@ -0,0 +1,133 @@
+use crate::state::{StateError, StateReader, StateWriter};
+use crate::utils::BitOps;
+
+pub const CAMERA_WIDTH: usize   = 128;
+pub const CAMERA_HEIGHT: usize  = 112;
+
+const NUM_REGS: usize           = 0x36;
+const REGS_MASK: u16            = 0x7F;
+const CAPTURE_REG: usize        = 0x00;
+const DITHER_START: usize       = 0x06;
+const CAPTURE_BIT: u8           = 0;
+
+// The captured image is written to the first RAM bank, as 16x14 tiles
+const IMAGE_RAM_START: usize    = 0x0100;
+const TILE_SIZE: usize          = 16;
+const TILES_PER_ROW: usize      = CAMERA_WIDTH / 8;
+
+/// Provides the images seen by the Game Boy Camera's sensor
+pub trait CameraSource {
+    /// Returns a 128x112 image, as row-major brightness values from 0 (black) to 255 (white)
+    fn capture(&mut self) -> [u8; CAMERA_WIDTH * CAMERA_HEIGHT];
+}
+
+/*
+ * Game Boy Camera
+ *
+ * Writing $10 to the RAM bank register maps the sensor registers into
+ * $A000-$A07F, rather than RAM.
+ *
+ * +--------------+---------------------------------------+
+ * | $A000        | Bit 0 starts a capture, reads as busy |
+ * | $A001-$A005  | Gain, exposure and edge settings      |
+ * | $A006-$A035  | 4x4 dithering matrix, 3 thresholds    |
+ * |              | per pixel                             |
+ * +--------------+---------------------------------------+
+ *
+ * A capture converts the sensor image to four shades by comparing each pixel
+ * against the thresholds for its position in the matrix, then stores it as
+ * tile data in RAM. Gain, exposure and edge enhancement aren't emulated, so the
+ * source is expected to provide an already exposed image.
+ */
+pub struct Camera {
+    regs: [u8; NUM_REGS],
+    source: Option<Box<dyn CameraSource>>,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Camera {
+    pub fn new() -> Self {
+        Self {
+            regs: [0; NUM_REGS],
+            source: None,
+        }
+    }
+
+    pub fn set_source(&mut self, source: Option<Box<dyn CameraSource>>) {
+        self.source = source;
+    }
+
+    /// Clears the registers, leaving the source plugged in
+    pub fn reset(&mut self) {
+        self.regs = [0; NUM_REGS];
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.regs);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.regs = state.read_array()?;
+        Ok(())
+    }
+
+    pub fn read_reg(&self, addr: u16) -> u8 {
+        // Only the capture register can be read back, the rest are write-only
+        if (addr & REGS_MASK) as usize == CAPTURE_REG {
+            self.regs[CAPTURE_REG]
+        } else {
+            0x00
+        }
+    }
+
+    pub fn write_reg(&mut self, addr: u16, val: u8, ram: &mut [u8]) {
+        let reg = (addr & REGS_MASK) as usize;
+        if reg >= NUM_REGS {
+            return;
+        }
+
+        self.regs[reg] = val;
+        if reg == CAPTURE_REG && val.get_bit(CAPTURE_BIT) {
+            self.capture(ram);
+            // The capture completes instantly, so the game will never see it busy
+            self.regs[CAPTURE_REG].set_bit(CAPTURE_BIT, false);
+        }
+    }
+
+    fn capture(&mut self, ram: &mut [u8]) {
+        let image = match &mut self.source {
+            Some(source) => source.capture(),
+            None => [0xFF; CAMERA_WIDTH * CAMERA_HEIGHT],
+        };
+
+        for y in 0..CAMERA_HEIGHT {
+            for x in 0..CAMERA_WIDTH {
+                let shade = self.dither(x, y, image[y * CAMERA_WIDTH + x]);
+                let tile = (y / 8) * TILES_PER_ROW + (x / 8);
+                let addr = IMAGE_RAM_START + tile * TILE_SIZE + (y % 8) * 2;
+                let bit = 7 - (x % 8) as u8;
+                ram[addr].set_bit(bit, shade.get_bit(0));
+                ram[addr + 1].set_bit(bit, shade.get_bit(1));
+            }
+        }
+    }
+
+    fn dither(&self, x: usize, y: usize, brightness: u8) -> u8 {
+        let idx = DITHER_START + ((y % 4) * 4 + (x % 4)) * 3;
+        let thresholds = &self.regs[idx..(idx + 3)];
+        if brightness < thresholds[0] {
+            3
+        } else if brightness < thresholds[1] {
+            2
+        } else if brightness < thresholds[2] {
+            1
+        } else {
+            0
+        }
+    }
+}
@@ -0,0 +1,354 @@
+use crate::state::{StateError, StateReader, StateWriter};
+use crate::utils::BitOps;
+
+pub const EEPROM_SIZE: usize    = 256;
+
+const ACCEL_CENTER: u16         = 0x81D0;
+// Change in the reading for a full 1g tilt
+const ACCEL_GRAVITY: f32        = 0x70 as f32;
+const ACCEL_RESET: u16          = 0x8000;
+
+const ACCEL_ERASE_REG: u8       = 0x0;
+const ACCEL_LATCH_REG: u8       = 0x1;
+const ACCEL_X_LOW_REG: u8       = 0x2;
+const ACCEL_X_HIGH_REG: u8      = 0x3;
+const ACCEL_Y_LOW_REG: u8       = 0x4;
+const ACCEL_Y_HIGH_REG: u8      = 0x5;
+const UNUSED_ZERO_REG: u8       = 0x6;
+const EEPROM_REG: u8            = 0x8;
+
+const EEPROM_DO_BIT: u8         = 0;
+const EEPROM_DI_BIT: u8         = 1;
+const EEPROM_CLK_BIT: u8        = 6;
+const EEPROM_CS_BIT: u8         = 7;
+
+const COMMAND_BITS: u8          = 10;
+const WORD_BITS: u8             = 16;
+
+#[derive(Clone, Copy, PartialEq)]
+enum EepromState {
+    Idle,
+    Command,
+    Read,
+    Write(u8),
+    WriteAll,
+}
+
+/*
+ * MBC7
+ *
+ * Used only by Kirby Tilt 'n' Tumble and Command Master. Rather than RAM, the
+ * $A000-$AFFF area holds a set of registers, selected by bits 4-7 of the address.
+ *
+ * +--------+----------------------------------------+
+ * | $Ax0x  | Write $55 to reset the accelerometer   |
+ * | $Ax1x  | Write $AA to latch the accelerometer   |
+ * | $Ax2x  | Accelerometer X low                    |
+ * | $Ax3x  | Accelerometer X high                   |
+ * | $Ax4x  | Accelerometer Y low                    |
+ * | $Ax5x  | Accelerometer Y high                   |
+ * | $Ax6x  | Always $00                             |
+ * | $Ax8x  | EEPROM pins (CS, CLK, DI, DO)          |
+ * +--------+----------------------------------------+
+ *
+ * Saves are stored on a 93LC56 serial EEPROM, holding 128 16-bit words. It's
+ * driven by toggling its pins, with each command starting with a 1 bit,
+ * followed by a 2-bit opcode and 8-bit address (of which only 7 are used).
+ *
+ * +----------+-------------+------------------------------+
+ * | READ     | 10 AAAAAAAA | Shift out the word at A      |
+ * | WRITE    | 01 AAAAAAAA | Shift in a word to write at A|
+ * | ERASE    | 11 AAAAAAAA | Set the word at A to $FFFF   |
+ * | EWEN     | 00 11xxxxxx | Enable writing               |
+ * | EWDS     | 00 00xxxxxx | Disable writing              |
+ * | ERAL     | 00 10xxxxxx | Set all words to $FFFF       |
+ * | WRAL     | 00 01xxxxxx | Shift in a word to write all |
+ * +----------+-------------+------------------------------+
+ */
+pub struct Mbc7 {
+    regs_enabled: bool,
+    tilt: (f32, f32),
+    accel_x: u16,
+    accel_y: u16,
+    accel_latched: bool,
+    cs: bool,
+    clk: bool,
+    di: bool,
+    do_bit: bool,
+    write_enabled: bool,
+    state: EepromState,
+    shift: u16,
+    bits: u8,
+}
+
+impl Default for Mbc7 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mbc7 {
+    pub fn new() -> Self {
+        Self {
+            regs_enabled: false,
+            tilt: (0.0, 0.0),
+            accel_x: ACCEL_RESET,
+            accel_y: ACCEL_RESET,
+            accel_latched: false,
+            cs: false,
+            clk: false,
+            di: false,
+            do_bit: true,
+            write_enabled: false,
+            state: EepromState::Idle,
+            shift: 0,
+            bits: 0,
+        }
+    }
+
+    /// Sets the tilt of the cartridge in g, with positive values to the right and towards the player
+    pub fn set_tilt(&mut self, x: f32, y: f32) {
+        self.tilt = (x.clamp(-1.0, 1.0), y.clamp(-1.0, 1.0));
+    }
+
+    /// Clears the registers and the EEPROM's interface, whose contents live in the cart RAM
+    pub fn reset(&mut self) {
+        *self = Self {
+            tilt: self.tilt,
+            ..Self::new()
+        };
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.regs_enabled
+    }
+
+    /// The registers require a second enable, written to $4000-$5FFF
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.regs_enabled = enabled;
+    }
+
+    // The tilt is an input from the frontend, so isn't saved
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.regs_enabled);
+        state.write_u16(self.accel_x);
+        state.write_u16(self.accel_y);
+        state.write_bool(self.accel_latched);
+        state.write_bool(self.cs);
+        state.write_bool(self.clk);
+        state.write_bool(self.di);
+        state.write_bool(self.do_bit);
+        state.write_bool(self.write_enabled);
+        let (kind, addr) = match self.state {
+            EepromState::Idle =>        { (0, 0) },
+            EepromState::Command =>     { (1, 0) },
+            EepromState::Read =>        { (2, 0) },
+            EepromState::Write(addr) => { (3, addr) },
+            EepromState::WriteAll =>    { (4, 0) },
+        };
+        state.write_u8(kind);
+        state.write_u8(addr);
+        state.write_u16(self.shift);
+        state.write_u8(self.bits);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.regs_enabled = state.read_bool()?;
+        self.accel_x = state.read_u16()?;
+        self.accel_y = state.read_u16()?;
+        self.accel_latched = state.read_bool()?;
+        self.cs = state.read_bool()?;
+        self.clk = state.read_bool()?;
+        self.di = state.read_bool()?;
+        self.do_bit = state.read_bool()?;
+        self.write_enabled = state.read_bool()?;
+        let kind = state.read_u8()?;
+        let addr = state.read_u8()?;
+        self.state = match kind {
+            0 => { EepromState::Idle },
+            1 => { EepromState::Command },
+            2 => { EepromState::Read },
+            3 => { EepromState::Write(addr & 0x7F) },
+            4 => { EepromState::WriteAll },
+            _ => { return Err(StateError::Invalid("unknown EEPROM state")) },
+        };
+        self.shift = state.read_u16()?;
+        self.bits = state.read_u8()?;
+        let max_bits = match self.state {
+            EepromState::Idle => { u8::MAX },
+            EepromState::Command => { COMMAND_BITS },
+            _ => { WORD_BITS },
+        };
+        // Any further would never reach the end of the transfer
+        if self.bits >= max_bits {
+            return Err(StateError::Invalid("EEPROM transfer out of range"));
+        }
+        Ok(())
+    }
+
+    pub fn read_reg(&self, addr: u16) -> u8 {
+        let reg = ((addr >> 4) & 0x0F) as u8;
+        match reg {
+            ACCEL_X_LOW_REG =>  { self.accel_x as u8 },
+            ACCEL_X_HIGH_REG => { (self.accel_x >> 8) as u8 },
+            ACCEL_Y_LOW_REG =>  { self.accel_y as u8 },
+            ACCEL_Y_HIGH_REG => { (self.accel_y >> 8) as u8 },
+            UNUSED_ZERO_REG =>  { 0x00 },
+            EEPROM_REG => {
+                let mut ret = 0;
+                ret.set_bit(EEPROM_CS_BIT, self.cs);
+                ret.set_bit(EEPROM_CLK_BIT, self.clk);
+                ret.set_bit(EEPROM_DI_BIT, self.di);
+                ret.set_bit(EEPROM_DO_BIT, self.do_bit);
+                ret
+            },
+            _ => { 0xFF }
+        }
+    }
+
+    pub fn write_reg(&mut self, addr: u16, val: u8, eeprom: &mut [u8]) {
+        let reg = ((addr >> 4) & 0x0F) as u8;
+        match reg {
+            ACCEL_ERASE_REG if val == 0x55 => {
+                self.accel_x = ACCEL_RESET;
+                self.accel_y = ACCEL_RESET;
+                self.accel_latched = false;
+            },
+            ACCEL_LATCH_REG if val == 0xAA && !self.accel_latched => {
+                self.accel_x = (ACCEL_CENTER as f32 + self.tilt.0 * ACCEL_GRAVITY) as u16;
+                self.accel_y = (ACCEL_CENTER as f32 + self.tilt.1 * ACCEL_GRAVITY) as u16;
+                self.accel_latched = true;
+            },
+            EEPROM_REG => {
+                self.write_eeprom_pins(val, eeprom);
+            },
+            _ => {}
+        }
+    }
+
+    fn write_eeprom_pins(&mut self, val: u8, eeprom: &mut [u8]) {
+        let cs = val.get_bit(EEPROM_CS_BIT);
+        let clk = val.get_bit(EEPROM_CLK_BIT);
+        self.di = val.get_bit(EEPROM_DI_BIT);
+
+        // Deselecting the chip aborts whatever command was in progress
+        if !cs {
+            self.state = EepromState::Idle;
+        } else if clk && !self.clk {
+            self.clock_eeprom(eeprom);
+        }
+        self.cs = cs;
+        self.clk = clk;
+    }
+
+    // Handles a rising edge on the EEPROM clock pin
+    fn clock_eeprom(&mut self, eeprom: &mut [u8]) {
+        match self.state {
+            EepromState::Idle => {
+                // Commands begin with a start bit of 1
+                if self.di {
+                    self.state = EepromState::Command;
+                    self.shift = 0;
+                    self.bits = 0;
+                }
+            },
+            EepromState::Command => {
+                self.shift_in();
+                if self.bits == COMMAND_BITS {
+                    self.run_command(eeprom);
+                }
+            },
+            EepromState::Read => {
+                self.do_bit = self.shift.get_bit(15);
+                self.shift <<= 1;
+                self.bits += 1;
+                if self.bits == WORD_BITS {
+                    self.state = EepromState::Idle;
+                }
+            },
+            EepromState::Write(addr) => {
+                self.shift_in();
+                if self.bits == WORD_BITS {
+                    if self.write_enabled {
+                        write_word(eeprom, addr, self.shift);
+                    }
+                    self.finish_write();
+                }
+            },
+            EepromState::WriteAll => {
+                self.shift_in();
+                if self.bits == WORD_BITS {
+                    if self.write_enabled {
+                        for addr in 0..(EEPROM_SIZE / 2) {
+                            write_word(eeprom, addr as u8, self.shift);
+                        }
+                    }
+                    self.finish_write();
+                }
+            },
+        }
+    }
+
+    fn shift_in(&mut self) {
+        self.shift = (self.shift << 1) | (self.di as u16);
+        self.bits += 1;
+    }
+
+    fn finish_write(&mut self) {
+        // Writes complete instantly, so DO immediately reports the chip is ready
+        self.do_bit = true;
+        self.state = EepromState::Idle;
+    }
+
+    fn run_command(&mut self, eeprom: &mut [u8]) {
+        let opcode = (self.shift >> 8) & 0b11;
+        let addr = (self.shift & 0x7F) as u8;
+        self.state = EepromState::Idle;
+        self.bits = 0;
+
+        match opcode {
+            0b10 => {
+                // A dummy 0 bit is output before the data
+                self.do_bit = false;
+                self.shift = read_word(eeprom, addr);
+                self.state = EepromState::Read;
+            },
+            0b01 => {
+                self.shift = 0;
+                self.state = EepromState::Write(addr);
+            },
+            0b11 => {
+                if self.write_enabled {
+                    write_word(eeprom, addr, 0xFFFF);
+                }
+                self.do_bit = true;
+            },
+            _ => {
+                match (self.shift >> 6) & 0b11 {
+                    0b11 => { self.write_enabled = true; },
+                    0b00 => { self.write_enabled = false; },
+                    0b10 => {
+                        if self.write_enabled {
+                            eeprom.fill(0xFF);
+                        }
+                        self.do_bit = true;
+                    },
+                    _ => {
+                        self.shift = 0;
+                        self.state = EepromState::WriteAll;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn read_word(eeprom: &[u8], addr: u8) -> u16 {
+    let idx = 2 * addr as usize;
+    u16::from_le_bytes([eeprom[idx], eeprom[idx + 1]])
+}
+
+fn write_word(eeprom: &mut [u8], addr: u8, val: u16) {
+    let idx = 2 * addr as usize;
+    eeprom[idx..(idx + 2)].copy_from_slice(&val.to_le_bytes());
+}
@@ -1,8 +1,16 @@
+pub mod camera;
+mod mbc7;
 mod rtc;
 
+use std::fmt;
 use std::str::from_utf8;
 
-use rtc::Rtc;
+use camera::{Camera, CameraSource};
+use mbc7::{Mbc7, EEPROM_SIZE};
+use rtc::{Rtc, RTC_SAVE_SIZE_OLD};
+use crate::error::GbError;
+use crate::state::bess::INFO_SIZE;
+use crate::state::{Region, StateError, StateReader, StateWriter};
 use crate::utils::BitOps;
 
 pub const ROM_START: u16        = 0x0000;
@@ -24,24 +32,109 @@ const ROM_BANK_LOW_STOP: u16    = 0x2FFF;
 const ROM_BANK_HIGH_START: u16  = 0x3000;
 const ROM_BANK_HIGH_STOP: u16   = 0x3FFF;
 
+const LOGO_START: usize         = 0x0104;
+const LOGO_STOP: usize          = 0x0133;
 const TITLE_START: usize        = 0x0134;
 const TITLE_STOP: usize         = 0x0142;
+const CGB_FLAG_ADDR: usize      = 0x0143;
+const NEW_LICENSEE_ADDR: usize  = 0x0144;
+const SGB_FLAG_ADDR: usize      = 0x0146;
 const CART_TYPE_ADDR: usize     = 0x0147;
+const ROM_SIZE_ADDR: usize      = 0x0148;
 const RAM_SIZE_ADDR: usize      = 0x0149;
+const DESTINATION_ADDR: usize   = 0x014A;
+const OLD_LICENSEE_ADDR: usize  = 0x014B;
+const VERSION_ADDR: usize       = 0x014C;
+const HEADER_SUM_ADDR: usize    = 0x014D;
+const GLOBAL_SUM_ADDR: usize    = 0x014E;
+const HEADER_SIZE: usize        = 0x0150;
 
 const ROM_BANK_SIZE: usize      = 0x4000;
 const RAM_BANK_SIZE: usize      = 0x2000;
 
 const MBC2_ROM_CONTROL_BIT: u8  = 8;
+const MBC5_RUMBLE_BIT: u8       = 3;
+const MBC7_REGS_STOP: u16       = 0xAFFF;
+const CAMERA_REGS_BIT: u8       = 4;
+const MBC2_RAM_SIZE: usize      = 0x200;
+const CAMERA_RAM_SIZE: usize    = 0x20000;
 
-#[derive(Clone, Copy, PartialEq)]
+// MBC1 multicarts are 8 Mbit, with a game (or the menu) every 16 banks
+const MBC1M_ROM_SIZE: usize     = 0x10_0000;
+const MBC1M_GAME_BANKS: usize   = 0x10;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum MBC {
     NONE,
     MBC1,
     MBC2,
     MBC3,
     MBC5,
-    INV,
+    MBC7,
+    CAMERA,
+}
+
+/// Which feature set a cartridge expects, based on the CGB flag in its header
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HardwareMode {
+    /// Original Game Boy only
+    Dmg,
+    /// Enhanced for the CGB, but still runs on the original hardware
+    CgbCompat,
+    /// Requires a CGB
+    CgbOnly,
+}
+
+impl HardwareMode {
+    pub fn is_cgb(&self) -> bool {
+        *self != HardwareMode::Dmg
+    }
+}
+
+/// The global checksum stored in a ROM's header, and the one calculated from its contents
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct GlobalChecksum {
+    pub stored: u16,
+    pub computed: u16,
+}
+
+impl GlobalChecksum {
+    pub fn is_valid(&self) -> bool {
+        self.stored == self.computed
+    }
+}
+
+// An old licensee code of $33 means the new code should be used instead
+const USE_NEW_LICENSEE: u8      = 0x33;
+const MIN_ROM_SIZE: usize       = 0x8000;
+
+/// A summary of the cartridge header, for display in frontends
+#[derive(Clone, Debug)]
+pub struct CartInfo {
+    pub title: String,
+    pub cart_type: u8,
+    pub mbc: MBC,
+    pub rom_size: usize,
+    pub ram_size: usize,
+    pub has_battery: bool,
+    pub licensee: String,
+    pub japanese: bool,
+    pub hardware_mode: HardwareMode,
+    pub sgb: bool,
+    pub version: u8,
+}
+
+impl fmt::Display for CartInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Title: {}", self.title)?;
+        writeln!(f, "Type: ${:02X} ({:?}{})", self.cart_type, self.mbc, if self.has_battery { ", battery" } else { "" })?;
+        writeln!(f, "ROM: {} KiB", self.rom_size / 1024)?;
+        writeln!(f, "RAM: {} KiB", self.ram_size / 1024)?;
+        writeln!(f, "Licensee: {}", self.licensee)?;
+        writeln!(f, "Destination: {}", if self.japanese { "Japan" } else { "Overseas" })?;
+        writeln!(f, "Hardware: {:?}{}", self.hardware_mode, if self.sgb { ", SGB" } else { "" })?;
+        write!(f, "Version: {}", self.version)
+    }
 }
 
 const RAM_SIZES: [usize; 6] = [
@@ -98,8 +191,21 @@ pub struct Cart {
     ram_bank: u8,
     mbc: MBC,
     rtc: Rtc,
+    mbc7: Mbc7,
+    camera: Camera,
     rom_mode: bool,
     ram_enabled: bool,
+    mbc1_low_bank: u8,
+    mbc1_high_bank: u8,
+    zero_bank: u16,
+    multicart: bool,
+    rumble: bool,
+}
+
+impl Default for Cart {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Cart {
@@ -111,30 +217,122 @@ impl Cart {
             ram_bank: 0,
             mbc: MBC::NONE,
             rtc: Rtc::new(),
+            mbc7: Mbc7::new(),
+            camera: Camera::new(),
             rom_mode: true,
             ram_enabled: false,
+            mbc1_low_bank: 1,
+            mbc1_high_bank: 0,
+            zero_bank: 0,
+            multicart: false,
+            rumble: false,
+        }
+    }
+
+    /// Returns the details listed in the cartridge header
+    pub fn get_info(&self) -> CartInfo {
+        let licensee = if self.header(OLD_LICENSEE_ADDR) == USE_NEW_LICENSEE {
+            let code = [self.header(NEW_LICENSEE_ADDR), self.header(NEW_LICENSEE_ADDR + 1)];
+            String::from_utf8_lossy(&code).to_string()
+        } else {
+            format!("{:02X}", self.header(OLD_LICENSEE_ADDR))
+        };
+
+        CartInfo {
+            title: self.get_title().to_string(),
+            cart_type: self.header(CART_TYPE_ADDR),
+            mbc: self.mbc,
+            rom_size: MIN_ROM_SIZE.checked_shl(self.header(ROM_SIZE_ADDR) as u32).unwrap_or(0),
+            ram_size: self.ram.len(),
+            has_battery: self.has_battery(),
+            licensee,
+            japanese: self.header(DESTINATION_ADDR) == 0x00,
+            hardware_mode: self.get_hardware_mode(),
+            sgb: self.supports_sgb(),
+            version: self.header(VERSION_ADDR),
+        }
+    }
+
+    /// Returns the cartridge RAM, followed by the clock state for carts with an RTC
+    pub fn get_battery_data(&self) -> Vec<u8> {
+        let mut data = self.ram.clone();
+        if self.has_rtc() {
+            data.extend(self.rtc.save());
         }
+        data
     }
 
-    pub fn get_battery_data(&self) -> &[u8] {
-        &self.ram
+    // Reads a byte of the header, which is 0 while there's no cartridge in
+    fn header(&self, addr: usize) -> u8 {
+        self.rom.get(addr).copied().unwrap_or(0)
     }
 
-    fn get_mbc(&self) -> MBC {
-        let cart_type = self.rom[CART_TYPE_ADDR];
+    // Returns None for mappers which aren't emulated
+    fn get_mbc(cart_type: u8) -> Option<MBC> {
         match cart_type {
-            0x00 =>         { MBC::NONE },
-            0x01..=0x03 =>  { MBC::MBC1 },
-            0x05..=0x06 =>  { MBC::MBC2 },
-            0x0F..=0x13 =>  { MBC::MBC3 },
-            0x19..=0x1E =>  { MBC::MBC5 },
-            _ =>            { MBC::INV },
+            0x00 =>         { Some(MBC::NONE)   },
+            0x01..=0x03 =>  { Some(MBC::MBC1)   },
+            0x05..=0x06 =>  { Some(MBC::MBC2)   },
+            0x0F..=0x13 =>  { Some(MBC::MBC3)   },
+            0x19..=0x1E =>  { Some(MBC::MBC5)   },
+            0x22 =>         { Some(MBC::MBC7)   },
+            0xFC =>         { Some(MBC::CAMERA) },
+            _ =>            { None              },
         }
     }
 
+    /// Multicarts don't mark themselves in the header, but each game within has its own copy of the Nintendo logo
+    fn is_multicart(&self) -> bool {
+        if self.mbc != MBC::MBC1 || self.rom.len() != MBC1M_ROM_SIZE {
+            return false;
+        }
+
+        let logo = &self.rom[LOGO_START..=LOGO_STOP];
+        let num_games = (1..MBC1M_ROM_SIZE / (MBC1M_GAME_BANKS * ROM_BANK_SIZE)).filter(|game| {
+            let offset = game * MBC1M_GAME_BANKS * ROM_BANK_SIZE;
+            &self.rom[(offset + LOGO_START)..=(offset + LOGO_STOP)] == logo
+        }).count();
+        // The menu plus at least one more game
+        num_games > 1
+    }
+
+    /// Checks the header bytes $0134-$014C against the checksum at $014D, which the boot ROM requires to match
+    pub fn verify_header_checksum(&self) -> bool {
+        if self.rom.len() <= HEADER_SUM_ADDR {
+            return false;
+        }
+
+        let checksum = self.rom[TITLE_START..=VERSION_ADDR].iter().fold(0u8, |sum, byte| {
+            sum.wrapping_sub(*byte).wrapping_sub(1)
+        });
+        checksum == self.rom[HEADER_SUM_ADDR]
+    }
+
+    /// Sums every byte of the ROM other than the checksum itself. Real hardware never checks this value
+    pub fn get_global_checksum(&self) -> GlobalChecksum {
+        if self.rom.len() <= GLOBAL_SUM_ADDR + 1 {
+            return GlobalChecksum { stored: 0, computed: 0 };
+        }
+
+        let stored = u16::from_be_bytes([self.rom[GLOBAL_SUM_ADDR], self.rom[GLOBAL_SUM_ADDR + 1]]);
+        let computed = self.rom.iter().enumerate()
+            .filter(|(addr, _)| *addr != GLOBAL_SUM_ADDR && *addr != GLOBAL_SUM_ADDR + 1)
+            .fold(0u16, |sum, (_, byte)| sum.wrapping_add(*byte as u16));
+        GlobalChecksum { stored, computed }
+    }
+
+    pub fn verify_global_checksum(&self) -> bool {
+        self.get_global_checksum().is_valid()
+    }
+
     pub fn get_title(&self) -> &str {
-        let data = &self.rom[TITLE_START..TITLE_STOP];
-        from_utf8(data).unwrap().trim_end_matches(char::from(0))
+        let data = self.rom.get(TITLE_START..TITLE_STOP).unwrap_or_default();
+        // Anything which isn't text, as in some unlicensed games, ends the title early
+        let title = match from_utf8(data) {
+            Ok(title) => { title },
+            Err(err) => { from_utf8(&data[..err.valid_up_to()]).unwrap_or_default() },
+        };
+        title.trim_end_matches(char::from(0))
     }
 
     pub fn has_battery(&self) -> bool {
@@ -142,12 +340,50 @@ impl Cart {
             0x03, 0x06, 0x09,
             0x0D, 0x0F, 0x10,
             0x13, 0x1B, 0x1E,
+            0x22, 0xFC,
         ];
 
-        let cart_type = self.rom[CART_TYPE_ADDR];
+        let cart_type = self.header(CART_TYPE_ADDR);
         has_battery.contains(&cart_type)
     }
 
+    fn has_rumble(&self) -> bool {
+        let cart_type = self.header(CART_TYPE_ADDR);
+        (0x1C..=0x1E).contains(&cart_type)
+    }
+
+    /// Whether the game currently has the rumble motor switched on
+    pub fn is_rumbling(&self) -> bool {
+        self.rumble
+    }
+
+    fn has_rtc(&self) -> bool {
+        let cart_type = self.header(CART_TYPE_ADDR);
+        cart_type == 0x0F || cart_type == 0x10
+    }
+
+    /// Sets where the Game Boy Camera gets its pictures from, or None for a blank image
+    pub fn set_camera_source(&mut self, source: Option<Box<dyn CameraSource>>) {
+        self.camera.set_source(source);
+    }
+
+    /// Sets the accelerometer reading for MBC7 carts, in g along each axis
+    pub fn set_tilt(&mut self, x: f32, y: f32) {
+        self.mbc7.set_tilt(x, y);
+    }
+
+    pub fn supports_sgb(&self) -> bool {
+        self.rom.get(SGB_FLAG_ADDR) == Some(&0x03)
+    }
+
+    pub fn get_hardware_mode(&self) -> HardwareMode {
+        match self.rom.get(CGB_FLAG_ADDR) {
+            Some(0x80) => { HardwareMode::CgbCompat },
+            Some(0xC0) => { HardwareMode::CgbOnly },
+            _ =>          { HardwareMode::Dmg },
+        }
+    }
+
     fn has_external_ram(&self) -> bool {
         let has_ext_ram = [
             0x02, 0x03, 0x08,
@@ -155,14 +391,15 @@ impl Cart {
             0x10, 0x12, 0x13,
             0x16, 0x17, 0x1A,
             0x1B, 0x1D, 0x1E,
+            0xFC,
         ];
 
-        let cart_type = self.rom[CART_TYPE_ADDR];
+        let cart_type = self.header(CART_TYPE_ADDR);
         has_ext_ram.contains(&cart_type)
     }
 
     fn init_ext_ram(&mut self) {
-        let mut ram_size_idx = self.rom[RAM_SIZE_ADDR] as usize;
+        let mut ram_size_idx = self.header(RAM_SIZE_ADDR) as usize;
 
         // Some headers don't report their external RAM capacity correctly
         if self.has_external_ram() && ram_size_idx == 0 {
@@ -170,58 +407,260 @@ impl Cart {
         }
 
         if self.mbc == MBC::MBC2 {
-            // MBC2 always has 512 bytes of RAM directly on chip
-            self.ram = vec![0; 512];
+            // MBC2 always has 512 half-bytes of RAM directly on chip
+            self.ram = vec![0; MBC2_RAM_SIZE];
+        } else if self.mbc == MBC::MBC7 {
+            // MBC7 saves to an EEPROM, which starts erased
+            self.ram = vec![0xFF; EEPROM_SIZE];
+        } else if self.mbc == MBC::CAMERA {
+            // The camera always has 128 KiB, which captures are stored in no matter what the header says
+            self.ram = vec![0; CAMERA_RAM_SIZE];
         } else {
-            let ram_size = RAM_SIZES[ram_size_idx] * 1024;
+            let ram_size = RAM_SIZES.get(ram_size_idx).copied().unwrap_or(0) * 1024;
             self.ram = vec![0; ram_size];
         }
     }
 
-    pub fn load_cart(&mut self, rom: &[u8]) {
+    /// Puts the mapper back how it is at power on, keeping the ROM, saved data, and clock
+    pub fn reset(&mut self) {
+        self.rom_bank = 1;
+        self.ram_bank = 0;
+        self.rom_mode = true;
+        self.ram_enabled = false;
+        self.mbc1_low_bank = 1;
+        self.mbc1_high_bank = 0;
+        self.zero_bank = 0;
+        self.rumble = false;
+        self.mbc7.reset();
+        self.camera.reset();
+    }
+
+    /// Inserts a game, or leaves the current one in place if it can't be run
+    pub fn load_cart(&mut self, rom: &[u8]) -> Result<(), GbError> {
+        if rom.len() < HEADER_SIZE {
+            return Err(GbError::RomTooSmall(rom.len()));
+        }
+        let cart_type = rom[CART_TYPE_ADDR];
+        self.mbc = Self::get_mbc(cart_type).ok_or(GbError::UnsupportedCartridge(cart_type))?;
         self.rom = rom.to_vec();
-        self.mbc = self.get_mbc();
+        self.multicart = self.is_multicart();
         self.init_ext_ram();
+        Ok(())
     }
 
     pub fn read_cart(&self, addr: u16) -> u8 {
-        if (addr as usize) < ROM_BANK_SIZE {
-            self.rom[addr as usize]
-        } else {
-            let rel_addr = (addr as usize) - ROM_BANK_SIZE;
-            let bank_addr = (self.rom_bank as usize) * ROM_BANK_SIZE + rel_addr;
-            self.rom[bank_addr]
+        // With no cartridge in, nothing drives the data bus
+        if self.rom.is_empty() {
+            return 0xFF;
         }
+        let (bank, rel_addr) = if (addr as usize) < ROM_BANK_SIZE {
+            (self.zero_bank, addr as usize)
+        } else {
+            (self.rom_bank, (addr as usize) - ROM_BANK_SIZE)
+        };
+        // Bank numbers larger than the ROM wrap around, as the extra bits aren't wired up
+        let bank_addr = (bank as usize) * ROM_BANK_SIZE + rel_addr;
+        self.rom[bank_addr % self.rom.len()]
+    }
+
+    /// Returns which ROM bank is mapped to the given address, wrapped to the banks the ROM actually has
+    pub fn get_rom_bank(&self, addr: u16) -> u16 {
+        let bank = if (addr as usize) < ROM_BANK_SIZE { self.zero_bank } else { self.rom_bank };
+        let num_banks = (self.rom.len() / ROM_BANK_SIZE).max(1);
+        ((bank as usize) % num_banks) as u16
     }
 
     pub fn read_ram(&self, addr: u16) -> u8 {
         match self.mbc {
-            MBC::NONE | MBC::MBC1 | MBC::MBC2 | MBC::MBC5 => {
+            // Without a mapper there's nothing to disable the RAM
+            MBC::NONE => {
                 self.read_ram_helper(addr)
             },
+            MBC::MBC1 | MBC::MBC5 => {
+                self.read_enabled_ram(addr)
+            },
+            MBC::MBC2 => {
+                self.mbc2_read_ram(addr)
+            },
             MBC::MBC3 => {
                 self.mbc3_read_ram(addr)
-            }
-            _ => unimplemented!()
+            },
+            MBC::MBC7 => {
+                self.mbc7_read_ram(addr)
+            },
+            MBC::CAMERA => {
+                self.camera_read_ram(addr)
+            },
         }
     }
 
+    fn mbc2_read_ram(&self, addr: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        // Only the lower nibble is stored, the upper bits are left floating
+        let rel_addr = (addr - EXT_RAM_START) as usize;
+        self.ram[rel_addr % MBC2_RAM_SIZE] | 0xF0
+    }
+
     fn mbc3_read_ram(&self, addr: u16) -> u8 {
-        if self.rtc.is_enabled() && (0x08 <= self.ram_bank && self.ram_bank <= 0x0C) {
+        if self.has_rtc() && self.ram_enabled && (0x08..=0x0C).contains(&self.ram_bank) {
             self.rtc.read_byte(self.ram_bank)
+        } else {
+            self.read_enabled_ram(addr)
+        }
+    }
+
+    fn mbc7_read_ram(&self, addr: u16) -> u8 {
+        if self.ram_enabled && self.mbc7.is_enabled() && addr <= MBC7_REGS_STOP {
+            self.mbc7.read_reg(addr)
+        } else {
+            0xFF
+        }
+    }
+
+    fn camera_read_ram(&self, addr: u16) -> u8 {
+        // Unlike other mappers, RAM can always be read, the enable only affects writes
+        if self.ram_bank.get_bit(CAMERA_REGS_BIT) {
+            self.camera.read_reg(addr)
         } else {
             self.read_ram_helper(addr)
         }
     }
 
+    // Disabled RAM isn't driven, so the data lines float high
+    fn read_enabled_ram(&self, addr: u16) -> u8 {
+        if self.ram_enabled {
+            self.read_ram_helper(addr)
+        } else {
+            0xFF
+        }
+    }
+
     fn read_ram_helper(&self, addr: u16) -> u8 {
+        if self.ram.is_empty() {
+            return 0xFF;
+        }
+
         let rel_addr = (addr - EXT_RAM_START) as usize;
         let bank_addr = (self.ram_bank as usize) * RAM_BANK_SIZE + rel_addr;
-        self.ram[bank_addr]
+        // Carts with less than a full bank of RAM mirror it
+        self.ram[bank_addr % self.ram.len()]
     }
 
     pub fn set_battery_data(&mut self, data: &[u8]) {
-        self.ram.copy_from_slice(data);
+        let ram_len = self.ram.len().min(data.len());
+        self.load_ram(&data[..ram_len]);
+        self.load_rtc(&data[ram_len..]);
+    }
+
+    /// Copies in saved cartridge RAM, with anything past its end ignored
+    pub fn load_ram(&mut self, data: &[u8]) {
+        let len = self.ram.len().min(data.len());
+        self.ram[..len].copy_from_slice(&data[..len]);
+    }
+
+    /// Returns the clock state in the same layout as battery saves, for carts with an RTC
+    pub fn get_rtc_data(&self) -> Option<Vec<u8>> {
+        self.has_rtc().then(|| self.rtc.save())
+    }
+
+    pub fn load_rtc(&mut self, data: &[u8]) {
+        if self.has_rtc() && data.len() >= RTC_SAVE_SIZE_OLD {
+            self.rtc.load(data);
+        }
+    }
+
+    /// Returns the title bytes ($0134-$0143) followed by the global checksum, which BESS uses to identify the game
+    pub fn get_bess_info(&self) -> [u8; INFO_SIZE] {
+        let mut info = [0; INFO_SIZE];
+        let header = TITLE_START..=CGB_FLAG_ADDR;
+        for (byte, addr) in info.iter_mut().zip(header.chain(GLOBAL_SUM_ADDR..=GLOBAL_SUM_ADDR + 1)) {
+            *byte = self.rom.get(addr).copied().unwrap_or(0);
+        }
+        info
+    }
+
+    /// Lists register writes which put the mapper back into its current state when replayed on a freshly loaded cart
+    pub fn get_mbc_writes(&self) -> Vec<(u16, u8)> {
+        let ram_enable = if self.ram_enabled { 0x0A } else { 0x00 };
+        match self.mbc {
+            MBC::MBC1 => {
+                vec![
+                    (RAM_ENABLE_START, ram_enable),
+                    (ROM_BANK_NUM_START, self.mbc1_low_bank),
+                    (RAM_BANK_NUM_START, self.mbc1_high_bank),
+                    (ROM_RAM_MODE_START, if self.rom_mode { 0x00 } else { 0x01 }),
+                ]
+            },
+            MBC::MBC2 => {
+                // Bit 8 of the address selects the ROM bank register
+                vec![
+                    (RAM_ENABLE_START, ram_enable),
+                    (RAM_ENABLE_START | (1 << MBC2_ROM_CONTROL_BIT), self.rom_bank as u8),
+                ]
+            },
+            MBC::MBC3 | MBC::CAMERA => {
+                vec![
+                    (RAM_ENABLE_START, ram_enable),
+                    (ROM_BANK_NUM_START, self.rom_bank as u8),
+                    (RAM_BANK_NUM_START, self.ram_bank),
+                ]
+            },
+            MBC::MBC5 => {
+                let mut ram_bank = self.ram_bank;
+                ram_bank.set_bit(MBC5_RUMBLE_BIT, self.rumble);
+                vec![
+                    (RAM_ENABLE_START, ram_enable),
+                    (ROM_BANK_LOW_START, self.rom_bank as u8),
+                    (ROM_BANK_HIGH_START, (self.rom_bank >> 8) as u8),
+                    (RAM_BANK_NUM_START, ram_bank),
+                ]
+            },
+            MBC::MBC7 => {
+                vec![
+                    (RAM_ENABLE_START, ram_enable),
+                    (ROM_BANK_NUM_START, self.rom_bank as u8),
+                    (RAM_BANK_NUM_START, if self.mbc7.is_enabled() { 0x40 } else { 0x00 }),
+                ]
+            },
+            MBC::NONE => { Vec::new() },
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u32(self.ram.len() as u32);
+        state.write_region(Region::CartRam, &self.ram);
+        state.write_u16(self.rom_bank);
+        state.write_u8(self.ram_bank);
+        state.write_bool(self.rom_mode);
+        state.write_bool(self.ram_enabled);
+        state.write_u8(self.mbc1_low_bank);
+        state.write_u8(self.mbc1_high_bank);
+        state.write_u16(self.zero_bank);
+        state.write_bool(self.rumble);
+        self.rtc.save_state(state);
+        self.mbc7.save_state(state);
+        self.camera.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let ram_len = state.read_u32()? as usize;
+        if ram_len != self.ram.len() {
+            return Err(StateError::Invalid("cartridge RAM size doesn't match"));
+        }
+        self.load_ram(state.read_bytes(ram_len)?);
+        self.rom_bank = state.read_u16()?;
+        self.ram_bank = state.read_u8()?;
+        self.rom_mode = state.read_bool()?;
+        self.ram_enabled = state.read_bool()?;
+        self.mbc1_low_bank = state.read_u8()?;
+        self.mbc1_high_bank = state.read_u8()?;
+        self.zero_bank = state.read_u16()?;
+        self.rumble = state.read_bool()?;
+        self.rtc.load_state(state)?;
+        self.mbc7.load_state(state)?;
+        self.camera.load_state(state)
     }
 
     pub fn write_cart(&mut self, addr: u16, val: u8) {
@@ -231,64 +670,83 @@ impl Cart {
             MBC::MBC2 => { self.mbc2_write_rom(addr, val); },
             MBC::MBC3 => { self.mbc3_write_rom(addr, val); },
             MBC::MBC5 => { self.mbc5_write_rom(addr, val); },
-            _ => unimplemented!()
+            MBC::MBC7 => { self.mbc7_write_rom(addr, val); },
+            MBC::CAMERA => { self.camera_write_rom(addr, val); },
         }
     }
 
     pub fn write_ram(&mut self, addr: u16, val: u8) {
         match self.mbc {
             MBC::NONE => {
-                let rel_addr = addr - EXT_RAM_START;
-                self.ram[rel_addr as usize] = val;
+                self.write_ram_unchecked(addr, val);
             },
             MBC::MBC1 | MBC::MBC5 => {
                 self.write_ram_helper(addr, val)
             },
+            MBC::MBC2 => self.mbc2_write_ram(addr, val),
             MBC::MBC3 => self.mbc3_write_ram(addr, val),
-            _ => unimplemented!()
+            MBC::MBC7 => self.mbc7_write_ram(addr, val),
+            MBC::CAMERA => self.camera_write_ram(addr, val),
         }
     }
 
     fn mbc1_write_rom(&mut self, addr: u16, val: u8) {
         match addr {
             RAM_ENABLE_START..=RAM_ENABLE_STOP => {
-                self.ram_enabled = val == 0x0A;
+                self.ram_enabled = (val & 0x0F) == 0x0A;
             },
             ROM_BANK_NUM_START..=ROM_BANK_NUM_STOP => {
-                let bank = (val & 0x1F) as u16;
-                match bank {
-                    // Bank numbers 0x00, 0x20, 0x40, 0x60 aren't used
-                    // Instead they load the next bank
-                    0x00 | 0x20 | 0x40 | 0x60 => {
-                        self.rom_bank = bank + 1;
-                    },
-                    _ => {
-                        self.rom_bank = bank;
-                    }
-                }
+                self.mbc1_low_bank = val & 0x1F;
             },
             RAM_BANK_NUM_START..=RAM_BANK_NUM_STOP => {
-                let bits = val & 0b11;
-
-                if self.rom_mode {
-                    self.rom_bank |= (bits << 5) as u16;
-                } else {
-                    self.ram_bank = bits;
-                }
+                self.mbc1_high_bank = val & 0b11;
             },
             ROM_RAM_MODE_START..=ROM_RAM_MODE_STOP => {
-                self.rom_mode = val == 0;
+                self.rom_mode = !val.get_bit(0);
             },
             _ => unreachable!()
         }
+        self.mbc1_update_banks();
+    }
+
+    /*
+     * MBC1 Bank Wiring
+     *
+     * The 5-bit low register selects the $4000-$7FFF bank, with 0 treated as 1.
+     * The 2-bit high register supplies the bits above it. In RAM banking mode,
+     * it also selects the RAM bank and the bank mapped to $0000-$3FFF.
+     *
+     * Multicarts don't connect bit 4 of the low register, so the high register
+     * is shifted down a bit to select between the 16-bank games instead.
+     */
+    fn mbc1_update_banks(&mut self) {
+        let (low_mask, high_shift) = if self.multicart { (0x0F, 4) } else { (0x1F, 5) };
+        // The check for bank 0 happens before the multicart wiring drops bit 4
+        let low = if self.mbc1_low_bank == 0 { 1 } else { self.mbc1_low_bank & low_mask };
+        let high = (self.mbc1_high_bank as u16) << high_shift;
+
+        self.rom_bank = high | (low as u16);
+        if self.rom_mode {
+            self.zero_bank = 0;
+            self.ram_bank = 0;
+        } else {
+            self.zero_bank = high;
+            self.ram_bank = self.mbc1_high_bank;
+        }
     }
 
     fn mbc2_write_rom(&mut self, addr: u16, val: u8) {
+        // The MBC2 registers are only mapped to the lower half of the ROM area
+        if addr > ROM_BANK_NUM_STOP {
+            return;
+        }
+
         let bank_swap = addr.get_bit(MBC2_ROM_CONTROL_BIT);
         if bank_swap {
-            self.rom_bank = (val & 0x0F) as u16;
+            let bank = (val & 0x0F) as u16;
+            self.rom_bank = if bank == 0 { 1 } else { bank };
         } else {
-            self.ram_enabled = val == 0x0A;
+            self.ram_enabled = (val & 0x0F) == 0x0A;
         }
     }
 
@@ -308,7 +766,7 @@ impl Cart {
                 self.ram_bank = val;
             },
             ROM_RAM_MODE_START..=ROM_RAM_MODE_STOP => {
-                self.rtc.write_byte(self.ram_bank, val);
+                self.rtc.write_latch(val);
             },
             _ => unreachable!()
         }
@@ -324,34 +782,323 @@ impl Cart {
                 self.rom_bank |= val as u16;
             },
             ROM_BANK_HIGH_START..=ROM_BANK_HIGH_STOP => {
-                self.rom_bank.set_bit(9, val != 0);
+                self.rom_bank.set_bit(8, val.get_bit(0));
             },
             RAM_BANK_NUM_START..=RAM_BANK_NUM_STOP => {
-                self.ram_bank = val & 0x0F;
+                // Rumble carts wire the highest RAM bank bit to the motor instead
+                if self.has_rumble() {
+                    self.rumble = val.get_bit(MBC5_RUMBLE_BIT);
+                    self.ram_bank = val & 0x07;
+                } else {
+                    self.ram_bank = val & 0x0F;
+                }
             },
             _ => unreachable!()
         }
     }
 
+    fn mbc2_write_ram(&mut self, addr: u16, val: u8) {
+        if self.ram_enabled {
+            // The 512 bytes of RAM are mirrored across the entire external RAM range
+            let rel_addr = (addr - EXT_RAM_START) as usize;
+            self.ram[rel_addr % MBC2_RAM_SIZE] = val & 0x0F;
+        }
+    }
+
+    fn mbc7_write_rom(&mut self, addr: u16, val: u8) {
+        match addr {
+            RAM_ENABLE_START..=RAM_ENABLE_STOP => {
+                self.ram_enabled = (val & 0x0F) == 0x0A;
+            },
+            ROM_BANK_NUM_START..=ROM_BANK_NUM_STOP => {
+                self.rom_bank = (val & 0x7F) as u16;
+            },
+            RAM_BANK_NUM_START..=RAM_BANK_NUM_STOP => {
+                self.mbc7.set_enabled(val == 0x40);
+            },
+            _ => {}
+        }
+    }
+
+    fn camera_write_rom(&mut self, addr: u16, val: u8) {
+        match addr {
+            RAM_ENABLE_START..=RAM_ENABLE_STOP => {
+                self.ram_enabled = (val & 0x0F) == 0x0A;
+            },
+            ROM_BANK_NUM_START..=ROM_BANK_NUM_STOP => {
+                self.rom_bank = (val & 0x3F) as u16;
+            },
+            RAM_BANK_NUM_START..=RAM_BANK_NUM_STOP => {
+                // Bit 4 maps the camera registers rather than a RAM bank
+                self.ram_bank = val & 0x1F;
+            },
+            _ => {}
+        }
+    }
+
     fn mbc3_write_ram(&mut self, addr: u16, val: u8) {
         match self.ram_bank {
             0x00..=0x03 => {
                 self.write_ram_helper(addr, val);
             },
-            0x08..=0x0C => {
-                if self.ram_enabled {
-                    self.rtc.write_byte(self.ram_bank, val);
-                }
+            0x08..=0x0C if self.has_rtc() && self.ram_enabled => {
+                self.rtc.write_byte(self.ram_bank, val);
             },
             _ => {}
         }
     }
 
+    fn mbc7_write_ram(&mut self, addr: u16, val: u8) {
+        if self.ram_enabled && self.mbc7.is_enabled() && addr <= MBC7_REGS_STOP {
+            self.mbc7.write_reg(addr, val, &mut self.ram);
+        }
+    }
+
+    fn camera_write_ram(&mut self, addr: u16, val: u8) {
+        if self.ram_bank.get_bit(CAMERA_REGS_BIT) {
+            self.camera.write_reg(addr, val, &mut self.ram);
+        } else {
+            self.write_ram_helper(addr, val);
+        }
+    }
+
     fn write_ram_helper(&mut self, addr: u16, val: u8) {
         if self.ram_enabled {
-            let rel_addr = (addr - EXT_RAM_START) as usize;
-            let ram_addr = (self.ram_bank as usize) * RAM_BANK_SIZE + rel_addr;
-            self.ram[ram_addr] = val;
+            self.write_ram_unchecked(addr, val);
+        }
+    }
+
+    fn write_ram_unchecked(&mut self, addr: u16, val: u8) {
+        if self.ram.is_empty() {
+            return;
+        }
+
+        let rel_addr = (addr - EXT_RAM_START) as usize;
+        let ram_addr = (self.ram_bank as usize) * RAM_BANK_SIZE + rel_addr;
+        let len = self.ram.len();
+        self.ram[ram_addr % len] = val;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A ROM whose banks each start with their own number, so reads show which one is mapped
+    fn banked_rom(cart_type: u8, rom_size: usize, ram_size_idx: u8) -> Vec<u8> {
+        let mut rom = vec![0; rom_size];
+        for (bank, data) in rom.chunks_mut(ROM_BANK_SIZE).enumerate() {
+            data[..2].copy_from_slice(&(bank as u16).to_le_bytes());
+        }
+        rom[CART_TYPE_ADDR] = cart_type;
+        rom[RAM_SIZE_ADDR] = ram_size_idx;
+        rom
+    }
+
+    fn load(rom: &[u8]) -> Cart {
+        let mut cart = Cart::new();
+        cart.load_cart(rom).unwrap();
+        cart
+    }
+
+    #[test]
+    fn mbc1_multicart_banking() {
+        let mut rom = banked_rom(0x01, MBC1M_ROM_SIZE, 0);
+        // Each game, including the menu, begins with its own copy of the logo
+        for game in 0..3 {
+            let offset = game * MBC1M_GAME_BANKS * ROM_BANK_SIZE;
+            rom[(offset + LOGO_START)..=(offset + LOGO_STOP)].fill(0xCE);
+        }
+        let mut cart = load(&rom);
+
+        cart.write_cart(RAM_BANK_NUM_START, 0x01);
+        cart.write_cart(ROM_BANK_NUM_START, 0x02);
+        assert_eq!(cart.read_cart(0x4000), 0x12);
+        // Bit 4 of the low register isn't wired, but still counts when checking for bank 0
+        cart.write_cart(ROM_BANK_NUM_START, 0x10);
+        assert_eq!(cart.read_cart(0x4000), 0x10);
+        cart.write_cart(ROM_BANK_NUM_START, 0x00);
+        assert_eq!(cart.read_cart(0x4000), 0x11);
+
+        // Mode 1 maps the start of the selected game at $0000
+        assert_eq!(cart.read_cart(0x0000), 0x00);
+        cart.write_cart(ROM_RAM_MODE_START, 0x01);
+        assert_eq!(cart.read_cart(0x0000), 0x10);
+    }
+
+    #[test]
+    fn mbc1_without_matching_logos_isnt_a_multicart() {
+        let mut rom = banked_rom(0x01, MBC1M_ROM_SIZE, 0);
+        rom[LOGO_START..=LOGO_STOP].fill(0xCE);
+        let mut cart = load(&rom);
+        cart.write_cart(RAM_BANK_NUM_START, 0x01);
+        cart.write_cart(ROM_BANK_NUM_START, 0x02);
+        assert_eq!(cart.read_cart(0x4000), 0x22);
+    }
+
+    #[test]
+    fn mbc2_banking_and_ram() {
+        let mut cart = load(&banked_rom(0x06, 0x40000, 0));
+
+        // Address bit 8 picks the ROM bank register rather than the RAM enable
+        cart.write_cart(0x2100, 0x03);
+        assert_eq!(cart.read_cart(0x4000), 0x03);
+        cart.write_cart(0x2100, 0x00);
+        assert_eq!(cart.read_cart(0x4000), 0x01);
+        cart.write_cart(0x4100, 0x05);
+        assert_eq!(cart.read_cart(0x4000), 0x01);
+
+        cart.write_ram(EXT_RAM_START, 0x0C);
+        cart.write_cart(0x2000, 0x0A);
+        assert_eq!(cart.read_cart(0x4000), 0x01);
+        assert_eq!(cart.read_ram(EXT_RAM_START), 0xF0);
+        cart.write_ram(EXT_RAM_START, 0xAB);
+        // Only half-bytes are stored, and the 512 of them are mirrored across the whole area
+        assert_eq!(cart.read_ram(EXT_RAM_START), 0xFB);
+        assert_eq!(cart.read_ram(EXT_RAM_START + MBC2_RAM_SIZE as u16), 0xFB);
+        assert_eq!(cart.get_battery_data().len(), MBC2_RAM_SIZE);
+    }
+
+    #[test]
+    fn mbc3_rtc_and_ram_banking() {
+        let mut cart = load(&banked_rom(0x10, 0x40000, 3));
+        cart.write_cart(RAM_ENABLE_START, 0x0A);
+
+        cart.write_cart(RAM_BANK_NUM_START, 0x01);
+        cart.write_ram(EXT_RAM_START, 0x11);
+        cart.write_cart(RAM_BANK_NUM_START, 0x00);
+        assert_eq!(cart.read_ram(EXT_RAM_START), 0x00);
+        cart.write_cart(RAM_BANK_NUM_START, 0x01);
+        assert_eq!(cart.read_ram(EXT_RAM_START), 0x11);
+
+        // Halting the clock first keeps it from ticking over partway through
+        cart.write_cart(RAM_BANK_NUM_START, 0x0C);
+        cart.write_ram(EXT_RAM_START, 0x40);
+        cart.write_cart(RAM_BANK_NUM_START, 0x08);
+        cart.write_ram(EXT_RAM_START, 30);
+        cart.write_cart(ROM_RAM_MODE_START, 0x00);
+        cart.write_cart(ROM_RAM_MODE_START, 0x01);
+        assert_eq!(cart.read_ram(EXT_RAM_START), 30);
+        cart.write_cart(RAM_BANK_NUM_START, 0x0C);
+        assert_eq!(cart.read_ram(EXT_RAM_START), 0x40);
+
+        // The clock is saved after the RAM, and comes back with it
+        let data = cart.get_battery_data();
+        assert_eq!(data.len(), 0x8000 + rtc::RTC_SAVE_SIZE);
+        let mut other = load(&banked_rom(0x10, 0x40000, 3));
+        other.set_battery_data(&data);
+        other.write_cart(RAM_ENABLE_START, 0x0A);
+        other.write_cart(RAM_BANK_NUM_START, 0x08);
+        assert_eq!(other.read_ram(EXT_RAM_START), 30);
+        other.write_cart(RAM_BANK_NUM_START, 0x01);
+        assert_eq!(other.read_ram(EXT_RAM_START), 0x11);
+    }
+
+    #[test]
+    fn mbc5_rumble_bit() {
+        let mut cart = load(&banked_rom(0x1E, 0x40000, 3));
+        cart.write_cart(RAM_ENABLE_START, 0x0A);
+        cart.write_cart(RAM_BANK_NUM_START, 0x09);
+        assert!(cart.is_rumbling());
+        cart.write_ram(EXT_RAM_START, 0x22);
+        cart.write_cart(RAM_BANK_NUM_START, 0x01);
+        assert!(!cart.is_rumbling());
+        assert_eq!(cart.read_ram(EXT_RAM_START), 0x22);
+
+        let mut plain = load(&banked_rom(0x1B, 0x40000, 3));
+        plain.write_cart(RAM_BANK_NUM_START, 0x09);
+        assert!(!plain.is_rumbling());
+    }
+
+    #[test]
+    fn mbc5_high_rom_bank_bit() {
+        let mut cart = load(&banked_rom(0x19, 0x800000, 0));
+        cart.write_cart(ROM_BANK_HIGH_START, 0x01);
+        cart.write_cart(ROM_BANK_NUM_START, 0x00);
+        assert_eq!(cart.read_cart(0x4000), 0x00);
+        assert_eq!(cart.read_cart(0x4001), 0x01);
+
+        // Only bit 0 of the register is wired up
+        cart.write_cart(ROM_BANK_HIGH_START, 0x02);
+        cart.write_cart(ROM_BANK_NUM_START, 0x05);
+        assert_eq!(cart.read_cart(0x4000), 0x05);
+        assert_eq!(cart.read_cart(0x4001), 0x00);
+    }
+
+    fn mbc7_clock(cart: &mut Cart, bits: &[bool]) -> Vec<bool> {
+        bits.iter().map(|&bit| {
+            let pins = 0x80 | ((bit as u8) << 1);
+            cart.write_ram(0xA080, pins);
+            cart.write_ram(0xA080, pins | 0x40);
+            cart.read_ram(0xA080).get_bit(0)
+        }).collect()
+    }
+
+    fn mbc7_command(cart: &mut Cart, opcode: u16, addr: u8) -> Vec<bool> {
+        let command = 0x400 | (opcode << 8) | addr as u16;
+        let bits: Vec<bool> = (0..11).rev().map(|i| command.get_bit(i)).collect();
+        mbc7_clock(cart, &bits)
+    }
+
+    #[test]
+    fn mbc7_registers_and_eeprom() {
+        let mut cart = load(&banked_rom(0x22, 0x80000, 0));
+        cart.write_cart(ROM_BANK_NUM_START, 0x05);
+        assert_eq!(cart.read_cart(0x4000), 0x05);
+
+        // Both enables are needed before the registers appear
+        cart.write_cart(RAM_ENABLE_START, 0x0A);
+        assert_eq!(cart.read_ram(0xA060), 0xFF);
+        cart.write_cart(RAM_BANK_NUM_START, 0x40);
+        assert_eq!(cart.read_ram(0xA060), 0x00);
+        // As with other mappers, only the low nibble counts towards enabling RAM
+        cart.write_cart(RAM_ENABLE_START, 0x1A);
+        assert_eq!(cart.read_ram(0xA060), 0x00);
+
+        cart.write_ram(0xA000, 0x55);
+        cart.write_ram(0xA010, 0xAA);
+        assert_eq!(cart.read_ram(0xA020), 0xD0);
+        assert_eq!(cart.read_ram(0xA030), 0x81);
+
+        // EWEN, then WRITE $1234 to word 5, then READ it back
+        mbc7_command(&mut cart, 0b00, 0xC0);
+        mbc7_command(&mut cart, 0b01, 0x05);
+        let word: Vec<bool> = (0..16).rev().map(|i| 0x1234u16.get_bit(i)).collect();
+        mbc7_clock(&mut cart, &word);
+        mbc7_command(&mut cart, 0b10, 0x05);
+        let read = mbc7_clock(&mut cart, &[false; 16]);
+        assert_eq!(read, word);
+        assert_eq!(&cart.get_battery_data()[10..12], &[0x34, 0x12]);
+    }
+
+    #[test]
+    fn mbc3_without_rtc_ignores_clock_registers() {
+        let mut cart = load(&banked_rom(0x13, 0x40000, 3));
+        cart.write_cart(RAM_ENABLE_START, 0x0A);
+        cart.write_cart(RAM_BANK_NUM_START, 0x08);
+        cart.write_ram(EXT_RAM_START, 0x22);
+        assert!(cart.get_battery_data().iter().all(|&byte| byte == 0));
+        assert_eq!(cart.get_rtc_data(), None);
+    }
+
+    #[test]
+    fn camera_captures_into_ram_despite_header() {
+        // A header which claims there's no RAM at all
+        let mut cart = load(&banked_rom(0xFC, 0x40000, 0));
+        assert_eq!(cart.get_battery_data().len(), 0x20000);
+
+        cart.write_cart(RAM_ENABLE_START, 0x0A);
+        for addr in EXT_RAM_START..(EXT_RAM_START + 0x1000) {
+            cart.write_ram(addr, 0xFF);
         }
+
+        // With no source plugged in, the sensor sees white, which is stored as color 0
+        cart.write_cart(RAM_BANK_NUM_START, 0x10);
+        cart.write_ram(EXT_RAM_START, 0x01);
+        assert_eq!(cart.read_ram(EXT_RAM_START), 0x00);
+        let data = cart.get_battery_data();
+        assert!(data[0x0100..0x0F00].iter().all(|&byte| byte == 0x00));
+        assert_eq!(data[0x00FF], 0xFF);
+        assert_eq!(data[0x0F00], 0xFF);
     }
 }
@@ -1,62 +1,65 @@
 extern crate wasm_timer;
-use wasm_timer::Instant;
+use wasm_timer::{SystemTime, UNIX_EPOCH};
 
+use crate::state::{StateError, StateReader, StateWriter};
 use crate::utils::BitOps;
 
 const SECS_IN_MIN: u64  = 60;
 const MINS_IN_HOUR: u64 = 60;
 const HOURS_IN_DAY: u64 = 24;
+const DAYS_MAX: u64     = 512;
 
 const DAY_HIGH_BIT: u8      = 0;
 const HALT_BIT: u8          = 6;
 const DAY_OVERFLOW_BIT: u8  = 7;
 
-pub struct Rtc {
-    start: Instant,
+const NUM_REGS: usize       = 5;
+const REG_SIZE: usize       = 4;
+/// Length of the RTC block appended to battery saves, using a 64-bit timestamp
+pub const RTC_SAVE_SIZE: usize      = 2 * NUM_REGS * REG_SIZE + 8;
+/// Older emulators only store a 32-bit timestamp
+pub const RTC_SAVE_SIZE_OLD: usize  = 2 * NUM_REGS * REG_SIZE + 4;
+
+#[derive(Clone, Copy)]
+struct RtcRegs {
     seconds: u8,
     minutes: u8,
     hours: u8,
     days: u16,
-    enabled: bool,
-    // TODO: Check this
     halted: bool,
+    day_overflow: bool,
 }
 
-impl Rtc {
-    pub fn new() -> Self {
+impl RtcRegs {
+    fn new() -> Self {
         Self {
-            start: Instant::now(),
             seconds: 0,
             minutes: 0,
             hours: 0,
             days: 0,
-            enabled: false,
             halted: false,
+            day_overflow: false,
         }
     }
 
-    pub fn latch_time(&mut self) {
-        let now = Instant::now();
-        let delta = now.duration_since(self.start);
-        let d_sec = delta.as_secs();
+    fn advance(&mut self, secs: u64) {
+        let total = self.seconds as u64 + secs;
+        self.seconds = (total % SECS_IN_MIN) as u8;
 
-        self.seconds = (d_sec % SECS_IN_MIN) as u8;
+        let total = self.minutes as u64 + total / SECS_IN_MIN;
+        self.minutes = (total % MINS_IN_HOUR) as u8;
 
-        let d_min = d_sec / SECS_IN_MIN;
-        self.minutes = (d_min % MINS_IN_HOUR) as u8;
+        let total = self.hours as u64 + total / MINS_IN_HOUR;
+        self.hours = (total % HOURS_IN_DAY) as u8;
 
-        let d_hour = d_min / MINS_IN_HOUR;
-        self.hours = (d_hour % HOURS_IN_DAY) as u8;
-
-        let d_days = d_hour / HOURS_IN_DAY;
-        self.days = d_days as u16;
-    }
-
-    pub fn is_enabled(&self) -> bool {
-        self.enabled
+        let total = self.days as u64 + total / HOURS_IN_DAY;
+        if total >= DAYS_MAX {
+            self.day_overflow = true;
+        }
+        self.days = (total % DAYS_MAX) as u16;
     }
 
-    pub fn read_byte(&self, bank: u8) -> u8 {
+    fn read_byte(&self, bank: u8) -> u8 {
         match bank {
             0x08 => { self.seconds },
             0x09 => { self.minutes },
@@ -64,36 +67,186 @@ impl Rtc {
             0x0B => { (self.days & 0xFF) as u8 },
             0x0C => {
                 let mut ret = 0;
-                ret.set_bit(DAY_HIGH_BIT, self.days.get_bit(9));
+                ret.set_bit(DAY_HIGH_BIT, self.days.get_bit(8));
                 ret.set_bit(HALT_BIT, self.halted);
-                ret.set_bit(DAY_OVERFLOW_BIT, self.days.get_bit(10));
+                ret.set_bit(DAY_OVERFLOW_BIT, self.day_overflow);
                 ret
             },
             _ => { unreachable!() }
         }
     }
 
-    pub fn write_byte(&mut self, bank: u8, val: u8) {
+    fn write_byte(&mut self, bank: u8, val: u8) {
         match bank {
-            0x08 => { self.seconds = val; },
-            0x09 => { self.minutes = val; },
-            0x0A => { self.hours = val; },
+            0x08 => { self.seconds = val & 0x3F; },
+            0x09 => { self.minutes = val & 0x3F; },
+            0x0A => { self.hours = val & 0x1F; },
             0x0B => {
                 self.days = (self.days & 0xFF00) | (val as u16);
             },
             0x0C => {
-                self.days.set_bit(9, val.get_bit(DAY_HIGH_BIT));
+                self.days.set_bit(8, val.get_bit(DAY_HIGH_BIT));
                 self.halted = val.get_bit(HALT_BIT);
-                self.days.set_bit(10, val.get_bit(DAY_OVERFLOW_BIT));
+                self.day_overflow = val.get_bit(DAY_OVERFLOW_BIT);
             },
-            _ => {
-                if val == 0x00 {
-                    self.enabled = false;
-                } else if val == 0x01 && !self.enabled {
-                    self.enabled = true;
-                    self.latch_time();
-                }
+            _ => { unreachable!() }
+        }
+    }
+}
+
+/*
+ * MBC3 Real Time Clock
+ *
+ * The clock keeps counting while the emulator is closed, so rather than
+ * ticking with the CPU it's advanced by the wall clock time that has passed
+ * since it was last updated.
+ *
+ * Games can't read the counting registers directly. Writing $00 then $01 to
+ * $6000-$7FFF copies them into a set of latched registers, which are what's
+ * visible in the external RAM area.
+ */
+pub struct Rtc {
+    regs: RtcRegs,
+    latched: RtcRegs,
+    last_update: u64,
+    latch_ready: bool,
+}
+
+impl Default for Rtc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Rtc {
+    pub fn new() -> Self {
+        Self {
+            regs: RtcRegs::new(),
+            latched: RtcRegs::new(),
+            last_update: now(),
+            latch_ready: false,
+        }
+    }
+
+    fn update(&mut self) {
+        let now = now();
+        self.regs = self.get_current_regs(now);
+        self.last_update = now;
+    }
+
+    fn get_current_regs(&self, now: u64) -> RtcRegs {
+        let mut regs = self.regs;
+        if !regs.halted {
+            regs.advance(now.saturating_sub(self.last_update));
+        }
+        regs
+    }
+
+    pub fn write_latch(&mut self, val: u8) {
+        if val == 0x01 && self.latch_ready {
+            self.update();
+            self.latched = self.regs;
+        }
+        self.latch_ready = val == 0x00;
+    }
+
+    pub fn read_byte(&self, bank: u8) -> u8 {
+        self.latched.read_byte(bank)
+    }
+
+    pub fn write_byte(&mut self, bank: u8, val: u8) {
+        self.update();
+        self.regs.write_byte(bank, val);
+        self.latched.write_byte(bank, val);
+    }
+
+    /*
+     * RTC Save Format
+     *
+     * Follows the layout used by VBA-M and BGB, appended after the cartridge RAM.
+     * All values are little endian.
+     *
+     * +----------------------+ $00
+     * | Sec, Min, Hour, DayL |
+     * |  DayH (32-bit each)  |
+     * +----------------------+ $14
+     * |   Latched copies of  |
+     * |   the same registers |
+     * +----------------------+ $28
+     * |  UNIX time of save   |
+     * |   (32 or 64-bit)     |
+     * +----------------------+ $2C/$30
+     */
+    pub fn save(&self) -> Vec<u8> {
+        let now = now();
+        let regs = self.get_current_regs(now);
+        let mut data = Vec::with_capacity(RTC_SAVE_SIZE);
+        for regs in [&regs, &self.latched] {
+            for bank in 0x08..=0x0C {
+                data.extend_from_slice(&(regs.read_byte(bank) as u32).to_le_bytes());
             }
         }
+        data.extend_from_slice(&now.to_le_bytes());
+        data
+    }
+
+    pub fn load(&mut self, data: &[u8]) {
+        if data.len() < RTC_SAVE_SIZE_OLD {
+            return;
+        }
+
+        let reg = |idx: usize| data[idx * REG_SIZE];
+        for (i, bank) in (0x08..=0x0C).enumerate() {
+            self.regs.write_byte(bank, reg(i));
+            self.latched.write_byte(bank, reg(i + NUM_REGS));
+        }
+
+        let time_start = 2 * NUM_REGS * REG_SIZE;
+        let mut timestamp = [0; 8];
+        let time_len = (data.len() - time_start).min(8);
+        timestamp[..time_len].copy_from_slice(&data[time_start..(time_start + time_len)]);
+        self.last_update = u64::from_le_bytes(timestamp);
+        // Catch up on the time that passed while the emulator was closed
+        self.update();
+    }
+
+    // The clock follows real time, so any time passed since the state was saved is caught up on when loaded
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.save());
+        state.write_bool(self.latch_ready);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.load(state.read_bytes(RTC_SAVE_SIZE)?);
+        self.latch_ready = state.read_bool()?;
+        Ok(())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn day_counter_carries_into_high_bit() {
+        let mut regs = RtcRegs::new();
+        regs.write_byte(0x08, 59);
+        regs.write_byte(0x09, 59);
+        regs.write_byte(0x0A, 23);
+        regs.write_byte(0x0B, 0xFF);
+        regs.advance(1);
+        assert_eq!(regs.read_byte(0x0B), 0x00);
+        assert_eq!(regs.read_byte(0x0C) & 0x01, 0x01);
+
+        regs.write_byte(0x0C, 0x00);
+        assert_eq!(regs.read_byte(0x0B), 0x00);
+        regs.write_byte(0x0C, 0x01);
+        regs.write_byte(0x0B, 0x05);
+        regs.advance(0);
+        assert_eq!(regs.days, 0x105);
     }
 }
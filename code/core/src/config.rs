@@ -0,0 +1,115 @@
+use crate::ppu::Renderer;
+
+/// What WRAM, VRAM and HRAM hold when the system is powered on
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RamInit {
+    /// Every byte cleared to $00
+    Zero,
+    /// Approximates the striped WRAM seen on many DMG units, with VRAM cleared as the boot ROM would
+    Hardware,
+    /// Pseudo-random values generated from the given seed, so a run can be repeated exactly
+    Random(u64),
+}
+
+impl RamInit {
+    /// Returns a generator for the power on value of each byte in a memory region
+    pub fn pattern(&self, vram: bool) -> RamPattern {
+        RamPattern {
+            init: *self,
+            vram,
+            idx: 0,
+            state: match *self {
+                RamInit::Random(seed) => seed,
+                _ => HARDWARE_SEED,
+            },
+        }
+    }
+}
+
+// Any non-zero value works for the xorshift generator
+const HARDWARE_SEED: u64 = 0x2545_F491_4F6C_DD1D;
+
+pub struct RamPattern {
+    init: RamInit,
+    vram: bool,
+    idx: usize,
+    state: u64,
+}
+
+impl RamPattern {
+    fn next_random(&mut self) -> u8 {
+        // xorshift64, which is plenty random for garbage RAM
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state >> 32) as u8
+    }
+}
+
+impl Iterator for RamPattern {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        let idx = self.idx;
+        self.idx += 1;
+        let val = match self.init {
+            RamInit::Zero => { 0x00 },
+            RamInit::Hardware => {
+                if self.vram {
+                    0x00
+                } else {
+                    // Alternating runs of $00 and $FF, with the occasional bit flipped
+                    let base = if (idx / 8) & 1 == 0 { 0x00 } else { 0xFF };
+                    let noise = self.next_random();
+                    let flip = if noise < 0x08 { 1 << (noise & 0x07) } else { 0 };
+                    base ^ flip
+                }
+            },
+            RamInit::Random(_) => { self.next_random() },
+        };
+        Some(val)
+    }
+}
+
+/// The hardware revision being emulated
+///
+/// Each boot ROM leaves different values in the registers, which games use to detect what they're running on
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Model {
+    /// Original Game Boy
+    Dmg,
+    /// Game Boy Pocket and Light
+    Mgb,
+    /// Super Game Boy
+    Sgb,
+    /// Game Boy Color
+    Cgb,
+    /// Game Boy Advance, running Game Boy games
+    Agb,
+}
+
+impl Model {
+    /// Whether this model is capable of running CGB games in color
+    pub fn is_color(&self) -> bool {
+        matches!(self, Model::Cgb | Model::Agb)
+    }
+}
+
+/// Options for how the emulated system is set up at power on
+#[derive(Clone, Copy, Debug)]
+pub struct CpuConfig {
+    pub ram_init: RamInit,
+    /// The model to emulate, or None to pick whichever suits the loaded cartridge
+    pub model: Option<Model>,
+    pub renderer: Renderer,
+}
+
+impl Default for CpuConfig {
+    fn default() -> Self {
+        Self {
+            ram_init: RamInit::Zero,
+            model: None,
+            renderer: Renderer::Scanline,
+        }
+    }
+}
@@ -1,13 +1,32 @@
 pub mod opcodes;
+pub mod profile;
+pub mod trace;
 
 use crate::bus::Bus;
+use crate::cart::camera::CameraSource;
+use crate::config::{CpuConfig, Model};
+use crate::cart::{CartInfo, GlobalChecksum, HardwareMode};
+use crate::error::GbError;
+use crate::hooks::{HookId, MemAccess, MemoryHook};
+use crate::cpu::trace::{TraceEntry, Tracer};
 use crate::io::Buttons;
+use crate::ppu::compat::CompatPalette;
 use crate::ppu::modes::LcdResults;
+use crate::ppu::sprite::Sprite;
+use crate::serial::SerialDevice;
+use crate::state::bess::{self, BessCore, BessState};
+use crate::state::{StateError, StateReader, StateWriter, NATIVE_MAGIC, NATIVE_VERSION};
+use crate::ppu::{Layer, NUM_OAM_SPRITES, TILEMAP_PIXELS};
 use crate::utils::*;
 
+use std::mem;
+
+// The PPU runs at the full clock speed, four dots for every CPU M-cycle
+const DOTS_PER_M_CYCLE: u8  = 4;
+
 const IF: u16           = 0xFF0F;
 const IE: u16           = 0xFFFF;
-const IRQ_PRIORITIES: [Interrupts; 5] = [
+pub const IRQ_PRIORITIES: [Interrupts; 5] = [
     Interrupts::Vblank,
     Interrupts::Stat,
     Interrupts::Timer,
@@ -44,7 +63,7 @@ pub enum Regs16 {
     SP,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum Interrupts {
     Vblank,
     Stat,
@@ -65,6 +84,41 @@ impl Interrupts {
     }
 }
 
+/// Describes everything that happened during a single call to Cpu::step
+#[derive(Clone, Copy, Default, Debug)]
+pub struct StepInfo {
+    /// M-cycles taken, including any interrupt dispatch
+    pub cycles: u32,
+    /// The opcode executed, or None if the CPU was halted
+    pub opcode: Option<u8>,
+    pub pc_before: u16,
+    pub pc_after: u16,
+    /// The interrupt which was jumped to, if any
+    pub interrupt: Option<Interrupts>,
+    /// Whether a full frame has been rendered and is ready to draw
+    pub frame_ready: bool,
+    pub vblank_irq: bool,
+    pub stat_irq: bool,
+    pub timer_irq: bool,
+    pub serial_irq: bool,
+    /// Whether the CPU has hung after running an invalid opcode
+    pub locked: bool,
+}
+
+/// A copy of the CPU's registers at a single point in time
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct RegisterFile {
+    pub pc: u16,
+    pub sp: u16,
+    pub af: u16,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+    /// Interrupt master enable
+    pub ime: bool,
+    pub halted: bool,
+}
+
 pub struct Cpu {
     pc: u16,
     sp: u16,
@@ -78,31 +132,56 @@ pub struct Cpu {
     l: u8,
     irq_enabled: bool,
     halted: bool,
+    locked: Option<u8>,
+    config: CpuConfig,
     bus: Bus,
-    last_read: Option<u16>,
-    last_write: Option<u16>,
     dirty_battery: bool,
+    battery_idle_frames: u32,
+    instr_cycles: u8,
+    total_cycles: u64,
+    step_info: StepInfo,
+    tracer: Option<Box<dyn Tracer>>,
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Cpu {
     pub fn new() -> Self {
+        Self::with_config(CpuConfig::default())
+    }
+
+    pub fn with_config(config: CpuConfig) -> Self {
+        Self::with_bus(config, Bus::new())
+    }
+
+    // Powers on with the given hardware, which is how a reset keeps the cartridge plugged in
+    fn with_bus(config: CpuConfig, bus: Bus) -> Self {
         let mut cpu = Self {
             pc: 0x0100,
             sp: 0xFFFE,
-            a: 0x01,
+            a: 0x00,
             b: 0x00,
-            c: 0x13,
+            c: 0x00,
             d: 0x00,
-            e: 0xD8,
-            f: 0xB0,
-            h: 0x01,
-            l: 0x4D,
+            e: 0x00,
+            f: 0x00,
+            h: 0x00,
+            l: 0x00,
             irq_enabled: false,
             halted: false,
-            bus: Bus::new(),
-            last_read: None,
-            last_write: None,
+            locked: None,
+            config,
+            bus,
             dirty_battery: false,
+            battery_idle_frames: 0,
+            instr_cycles: 0,
+            total_cycles: 0,
+            step_info: StepInfo::default(),
+            tracer: None,
         };
 
         // Magic values for RAM initialization
@@ -120,30 +199,127 @@ impl Cpu {
         cpu.write_ram(0xFF23, 0xBF);
         cpu.write_ram(0xFF24, 0x77);
         cpu.write_ram(0xFF25, 0xF3);
-        cpu.write_ram(0xFF26, 0xF1); // 0xF0 for SGB
+        cpu.write_ram(0xFF26, if cpu.get_model() == Model::Sgb { 0xF0 } else { 0xF1 });
         cpu.write_ram(0xFF40, 0x91);
         cpu.write_ram(0xFF47, 0xFC);
         cpu.write_ram(0xFF48, 0xFF);
         cpu.write_ram(0xFF49, 0xFF);
 
+        // Earlier models can't run in color, no matter what the cartridge asks for
+        if config.model.is_some_and(|model| !model.is_color()) {
+            cpu.bus.set_hardware_mode(Some(HardwareMode::Dmg));
+        }
+        cpu.reset_for_model();
+        cpu.bus.init_ram(config.ram_init);
+        cpu.bus.set_renderer(config.renderer);
         cpu
     }
 
+    /// Returns the model being emulated, which follows the cartridge if one wasn't chosen
+    pub fn get_model(&self) -> Model {
+        match self.config.model {
+            Some(model) => { model },
+            None if self.get_hardware_mode().is_cgb() => { Model::Cgb },
+            None => { Model::Dmg },
+        }
+    }
+
+    /*
+     * Post-boot Registers
+     *
+     * The boot ROM hands over to the game at $0100, leaving these values behind.
+     * Color models also differ depending on whether the game runs in CGB mode.
+     *
+     * +-------+------+------+------+------+------+------+------+------+
+     * | Model | A    | F    | B    | C    | D    | E    | H    | L    |
+     * +-------+------+------+------+------+------+------+------+------+
+     * | DMG   | $01  | $B0  | $00  | $13  | $00  | $D8  | $01  | $4D  |
+     * | MGB   | $FF  | $B0  | $00  | $13  | $00  | $D8  | $01  | $4D  |
+     * | SGB   | $01  | $00  | $00  | $14  | $00  | $00  | $C0  | $60  |
+     * | CGB   | $11  | $80  | $00  | $00  | $FF  | $56  | $00  | $0D  |
+     * | (DMG) | $11  | $80  | $00  | $00  | $00  | $08  | $00  | $7C  |
+     * | AGB   | $11  | $00  | $01  | $00  | $FF  | $56  | $00  | $0D  |
+     * | (DMG) | $11  | $00  | $01  | $00  | $00  | $08  | $00  | $7C  |
+     * +-------+------+------+------+------+------+------+------+------+
+     */
+    fn reset_for_model(&mut self) {
+        // Only the original monochrome hardware has the STAT write bug
+        self.bus.set_stat_write_bug(!self.get_model().is_color());
+
+        let cgb = self.get_hardware_mode().is_cgb();
+        let (af, bc, de, hl) = match self.get_model() {
+            Model::Dmg =>         { (0x01B0, 0x0013, 0x00D8, 0x014D) },
+            Model::Mgb =>         { (0xFFB0, 0x0013, 0x00D8, 0x014D) },
+            Model::Sgb =>         { (0x0100, 0x0014, 0x0000, 0xC060) },
+            Model::Cgb if cgb =>  { (0x1180, 0x0000, 0xFF56, 0x000D) },
+            Model::Cgb =>         { (0x1180, 0x0000, 0x0008, 0x007C) },
+            Model::Agb if cgb =>  { (0x1100, 0x0100, 0xFF56, 0x000D) },
+            Model::Agb =>         { (0x1100, 0x0100, 0x0008, 0x007C) },
+        };
+        self.set_r16(Regs16::AF, af);
+        self.set_r16(Regs16::BC, bc);
+        self.set_r16(Regs16::DE, de);
+        self.set_r16(Regs16::HL, hl);
+        self.set_r16(Regs16::SP, 0xFFFE);
+        self.pc = 0x0100;
+    }
+
+    /// Runs a single instruction, returning true if a frame is ready to be drawn
     pub fn tick(&mut self) -> bool {
-        self.last_read = None;
-        self.last_write = None;
-        let mut draw_time = false;
-        let cycles = if self.halted { 1 } else { opcodes::execute(self) };
-        let ppu_result = self.bus.update_ppu(cycles);
+        self.step().frame_ready
+    }
+
+    /// Runs a single instruction and reports what happened
+    pub fn step(&mut self) -> StepInfo {
+        self.instr_cycles = 0;
+        let start_cycles = self.total_cycles;
+        self.step_info = StepInfo {
+            pc_before: self.pc,
+            ..StepInfo::default()
+        };
+
+        let cycles = if self.halted || self.locked.is_some() {
+            1
+        } else {
+            let opcode = self.read_ram(self.pc);
+            self.step_info.opcode = Some(opcode);
+            if self.bus.has_hooks() {
+                self.bus.notify_hooks(MemAccess::execute(self.pc, opcode));
+            }
+            self.trace_instruction();
+            opcodes::execute(self)
+        };
+        // Memory accesses have already ticked the other components, catch up on any internal cycles
+        while self.instr_cycles < cycles {
+            self.tick_cycle();
+        }
+
+        // Once locked, not even an interrupt can get the CPU going again
+        if self.locked.is_none() && self.check_irq().is_some() {
+            self.step_info.interrupt = self.trigger_irq();
+        }
+
+        self.step_info.locked = self.locked.is_some();
+        self.step_info.cycles = (self.total_cycles - start_cycles) as u32;
+        self.step_info.pc_after = self.pc;
+        self.step_info
+    }
+
+    // Advances the rest of the system by a single M-cycle
+    fn tick_cycle(&mut self) {
+        self.instr_cycles += 1;
+        self.total_cycles += 1;
+        let ppu_result = self.bus.update_ppu(DOTS_PER_M_CYCLE);
         if ppu_result.irq {
             self.enable_irq_type(Interrupts::Stat, true);
+            self.step_info.stat_irq = true;
         }
         match ppu_result.lcd_result {
             LcdResults::RenderFrame => {
-                // Render final scanline
-                self.bus.render_scanline();
                 self.enable_irq_type(Interrupts::Vblank, true);
-                draw_time = true;
+                self.step_info.vblank_irq = true;
+                self.step_info.frame_ready = true;
+                self.battery_idle_frames = self.battery_idle_frames.saturating_add(1);
             },
             LcdResults::RenderLine => {
                 self.bus.render_scanline();
@@ -151,30 +327,52 @@ impl Cpu {
             _ => {},
         }
 
-        let timer_irq = self.bus.update_timer(cycles);
+        let timer_irq = self.bus.update_timer(1);
         if timer_irq {
             self.enable_irq_type(Interrupts::Timer, true);
+            self.step_info.timer_irq = true;
         }
 
-        if let Some(irq) = self.check_irq() {
-            self.trigger_irq(irq);
+        if self.bus.update_serial() {
+            self.enable_irq_type(Interrupts::Serial, true);
+            self.step_info.serial_irq = true;
         }
-        draw_time
     }
 
-    pub fn get_write(&self) -> Option<u16> {
-        self.last_write
+    /// Returns the number of T-cycles emulated since power on
+    pub fn elapsed_cycles(&self) -> u64 {
+        self.total_cycles * T_CYCLES_PER_M_CYCLE
+    }
+
+    pub fn elapsed_m_cycles(&self) -> u64 {
+        self.total_cycles
+    }
+
+    /// Returns the T-cycles emulated since an earlier reading of elapsed_cycles, such as the start of a frame
+    pub fn cycles_since(&self, start: u64) -> u64 {
+        self.elapsed_cycles().saturating_sub(start)
     }
 
-    pub fn get_read(&self) -> Option<u16> {
-        self.last_read
+    /// Registers a hook to be told about every read, write, and instruction the game makes, returning an ID
+    /// to remove it with. Hooks are kept through resets and loaded states.
+    pub fn add_memory_hook(&mut self, hook: Box<dyn MemoryHook>) -> HookId {
+        self.bus.add_hook(hook)
+    }
+
+    /// Removes a hook, returning false if it had already been removed
+    pub fn remove_memory_hook(&mut self, id: HookId) -> bool {
+        self.bus.remove_hook(id)
     }
 
     fn check_irq(&mut self) -> Option<Interrupts> {
         if !self.irq_enabled && !self.halted {
             return None;
         }
+        self.get_pending_irq()
+    }
 
+    // Finds the highest priority interrupt which is both requested and enabled
+    fn get_pending_irq(&self) -> Option<Interrupts> {
         let if_reg = self.read_ram(IF);
         let ie_reg = self.read_ram(IE);
         let irq_flags = if_reg & ie_reg;
@@ -203,7 +401,7 @@ impl Cpu {
     }
 
     pub fn fetch(&mut self) -> u8 {
-        let val = self.read_ram(self.pc);
+        let val = self.read_cycle(self.pc);
         self.pc += 1;
         val
     }
@@ -211,14 +409,26 @@ impl Cpu {
     pub fn fetch_u16(&mut self) -> u16 {
         let low = self.fetch();
         let high = self.fetch();
-        let val = merge_bytes(high, low);
-        val
+        merge_bytes(high, low)
+    }
+
+    /// Returns the details listed in the cartridge header
+    pub fn cart_info(&self) -> CartInfo {
+        self.bus.get_cart_info()
     }
 
-    pub fn get_battery_data(&self) -> &[u8] {
+    pub fn get_battery_data(&self) -> Vec<u8> {
         self.bus.get_battery_data()
     }
 
+    pub fn get_global_checksum(&self) -> GlobalChecksum {
+        self.bus.get_global_checksum()
+    }
+
+    pub fn get_hardware_mode(&self) -> HardwareMode {
+        self.bus.get_hardware_mode()
+    }
+
     pub fn get_title(&self) -> &str {
         self.bus.get_title()
     }
@@ -227,12 +437,75 @@ impl Cpu {
         self.bus.has_battery()
     }
 
+    /// Returns which ROM bank is mapped to the given address
+    pub fn get_rom_bank(&self, addr: u16) -> u16 {
+        self.bus.get_rom_bank(addr)
+    }
+
     pub fn is_battery_dirty(&self) -> bool {
         self.dirty_battery
     }
 
-    pub fn load_rom(&mut self, rom: &[u8]) {
-        self.bus.load_rom(rom);
+    /// Whether the battery backed data has changed, with no further changes for at least the given number of frames,
+    /// so frontends can wait for a game to finish saving rather than writing it out after every change
+    pub fn battery_dirty_since(&self, frames: u32) -> bool {
+        self.dirty_battery && self.battery_idle_frames >= frames
+    }
+
+    pub fn is_rumbling(&self) -> bool {
+        self.bus.is_rumbling()
+    }
+
+    /// Loads a game, setting up the registers for the hardware mode it requests
+    ///
+    /// ROMs too small to hold a header, or for mappers which aren't emulated, are refused, leaving
+    /// whatever was loaded before untouched.
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), GbError> {
+        self.bus.load_rom(rom)?;
+        self.reset_for_model();
+        Ok(())
+    }
+
+    /// Turns the power off and on again, restarting the game
+    ///
+    /// The cartridge keeps its saved data and clock, and the frontend's settings, such as the palettes,
+    /// hidden layers, serial device, and tracer, carry over. Battery data still waiting to be saved stays
+    /// that way, and a loaded boot ROM runs again.
+    pub fn reset(&mut self) {
+        let tracer = self.tracer.take();
+        let (dirty_battery, battery_idle_frames) = (self.dirty_battery, self.battery_idle_frames);
+        let mut bus = mem::take(&mut self.bus);
+        bus.reset();
+        *self = Self::with_bus(self.config, bus);
+        self.tracer = tracer;
+        self.dirty_battery = dirty_battery;
+        self.battery_idle_frames = battery_idle_frames;
+        if self.bus.is_boot_rom_mapped() {
+            self.start_boot_rom();
+        }
+    }
+
+    /// Starts from a boot ROM rather than skipping straight to the game, as a real Game Boy does at power on
+    ///
+    /// It's mapped over the cartridge until it writes to $FF50. Both DMG (256 byte) and CGB (2304 byte)
+    /// boot ROMs are accepted, otherwise this returns an error. Loading a game or changing the hardware mode
+    /// afterwards skips the boot ROM again, so this must come after them.
+    pub fn load_boot_rom(&mut self, data: &[u8]) -> Result<(), GbError> {
+        if !self.bus.load_boot_rom(data) {
+            return Err(GbError::InvalidBootRom(data.len()));
+        }
+        self.start_boot_rom();
+        Ok(())
+    }
+
+    // Puts the registers how they are at power on, for the boot ROM to take over from
+    fn start_boot_rom(&mut self) {
+        for reg in [Regs16::AF, Regs16::BC, Regs16::DE, Regs16::HL, Regs16::SP] {
+            self.set_r16(reg, 0x0000);
+        }
+        self.pc = 0x0000;
+        // The LCD is off at power on, leaving VRAM free for the boot ROM to clear
+        self.write_ram(0xFF40, 0x00);
     }
 
     pub fn press_button(&mut self, button: Buttons, pressed: bool) {
@@ -240,28 +513,309 @@ impl Cpu {
         self.enable_irq_type(Interrupts::Joypad, true);
     }
 
-    pub fn render(&self) -> [u8; DISPLAY_BUFFER] {
+    pub fn is_button_pressed(&self, button: Buttons) -> bool {
+        self.bus.is_button_pressed(button)
+    }
+
+    /// Returns every held button as a bitmask, with each button's bit numbered by its value
+    pub fn get_buttons(&self) -> u8 {
+        Buttons::ALL.iter().fold(0, |mask, &button| {
+            mask | ((self.is_button_pressed(button) as u8) << button as u8)
+        })
+    }
+
+    /// Presses and releases buttons to match a bitmask from get_buttons, only touching those which changed
+    pub fn set_buttons(&mut self, mask: u8) {
+        for button in Buttons::ALL {
+            let pressed = mask.get_bit(button as u8);
+            if pressed != self.is_button_pressed(button) {
+                self.press_button(button, pressed);
+            }
+        }
+    }
+
+    /// Returns the most recently drawn frame as RGBA, without copying it
+    pub fn render(&self) -> &[u8; DISPLAY_BUFFER] {
         self.bus.render()
     }
 
+    /// Whether the frame returned by render() differs from when this was last called
+    pub fn take_frame_changed(&mut self) -> bool {
+        self.bus.take_frame_changed()
+    }
+
+    /// Returns the screen as one color index per pixel, before any palette turns it into RGBA
+    pub fn render_indexed(&self) -> &[u8; INDEXED_BUFFER] {
+        self.bus.render_indexed()
+    }
+
+    /// Draws the tile data in VRAM, as an RGBA image of TILESET_WIDTH by TILESET_HEIGHT pixels
+    pub fn render_tileset(&self) -> Vec<u8> {
+        self.bus.render_tileset()
+    }
+
+    /// Draws background tile map 0 ($9800) or 1 ($9C00) as a 256x256 RGBA image
+    pub fn render_tilemap(&self, map_index: u8) -> [u8; TILEMAP_PIXELS * TILEMAP_PIXELS * 4] {
+        self.bus.render_tilemap(map_index)
+    }
+
+    /// Returns the 40 sprites currently in OAM
+    pub fn sprites(&self) -> &[Sprite; NUM_OAM_SPRITES] {
+        self.bus.sprites()
+    }
+
+    /// Returns the position of the screen's 160x144 window into the background map (SCX, SCY)
+    pub fn get_viewport(&self) -> Point {
+        self.bus.get_viewport()
+    }
+
+    pub fn verify_global_checksum(&self) -> bool {
+        self.bus.verify_global_checksum()
+    }
+
+    pub fn verify_header_checksum(&self) -> bool {
+        self.bus.verify_header_checksum()
+    }
+
     pub fn set_battery_data(&mut self, data: &[u8]) {
         self.bus.set_battery_data(data);
     }
 
-    fn trigger_irq(&mut self, irq: Interrupts) {
+    /*
+     * Save States
+     *
+     * States are written in the BESS format, beginning with our own complete
+     * snapshot of the system, followed by the standard blocks describing the
+     * CPU, memory, and mapper for other emulators to read. States made by
+     * other emulators are loaded from those blocks instead, which will get a
+     * game running again, but lose anything the standard doesn't cover, such
+     * as exactly where the PPU was within a line.
+     *
+     * Frontend settings, such as palettes, the connected link cable, and which
+     * buttons are held, are kept as they were.
+     */
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        state.write_bytes(NATIVE_MAGIC);
+        state.write_u16(NATIVE_VERSION);
+        state.write_bool(self.get_hardware_mode().is_cgb());
+        for reg in [self.a, self.f, self.b, self.c, self.d, self.e, self.h, self.l] {
+            state.write_u8(reg);
+        }
+        state.write_u16(self.pc);
+        state.write_u16(self.sp);
+        state.write_bool(self.irq_enabled);
+        state.write_bool(self.halted);
+        state.write_bool(self.locked.is_some());
+        state.write_u8(self.locked.unwrap_or(0));
+        state.write_u64(self.total_cycles);
+        self.bus.save_state(&mut state);
+
+        let regs = self.registers();
+        let core = BessCore {
+            model: bess::model_id(self.get_model()),
+            pc: regs.pc,
+            af: regs.af,
+            bc: regs.bc,
+            de: regs.de,
+            hl: regs.hl,
+            sp: regs.sp,
+            ime: regs.ime,
+            ie: self.read_ram(IE),
+            halted: regs.halted,
+            io_regs: self.bus.read_io_regs(),
+        };
+        let rtc = self.bus.get_rtc_data();
+        bess::write_blocks(&mut state, &self.bus.get_bess_info(), &core, &self.bus.get_mbc_writes(), rtc.as_deref());
+        state.into_bytes()
+    }
+
+    /// Loads a state made by save_state, or by another emulator which supports BESS
+    ///
+    /// The state must be for the game currently loaded. If it can't be loaded, the system is left as it was.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let bess = bess::parse(data)?;
+        if bess.info.is_some_and(|info| info != self.bus.get_bess_info()) {
+            return Err(StateError::WrongGame);
+        }
+        if bess.core.is_cgb() != self.get_model().is_color() {
+            return Err(StateError::WrongModel);
+        }
+
+        if !data.starts_with(NATIVE_MAGIC) {
+            self.load_bess(&bess);
+            return Ok(());
+        }
+
+        // Our own states are only checked as they're read, so keep a copy to go back to if that fails
+        let backup = self.save_state();
+        let result = self.load_native(data);
+        if result.is_err() {
+            // A state just taken should always load again, but if it doesn't, that's the more serious problem to report
+            self.load_native(&backup)?;
+        }
+        result
+    }
+
+    fn load_native(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(&data[NATIVE_MAGIC.len()..]);
+        let version = state.read_u16()?;
+        if version > NATIVE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        if state.read_bool()? != self.get_hardware_mode().is_cgb() {
+            return Err(StateError::WrongModel);
+        }
+
+        self.a = state.read_u8()?;
+        // The lower nibble of F is always zero
+        self.f = state.read_u8()? & 0xF0;
+        self.b = state.read_u8()?;
+        self.c = state.read_u8()?;
+        self.d = state.read_u8()?;
+        self.e = state.read_u8()?;
+        self.h = state.read_u8()?;
+        self.l = state.read_u8()?;
+        self.pc = state.read_u16()?;
+        self.sp = state.read_u16()?;
+        self.irq_enabled = state.read_bool()?;
+        self.halted = state.read_bool()?;
+        let locked = state.read_bool()?;
+        let opcode = state.read_u8()?;
+        self.locked = locked.then_some(opcode);
+        self.total_cycles = state.read_u64()?;
+        self.bus.load_state(&mut state, version)?;
+        self.dirty_battery = self.has_battery();
+        Ok(())
+    }
+
+    fn load_bess(&mut self, bess: &BessState) {
+        let core = &bess.core;
+        self.pc = core.pc;
+        self.set_r16(Regs16::AF, core.af);
+        self.set_r16(Regs16::BC, core.bc);
+        self.set_r16(Regs16::DE, core.de);
+        self.set_r16(Regs16::HL, core.hl);
+        self.set_r16(Regs16::SP, core.sp);
+        self.irq_enabled = core.ime;
+        self.halted = core.halted;
+        self.locked = None;
+        self.bus.load_bess(bess);
+        self.dirty_battery = self.has_battery();
+    }
+
+    /// Returns the bytes the game has sent over the serial port since the last call, as text
+    ///
+    /// Test ROMs such as Blargg's print their results this way, so they can be checked without a screen
+    pub fn take_serial_output(&mut self) -> String {
+        String::from_utf8_lossy(&self.bus.take_serial_output()).into_owned()
+    }
+
+    /// Returns the bytes the game has sent over the serial port since the last call, as they were sent
+    pub fn take_serial_bytes(&mut self) -> Vec<u8> {
+        self.bus.take_serial_output()
+    }
+
+    /// Connects a link cable peripheral to the serial port, or disconnects it if None
+    pub fn set_serial_device(&mut self, device: Option<Box<dyn SerialDevice>>) {
+        self.bus.set_serial_device(device);
+    }
+
+    pub fn set_camera_source(&mut self, source: Option<Box<dyn CameraSource>>) {
+        self.bus.set_camera_source(source);
+    }
+
+    /// Hands every instruction to the tracer before it runs, or stops tracing if None
+    pub fn set_tracer(&mut self, tracer: Option<Box<dyn Tracer>>) {
+        self.tracer = tracer;
+    }
+
+    fn trace_instruction(&mut self) {
+        if let Some(mut tracer) = self.tracer.take() {
+            let entry = TraceEntry {
+                regs: self.registers(),
+                opcode: self.read_ram(self.pc),
+                operands: [self.read_ram(self.pc.wrapping_add(1)), self.read_ram(self.pc.wrapping_add(2))],
+                cycles: self.elapsed_cycles(),
+            };
+            tracer.trace(&entry);
+            self.tracer = Some(tracer);
+        }
+    }
+
+    /// Hides or shows a layer for debugging, regardless of what the game has set in LCDC
+    pub fn set_layer_enabled(&mut self, layer: Layer, enabled: bool) {
+        self.bus.set_layer_enabled(layer, enabled);
+    }
+
+    pub fn is_layer_enabled(&self, layer: Layer) -> bool {
+        self.bus.is_layer_enabled(layer)
+    }
+
+    /// Sets the RGBA colors used to display the four DMG shades, from lightest to darkest
+    pub fn set_palette(&mut self, colors: [[u8; 4]; 4]) {
+        self.bus.set_palette(colors);
+    }
+
+    pub fn set_compat_palette(&mut self, palette: Option<CompatPalette>) {
+        self.bus.set_compat_palette(palette);
+    }
+
+    /// Feeds the accelerometer of tilt sensing carts, in g along each axis
+    pub fn set_tilt(&mut self, x: f32, y: f32) {
+        self.bus.set_tilt(x, y);
+    }
+
+    /// Forces a hardware mode, which should be done before the game starts running as the registers are reset
+    pub fn set_hardware_mode(&mut self, mode: Option<HardwareMode>) {
+        self.bus.set_hardware_mode(mode);
+        self.reset_for_model();
+    }
+
+    /*
+     * Interrupt Dispatch
+     *
+     * Servicing an interrupt takes 5 M-cycles:
+     * 1-2. Internal delays, while the prefetched opcode is discarded
+     * 3.   SP is decremented and the high byte of PC is pushed
+     * 4.   SP is decremented and the low byte of PC is pushed
+     * 5.   PC is set to the interrupt vector
+     *
+     * Which interrupt to jump to is only decided after the high byte is pushed.
+     * If that write lands on IE and disables the pending interrupt, a lower
+     * priority one is taken instead, or PC is set to $0000 if none remain.
+     */
+    fn trigger_irq(&mut self) -> Option<Interrupts> {
         // We always wake up from HALT if there's a waiting interrupt,
         // even if the master control is turned off
         self.halted = false;
 
-        if self.irq_enabled {
-            self.irq_enabled = false;
+        if !self.irq_enabled {
+            return None;
+        }
+        self.irq_enabled = false;
+
+        self.tick_cycle();
+        self.tick_cycle();
 
-            let vector = irq.get_vector();
-            self.push(self.pc);
-            self.set_pc(vector);
+        let pc = self.pc;
+        self.sp = self.sp.wrapping_sub(1);
+        self.write_cycle(self.sp, pc.high_byte());
+        let irq = self.get_pending_irq();
+        self.sp = self.sp.wrapping_sub(1);
+        self.write_cycle(self.sp, pc.low_byte());
 
-            self.enable_irq_type(irq, false);
+        self.tick_cycle();
+        match irq {
+            Some(irq) => {
+                self.set_pc(irq.get_vector());
+                self.enable_irq_type(irq, false);
+            },
+            None => {
+                self.set_pc(0x0000);
+            }
         }
+        irq
     }
 
     pub fn add_a_u8(&mut self, val: u8, adc: bool) {
@@ -365,6 +919,20 @@ impl Cpu {
         self.pc
     }
 
+    /// Returns all of the registers at once, without needing a mutable borrow
+    pub fn registers(&self) -> RegisterFile {
+        RegisterFile {
+            pc: self.pc,
+            sp: self.sp,
+            af: merge_bytes(self.a, self.f),
+            bc: merge_bytes(self.b, self.c),
+            de: merge_bytes(self.d, self.e),
+            hl: merge_bytes(self.h, self.l),
+            ime: self.irq_enabled,
+            halted: self.halted,
+        }
+    }
+
     pub fn get_r8(&mut self, r: Regs) -> u8 {
         match r {
             Regs::A => { self.a },
@@ -377,7 +945,7 @@ impl Cpu {
             Regs::L => { self.l },
             Regs::HL => {
                 let addr = self.get_r16(Regs16::HL);
-                self.read_ram(addr)
+                self.read_cycle(addr)
             }
         }
     }
@@ -421,22 +989,35 @@ impl Cpu {
     }
 
     pub fn pop(&mut self) -> u16 {
-        assert_ne!(self.sp, 0xFFFE, "Trying to pop when the stack is empty");
-        let low = self.read_ram(self.sp);
-        let high = self.read_ram(self.sp + 1);
+        // Nothing stops a game from popping past the top of memory, SP just wraps around
+        let low = self.read_cycle(self.sp);
+        let high = self.read_cycle(self.sp.wrapping_add(1));
         let val = merge_bytes(high, low);
-        self.sp += 2;
+        self.sp = self.sp.wrapping_add(2);
         val
     }
 
     pub fn push(&mut self, val: u16) {
-        self.sp -= 2;
-        self.write_ram(self.sp, val.low_byte());
-        self.write_ram(self.sp + 1, val.high_byte());
+        // SP is decremented during an internal cycle before the writes begin
+        self.tick_cycle();
+        self.sp = self.sp.wrapping_sub(2);
+        // The high byte is written first
+        self.write_cycle(self.sp.wrapping_add(1), val.high_byte());
+        self.write_cycle(self.sp, val.low_byte());
     }
 
-    pub fn read_ram(&mut self, addr: u16) -> u8 {
-        self.last_read = Some(addr);
+    /// Reads memory as the CPU does, taking an M-cycle
+    pub fn read_cycle(&mut self, addr: u16) -> u8 {
+        self.tick_cycle();
+        let val = self.bus.read_ram(addr);
+        if self.bus.has_hooks() {
+            self.bus.notify_hooks(MemAccess::read(addr, val));
+        }
+        val
+    }
+
+    /// Reads memory without affecting the rest of the system, such as for debugging
+    pub fn read_ram(&self, addr: u16) -> u8 {
         self.bus.read_ram(addr)
     }
 
@@ -490,6 +1071,21 @@ impl Cpu {
         self.halted = halted;
     }
 
+    /// Hangs the CPU, as happens on hardware when an invalid opcode is run.
+    /// The rest of the system keeps running, but no more instructions will execute.
+    pub fn lock_up(&mut self, opcode: u8) {
+        self.locked = Some(opcode);
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.is_some()
+    }
+
+    /// Returns the invalid opcode which hung the CPU, if it has locked up
+    pub fn get_locked_opcode(&self) -> Option<u8> {
+        self.locked
+    }
+
     pub fn set_irq(&mut self, enabled: bool) {
         self.irq_enabled = enabled;
     }
@@ -511,7 +1107,7 @@ impl Cpu {
             Regs::L => { self.l = val },
             Regs::HL => {
                 let addr = self.get_r16(Regs16::HL);
-                self.write_ram(addr, val);
+                self.write_cycle(addr, val);
             }
         }
     }
@@ -633,9 +1229,29 @@ impl Cpu {
         self.set_r8(reg, byte);
     }
 
+    /// Writes memory as the CPU does, taking an M-cycle
+    pub fn write_cycle(&mut self, addr: u16, val: u8) {
+        self.tick_cycle();
+        if !self.bus.has_hooks() {
+            self.write_bus(addr, val);
+            return;
+        }
+        let old = self.read_ram(addr);
+        self.write_bus(addr, val);
+        self.bus.notify_hooks(MemAccess::write(addr, old, val));
+    }
+
+    /// Writes memory without the passage of time, such as for debugging
     pub fn write_ram(&mut self, addr: u16, val: u8) {
-        self.last_write = Some(addr);
-        self.dirty_battery |= self.bus.write_ram(addr, val);
+        self.write_bus(addr, val);
+    }
+
+    // Notes whenever the write reaches battery backed RAM
+    fn write_bus(&mut self, addr: u16, val: u8) {
+        if self.bus.write_ram(addr, val) {
+            self.dirty_battery = true;
+            self.battery_idle_frames = 0;
+        }
     }
 
     pub fn xor_a_u8(&mut self, val: u8) {
@@ -649,3 +1265,179 @@ impl Cpu {
         self.set_flag(Flags::C, false);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A ROM-only cart which runs the given code from the entry point
+    fn rom_with(code: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100..(0x0100 + code.len())].copy_from_slice(code);
+        rom
+    }
+
+    #[test]
+    fn frame_takes_17556_cycles() {
+        let mut gb = Cpu::new();
+        // JR -2, which takes three M-cycles every time around
+        gb.load_rom(&rom_with(&[0x18, 0xFE])).unwrap();
+        while !gb.tick() {}
+
+        let mut loops = 1;
+        while !gb.tick() {
+            loops += 1;
+        }
+        assert_eq!(loops * 3, 17556);
+    }
+
+    fn dispatch_from(pc: u16, sp: u16, requested: u8) -> Cpu {
+        let mut gb = Cpu::new();
+        gb.load_rom(&rom_with(&[0x18, 0xFE])).unwrap();
+        gb.pc = pc;
+        gb.sp = sp;
+        gb.irq_enabled = true;
+        gb.write_ram(IE, requested);
+        gb.write_ram(IF, requested);
+        gb.trigger_irq();
+        gb
+    }
+
+    #[test]
+    fn interrupt_dispatch_pushes_pc() {
+        let gb = dispatch_from(0x1234, 0xDFF0, 0x01);
+        assert_eq!(gb.pc, 0x0040);
+        assert_eq!(gb.sp, 0xDFEE);
+        assert_eq!(gb.read_ram(0xDFEF), 0x12);
+        assert_eq!(gb.read_ram(0xDFEE), 0x34);
+        assert_eq!(gb.read_ram(IF) & 0x1F, 0x00);
+        assert!(!gb.irq_enabled);
+    }
+
+    #[test]
+    fn interrupt_dispatch_rechecks_after_pushing_to_ie() {
+        // The high byte of PC lands on IE, leaving only the STAT interrupt enabled
+        let gb = dispatch_from(0x0200, 0x0000, 0x03);
+        assert_eq!(gb.pc, 0x0048);
+        assert_eq!(gb.read_ram(IF) & 0x1F, 0x01);
+
+        // Nothing left enabled, so PC ends up at $0000
+        let gb = dispatch_from(0x1000, 0x0000, 0x01);
+        assert_eq!(gb.pc, 0x0000);
+        assert_eq!(gb.read_ram(IF) & 0x1F, 0x01);
+    }
+
+    // A ROM-only cart which counts up at $C000 forever, so every frame leaves something different behind
+    fn counting_rom() -> Vec<u8> {
+        rom_with(&[
+            0x21, 0x00, 0xC0,   // LD HL, $C000
+            0x34,               // INC (HL)
+            0x18, 0xFD,         // JR -3
+        ])
+    }
+
+    fn running_cpu() -> Cpu {
+        let mut gb = Cpu::new();
+        gb.load_rom(&counting_rom()).unwrap();
+        for _ in 0..3 {
+            while !gb.tick() {}
+        }
+        gb
+    }
+
+    #[test]
+    fn native_state_round_trip() {
+        let mut gb = running_cpu();
+        gb.write_ram(0xD123, 0x42);
+        let state = gb.save_state();
+        let regs = gb.registers();
+        let counter = gb.read_ram(0xC000);
+
+        while !gb.tick() {}
+        gb.write_ram(0xD123, 0x00);
+        assert_ne!(gb.read_ram(0xC000), counter);
+
+        gb.load_state(&state).unwrap();
+        assert_eq!(gb.registers(), regs);
+        assert_eq!(gb.read_ram(0xC000), counter);
+        assert_eq!(gb.read_ram(0xD123), 0x42);
+    }
+
+    #[test]
+    fn bess_state_round_trip() {
+        let gb = running_cpu();
+        let mut state = gb.save_state();
+        // Without our own magic, only the standard blocks are read, as with another emulator's state
+        state[..NATIVE_MAGIC.len()].copy_from_slice(b"XXXX");
+
+        let mut other = Cpu::new();
+        other.load_rom(&counting_rom()).unwrap();
+        other.load_state(&state).unwrap();
+        assert_eq!(other.registers(), gb.registers());
+        assert_eq!(other.read_ram(0xC000), gb.read_ram(0xC000));
+    }
+
+    #[test]
+    fn failed_load_keeps_current_state() {
+        let mut gb = running_cpu();
+        let mut state = gb.save_state();
+        while !gb.tick() {}
+        let regs = gb.registers();
+        let counter = gb.read_ram(0xC000);
+
+        // The cartridge RAM's length comes right after the CPU, so it's only found wrong once the registers are loaded
+        let ram_len = NATIVE_MAGIC.len() + 27;
+        state[ram_len..ram_len + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(gb.load_state(&state), Err(StateError::Invalid("cartridge RAM size doesn't match")));
+        assert_eq!(gb.registers(), regs);
+        assert_eq!(gb.read_ram(0xC000), counter);
+    }
+
+    #[test]
+    fn state_for_another_game_is_refused() {
+        let state = running_cpu().save_state();
+        let mut rom = counting_rom();
+        rom[0x0134] = b'X';
+        let mut other = Cpu::new();
+        other.load_rom(&rom).unwrap();
+        assert_eq!(other.load_state(&state), Err(StateError::WrongGame));
+    }
+
+    #[test]
+    fn reset_keeps_unsaved_battery_data() {
+        // MBC1 with 32 KiB of battery backed RAM
+        let mut rom = rom_with(&[0x18, 0xFE]);
+        rom[0x0147] = 0x03;
+        rom[0x0149] = 0x03;
+        let mut gb = Cpu::new();
+        gb.load_rom(&rom).unwrap();
+        gb.write_ram(0x0000, 0x0A);
+        gb.write_ram(0xA000, 0x42);
+        for _ in 0..3 {
+            while !gb.tick() {}
+        }
+        assert!(gb.battery_dirty_since(3));
+
+        gb.reset();
+        assert!(gb.battery_dirty_since(3));
+        assert_eq!(gb.get_battery_data()[0], 0x42);
+    }
+
+    #[test]
+    fn reset_runs_boot_rom_again() {
+        let mut boot_rom = vec![0x00; 0x100];
+        boot_rom[0x00] = 0x31;
+        let mut gb = Cpu::new();
+        gb.load_rom(&rom_with(&[0x18, 0xFE])).unwrap();
+        gb.load_boot_rom(&boot_rom).unwrap();
+        assert_eq!(gb.read_ram(0x0000), 0x31);
+
+        // Hand over to the game, as the boot ROM does when it's finished
+        gb.write_ram(0xFF50, 0x01);
+        assert_eq!(gb.read_ram(0x0000), 0x00);
+
+        gb.reset();
+        assert_eq!(gb.get_pc(), 0x0000);
+        assert_eq!(gb.read_ram(0x0000), 0x31);
+    }
+}
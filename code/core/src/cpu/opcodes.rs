@@ -26,8 +26,10 @@ pub fn execute(cpu: &mut Cpu) -> u8 {
     OPCODES[op_index as usize](cpu)
 }
 
-fn invalid(_cpu: &mut Cpu) -> u8 {
-    panic!("Invalid opcode");
+fn invalid(cpu: &mut Cpu) -> u8 {
+    let opcode = cpu.read_ram(cpu.get_pc().wrapping_sub(1));
+    cpu.lock_up(opcode);
+    1
 }
 
 // NOP
@@ -49,7 +51,7 @@ fn ld_01(cpu: &mut Cpu) -> u8 {
 fn ld_02(cpu: &mut Cpu) -> u8 {
     let val = cpu.get_r8(Regs::A);
     let addr = cpu.get_r16(Regs16::BC);
-    cpu.write_ram(addr, val);
+    cpu.write_cycle(addr, val);
     2
 }
 
@@ -95,8 +97,8 @@ fn rlca_07(cpu: &mut Cpu) -> u8 {
 fn ld_08(cpu: &mut Cpu) -> u8 {
     let addr = cpu.fetch_u16();
     let val = cpu.get_r16(Regs16::SP);
-    cpu.write_ram(addr, val.low_byte());
-    cpu.write_ram(addr + 1, val.high_byte());
+    cpu.write_cycle(addr, val.low_byte());
+    cpu.write_cycle(addr.wrapping_add(1), val.high_byte());
     5
 }
 
@@ -111,7 +113,7 @@ fn add_09(cpu: &mut Cpu) -> u8 {
 // ----
 fn ld_0a(cpu: &mut Cpu) -> u8 {
     let addr = cpu.get_r16(Regs16::BC);
-    let val = cpu.read_ram(addr);
+    let val = cpu.read_cycle(addr);
     cpu.set_r8(Regs::A, val);
     2
 }
@@ -173,7 +175,7 @@ fn ld_11(cpu: &mut Cpu) -> u8 {
 fn ld_12(cpu: &mut Cpu) -> u8 {
     let addr = cpu.get_r16(Regs16::DE);
     let val = cpu.get_r8(Regs::A);
-    cpu.write_ram(addr, val);
+    cpu.write_cycle(addr, val);
     2
 }
 
@@ -235,7 +237,7 @@ fn add_19(cpu: &mut Cpu) -> u8 {
 // ----
 fn ld_1a(cpu: &mut Cpu) -> u8 {
     let addr = cpu.get_r16(Regs16::DE);
-    let val = cpu.read_ram(addr);
+    let val = cpu.read_cycle(addr);
     cpu.set_r8(Regs::A, val);
     2
 }
@@ -304,7 +306,7 @@ fn ld_21(cpu: &mut Cpu) -> u8 {
 fn ld_22(cpu: &mut Cpu) -> u8 {
     let addr = cpu.get_r16(Regs16::HL);
     let val = cpu.get_r8(Regs::A);
-    cpu.write_ram(addr, val);
+    cpu.write_cycle(addr, val);
     cpu.set_r16(Regs16::HL, addr.wrapping_add(1));
     2
 }
@@ -394,7 +396,7 @@ fn add_29(cpu: &mut Cpu) -> u8 {
 // ----
 fn ld_2a(cpu: &mut Cpu) -> u8 {
     let addr = cpu.get_r16(Regs16::HL);
-    let val = cpu.read_ram(addr);
+    let val = cpu.read_cycle(addr);
     cpu.set_r8(Regs::A, val);
     cpu.set_r16(Regs16::HL, addr.wrapping_add(1));
     2
@@ -466,7 +468,7 @@ fn ld_31(cpu: &mut Cpu) -> u8 {
 fn ld_32(cpu: &mut Cpu) -> u8 {
     let addr = cpu.get_r16(Regs16::HL);
     let val = cpu.get_r8(Regs::A);
-    cpu.write_ram(addr, val);
+    cpu.write_cycle(addr, val);
     cpu.set_r16(Regs16::HL, addr.wrapping_sub(1));
     2
 }
@@ -497,7 +499,7 @@ fn dec_35(cpu: &mut Cpu) -> u8 {
 fn ld_36(cpu: &mut Cpu) -> u8 {
     let val = cpu.fetch();
     let addr = cpu.get_r16(Regs16::HL);
-    cpu.write_ram(addr, val);
+    cpu.write_cycle(addr, val);
     3
 }
 
@@ -535,7 +537,7 @@ fn add_39(cpu: &mut Cpu) -> u8 {
 // ----
 fn ld_3a(cpu: &mut Cpu) -> u8 {
     let addr = cpu.get_r16(Regs16::HL);
-    let val = cpu.read_ram(addr);
+    let val = cpu.read_cycle(addr);
     cpu.set_r8(Regs::A, val);
     cpu.set_r16(Regs16::HL, addr.wrapping_sub(1));
     2
@@ -1887,7 +1889,7 @@ fn ld_e0(cpu: &mut Cpu) -> u8 {
     let offset = cpu.fetch() as u16;
     let addr = 0xFF00 + offset;
     let val = cpu.get_r8(Regs::A);
-    cpu.write_ram(addr, val);
+    cpu.write_cycle(addr, val);
     3
 }
 
@@ -1905,7 +1907,7 @@ fn ld_e2(cpu: &mut Cpu) -> u8 {
     let offset = cpu.get_r8(Regs::C) as u16;
     let addr = 0xFF00 + offset;
     let val = cpu.get_r8(Regs::A);
-    cpu.write_ram(addr, val);
+    cpu.write_cycle(addr, val);
     2
 }
 
@@ -1974,7 +1976,7 @@ fn jp_e9(cpu: &mut Cpu) -> u8 {
 fn ld_ea(cpu: &mut Cpu) -> u8 {
     let addr = cpu.fetch_u16();
     let val = cpu.get_r8(Regs::A);
-    cpu.write_ram(addr, val);
+    cpu.write_cycle(addr, val);
     4
 }
 
@@ -1999,7 +2001,7 @@ fn rst_ef(cpu: &mut Cpu) -> u8 {
 fn ld_f0(cpu: &mut Cpu) -> u8 {
     let offset = cpu.fetch() as u16;
     let addr = 0xFF00 + offset;
-    let val = cpu.read_ram(addr);
+    let val = cpu.read_cycle(addr);
     cpu.set_r8(Regs::A, val);
     3
 }
@@ -2017,7 +2019,7 @@ fn pop_f1(cpu: &mut Cpu) -> u8 {
 fn ld_f2(cpu: &mut Cpu) -> u8 {
     let offset = cpu.get_r8(Regs::C) as u16;
     let addr = 0xFF00 + offset;
-    let val = cpu.read_ram(addr);
+    let val = cpu.read_cycle(addr);
     cpu.set_r8(Regs::A, val);
     2
 }
@@ -2081,7 +2083,7 @@ fn ld_f9(cpu: &mut Cpu) -> u8 {
 // ----
 fn ld_fa(cpu: &mut Cpu) -> u8 {
     let addr = cpu.fetch_u16();
-    let val = cpu.read_ram(addr);
+    let val = cpu.read_cycle(addr);
     cpu.set_r8(Regs::A, val);
     4
 }
@@ -0,0 +1,109 @@
+use crate::cpu::{Cpu, Interrupts, StepInfo, IRQ_PRIORITIES};
+
+use std::collections::HashMap;
+
+// Addresses past the cartridge ROM aren't banked the same way, so they're all counted as bank 0
+const ROM_END: u16 = 0x7FFF;
+
+/// An instruction address and the M-cycles spent running it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HotSpot {
+    pub bank: u16,
+    pub pc: u16,
+    pub cycles: u64,
+}
+
+/*
+ * Profiler
+ *
+ * Counts how many M-cycles are spent on each instruction, keyed by its ROM
+ * bank as well as its address, since the same address in the switchable
+ * bank is different code depending on which bank is mapped.
+ *
+ * Cycles are also totalled for each interrupt handler, including anything
+ * it calls. A handler is entered when its interrupt is dispatched, and left
+ * once the stack pointer rises above where it was just after the dispatch,
+ * meaning the return address has been popped. Handlers can interrupt each
+ * other, so these are kept as a stack, with the innermost being charged.
+ *
+ * Cycles spent halted aren't charged to anything but their own total, as
+ * otherwise the HALT instruction would usually be the hottest spot of all.
+ */
+pub struct Profiler {
+    cycles: HashMap<(u16, u16), u64>,
+    irq_cycles: [u64; IRQ_PRIORITIES.len()],
+    // The interrupts being handled, with the stack pointer just after each was dispatched
+    handlers: Vec<(Interrupts, u16)>,
+    halted_cycles: u64,
+    total_cycles: u64,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self {
+            cycles: HashMap::new(),
+            irq_cycles: [0; IRQ_PRIORITIES.len()],
+            handlers: Vec::new(),
+            halted_cycles: 0,
+            total_cycles: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Called after every step, charging its cycles to the instruction that ran
+    pub fn record(&mut self, gb: &Cpu, info: &StepInfo) {
+        let cycles = info.cycles as u64;
+        self.total_cycles += cycles;
+
+        let sp = gb.registers().sp;
+        while self.handlers.last().is_some_and(|&(_, handler_sp)| sp > handler_sp) {
+            self.handlers.pop();
+        }
+        if let Some(irq) = info.interrupt {
+            self.handlers.push((irq, sp));
+        }
+        if let Some(&(irq, _)) = self.handlers.last() {
+            self.irq_cycles[irq as usize] += cycles;
+        }
+
+        if info.opcode.is_none() {
+            self.halted_cycles += cycles;
+            return;
+        }
+        let pc = info.pc_before;
+        let bank = if pc <= ROM_END { gb.get_rom_bank(pc) } else { 0 };
+        *self.cycles.entry((bank, pc)).or_insert(0) += cycles;
+    }
+
+    /// Returns the count instructions which have taken the most cycles, hottest first
+    pub fn hot_spots(&self, count: usize) -> Vec<HotSpot> {
+        let mut spots: Vec<HotSpot> = self.cycles.iter()
+            .map(|(&(bank, pc), &cycles)| HotSpot { bank, pc, cycles })
+            .collect();
+        spots.sort_by(|a, b| b.cycles.cmp(&a.cycles).then((a.bank, a.pc).cmp(&(b.bank, b.pc))));
+        spots.truncate(count);
+        spots
+    }
+
+    /// Returns the cycles spent handling each interrupt
+    pub fn irq_cycles(&self) -> Vec<(Interrupts, u64)> {
+        IRQ_PRIORITIES.iter().map(|&irq| (irq, self.irq_cycles[irq as usize])).collect()
+    }
+
+    pub fn halted_cycles(&self) -> u64 {
+        self.halted_cycles
+    }
+
+    pub fn total_cycles(&self) -> u64 {
+        self.total_cycles
+    }
+}
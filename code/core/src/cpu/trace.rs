@@ -0,0 +1,17 @@
+use crate::cpu::RegisterFile;
+
+/// The CPU just before it runs an instruction
+#[derive(Clone, Copy, Debug)]
+pub struct TraceEntry {
+    pub regs: RegisterFile,
+    pub opcode: u8,
+    /// The two bytes after the opcode, whether or not the instruction uses them
+    pub operands: [u8; 2],
+    /// T-cycles emulated since power on
+    pub cycles: u64,
+}
+
+/// Sees every instruction as it's about to run, such as to log execution for comparing against other emulators
+pub trait Tracer {
+    fn trace(&mut self, entry: &TraceEntry);
+}
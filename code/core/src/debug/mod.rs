@@ -0,0 +1,324 @@
+pub mod disasm;
+pub mod tracer;
+
+use crate::cpu::{Cpu, Interrupts, Regs, Regs16, StepInfo};
+use crate::debug::disasm::{disassemble, is_call, is_ret};
+use crate::hooks::{Access, AccessLog, HookId, MemAccess};
+
+/*
+ * Debugger
+ *
+ * Keeps track of where the game should be stopped, and is checked after
+ * every step to see if it should be. What happens once it's stopped is up to
+ * the frontend, whether that's a command prompt or a web page.
+ *
+ * Breakpoints stop on executing, reading, or writing an address, with write
+ * breakpoints able to only stop on certain values. Interrupts can also be
+ * broken on as they're dispatched. On top of these, a single stop condition
+ * can be set for commands which run more than one instruction, such as
+ * stepping over a call, which is forgotten once it's been met.
+ *
+ * Reads and writes are seen through a memory hook, so every access an
+ * instruction makes is checked, but the debugger has to be attached to the
+ * game for read and write breakpoints to work.
+ */
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum BreakpointKind {
+    Read,
+    Write,
+    Exec,
+}
+
+/// Limits a write breakpoint to only the values it cares about
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum WriteCondition {
+    Equal(u8),
+    NotEqual(u8),
+}
+
+impl WriteCondition {
+    /// Parses an '==' or '!=' followed by a hex byte
+    pub fn parse(op: &str, val: &str) -> Option<Self> {
+        let val = u8::from_str_radix(val, 16).ok()?;
+        match op {
+            "==" => { Some(WriteCondition::Equal(val))    },
+            "!=" => { Some(WriteCondition::NotEqual(val)) },
+            _ =>    { None                                }
+        }
+    }
+
+    pub fn matches(&self, val: u8) -> bool {
+        match *self {
+            WriteCondition::Equal(target) => { val == target },
+            WriteCondition::NotEqual(target) => { val != target },
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct Breakpoint {
+    addr: u16,
+    kind: BreakpointKind,
+    condition: Option<WriteCondition>,
+}
+
+impl Breakpoint {
+    pub fn new(addr: u16, kind: BreakpointKind) -> Self {
+        Self { addr, kind, condition: None }
+    }
+
+    pub fn with_condition(addr: u16, condition: WriteCondition) -> Self {
+        Self { addr, kind: BreakpointKind::Write, condition: Some(condition) }
+    }
+
+    pub fn get_addr(&self) -> u16 {
+        self.addr
+    }
+
+    pub fn get_type(&self) -> BreakpointKind {
+        self.kind
+    }
+
+    pub fn get_condition(&self) -> Option<WriteCondition> {
+        self.condition
+    }
+}
+
+/// Where to stop again after resuming, for commands which run more than one instruction
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StopCondition {
+    /// Once this many more instructions have run, with 0 stopping after the next one as 1 does
+    Steps(u32),
+    /// Once a call has returned to this address, with the stack back where it was
+    Return { pc: u16, sp: u16 },
+    /// Once a RET pops the stack above this point, leaving the current routine
+    Finish { sp: u16 },
+    /// Once execution reaches this address, like a breakpoint which removes itself
+    Address(u16),
+}
+
+impl StopCondition {
+    /// Runs until the call about to be made has returned, or None if the next instruction isn't a call
+    pub fn step_over(gb: &Cpu) -> Option<Self> {
+        let pc = gb.get_pc();
+        if !is_call(gb.read_ram(pc)) {
+            return None;
+        }
+        let ret = pc.wrapping_add(disassemble(gb, pc).len);
+        Some(StopCondition::Return { pc: ret, sp: gb.registers().sp })
+    }
+
+    /// Runs until the current routine returns
+    pub fn finish(gb: &Cpu) -> Self {
+        StopCondition::Finish { sp: gb.registers().sp }
+    }
+}
+
+/// Why the debugger wants the game stopped
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BreakReason {
+    /// A stop condition was met, leaving the PC here
+    Stopped(u16),
+    Interrupt(Interrupts),
+    Exec(u16),
+    Read(u16),
+    Write(MemAccess),
+}
+
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    irq_breakpoints: Vec<Interrupts>,
+    stop: Option<StopCondition>,
+    // Every access the game makes, gathered into step_accesses as each step is checked
+    log: AccessLog,
+    step_accesses: Vec<MemAccess>,
+    hook: Option<HookId>,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self {
+            breakpoints: Vec::new(),
+            irq_breakpoints: Vec::new(),
+            stop: None,
+            log: AccessLog::new(),
+            step_accesses: Vec::new(),
+            hook: None,
+        }
+    }
+
+    /// Starts watching the game's memory accesses, which read and write breakpoints need.
+    /// Once attached, check must be called after every step, as that's what empties the log.
+    pub fn attach(&mut self, gb: &mut Cpu) {
+        if self.hook.is_none() {
+            self.hook = Some(gb.add_memory_hook(Box::new(self.log.clone())));
+        }
+    }
+
+    /// Stops watching the game's memory accesses, such as when something else takes over running it
+    pub fn detach(&mut self, gb: &mut Cpu) {
+        if let Some(hook) = self.hook.take() {
+            gb.remove_memory_hook(hook);
+        }
+        self.log.take_into(&mut self.step_accesses);
+        self.step_accesses.clear();
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16, kind: BreakpointKind) {
+        self.add(Breakpoint::new(addr, kind));
+    }
+
+    pub fn add_watchpoint(&mut self, addr: u16, condition: WriteCondition) {
+        self.add(Breakpoint::with_condition(addr, condition));
+    }
+
+    fn add(&mut self, breakpoint: Breakpoint) {
+        if !self.breakpoints.contains(&breakpoint) {
+            self.breakpoints.push(breakpoint);
+        }
+    }
+
+    /// Removes the first breakpoint at that address, returning false if there wasn't one
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        let Some(idx) = self.breakpoints.iter().position(|bp| bp.get_addr() == addr) else {
+            return false;
+        };
+        self.breakpoints.remove(idx);
+        true
+    }
+
+    pub fn get_breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    pub fn add_irq_breakpoint(&mut self, irq: Interrupts) {
+        if !self.irq_breakpoints.contains(&irq) {
+            self.irq_breakpoints.push(irq);
+        }
+    }
+
+    /// Starts or stops breaking on an interrupt, returning whether it's now broken on
+    pub fn toggle_irq_breakpoint(&mut self, irq: Interrupts) -> bool {
+        if let Some(idx) = self.irq_breakpoints.iter().position(|&bp| bp == irq) {
+            self.irq_breakpoints.remove(idx);
+            false
+        } else {
+            self.irq_breakpoints.push(irq);
+            true
+        }
+    }
+
+    pub fn get_irq_breakpoints(&self) -> &[Interrupts] {
+        &self.irq_breakpoints
+    }
+
+    pub fn set_stop_condition(&mut self, stop: Option<StopCondition>) {
+        self.stop = stop;
+    }
+
+    /// Called after every step, returning why the game should stop there, if it should
+    pub fn check(&mut self, gb: &Cpu, info: &StepInfo) -> Option<BreakReason> {
+        // Taken before anything else can stop the game, so nothing carries over into the next step
+        self.log.take_into(&mut self.step_accesses);
+        if self.check_stop_condition(gb, info) {
+            return Some(BreakReason::Stopped(info.pc_after));
+        }
+        if let Some(irq) = info.interrupt.filter(|irq| self.irq_breakpoints.contains(irq)) {
+            return Some(BreakReason::Interrupt(irq));
+        }
+
+        let pc = gb.get_pc();
+        for bp in &self.breakpoints {
+            let accessed = |kind: Access| self.step_accesses.iter().filter(move |access| {
+                access.kind == kind && access.addr == bp.get_addr()
+            });
+            match bp.get_type() {
+                BreakpointKind::Exec if bp.get_addr() == pc => {
+                    return Some(BreakReason::Exec(pc));
+                },
+                BreakpointKind::Read if accessed(Access::Read).next().is_some() => {
+                    return Some(BreakReason::Read(bp.get_addr()));
+                },
+                BreakpointKind::Write => {
+                    let mut writes = accessed(Access::Write);
+                    if let Some(write) = writes.find(|write| bp.get_condition().is_none_or(|condition| condition.matches(write.val))) {
+                        return Some(BreakReason::Write(*write));
+                    }
+                },
+                _ => {},
+            }
+        }
+        None
+    }
+
+    // Returns true once the stop condition has been met, forgetting it
+    fn check_stop_condition(&mut self, gb: &Cpu, info: &StepInfo) -> bool {
+        let Some(stop) = self.stop else {
+            return false;
+        };
+        let done = match stop {
+            StopCondition::Steps(count) => {
+                self.stop = Some(StopCondition::Steps(count.saturating_sub(1)));
+                count <= 1
+            },
+            StopCondition::Return { pc, sp } => {
+                info.pc_after == pc && gb.registers().sp == sp
+            },
+            StopCondition::Finish { sp } => {
+                info.opcode.is_some_and(is_ret) && gb.registers().sp > sp
+            },
+            StopCondition::Address(addr) => {
+                info.pc_after == addr
+            },
+        };
+        if done {
+            self.stop = None;
+        }
+        done
+    }
+}
+
+/// Parses an 8-bit register name, in lowercase
+pub fn name2reg8(name: &str) -> Option<Regs> {
+    match name {
+        "a" => { Some(Regs::A) },
+        "b" => { Some(Regs::B) },
+        "c" => { Some(Regs::C) },
+        "d" => { Some(Regs::D) },
+        "e" => { Some(Regs::E) },
+        "f" => { Some(Regs::F) },
+        "h" => { Some(Regs::H) },
+        "l" => { Some(Regs::L) },
+        _ =>   { None          }
+    }
+}
+
+/// Parses a 16-bit register name, in lowercase, other than the PC
+pub fn name2reg16(name: &str) -> Option<Regs16> {
+    match name {
+        "af" => { Some(Regs16::AF) },
+        "bc" => { Some(Regs16::BC) },
+        "de" => { Some(Regs16::DE) },
+        "hl" => { Some(Regs16::HL) },
+        "sp" => { Some(Regs16::SP) },
+        _ =>    { None             }
+    }
+}
+
+pub fn name2irq(name: &str) -> Option<Interrupts> {
+    match name {
+        "vblank" => { Some(Interrupts::Vblank) },
+        "stat" =>   { Some(Interrupts::Stat)   },
+        "timer" =>  { Some(Interrupts::Timer)  },
+        "serial" => { Some(Interrupts::Serial) },
+        "joypad" => { Some(Interrupts::Joypad) },
+        _ =>        { None                     }
+    }
+}
@@ -0,0 +1,32 @@
+use crate::cpu::trace::{TraceEntry, Tracer};
+use crate::debug::disasm::decode;
+
+use std::io::Write;
+
+/// Logs each instruction as a line of text, such as to a file for comparing against other emulators
+pub struct TextTracer<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> TextTracer<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+}
+
+impl<W: Write> Tracer for TextTracer<W> {
+    fn trace(&mut self, entry: &TraceEntry) {
+        let (name, len) = decode(entry.opcode, entry.operands[0]);
+        let mut bytes = format!("{:02x}", entry.opcode);
+        for arg in &entry.operands[..len as usize - 1] {
+            bytes = format!("{} {:02x}", bytes, arg);
+        }
+        let regs = &entry.regs;
+        // A failed write only loses trace output, which isn't worth interrupting the game over
+        let _ = writeln!(
+            self.writer,
+            "PC: 0x{:04x} | {:<8} | {:<14} | AF: 0x{:04x} BC: 0x{:04x} DE: 0x{:04x} HL: 0x{:04x} SP: 0x{:04x} | {}",
+            regs.pc, bytes, name, regs.af, regs.bc, regs.de, regs.hl, regs.sp, entry.cycles
+        );
+    }
+}
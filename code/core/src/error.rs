@@ -0,0 +1,45 @@
+use crate::state::StateError;
+
+use std::fmt;
+
+/// Why the emulator refused something a frontend asked of it
+///
+/// Problems with the data being loaded are reported here rather than panicking, as a bad file
+/// shouldn't take the whole frontend down with it, least of all in a browser
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum GbError {
+    /// The ROM ends before the cartridge header does, with its actual size
+    RomTooSmall(usize),
+    /// The cartridge header asks for a mapper which isn't emulated, with its cartridge type byte
+    UnsupportedCartridge(u8),
+    /// The boot ROM isn't the size of a DMG or CGB one, with its actual size
+    InvalidBootRom(usize),
+    /// A save state couldn't be loaded
+    State(StateError),
+}
+
+impl fmt::Display for GbError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GbError::RomTooSmall(size) => { write!(f, "ROM is too small to hold a cartridge header ({} bytes)", size) },
+            GbError::UnsupportedCartridge(cart_type) => { write!(f, "unsupported cartridge type ${:02X}", cart_type) },
+            GbError::InvalidBootRom(size) => { write!(f, "not a DMG or CGB boot ROM ({} bytes)", size) },
+            GbError::State(err) => { write!(f, "{}", err) },
+        }
+    }
+}
+
+impl std::error::Error for GbError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GbError::State(err) => { Some(err) },
+            _ => { None },
+        }
+    }
+}
+
+impl From<StateError> for GbError {
+    fn from(err: StateError) -> Self {
+        GbError::State(err)
+    }
+}
@@ -0,0 +1,175 @@
+use crate::cart::CartInfo;
+use crate::config::CpuConfig;
+use crate::cpu::Cpu;
+use crate::error::GbError;
+use crate::hooks::{HookId, MemoryHook};
+use crate::io::Buttons;
+use crate::serial::SerialDevice;
+use crate::utils::DISPLAY_BUFFER;
+
+/*
+ * Game Boy
+ *
+ * Everything a frontend needs to run a game, gathered in one place so it
+ * doesn't have to know how the CPU, bus, and PPU fit together. A frontend
+ * loads a ROM, then once per frame passes in the held buttons, calls
+ * run_frame, and draws the framebuffer. Battery saves and save states come
+ * out as bytes, leaving where they're stored up to the frontend.
+ *
+ * There is no sound yet, as the APU hasn't been written. Debuggers and other
+ * tools which need more than this can reach the Cpu underneath.
+ */
+
+pub struct GameBoy {
+    cpu: Cpu,
+    frames: u64,
+}
+
+impl Default for GameBoy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GameBoy {
+    pub fn new() -> Self {
+        Self::with_config(CpuConfig::default())
+    }
+
+    pub fn with_config(config: CpuConfig) -> Self {
+        Self { cpu: Cpu::with_config(config), frames: 0 }
+    }
+
+    /// Loads a game, ready to run from the start, or keeps the current one if this one can't be run
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), GbError> {
+        self.cpu.load_rom(rom)?;
+        self.frames = 0;
+        Ok(())
+    }
+
+    /// Runs a boot ROM before the game, if it's a DMG or CGB boot ROM. Must come after load_rom.
+    pub fn load_boot_rom(&mut self, data: &[u8]) -> Result<(), GbError> {
+        self.cpu.load_boot_rom(data)
+    }
+
+    /// Turns the power off and on again, keeping the game and its battery save
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+        self.frames = 0;
+    }
+
+    pub fn title(&self) -> &str {
+        self.cpu.get_title()
+    }
+
+    /// Returns the details listed in the cartridge header
+    pub fn cart_info(&self) -> CartInfo {
+        self.cpu.cart_info()
+    }
+
+    /// Runs until the next frame is ready to draw, returning false if the CPU locked up before then
+    pub fn run_frame(&mut self) -> bool {
+        while !self.cpu.tick() {
+            if self.cpu.is_locked() {
+                return false;
+            }
+        }
+        self.frames += 1;
+        true
+    }
+
+    /// Returns how many frames have been run since the game was loaded or reset
+    pub fn frame_count(&self) -> u64 {
+        self.frames
+    }
+
+    /// Returns the invalid opcode which locked up the CPU, if it has
+    pub fn locked_opcode(&self) -> Option<u8> {
+        self.cpu.get_locked_opcode()
+    }
+
+    /// Returns the most recently drawn frame as RGBA, SCREEN_WIDTH by SCREEN_HEIGHT pixels
+    pub fn framebuffer(&self) -> &[u8; DISPLAY_BUFFER] {
+        self.cpu.render()
+    }
+
+    /// Whether the framebuffer differs from when this was last called, so unchanged frames needn't be drawn again
+    pub fn take_frame_changed(&mut self) -> bool {
+        self.cpu.take_frame_changed()
+    }
+
+    pub fn press_button(&mut self, button: Buttons, pressed: bool) {
+        self.cpu.press_button(button, pressed);
+    }
+
+    /// Returns every held button as a bitmask, with each button's bit numbered by its value
+    pub fn buttons(&self) -> u8 {
+        self.cpu.get_buttons()
+    }
+
+    /// Holds exactly the buttons in a bitmask, as returned by buttons()
+    pub fn set_buttons(&mut self, mask: u8) {
+        self.cpu.set_buttons(mask);
+    }
+
+    pub fn has_battery(&self) -> bool {
+        self.cpu.has_battery()
+    }
+
+    /// Returns the battery backed data, such as the cartridge RAM and RTC, in the same format as .sav files
+    pub fn battery_data(&self) -> Vec<u8> {
+        self.cpu.get_battery_data()
+    }
+
+    /// Restores battery backed data saved earlier, which should be done right after load_rom
+    pub fn load_battery_data(&mut self, data: &[u8]) {
+        self.cpu.set_battery_data(data);
+        self.cpu.clean_battery();
+    }
+
+    /// Returns the battery backed data once the game has written to it and then left it alone for the given number
+    /// of frames, so games which save a little at a time are only written out once they've finished
+    pub fn take_battery_save(&mut self, idle_frames: u32) -> Option<Vec<u8>> {
+        if !self.cpu.has_battery() || !self.cpu.battery_dirty_since(idle_frames) {
+            return None;
+        }
+        self.cpu.clean_battery();
+        Some(self.cpu.get_battery_data())
+    }
+
+    /// Returns a snapshot of the whole system, as a BESS file other emulators can also load
+    pub fn save_state(&self) -> Vec<u8> {
+        self.cpu.save_state()
+    }
+
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), GbError> {
+        Ok(self.cpu.load_state(data)?)
+    }
+
+    /// Plugs a device into the link port, or unplugs it if None
+    pub fn set_serial_device(&mut self, device: Option<Box<dyn SerialDevice>>) {
+        self.cpu.set_serial_device(device);
+    }
+
+    /// Returns the text the game has sent over the serial port since the last call
+    pub fn take_serial_output(&mut self) -> String {
+        self.cpu.take_serial_output()
+    }
+
+    /// Registers a hook to be told about every read, write, and instruction, such as for cheats or achievements
+    pub fn add_memory_hook(&mut self, hook: Box<dyn MemoryHook>) -> HookId {
+        self.cpu.add_memory_hook(hook)
+    }
+
+    pub fn remove_memory_hook(&mut self, id: HookId) -> bool {
+        self.cpu.remove_memory_hook(id)
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+}
@@ -0,0 +1,100 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// Far more than any one instruction makes, so only a log nobody is emptying ever fills up
+const MAX_LOG_LEN: usize = 0x10000;
+
+/*
+ * Memory Hooks
+ *
+ * Frontends can register hooks on the bus to hear about every memory access
+ * the CPU makes, in the order it makes them, rather than only what the last
+ * instruction touched. Debuggers use them for read and write breakpoints,
+ * while cheat engines and achievement trackers can use them to watch for
+ * particular values being read or stored.
+ *
+ * Only accesses made by the running game are reported. Peeks and pokes made
+ * without the passage of time, such as the debugger printing memory, aren't.
+ */
+
+/// What the CPU did at an address
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Access {
+    Read,
+    Write,
+    /// Began running the instruction there, with val holding its opcode
+    Execute,
+}
+
+/// A single memory access made by the CPU
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct MemAccess {
+    pub kind: Access,
+    pub addr: u16,
+    pub val: u8,
+    /// What the address read as before a write, otherwise the same as val
+    pub old: u8,
+}
+
+impl MemAccess {
+    pub fn read(addr: u16, val: u8) -> Self {
+        Self { kind: Access::Read, addr, val, old: val }
+    }
+
+    pub fn write(addr: u16, old: u8, val: u8) -> Self {
+        Self { kind: Access::Write, addr, val, old }
+    }
+
+    pub fn execute(addr: u16, opcode: u8) -> Self {
+        Self { kind: Access::Execute, addr, val: opcode, old: opcode }
+    }
+}
+
+/// Something told about each memory access as it happens
+pub trait MemoryHook {
+    fn on_access(&mut self, access: &MemAccess);
+}
+
+impl<F: FnMut(&MemAccess)> MemoryHook for F {
+    fn on_access(&mut self, access: &MemAccess) {
+        self(access);
+    }
+}
+
+/// Identifies a registered hook, so it can be removed again
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct HookId(pub(crate) u32);
+
+/// Records accesses for a frontend to look through afterwards, such as a debugger checking after each step.
+/// Clones share the same record, so one can be registered as a hook while another is kept to read it.
+/// Once MAX_LOG_LEN accesses are waiting to be taken, any more are dropped.
+#[derive(Clone)]
+pub struct AccessLog(Rc<RefCell<Vec<MemAccess>>>);
+
+impl Default for AccessLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AccessLog {
+    pub fn new() -> Self {
+        Self(Rc::new(RefCell::new(Vec::new())))
+    }
+
+    /// Moves everything recorded since the last call into the given list, replacing what it held.
+    /// Both lists keep their capacity, so checking after every instruction doesn't allocate.
+    pub fn take_into(&self, accesses: &mut Vec<MemAccess>) {
+        accesses.clear();
+        accesses.append(&mut self.0.borrow_mut());
+    }
+}
+
+impl MemoryHook for AccessLog {
+    fn on_access(&mut self, access: &MemAccess) {
+        let mut log = self.0.borrow_mut();
+        if log.len() < MAX_LOG_LEN {
+            log.push(*access);
+        }
+    }
+}
@@ -1,15 +1,19 @@
+use crate::serial::*;
+use crate::sgb::{Sgb, SgbColors};
+use crate::state::{StateError, StateReader, StateWriter};
 use crate::timer::*;
 use crate::utils::*;
 
 pub const IO_START: u16   = 0xFF00;
 pub const IO_STOP: u16    = 0xFF3F;
 
-const JOYPAD_ADDR: u16    = 0xFF00;
+pub const JOYPAD_ADDR: u16 = 0xFF00;
 const IO_SIZE: usize      = (IO_STOP - IO_START + 1) as usize;
 
 const FACE_SELECT_BIT: u8 = 5;
 const DPAD_SELECT_BIT: u8 = 4;
 
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum Buttons {
     A       = 0,
     B       = 1,
@@ -21,6 +25,13 @@ pub enum Buttons {
     Down    = 7,
 }
 
+impl Buttons {
+    pub const ALL: [Buttons; 8] = [
+        Buttons::A, Buttons::B, Buttons::Select, Buttons::Start,
+        Buttons::Right, Buttons::Left, Buttons::Up, Buttons::Down,
+    ];
+}
+
 const DPAD_BUTTONS: [Buttons; 4] = [
     Buttons::Right, Buttons::Left, Buttons::Up, Buttons::Down,
 ];
@@ -35,6 +46,14 @@ pub struct IO {
     face_selected: bool,
     ram: [u8; IO_SIZE],
     timer: Timer,
+    serial: Serial,
+    sgb: Sgb,
+}
+
+impl Default for IO {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl IO {
@@ -45,6 +64,8 @@ impl IO {
             face_selected: false,
             ram: [0; IO_SIZE],
             timer: Timer::new(),
+            serial: Serial::new(),
+            sgb: Sgb::new(),
         }
     }
 
@@ -53,6 +74,9 @@ impl IO {
             DIV..=TAC => {
                 self.timer.read_timer(addr)
             },
+            SB..=SC => {
+                self.serial.read_serial(addr)
+            },
             JOYPAD_ADDR => {
                 self.read_joypad()
             },
@@ -64,22 +88,31 @@ impl IO {
     }
 
     fn read_joypad(&self) -> u8 {
-        if self.face_selected == self.dpad_selected {
-            return 0;
+        // With neither group selected, the SGB reports which controller is active
+        if !self.face_selected && !self.dpad_selected {
+            return self.sgb.get_joypad_id().unwrap_or(0x0F);
+        }
+
+        // Only the first controller is connected, any others appear to have nothing pressed
+        if self.sgb.get_current_player() != 0 {
+            return 0x0F;
         }
 
-        let mut ret = 0;
+        let mut ret = 0x0F;
         if self.dpad_selected {
             for btn in DPAD_BUTTONS {
                 let idx = btn as usize;
-                let mask = (if self.buttons[idx] { 0 } else { 1 }) << (idx - 4);
-                ret |= mask;
+                if self.buttons[idx] {
+                    ret &= !(1 << (idx - 4));
+                }
             }
-        } else {
+        }
+        if self.face_selected {
             for btn in FACE_BUTTONS {
                 let idx = btn as usize;
-                let mask = (if self.buttons[idx] { 0 } else { 1 }) << idx;
-                ret |= mask;
+                if self.buttons[idx] {
+                    ret &= !(1 << idx);
+                }
             }
         }
         ret
@@ -89,18 +122,86 @@ impl IO {
         self.buttons[button as usize] = pressed;
     }
 
+    pub fn is_button_pressed(&self, button: Buttons) -> bool {
+        self.buttons[button as usize]
+    }
+
+    pub fn take_serial_output(&mut self) -> Vec<u8> {
+        self.serial.take_output()
+    }
+
+    pub fn set_cgb_mode(&mut self, cgb: bool) {
+        self.serial.set_cgb_mode(cgb);
+    }
+
+    pub fn set_sgb_enabled(&mut self, enabled: bool) {
+        self.sgb.set_enabled(enabled);
+    }
+
+    pub fn take_sgb_colors(&mut self) -> Option<Option<SgbColors>> {
+        self.sgb.take_colors()
+    }
+
+    /// Sets DIV directly, for save states from other emulators
+    pub fn set_div(&mut self, div: u8) {
+        self.timer.set_div(div);
+    }
+
+    // The buttons held are left as they are, as they belong to the player rather than the state
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.dpad_selected);
+        state.write_bool(self.face_selected);
+        state.write_bytes(&self.ram);
+        self.timer.save_state(state);
+        self.serial.save_state(state);
+        self.sgb.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.dpad_selected = state.read_bool()?;
+        self.face_selected = state.read_bool()?;
+        self.ram = state.read_array()?;
+        self.timer.load_state(state)?;
+        self.serial.load_state(state)?;
+        self.sgb.load_state(state)
+    }
+
     pub fn update_timer(&mut self, cycles: u8) -> bool {
         self.timer.tick(cycles)
     }
 
+    /// Puts the registers back how they are at power on, keeping the serial device plugged in
+    pub fn reset(&mut self) {
+        self.buttons = [false; 8];
+        self.dpad_selected = false;
+        self.face_selected = false;
+        self.ram = [0; IO_SIZE];
+        self.timer = Timer::new();
+        self.serial.reset();
+        self.sgb = Sgb::new();
+    }
+
+    pub fn set_serial_device(&mut self, device: Box<dyn SerialDevice>) {
+        self.serial.set_device(device);
+    }
+
+    // Should be called once per M-cycle, after the timer has been ticked
+    pub fn update_serial(&mut self) -> bool {
+        self.serial.tick(self.timer.get_system_counter())
+    }
+
     pub fn write_u8(&mut self, addr: u16, val: u8) {
         match addr {
             DIV..=TAC => {
                 self.timer.write_timer(addr, val);
             },
+            SB..=SC => {
+                self.serial.write_serial(addr, val);
+            },
             JOYPAD_ADDR => {
                 self.face_selected = !val.get_bit(FACE_SELECT_BIT);
                 self.dpad_selected = !val.get_bit(DPAD_SELECT_BIT);
+                self.sgb.write_joypad(val);
             },
             _ => {
                 let relative_addr = addr - IO_START;
@@ -0,0 +1,194 @@
+/*
+ * IO Register Descriptions
+ *
+ * The name of each memory mapped register, along with how its bits are split
+ * into fields, for debuggers and other tools to show what a value means.
+ * Fields list a name for each of their values where there's a meaningful one,
+ * otherwise the value is shown as a number.
+ *
+ * The sound registers are included for completeness, as games still write
+ * them, even though there's no audio emulated to make use of them.
+ */
+
+/// A group of bits within a register
+pub struct IoField {
+    pub name: &'static str,
+    /// The lowest bit of the field
+    pub shift: u8,
+    pub width: u8,
+    /// The names of each possible value, or empty if the value itself is what matters
+    pub values: &'static [&'static str],
+}
+
+impl IoField {
+    pub fn get_value(&self, val: u8) -> u8 {
+        let mask = ((1u16 << self.width) - 1) as u8;
+        (val >> self.shift) & mask
+    }
+
+    /// Returns the field's value from the full register, by name if it has one
+    pub fn describe(&self, val: u8) -> String {
+        let field = self.get_value(val);
+        match self.values.get(field as usize) {
+            Some(name) => { name.to_string() },
+            None => { format!("{}", field) },
+        }
+    }
+}
+
+pub struct IoRegister {
+    pub addr: u16,
+    pub name: &'static str,
+    pub fields: &'static [IoField],
+}
+
+impl IoRegister {
+    /// Returns each field of the register as name=value, separated by spaces
+    pub fn decode(&self, val: u8) -> String {
+        let fields: Vec<String> = self.fields.iter()
+            .map(|field| format!("{}={}", field.name, field.describe(val)))
+            .collect();
+        fields.join(" ")
+    }
+}
+
+const OFF_ON: &[&str] = &["Off", "On"];
+const TILEMAPS: &[&str] = &["9800", "9C00"];
+
+const fn bit(name: &'static str, shift: u8) -> IoField {
+    IoField { name, shift, width: 1, values: &[] }
+}
+
+const fn flag(name: &'static str, shift: u8) -> IoField {
+    IoField { name, shift, width: 1, values: OFF_ON }
+}
+
+const fn bits(name: &'static str, shift: u8, width: u8) -> IoField {
+    IoField { name, shift, width, values: &[] }
+}
+
+const fn named(name: &'static str, shift: u8, width: u8, values: &'static [&'static str]) -> IoField {
+    IoField { name, shift, width, values }
+}
+
+const fn reg(addr: u16, name: &'static str, fields: &'static [IoField]) -> IoRegister {
+    IoRegister { addr, name, fields }
+}
+
+const IRQ_FIELDS: &[IoField] = &[
+    bit("vblank", 0), bit("stat", 1), bit("timer", 2), bit("serial", 3), bit("joypad", 4),
+];
+
+const PALETTE_FIELDS: &[IoField] = &[
+    bits("id0", 0, 2), bits("id1", 2, 2), bits("id2", 4, 2), bits("id3", 6, 2),
+];
+
+const CGB_PALETTE_INDEX_FIELDS: &[IoField] = &[
+    bits("index", 0, 6), flag("auto_inc", 7),
+];
+
+/// Every named IO register, in address order
+pub const IO_REGISTERS: &[IoRegister] = &[
+    // Joypad, a selected bit being cleared rather than set
+    reg(0xFF00, "JOYP", &[
+        bits("inputs", 0, 4),
+        named("select", 4, 2, &["Both", "Buttons", "D-pad", "None"]),
+    ]),
+    // Serial
+    reg(0xFF01, "SB", &[]),
+    reg(0xFF02, "SC", &[
+        named("clock", 0, 1, &["External", "Internal"]),
+        named("speed", 1, 1, &["Normal", "Fast"]),
+        named("transfer", 7, 1, &["Idle", "Active"]),
+    ]),
+    // Timer
+    reg(0xFF04, "DIV", &[]),
+    reg(0xFF05, "TIMA", &[]),
+    reg(0xFF06, "TMA", &[]),
+    reg(0xFF07, "TAC", &[
+        named("clock", 0, 2, &["4096Hz", "262144Hz", "65536Hz", "16384Hz"]),
+        flag("enable", 2),
+    ]),
+    reg(0xFF0F, "IF", IRQ_FIELDS),
+    // Sound
+    reg(0xFF10, "NR10", &[]),
+    reg(0xFF11, "NR11", &[]),
+    reg(0xFF12, "NR12", &[]),
+    reg(0xFF13, "NR13", &[]),
+    reg(0xFF14, "NR14", &[]),
+    reg(0xFF16, "NR21", &[]),
+    reg(0xFF17, "NR22", &[]),
+    reg(0xFF18, "NR23", &[]),
+    reg(0xFF19, "NR24", &[]),
+    reg(0xFF1A, "NR30", &[]),
+    reg(0xFF1B, "NR31", &[]),
+    reg(0xFF1C, "NR32", &[]),
+    reg(0xFF1D, "NR33", &[]),
+    reg(0xFF1E, "NR34", &[]),
+    reg(0xFF20, "NR41", &[]),
+    reg(0xFF21, "NR42", &[]),
+    reg(0xFF22, "NR43", &[]),
+    reg(0xFF23, "NR44", &[]),
+    reg(0xFF24, "NR50", &[]),
+    reg(0xFF25, "NR51", &[]),
+    reg(0xFF26, "NR52", &[]),
+    // LCD
+    reg(0xFF40, "LCDC", &[
+        flag("bg", 0),
+        flag("obj", 1),
+        named("obj_size", 2, 1, &["8x8", "8x16"]),
+        named("bg_map", 3, 1, TILEMAPS),
+        named("tiles", 4, 1, &["8800", "8000"]),
+        flag("win", 5),
+        named("win_map", 6, 1, TILEMAPS),
+        flag("lcd", 7),
+    ]),
+    reg(0xFF41, "STAT", &[
+        named("mode", 0, 2, &["HBlank", "VBlank", "OAM", "Drawing"]),
+        bit("lyc_eq", 2),
+        flag("hblank_int", 3),
+        flag("vblank_int", 4),
+        flag("oam_int", 5),
+        flag("lyc_int", 6),
+    ]),
+    reg(0xFF42, "SCY", &[]),
+    reg(0xFF43, "SCX", &[]),
+    reg(0xFF44, "LY", &[]),
+    reg(0xFF45, "LYC", &[]),
+    reg(0xFF46, "DMA", &[]),
+    reg(0xFF47, "BGP", PALETTE_FIELDS),
+    reg(0xFF48, "OBP0", PALETTE_FIELDS),
+    reg(0xFF49, "OBP1", PALETTE_FIELDS),
+    reg(0xFF4A, "WY", &[]),
+    reg(0xFF4B, "WX", &[]),
+    // Game Boy Color only
+    reg(0xFF4D, "KEY1", &[
+        bit("switch", 0),
+        named("speed", 7, 1, &["Normal", "Double"]),
+    ]),
+    reg(0xFF4F, "VBK", &[bits("bank", 0, 1)]),
+    reg(0xFF50, "BANK", &[]),
+    reg(0xFF51, "HDMA1", &[]),
+    reg(0xFF52, "HDMA2", &[]),
+    reg(0xFF53, "HDMA3", &[]),
+    reg(0xFF54, "HDMA4", &[]),
+    reg(0xFF55, "HDMA5", &[
+        bits("length", 0, 7),
+        named("mode", 7, 1, &["General", "HBlank"]),
+    ]),
+    reg(0xFF56, "RP", &[]),
+    reg(0xFF68, "BCPS", CGB_PALETTE_INDEX_FIELDS),
+    reg(0xFF69, "BCPD", &[]),
+    reg(0xFF6A, "OCPS", CGB_PALETTE_INDEX_FIELDS),
+    reg(0xFF6B, "OCPD", &[]),
+    reg(0xFF6C, "OPRI", &[named("priority", 0, 1, &["OAM", "Coordinate"])]),
+    reg(0xFF70, "SVBK", &[bits("bank", 0, 3)]),
+    reg(0xFF76, "PCM12", &[]),
+    reg(0xFF77, "PCM34", &[]),
+    reg(0xFFFF, "IE", IRQ_FIELDS),
+];
+
+/// Returns the description of the register at that address, if there is one
+pub fn find_io_register(addr: u16) -> Option<&'static IoRegister> {
+    IO_REGISTERS.iter().find(|reg| reg.addr == addr)
+}
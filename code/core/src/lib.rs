@@ -1,8 +1,19 @@
 pub mod bus;
 pub mod cart;
+pub mod config;
 pub mod cpu;
+pub mod debug;
+pub mod error;
+pub mod gameboy;
+pub mod hooks;
 pub mod io;
+pub mod ioregs;
+pub mod movie;
 pub mod ppu;
+pub mod rewind;
+pub mod serial;
+pub mod sgb;
+pub mod state;
 pub mod timer;
 pub mod wram;
 pub mod utils;
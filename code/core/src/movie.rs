@@ -0,0 +1,109 @@
+use crate::cpu::Cpu;
+use crate::state::{StateError, StateReader, StateWriter};
+
+const MOVIE_MAGIC: &[u8; 4]     = b"GBMV";
+const MOVIE_VERSION: u16        = 1;
+
+/*
+ * Movies
+ *
+ * A recording of the buttons held on each frame, starting from a save state.
+ * Everything the game can see is in the state, including the power on RAM
+ * from whichever seed was used, so replaying the same buttons from the same
+ * state plays out exactly the same. The one exception is the MBC3 clock,
+ * which follows the real time.
+ *
+ * +------------------+
+ * | "GBMV"           |
+ * | Version (16)     |
+ * +------------------+
+ * | State size (32)  |
+ * | Save state       |
+ * +------------------+
+ * | Frames (32)      |
+ * | Buttons (8) for  |
+ * | each frame       |
+ * +------------------+
+ *
+ * Each frame's buttons are a bitmask as returned by Cpu::get_buttons, and
+ * are set just before the frame is run, both while recording and playing.
+ */
+pub struct Movie {
+    start_state: Vec<u8>,
+    frames: Vec<u8>,
+    position: usize,
+}
+
+impl Movie {
+    /// Starts a recording from the emulator's current state
+    pub fn record(gb: &Cpu) -> Self {
+        Self {
+            start_state: gb.save_state(),
+            frames: Vec::new(),
+            position: 0,
+        }
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, StateError> {
+        let mut reader = StateReader::new(data);
+        if &reader.read_array::<4>()? != MOVIE_MAGIC {
+            return Err(StateError::Invalid("not a movie"));
+        }
+        let version = reader.read_u16()?;
+        if version > MOVIE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        let state_len = reader.read_u32()? as usize;
+        let start_state = reader.read_bytes(state_len)?.to_vec();
+        let num_frames = reader.read_u32()? as usize;
+        let frames = reader.read_bytes(num_frames)?.to_vec();
+        Ok(Self { start_state, frames, position: 0 })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        writer.write_bytes(MOVIE_MAGIC);
+        writer.write_u16(MOVIE_VERSION);
+        writer.write_u32(self.start_state.len() as u32);
+        writer.write_bytes(&self.start_state);
+        writer.write_u32(self.frames.len() as u32);
+        writer.write_bytes(&self.frames);
+        writer.into_bytes()
+    }
+
+    /// Returns how many frames have been recorded
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Called before each frame is run while recording, noting which buttons are held
+    pub fn record_frame(&mut self, gb: &Cpu) {
+        self.frames.push(gb.get_buttons());
+    }
+
+    /// Puts the emulator back to where the recording started, ready to play it from the beginning
+    pub fn start_playback(&mut self, gb: &mut Cpu) -> Result<(), StateError> {
+        // Buttons aren't saved, so they're set to match first, letting the state undo the interrupt pressing them requests
+        if let Some(&buttons) = self.frames.first() {
+            gb.set_buttons(buttons);
+        }
+        gb.load_state(&self.start_state)?;
+        self.position = 0;
+        Ok(())
+    }
+
+    /// Called before each frame is run while playing, holding the recorded buttons,
+    /// returning false once every frame has been played
+    pub fn play_frame(&mut self, gb: &mut Cpu) -> bool {
+        let Some(&buttons) = self.frames.get(self.position) else {
+            return false;
+        };
+        gb.set_buttons(buttons);
+        self.position += 1;
+        true
+    }
+}
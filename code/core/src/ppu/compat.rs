@@ -0,0 +1,112 @@
+/*
+ * When a DMG game is started on a CGB, the boot ROM colorizes it by loading a
+ * set of palettes into the CGB palette RAM. The player can choose one of these
+ * by holding a button combination while the logo is displayed.
+ *
+ * Each entry holds the four shades for the background, OBP0, and OBP1 palettes
+ */
+type Shades = [u32; 4];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CompatPalette {
+    /// Up
+    Brown,
+    /// Up + A
+    Red,
+    /// Up + B
+    DarkBrown,
+    /// Left
+    Blue,
+    /// Left + A
+    DarkBlue,
+    /// Left + B
+    Grayscale,
+    /// Down
+    Pastel,
+    /// Down + A
+    Orange,
+    /// Down + B
+    Yellow,
+    /// Right, also used by the boot ROM for games it doesn't recognize
+    Green,
+    /// Right + A
+    DarkGreen,
+    /// Right + B
+    Inverted,
+}
+
+const BROWN: Shades     = [0xFFFFFF, 0xFFAD63, 0x843100, 0x000000];
+const RED: Shades       = [0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000];
+const GREEN: Shades     = [0xFFFFFF, 0x7BFF31, 0x008400, 0x000000];
+const BLUE: Shades      = [0xFFFFFF, 0x63A5FF, 0x0000FF, 0x000000];
+
+impl CompatPalette {
+    pub const ALL: [CompatPalette; 12] = [
+        CompatPalette::Brown,       CompatPalette::Red,         CompatPalette::DarkBrown,
+        CompatPalette::Blue,        CompatPalette::DarkBlue,    CompatPalette::Grayscale,
+        CompatPalette::Pastel,      CompatPalette::Orange,      CompatPalette::Yellow,
+        CompatPalette::Green,       CompatPalette::DarkGreen,   CompatPalette::Inverted,
+    ];
+
+    pub fn get_name(&self) -> &'static str {
+        match *self {
+            CompatPalette::Brown =>     { "Brown" },
+            CompatPalette::Red =>       { "Red" },
+            CompatPalette::DarkBrown => { "Dark Brown" },
+            CompatPalette::Blue =>      { "Blue" },
+            CompatPalette::DarkBlue =>  { "Dark Blue" },
+            CompatPalette::Grayscale => { "Grayscale" },
+            CompatPalette::Pastel =>    { "Pastel" },
+            CompatPalette::Orange =>    { "Orange" },
+            CompatPalette::Yellow =>    { "Yellow" },
+            CompatPalette::Green =>     { "Green" },
+            CompatPalette::DarkGreen => { "Dark Green" },
+            CompatPalette::Inverted =>  { "Inverted" },
+        }
+    }
+
+    fn get_shades(&self) -> [Shades; 3] {
+        match *self {
+            CompatPalette::Brown =>     { [BROWN, BROWN, BROWN] },
+            CompatPalette::Red =>       { [RED, GREEN, BLUE] },
+            CompatPalette::DarkBrown => { [[0xFFE6C5, 0xCE9C84, 0x846B29, 0x5A3108], BROWN, BROWN] },
+            CompatPalette::Blue =>      { [BLUE, RED, GREEN] },
+            CompatPalette::DarkBlue =>  { [[0xFFFFFF, 0x8C8CDE, 0x52528C, 0x000000], RED, BROWN] },
+            CompatPalette::Grayscale => {
+                let gray = [0xFFFFFF, 0xA5A5A5, 0x525252, 0x000000];
+                [gray, gray, gray]
+            },
+            CompatPalette::Pastel => {
+                let pastel = [0xFFFFA5, 0xFF9494, 0x9494FF, 0x000000];
+                [pastel, pastel, pastel]
+            },
+            CompatPalette::Orange => {
+                let orange = [0xFFFFFF, 0xFFFF00, 0xFF0000, 0x000000];
+                [orange, orange, orange]
+            },
+            CompatPalette::Yellow =>    { [[0xFFFFFF, 0xFFFF00, 0x7B4A00, 0x000000], BLUE, GREEN] },
+            CompatPalette::Green =>     { [[0xFFFFFF, 0x7BFF31, 0x0063C5, 0x000000], RED, RED] },
+            CompatPalette::DarkGreen => {
+                let dark_green = [0xFFFFFF, 0x52FF00, 0xFF4200, 0x000000];
+                [dark_green, dark_green, dark_green]
+            },
+            CompatPalette::Inverted => {
+                let inverted = [0x000000, 0x008484, 0xFFDE00, 0xFFFFFF];
+                [inverted, inverted, inverted]
+            },
+        }
+    }
+
+    pub fn get_bg_color(&self, shade: u8) -> [u8; 4] {
+        to_rgba(self.get_shades()[0][shade as usize])
+    }
+
+    pub fn get_obj_color(&self, palette1: bool, shade: u8) -> [u8; 4] {
+        let idx = if palette1 { 2 } else { 1 };
+        to_rgba(self.get_shades()[idx][shade as usize])
+    }
+}
+
+fn to_rgba(color: u32) -> [u8; 4] {
+    [(color >> 16) as u8, (color >> 8) as u8, color as u8, 255]
+}
@@ -0,0 +1,51 @@
+use crate::utils::GB_PALETTE;
+
+/*
+ * Built in colors for the four DMG shades, from lightest to darkest, to
+ * mimic the screens of the various monochrome models.
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DisplayPalette {
+    /// Plain shades of gray
+    Grayscale,
+    /// The yellow-green "pea soup" screen of the original Game Boy
+    DmgGreen,
+    /// The olive tinted screen of the Game Boy Pocket
+    Pocket,
+    /// The blue-green backlit screen of the Game Boy Light
+    LightBlue,
+    /// Evenly spaced grays, with the darkest shades as far apart as possible
+    HighContrast,
+}
+
+impl DisplayPalette {
+    pub const ALL: [DisplayPalette; 5] = [
+        DisplayPalette::Grayscale,  DisplayPalette::DmgGreen,   DisplayPalette::Pocket,
+        DisplayPalette::LightBlue,  DisplayPalette::HighContrast,
+    ];
+
+    pub fn get_name(&self) -> &'static str {
+        match *self {
+            DisplayPalette::Grayscale =>    { "Grayscale" },
+            DisplayPalette::DmgGreen =>     { "DMG Green" },
+            DisplayPalette::Pocket =>       { "Pocket" },
+            DisplayPalette::LightBlue =>    { "Light Blue" },
+            DisplayPalette::HighContrast => { "High Contrast" },
+        }
+    }
+
+    /// Returns the RGBA color of each shade, suitable for Cpu::set_palette
+    pub fn get_colors(&self) -> [[u8; 4]; 4] {
+        match *self {
+            DisplayPalette::Grayscale =>    { GB_PALETTE },
+            DisplayPalette::DmgGreen =>     { to_rgba([0x9BBC0F, 0x8BAC0F, 0x306230, 0x0F380F]) },
+            DisplayPalette::Pocket =>       { to_rgba([0xC4CFA1, 0x8B956D, 0x4D533C, 0x1F1F1F]) },
+            DisplayPalette::LightBlue =>    { to_rgba([0xE0F8F8, 0x86C0D0, 0x306888, 0x081830]) },
+            DisplayPalette::HighContrast => { to_rgba([0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000]) },
+        }
+    }
+}
+
+fn to_rgba(shades: [u32; 4]) -> [[u8; 4]; 4] {
+    shades.map(|color| [(color >> 16) as u8, (color >> 8) as u8, color as u8, 255])
+}
@@ -0,0 +1,321 @@
+use super::*;
+
+use std::collections::VecDeque;
+
+const SPRITE_FETCH_DOTS: u8         = 6;
+const FETCH_STEP_DOTS: u8           = 2;
+
+#[derive(Clone, Copy)]
+struct BgFifoPixel {
+    color: u8,
+    attrs: u8,
+}
+
+#[derive(Clone, Copy)]
+struct ObjFifoPixel {
+    color: u8,
+    oam_idx: usize,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum FetchStep {
+    Tile,
+    DataLow,
+    DataHigh,
+    Push,
+}
+
+/*
+ * Pixel FIFO
+ *
+ * Rather than drawing a whole line at once, the PPU emits a pixel every dot
+ * of mode 3 from a queue of background pixels, with sprite pixels mixed in
+ * over the top. A fetcher keeps the background queue topped up, taking two
+ * dots for each step:
+ *
+ * +-----------+---------------------------------------------------+
+ * | Tile      | Read the tile index (and attributes) from the map |
+ * | Data Low  | Read the low bitplane of the tile's row           |
+ * | Data High | Read the high bitplane of the tile's row          |
+ * | Push      | Push 8 pixels, waiting until the queue is empty   |
+ * +-----------+---------------------------------------------------+
+ *
+ * The first SCX % 8 pixels are thrown away for fine scrolling. Reaching WX
+ * clears the queue and restarts the fetcher on the window map, and reaching
+ * a sprite's X coordinate stalls output while its row is fetched and mixed
+ * into the sprite queue. As registers are read while the line is drawn,
+ * changes made partway through a line take effect from that point on.
+ */
+pub(super) struct PixelFifo {
+    active: bool,
+    x: usize,
+    line: u8,
+    discard: u8,
+    bg: VecDeque<BgFifoPixel>,
+    obj: [Option<ObjFifoPixel>; TILESIZE],
+    step: FetchStep,
+    step_dots: u8,
+    fetch_x: usize,
+    tile_index: usize,
+    tile_attrs: u8,
+    tile_y: usize,
+    tile_row: [u8; TILESIZE],
+    in_window: bool,
+    sprites_fetched: [bool; SPRITES_PER_LINE],
+    stall: u8,
+    buffer: LineBuffer,
+}
+
+impl PixelFifo {
+    pub(super) fn new() -> Self {
+        Self {
+            active: false,
+            x: 0,
+            line: 0,
+            discard: 0,
+            bg: VecDeque::with_capacity(2 * TILESIZE),
+            obj: [None; TILESIZE],
+            step: FetchStep::Tile,
+            step_dots: 0,
+            fetch_x: 0,
+            tile_index: 0,
+            tile_attrs: 0,
+            tile_y: 0,
+            tile_row: [0; TILESIZE],
+            in_window: false,
+            sprites_fetched: [false; SPRITES_PER_LINE],
+            stall: 0,
+            buffer: LineBuffer::new(),
+        }
+    }
+}
+
+impl Ppu {
+    // Advances the LCD a dot at a time, drawing pixels during mode 3
+    pub(super) fn step_fifo(&mut self, dots: u8) -> LcdResults {
+        let mut result = LcdResults::NoAction;
+        for _ in 0..dots {
+            if self.mode.get_mode() == LcdModeType::VRAMReadMode {
+                self.fifo_dot();
+            }
+            let dot_result = self.mode.step(1);
+            if dot_result != LcdResults::NoAction {
+                result = dot_result;
+            }
+        }
+        result
+    }
+
+    // Draws whatever remains of the line and hands it back, ready for the next one to begin
+    pub(super) fn finish_fifo_line(&mut self, line: u8) -> LineBuffer {
+        if !self.fifo.active {
+            self.start_fifo_line(line);
+        }
+        while self.fifo.x < SCREEN_WIDTH {
+            self.fifo_dot();
+        }
+
+        self.fifo.active = false;
+        std::mem::replace(&mut self.fifo.buffer, LineBuffer::new())
+    }
+
+    fn start_fifo_line(&mut self, line: u8) {
+        self.scan_oam(line);
+
+        let fifo = &mut self.fifo;
+        fifo.active = true;
+        fifo.x = 0;
+        fifo.line = line;
+        fifo.discard = self.lcd_regs[(SCX - LCD_REG_START) as usize] % TILESIZE as u8;
+        fifo.bg.clear();
+        fifo.obj = [None; TILESIZE];
+        fifo.step = FetchStep::Tile;
+        fifo.step_dots = 0;
+        fifo.fetch_x = 0;
+        fifo.in_window = false;
+        fifo.sprites_fetched = [false; SPRITES_PER_LINE];
+        fifo.stall = 0;
+    }
+
+    fn fifo_dot(&mut self) {
+        if !self.fifo.active {
+            self.start_fifo_line(self.mode.get_line());
+        }
+        if self.fifo.x >= SCREEN_WIDTH {
+            return;
+        }
+        if self.fifo.stall > 0 {
+            self.fifo.stall -= 1;
+            return;
+        }
+
+        self.check_window_start();
+        if self.fifo.discard == 0 && self.check_sprite_fetch() {
+            return;
+        }
+        self.step_fetcher();
+        self.push_pixel();
+    }
+
+    fn check_window_start(&mut self) {
+        if self.fifo.in_window {
+            return;
+        }
+        let Some(left) = self.get_window_left() else {
+            return;
+        };
+        if (self.fifo.x as isize) < left {
+            return;
+        }
+
+        self.window_drawn = true;
+        // A hidden window still counts as drawn, but the background carries on underneath it
+        if !self.is_layer_enabled(Layer::Window) {
+            return;
+        }
+        let fifo = &mut self.fifo;
+        fifo.in_window = true;
+        // With WX below 7, the window's first few pixels are off the left edge and are thrown away
+        if left < 0 {
+            fifo.discard = (-left) as u8;
+        }
+        fifo.bg.clear();
+        fifo.step = FetchStep::Tile;
+        fifo.step_dots = 0;
+        fifo.fetch_x = 0;
+    }
+
+    // Fetches the next sprite reached by the current pixel, returning true if output needs to stall
+    fn check_sprite_fetch(&mut self) -> bool {
+        if !self.is_sprite_layer_displayed() || !self.is_layer_enabled(Layer::Sprites) {
+            return false;
+        }
+
+        // Sprites partially off the left edge all begin at the first pixel, so take the leftmost first
+        let mut next: Option<(isize, usize)> = None;
+        for (i, &oam_idx) in self.get_line_sprites().iter().enumerate() {
+            let spr_x = self.oam[oam_idx].get_coords().0;
+            if !self.fifo.sprites_fetched[i] && spr_x <= self.fifo.x as isize && next.is_none_or(|(x, _)| spr_x < x) {
+                next = Some((spr_x, i));
+            }
+        }
+
+        let Some((spr_x, i)) = next else {
+            return false;
+        };
+        self.fifo.sprites_fetched[i] = true;
+        let oam_idx = self.line_sprites[i];
+        let row = self.get_sprite_row(&self.oam[oam_idx], self.fifo.line);
+        for (px, &color) in row.iter().enumerate() {
+            let offset = spr_x + px as isize - self.fifo.x as isize;
+            if color == 0 || offset < 0 {
+                continue;
+            }
+            // Whichever sprite was fetched first wins on the DMG, while the CGB favors the first in OAM
+            let slot = &mut self.fifo.obj[offset as usize];
+            let replace = match slot {
+                Some(existing) => { self.cgb_mode && oam_idx < existing.oam_idx },
+                None => { true },
+            };
+            if replace {
+                *slot = Some(ObjFifoPixel { color, oam_idx });
+            }
+        }
+        self.fifo.stall = SPRITE_FETCH_DOTS - 1;
+        true
+    }
+
+    fn step_fetcher(&mut self) {
+        if self.fifo.step == FetchStep::Push {
+            if self.fifo.bg.is_empty() {
+                let fifo = &mut self.fifo;
+                for color in fifo.tile_row {
+                    fifo.bg.push_back(BgFifoPixel { color, attrs: fifo.tile_attrs });
+                }
+                fifo.fetch_x += 1;
+                fifo.step = FetchStep::Tile;
+            }
+            return;
+        }
+
+        self.fifo.step_dots += 1;
+        if self.fifo.step_dots < FETCH_STEP_DOTS {
+            return;
+        }
+        self.fifo.step_dots = 0;
+
+        match self.fifo.step {
+            FetchStep::Tile => {
+                self.fetch_tile_index();
+                self.fifo.step = FetchStep::DataLow;
+            },
+            FetchStep::DataLow => {
+                self.fifo.step = FetchStep::DataHigh;
+            },
+            FetchStep::DataHigh => {
+                self.fifo.tile_row = self.get_bg_tile_row(self.fifo.tile_index, self.fifo.tile_attrs, self.fifo.tile_y);
+                self.fifo.step = FetchStep::Push;
+            },
+            FetchStep::Push => { unreachable!() },
+        }
+    }
+
+    fn fetch_tile_index(&mut self) {
+        let (map_offset, map_x, map_y) = if self.fifo.in_window {
+            let map_offset = self.get_wndw_tile_map_index() as usize * TILE_MAP_TABLE_SIZE;
+            (map_offset, self.fifo.fetch_x, self.window_line)
+        } else {
+            let map_offset = self.get_bg_tile_map_index() as usize * TILE_MAP_TABLE_SIZE;
+            let viewport = self.get_viewport_coords();
+            let map_x = viewport.x as usize / TILESIZE + self.fifo.fetch_x;
+            let map_y = (viewport.y as usize + self.fifo.line as usize) % MAP_PIXELS;
+            (map_offset, map_x, map_y)
+        };
+
+        let map_num = (map_y / TILESIZE) * LAYERSIZE + (map_x % LAYERSIZE);
+        let (tile_index, attrs) = self.get_map_tile(map_offset, map_num);
+        self.fifo.tile_index = tile_index;
+        self.fifo.tile_attrs = attrs;
+        self.fifo.tile_y = map_y % TILESIZE;
+    }
+
+    // Shifts a pixel out of the queues and onto the screen, mixing the background and sprites
+    fn push_pixel(&mut self) {
+        let Some(bg) = self.fifo.bg.pop_front() else {
+            return;
+        };
+        if self.fifo.discard > 0 {
+            self.fifo.discard -= 1;
+            return;
+        }
+
+        let x = self.fifo.x;
+        let obj = self.fifo.obj[0];
+        self.fifo.obj.rotate_left(1);
+        self.fifo.obj[TILESIZE - 1] = None;
+        self.fifo.x += 1;
+
+        let mut bg_pixel = BgPixel::default();
+        let layer = if self.fifo.in_window { Layer::Window } else { Layer::Background };
+        if self.is_bg_layer_displayed() && self.is_layer_enabled(layer) {
+            let palette = self.get_bg_palette();
+            let (pixel, shade, color) = self.get_bg_color(bg.color, bg.attrs, &palette);
+            bg_pixel = pixel;
+            self.fifo.buffer.set_pixel(x, shade, color);
+        }
+
+        if let Some(obj) = obj {
+            if !self.is_sprite_layer_displayed() || !self.is_layer_enabled(Layer::Sprites) {
+                return;
+            }
+            let spr = self.oam[obj.oam_idx];
+            // On the CGB, clearing LCDC bit 0 puts sprites above the background regardless of any priority flags
+            let bg_master_priority = !self.cgb_mode || self.read_lcd_reg(LCDC).get_bit(LCDC_BG_WNDW_ENABLED_BIT);
+            if bg_master_priority && (spr.get_bg_priority() || bg_pixel.priority) && bg_pixel.color != 0 {
+                return;
+            }
+            let (shade, color) = self.get_obj_color(&spr, obj.color);
+            self.fifo.buffer.set_pixel(x, shade, color);
+        }
+    }
+}
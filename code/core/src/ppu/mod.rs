@@ -1,10 +1,20 @@
+pub mod compat;
+pub mod display;
+mod fifo;
 pub mod modes;
-mod sprite;
+mod palette;
+pub mod sprite;
 mod tile;
 
+use crate::sgb::SgbColors;
+use crate::state::{Region, StateError, StateReader, StateWriter};
 use crate::utils::*;
 
-use modes::{Lcd, LcdModeType, LcdResults};
+use compat::CompatPalette;
+use fifo::PixelFifo;
+use std::ops::Range;
+use modes::{Lcd, LcdModeType, LcdResults, VRAM_READ_MIN_LEN};
+use palette::CgbPalette;
 use sprite::Sprite;
 use tile::Tile;
 
@@ -14,6 +24,15 @@ pub const OAM_START: u16            = 0xFE00;
 pub const OAM_STOP: u16             = 0xFE9F;
 pub const LCD_REG_START: u16        = 0xFF40;
 pub const LCD_REG_STOP: u16         = 0xFF4B;
+pub const VBK: u16                  = 0xFF4F;
+pub const CGB_PALETTE_START: u16    = 0xFF68;
+pub const CGB_PALETTE_STOP: u16     = 0xFF6B;
+
+// The tileset viewer lays out tiles in rows of 16
+pub const TILESET_WIDTH: usize      = 16 * TILESIZE;
+pub const TILESET_HEIGHT: usize     = (NUM_TILES / 16) * TILESIZE;
+// Each tile map covers 32x32 tiles
+pub const TILEMAP_PIXELS: usize     = MAP_PIXELS;
 
 const TILE_SET_START: u16           = 0x8000;
 const TILE_SET_STOP: u16            = 0x97FF;
@@ -26,8 +45,9 @@ const TILE_MAP_SIZE: usize          = (TILE_MAP_STOP - TILE_MAP_START + 1) as us
 const LCD_REG_SIZE: usize           = (LCD_REG_STOP - LCD_REG_START + 1) as usize;
 const TILE_MAP_TABLE_SIZE: usize    = TILE_MAP_SIZE / 2;
 
-const NUM_OAM_SPRITES: usize        = 40;
+pub const NUM_OAM_SPRITES: usize    = 40;
 const BYTES_PER_SPRITE: u16         = 4;
+const SPRITES_PER_LINE: usize       = 10;
 
 const TILESIZE: usize               = 8;
 const LAYERSIZE: usize              = 32;
@@ -44,6 +64,27 @@ const OBP0: u16                     = 0xFF48;
 const OBP1: u16                     = 0xFF49;
 const WY: u16                       = 0xFF4A;
 const WX: u16                       = 0xFF4B;
+pub const BCPS: u16                 = 0xFF68;
+const BCPD: u16                     = 0xFF69;
+pub const OCPS: u16                 = 0xFF6A;
+const OCPD: u16                     = 0xFF6B;
+
+const NUM_VRAM_BANKS: usize         = 2;
+const VRAM_BANK_SIZE: usize         = (VRAM_STOP - VRAM_START + 1) as usize;
+const OAM_SIZE: usize               = (OAM_STOP - OAM_START + 1) as usize;
+
+// Registers which change how a line is drawn, so writing them partway through splits the line
+const MID_LINE_REGS: [u16; 8]       = [LCDC, SCY, SCX, BGP, OBP0, OBP1, WY, WX];
+// The window's left edge is drawn at WX - 7, and anything past the last column is hidden
+const WX_OFFSET: isize              = 7;
+const WX_MAX: u8                    = 166;
+
+// Dots into mode 3 before the first pixel reaches the screen
+const MODE3_PIXEL_DELAY: usize      = 12;
+// Extra dots mode 3 takes while the fetcher switches to the window, or fetches a sprite
+const WINDOW_PENALTY: usize         = 6;
+const SPRITE_PENALTY: usize         = 6;
+const OFFSCREEN_SPRITE_PENALTY: usize = 11;
 
 // Bit flags for LCDC
 const LCDC_LCD_ENABLED_BIT: u8      = 7;
@@ -61,6 +102,84 @@ const STAT_OAM_IRQ_BIT: u8          = 5;
 const STAT_VBLANK_IRQ_BIT: u8       = 4;
 const STAT_HBLANK_IRQ_BIT: u8       = 3;
 const STAT_LY_EQ_LYC_BIT: u8        = 2;
+const STAT_WRITABLE_MASK: u8        = 0b0111_1000;
+
+// Bit flags for CGB background map attributes, stored in VRAM bank 1
+const BG_ATTR_PALETTE_MASK: u8      = 0b0000_0111;
+const BG_ATTR_BANK_BIT: u8          = 3;
+const BG_ATTR_X_FLIP_BIT: u8        = 5;
+const BG_ATTR_Y_FLIP_BIT: u8        = 6;
+const BG_ATTR_PRIORITY_BIT: u8      = 7;
+
+// What a background or window pixel needs to remember to resolve sprite priority
+#[derive(Clone, Copy, Default)]
+struct BgPixel {
+    color: u8,
+    priority: bool,
+}
+
+// The highest priority sprite pixel found so far at a point on the line
+#[derive(Clone, Copy)]
+struct ObjPixel {
+    // Compared as a tuple, with lower values winning
+    priority: (isize, usize),
+    behind_bg: bool,
+    shade: u8,
+    color: [u8; 4],
+}
+
+// Scratch space for the scanline currently being rendered
+struct LineBuffer {
+    rgba: [u8; SCREEN_WIDTH * 4],
+    bg: [BgPixel; SCREEN_WIDTH],
+    obj: [Option<ObjPixel>; SCREEN_WIDTH],
+    shades: [u8; SCREEN_WIDTH],
+}
+
+impl LineBuffer {
+    fn new() -> Self {
+        Self {
+            rgba: [0xFF; SCREEN_WIDTH * 4],
+            bg: [BgPixel::default(); SCREEN_WIDTH],
+            obj: [None; SCREEN_WIDTH],
+            shades: [0; SCREEN_WIDTH],
+        }
+    }
+
+    fn set_pixel(&mut self, x: usize, shade: u8, color: [u8; 4]) {
+        self.shades[x] = shade;
+        self.rgba[(4 * x)..(4 * x + 4)].copy_from_slice(&color);
+    }
+}
+
+/// How the picture is drawn from the PPU's state
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Renderer {
+    /// Draws each line in one go once it's finished, which is fast but misses changes made partway through
+    Scanline,
+    /// Emulates the pixel fetcher and FIFOs a dot at a time, for games which rely on mid-line effects
+    PixelFifo,
+}
+
+/// One of the three layers the PPU composes the screen from
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Layer {
+    Background,
+    Window,
+    Sprites,
+}
+
+impl Layer {
+    pub const ALL: [Layer; 3] = [Layer::Background, Layer::Window, Layer::Sprites];
+
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Layer::Background => { "background" },
+            Layer::Window => { "window" },
+            Layer::Sprites => { "sprites" },
+        }
+    }
+}
 
 pub struct PpuUpdateResult {
     pub lcd_result: LcdResults,
@@ -69,38 +188,113 @@ pub struct PpuUpdateResult {
 
 pub struct Ppu {
     screen_buffer: [u8; DISPLAY_BUFFER],
+    indexed_buffer: [u8; INDEXED_BUFFER],
+    frame_changed: bool,
     mode: Lcd,
-    tiles: [Tile; NUM_TILES],
+    tiles: [[Tile; NUM_TILES]; NUM_VRAM_BANKS],
     maps: [u8; TILE_MAP_SIZE],
+    attr_maps: [u8; TILE_MAP_SIZE],
     lcd_regs: [u8; LCD_REG_SIZE],
     oam: [Sprite; NUM_OAM_SPRITES],
+    vram_bank: usize,
+    cgb_mode: bool,
+    bg_palettes: CgbPalette,
+    obj_palettes: CgbPalette,
+    compat_palette: Option<CompatPalette>,
+    dmg_colors: [[u8; 4]; 4],
+    sgb_colors: Option<SgbColors>,
+    renderer: Renderer,
+    layers_enabled: [bool; Layer::ALL.len()],
+    fifo: PixelFifo,
+    line_buffer: LineBuffer,
+    line_x: usize,
+    line_sprites: [usize; SPRITES_PER_LINE],
+    num_line_sprites: usize,
+    oam_scan_line: Option<u8>,
+    wy_triggered: bool,
+    window_line: usize,
+    window_drawn: bool,
+    stat_write_bug: bool,
+    stat_irq_pending: bool,
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Ppu {
     pub fn new() -> Self {
         Self {
             screen_buffer: [0; DISPLAY_BUFFER],
+            indexed_buffer: [0; INDEXED_BUFFER],
+            frame_changed: true,
             mode: Lcd::new(),
-            tiles: [Tile::new(); NUM_TILES],
+            tiles: [[Tile::new(); NUM_TILES]; NUM_VRAM_BANKS],
             maps: [0; TILE_MAP_SIZE],
+            attr_maps: [0; TILE_MAP_SIZE],
             lcd_regs: [0; LCD_REG_SIZE],
             oam: [Sprite::new(); NUM_OAM_SPRITES],
+            vram_bank: 0,
+            cgb_mode: false,
+            bg_palettes: CgbPalette::new(),
+            obj_palettes: CgbPalette::new(),
+            compat_palette: None,
+            dmg_colors: GB_PALETTE,
+            sgb_colors: None,
+            renderer: Renderer::Scanline,
+            layers_enabled: [true; Layer::ALL.len()],
+            fifo: PixelFifo::new(),
+            line_buffer: LineBuffer::new(),
+            line_x: 0,
+            line_sprites: [0; SPRITES_PER_LINE],
+            num_line_sprites: 0,
+            oam_scan_line: None,
+            wy_triggered: false,
+            window_line: 0,
+            window_drawn: false,
+            stat_write_bug: false,
+            stat_irq_pending: false,
         }
     }
 
+    /// Clears the video memory and registers, keeping the palettes, renderer, and layers the frontend chose
+    pub fn reset(&mut self) {
+        *self = Self {
+            compat_palette: self.compat_palette,
+            dmg_colors: self.dmg_colors,
+            renderer: self.renderer,
+            layers_enabled: self.layers_enabled,
+            ..Self::new()
+        };
+    }
+
     pub fn update(&mut self, cycles: u8) -> PpuUpdateResult {
         let old_mode = self.mode.get_mode();
         let old_line = self.mode.get_line();
-        let lcd_result = self.mode.step(cycles);
+        let lcd_result = match self.renderer {
+            Renderer::Scanline => { self.mode.step(cycles) },
+            Renderer::PixelFifo => { self.step_fifo(cycles) },
+        };
         let mut stat = self.read_lcd_reg(STAT);
-        let mut irq = false;
+        let mut irq = std::mem::take(&mut self.stat_irq_pending);
 
         let scanline = self.mode.get_line();
         if old_line != scanline {
             let lyc = self.read_lcd_reg(LYC);
             stat.set_bit(STAT_LY_EQ_LYC_BIT, scanline == lyc);
             irq = (scanline == lyc) && stat.get_bit(STAT_LY_LYC_IRQ_BIT);
-            self.write_lcd_reg(LY, scanline);
+            self.set_lcd_reg(LY, scanline);
+            self.oam_scan_line = None;
+
+            // The window can only appear once LY has matched WY, which is checked at the start of each line
+            // Once it has, it stays available for the rest of the frame, no matter what WY is changed to
+            if scanline == 0 {
+                self.wy_triggered = false;
+                self.window_line = 0;
+            }
+            self.wy_triggered |= scanline == self.read_lcd_reg(WY);
         }
 
         let mode = self.mode.get_mode();
@@ -114,163 +308,393 @@ impl Ppu {
                 },
                 LcdModeType::OAMReadMode => {
                     irq |= stat.get_bit(STAT_OAM_IRQ_BIT);
-                }
-                _ => {},
+                },
+                LcdModeType::VRAMReadMode => {
+                    let len = self.get_mode3_len(scanline);
+                    self.mode.set_vram_len(len);
+                },
             }
         }
 
         stat &= 0b1111_1100;
         stat |= mode.get_idx();
-        self.write_lcd_reg(STAT, stat);
+        self.set_lcd_reg(STAT, stat);
 
         PpuUpdateResult{ lcd_result, irq }
     }
 
-    pub fn render(&self) -> [u8; DISPLAY_BUFFER] {
+    // Finds the first 10 sprites in OAM which cover the given line, as only these can be drawn on it
+    // This is done once per line, with the results kept for mode 3 timing and both renderers
+    fn scan_oam(&mut self, line: u8) {
+        if self.oam_scan_line == Some(line) {
+            return;
+        }
+
+        let mut num_sprites = 0;
+        for (oam_idx, spr) in self.oam.iter().enumerate() {
+            if num_sprites == SPRITES_PER_LINE {
+                break;
+            }
+            if self.is_sprite_on_line(spr, line) {
+                self.line_sprites[num_sprites] = oam_idx;
+                num_sprites += 1;
+            }
+        }
+        self.num_line_sprites = num_sprites;
+        self.oam_scan_line = Some(line);
+    }
+
+    // The OAM indices of the sprites found by the last scan, in OAM order
+    fn get_line_sprites(&self) -> &[usize] {
+        &self.line_sprites[..self.num_line_sprites]
+    }
+
+    /*
+     * Mode 3 Length
+     *
+     * Mode 3 takes 172 dots at a minimum, but the fetcher can be held up by:
+     * - Fine scrolling, which discards SCX % 8 pixels at the start of the line
+     * - Switching to the window, which takes 6 dots
+     * - Each sprite, which takes 6 dots to fetch. If the background tile its
+     *   left edge lands in is still being fetched, that's waited on first,
+     *   taking up to 5 more dots for the first sprite in that tile. Sprites
+     *   entirely off the left edge always take 11 dots.
+     */
+    fn get_mode3_len(&mut self, line: u8) -> usize {
+        let fine_scroll = (self.read_lcd_reg(SCX) % TILESIZE as u8) as usize;
+        let mut len = VRAM_READ_MIN_LEN + fine_scroll;
+
+        if self.get_window_left().is_some() {
+            len += WINDOW_PENALTY;
+        }
+
+        if self.is_sprite_layer_displayed() {
+            self.scan_oam(line);
+            let mut sprite_xs = self.line_sprites.map(|idx| self.oam[idx].get_coords().0);
+            let sprite_xs = &mut sprite_xs[..self.num_line_sprites];
+            sprite_xs.sort_unstable();
+
+            let mut last_tile = None;
+            for &x in sprite_xs.iter() {
+                if x >= SCREEN_WIDTH as isize {
+                    continue;
+                }
+                if x == -(TILESIZE as isize) {
+                    len += OFFSCREEN_SPRITE_PENALTY;
+                    continue;
+                }
+                let fetch_x = x + fine_scroll as isize;
+                let tile = fetch_x.div_euclid(TILESIZE as isize);
+                if last_tile != Some(tile) {
+                    let pixels_right = TILESIZE - 1 - fetch_x.rem_euclid(TILESIZE as isize) as usize;
+                    len += pixels_right.saturating_sub(2);
+                    last_tile = Some(tile);
+                }
+                len += SPRITE_PENALTY;
+            }
+        }
+
+        len
+    }
+
+    pub fn render(&self) -> &[u8; DISPLAY_BUFFER] {
         if self.is_lcd_enabled() {
-            self.screen_buffer
+            &self.screen_buffer
         } else {
-            [0; DISPLAY_BUFFER]
+            &[0; DISPLAY_BUFFER]
         }
     }
 
-    pub fn render_scanline(&mut self) {
-        let line = self.read_lcd_reg(LY);
-        let mut row = [0xFF; SCREEN_WIDTH * 4];
+    /// Returns whether the picture has changed since this was last called, so unchanged frames needn't be redrawn
+    pub fn take_frame_changed(&mut self) -> bool {
+        std::mem::take(&mut self.frame_changed)
+    }
 
-        if self.is_bg_layer_displayed() {
-            self.render_bg(&mut row, line);
+    /// Returns the color index of each pixel before it's turned into RGBA, one byte per pixel
+    ///
+    /// On the DMG this is the shade picked by BGP, OBP0, or OBP1, from 0 (lightest) to 3 (darkest),
+    /// while on the CGB it's the color's index within the background or sprite palette it was drawn with.
+    pub fn render_indexed(&self) -> &[u8; INDEXED_BUFFER] {
+        if self.is_lcd_enabled() {
+            &self.indexed_buffer
+        } else {
+            &[0; INDEXED_BUFFER]
         }
+    }
 
-        if self.is_window_layer_displayed() {
-            self.render_window(&mut row, line);
+    /// Draws every tile in the first VRAM bank into an RGBA image, 16 tiles wide, colored with the background palette
+    pub fn render_tileset(&self) -> Vec<u8> {
+        let mut atlas = vec![0; TILESET_WIDTH * TILESET_HEIGHT * 4];
+        let palette = self.get_bg_palette();
+        let tiles_per_row = TILESET_WIDTH / TILESIZE;
+        for (idx, tile) in self.tiles[0].iter().enumerate() {
+            let tile_x = (idx % tiles_per_row) * TILESIZE;
+            let tile_y = (idx / tiles_per_row) * TILESIZE;
+            for row in 0..TILESIZE {
+                for (col, &cell) in tile.get_row(row).iter().enumerate() {
+                    let (_, _, color) = self.get_bg_color(cell, 0, &palette);
+                    let pixel = (tile_y + row) * TILESET_WIDTH + tile_x + col;
+                    atlas[(4 * pixel)..(4 * pixel + 4)].copy_from_slice(&color);
+                }
+            }
         }
+        atlas
+    }
 
-        if self.is_sprite_layer_displayed() {
-            self.render_sprites(&mut row, line);
+    /// Draws the whole of one of the two background tile maps into a 256x256 RGBA image, using the current tileset and palette
+    pub fn render_tilemap(&self, map_index: u8) -> [u8; TILEMAP_PIXELS * TILEMAP_PIXELS * 4] {
+        let mut image = [0; TILEMAP_PIXELS * TILEMAP_PIXELS * 4];
+        let map_offset = (map_index & 0b1) as usize * TILE_MAP_TABLE_SIZE;
+        let palette = self.get_bg_palette();
+        for y in 0..TILEMAP_PIXELS {
+            for x in 0..TILEMAP_PIXELS {
+                let (_, _, color) = self.get_map_pixel(map_offset, x, y, &palette);
+                let pixel = y * TILEMAP_PIXELS + x;
+                image[(4 * pixel)..(4 * pixel + 4)].copy_from_slice(&color);
+            }
+        }
+        image
+    }
+
+    /// Returns the top left corner of the screen within the background map, which wraps around at the edges
+    pub fn get_viewport(&self) -> Point {
+        self.get_viewport_coords()
+    }
+
+    /// Returns the decoded contents of OAM
+    pub fn sprites(&self) -> &[Sprite; NUM_OAM_SPRITES] {
+        &self.oam
+    }
+
+    pub fn render_scanline(&mut self) {
+        let line = self.read_lcd_reg(LY);
+        let mut buffer = match self.renderer {
+            Renderer::Scanline => {
+                self.draw_line_until(line, SCREEN_WIDTH);
+                self.line_x = 0;
+                std::mem::replace(&mut self.line_buffer, LineBuffer::new())
+            },
+            Renderer::PixelFifo => { self.finish_fifo_line(line) },
+        };
+
+        // The window keeps its own line counter, which only advances on lines it appeared on
+        if self.window_drawn {
+            self.window_line += 1;
+            self.window_drawn = false;
+        }
+
+        if let Some(sgb) = &self.sgb_colors {
+            if !self.cgb_mode {
+                sgb.colorize_line(&mut buffer.rgba, &buffer.shades, line);
+            }
         }
 
         let start_idx = line as usize * SCREEN_WIDTH * 4;
         let end_idx = (line + 1) as usize * SCREEN_WIDTH * 4;
-        self.screen_buffer[start_idx..end_idx].copy_from_slice(&row);
+        self.frame_changed |= self.screen_buffer[start_idx..end_idx] != buffer.rgba;
+        self.screen_buffer[start_idx..end_idx].copy_from_slice(&buffer.rgba);
+
+        let start_idx = line as usize * SCREEN_WIDTH;
+        self.indexed_buffer[start_idx..(start_idx + SCREEN_WIDTH)].copy_from_slice(&buffer.shades);
     }
 
-    fn render_bg(&self, buffer: &mut [u8], line: u8) {
+    // Draws the line from wherever it was left up to the given pixel, using the current register values
+    fn draw_line_until(&mut self, line: u8, end_x: usize) {
+        let xs = self.line_x..end_x;
+        if xs.is_empty() {
+            return;
+        }
+        let mut buffer = std::mem::replace(&mut self.line_buffer, LineBuffer::new());
+
+        if self.is_bg_layer_displayed() && self.is_layer_enabled(Layer::Background) {
+            self.render_bg(&mut buffer, line, xs.clone());
+        }
+
+        if let Some(left) = self.get_window_left() {
+            if left < xs.end as isize {
+                if self.is_layer_enabled(Layer::Window) {
+                    self.render_window(&mut buffer, left, xs.clone());
+                }
+                self.window_drawn = true;
+            }
+        }
+
+        if self.is_sprite_layer_displayed() && self.is_layer_enabled(Layer::Sprites) {
+            self.scan_oam(line);
+            self.render_sprites(&mut buffer, line, xs);
+        }
+
+        self.line_buffer = buffer;
+        self.line_x = end_x;
+    }
+
+    fn render_bg(&self, buffer: &mut LineBuffer, line: u8, xs: Range<usize>) {
         let map_offset = self.get_bg_tile_map_index() as usize * TILE_MAP_TABLE_SIZE;
         let palette = self.get_bg_palette();
         let viewport = self.get_viewport_coords();
         let current_y = viewport.y as usize + line as usize;
         let y = current_y % MAP_PIXELS;
-        let row = current_y % TILESIZE;
-        for px in 0..SCREEN_WIDTH {
-            let current_x = viewport.x as usize + px as usize;
+        for px in xs {
+            let current_x = viewport.x as usize + px;
             let x = current_x % MAP_PIXELS;
-            let col = current_x % TILESIZE;
-            let map_num = (y / TILESIZE) * LAYERSIZE + (x / TILESIZE);
-            let tile_index = self.maps[map_offset + map_num] as usize;
-            let adjusted_tile_index = if self.get_bg_wndw_tile_set_index() == 1 {
-                tile_index as usize
-            } else {
-                (256 + tile_index as i8 as isize) as usize
-            };
-            let tile = self.tiles[adjusted_tile_index];
-            let data = tile.get_row(row);
-            let cell = data[col];
-            let color_idx = palette[cell as usize];
-            let color = GB_PALETTE[color_idx as usize];
-            for i in 0..4 {
-                buffer[4 * px + i] = color[i];
-            }
+            let (pixel, shade, color) = self.get_map_pixel(map_offset, x, y, &palette);
+            buffer.bg[px] = pixel;
+            buffer.set_pixel(px, shade, color);
         }
     }
 
-    fn render_window(&self, buffer: &mut [u8], line: u8) {
+    fn render_window(&self, buffer: &mut LineBuffer, left: isize, xs: Range<usize>) {
         let map_offset = self.get_wndw_tile_map_index() as usize * TILE_MAP_TABLE_SIZE;
         let palette = self.get_bg_palette();
-        let coords = self.get_window_coords();
-        if (coords.x as usize > SCREEN_WIDTH) || (coords.y > line) {
-            return;
+        let start = xs.start.max(left.max(0) as usize);
+        for x in start..xs.end {
+            let map_x = (x as isize - left) as usize;
+            let (pixel, shade, color) = self.get_map_pixel(map_offset, map_x, self.window_line, &palette);
+            buffer.bg[x] = pixel;
+            buffer.set_pixel(x, shade, color);
+        }
+    }
+
+    // Looks up the color index, DMG shade, and RGBA value of a pixel within one of the background tile maps
+    fn get_map_pixel(&self, map_offset: usize, x: usize, y: usize, palette: &[u8; 4]) -> (BgPixel, u8, [u8; 4]) {
+        let map_num = (y / TILESIZE) * LAYERSIZE + (x / TILESIZE);
+        let (tile_index, attrs) = self.get_map_tile(map_offset, map_num);
+        let row = self.get_bg_tile_row(tile_index, attrs, y % TILESIZE);
+        self.get_bg_color(row[x % TILESIZE], attrs, palette)
+    }
+
+    // Returns the tile set index and CGB attributes for an entry in one of the tile maps
+    fn get_map_tile(&self, map_offset: usize, map_num: usize) -> (usize, u8) {
+        let tile_index = self.maps[map_offset + map_num] as usize;
+        let adjusted_tile_index = if self.get_bg_wndw_tile_set_index() == 1 {
+            tile_index
+        } else {
+            (256 + tile_index as i8 as isize) as usize
+        };
+        let attrs = if self.cgb_mode { self.attr_maps[map_offset + map_num] } else { 0 };
+        (adjusted_tile_index, attrs)
+    }
+
+    // Gets a row of a background tile's color indices, flipped as its attributes ask
+    fn get_bg_tile_row(&self, tile_index: usize, attrs: u8, row: usize) -> [u8; TILESIZE] {
+        let bank = if attrs.get_bit(BG_ATTR_BANK_BIT) { 1 } else { 0 };
+        let row = if attrs.get_bit(BG_ATTR_Y_FLIP_BIT) { TILESIZE - 1 - row } else { row };
+        let mut pixels = self.tiles[bank][tile_index].get_row(row);
+        if attrs.get_bit(BG_ATTR_X_FLIP_BIT) {
+            pixels.reverse();
         }
-        let y = (line - coords.y) as usize;
-        let row = y % TILESIZE;
-        for x in (coords.x as usize)..SCREEN_WIDTH {
-            let col = x % TILESIZE;
-            let map_num = (y / TILESIZE) * LAYERSIZE + (x / TILESIZE);
-            let tile_index = self.maps[map_offset + map_num] as usize;
-            let adjusted_tile_index = if self.get_bg_wndw_tile_set_index() == 1 {
-                tile_index as usize
+        pixels
+    }
+
+    fn get_bg_color(&self, cell: u8, attrs: u8, palette: &[u8; 4]) -> (BgPixel, u8, [u8; 4]) {
+        // The CGB has no shades, so its palettes are indexed by the color number itself
+        let (shade, color) = if self.cgb_mode {
+            (cell, self.bg_palettes.get_color(attrs & BG_ATTR_PALETTE_MASK, cell))
+        } else {
+            let shade = palette[cell as usize];
+            (shade, self.get_dmg_bg_color(shade))
+        };
+        let pixel = BgPixel { color: cell, priority: attrs.get_bit(BG_ATTR_PRIORITY_BIT) };
+        (pixel, shade, color)
+    }
+
+    // Looks up the DMG shade and RGBA value of a sprite's pixel
+    fn get_obj_color(&self, spr: &Sprite, cell: u8) -> (u8, [u8; 4]) {
+        if self.cgb_mode {
+            return (cell, self.obj_palettes.get_color(spr.get_cgb_palette(), cell));
+        }
+        let palette = self.get_sprite_palette(spr.use_palette1());
+        let shade = palette[cell as usize];
+        (shade, self.get_dmg_obj_color(spr.use_palette1(), shade))
+    }
+
+    // Gets the row of a sprite's tile which falls on the given line, flipped as its attributes ask
+    fn get_sprite_row(&self, spr: &Sprite, line: u8) -> [u8; TILESIZE] {
+        let height = if self.are_sprites_8x16() { 16 } else { 8 };
+        let y = line as isize - spr.get_coords().1;
+        let y_flipped = spr.is_y_flipped();
+        let spr_idx = if height == 16 {
+            if (y < 8 && !y_flipped) || (8 <= y && y_flipped) {
+                spr.get_tile_num() & 0xFE
             } else {
-                (256 + tile_index as i8 as isize) as usize
-            };
-            let tile = self.tiles[adjusted_tile_index];
-            let data = tile.get_row(row);
-            let cell = data[col];
-            let color_idx = palette[cell as usize];
-            let color = GB_PALETTE[color_idx as usize];
-            for i in 0..4 {
-                buffer[4 * x + i] = color[i];
+                spr.get_tile_num() | 0x01
             }
+        } else {
+            spr.get_tile_num()
+        };
+        let bank = if self.cgb_mode { spr.get_vram_bank() } else { 0 };
+        let mut data_y = if y_flipped { height - y - 1 } else { y };
+        data_y %= 8;
+        let mut row = self.tiles[bank][spr_idx as usize].get_row(data_y as usize);
+        if spr.is_x_flipped() {
+            row.reverse();
         }
+        row
+    }
+
+    // Checks if a sprite covers any part of the given line
+    fn is_sprite_on_line(&self, spr: &Sprite, line: u8) -> bool {
+        let height = if self.are_sprites_8x16() { 16 } else { 8 };
+        let top = spr.get_coords().1;
+        let signed_line = line as isize;
+        top <= signed_line && signed_line < top + height
     }
 
-    fn render_sprites(&self, buffer: &mut [u8], line: u8) {
-        let sprites = self.sort_sprites();
-        let bg_palette = self.get_bg_palette();
-        let is_8x16 = self.are_sprites_8x16();
-        for spr in sprites {
-            let height = if is_8x16 { 16 } else { 8 };
+    fn render_sprites(&self, buffer: &mut LineBuffer, line: u8, xs: Range<usize>) {
+        for &oam_idx in self.get_line_sprites() {
+            let spr = &self.oam[oam_idx];
             let coords = spr.get_coords();
-            let signed_line = line as isize;
-            if signed_line < coords.1 || coords.1 + height <= signed_line  {
-                continue
-            }
-            let palette = self.get_sprite_palette(spr.use_palette1());
-            let behind_bg = spr.get_bg_priority();
-            let y = (signed_line - coords.1) as isize;
-            let y_flipped = spr.is_y_flipped();
-            let spr_idx = if is_8x16 {
-                if (y < 8 && !y_flipped) || (8 < y && y_flipped) {
-                    spr.get_tile_num() & 0xFE
-                } else {
-                    spr.get_tile_num() | 0x01
-                }
-            } else {
-                spr.get_tile_num()
-            };
-            let tile = self.tiles[spr_idx as usize];
-            let screen_y = y + coords.1;
-            if screen_y < 0 || screen_y >= SCREEN_HEIGHT as isize {
-                continue;
-            }
-            let mut data_y = if y_flipped { height - y - 1 } else { y };
-            data_y %= 8;
-            let row = tile.get_row(data_y as usize);
-            for x in 0..8 {
-                let data_x = if spr.is_x_flipped() { 7 - x } else { x };
-                let cell = row[data_x as usize];
-                // Continue if pixel is transparent
+            // The DMG favors the leftmost sprite, then the first in OAM, while the CGB only uses OAM order
+            let priority = if self.cgb_mode { (0, oam_idx) } else { (coords.0, oam_idx) };
+            let row = self.get_sprite_row(spr, line);
+            for (x, &cell) in row.iter().enumerate() {
+                // Transparent pixels never hide a lower priority sprite
                 if cell == 0 {
                     continue;
                 }
-                let screen_x = x + coords.0;
-                if screen_x < 0 || screen_x >= SCREEN_WIDTH as isize {
+                let screen_x = x as isize + coords.0;
+                if screen_x < xs.start as isize || screen_x >= xs.end as isize {
                     continue;
                 }
-                let buffer_idx = 4 * (screen_x as usize);
-                let current_rgba = &buffer[buffer_idx..(buffer_idx + 4)];
-                // If current RGBA value isn't the transparent color, continue
-                if behind_bg && current_rgba != GB_PALETTE[bg_palette[0] as usize] {
+                if buffer.obj[screen_x as usize].is_some_and(|obj| obj.priority <= priority) {
                     continue;
                 }
-                let color_idx = palette[cell as usize];
-                let color = GB_PALETTE[color_idx as usize];
-                for i in 0..4 {
-                    buffer[buffer_idx + i] = color[i];
+                let (shade, color) = self.get_obj_color(spr, cell);
+                buffer.obj[screen_x as usize] = Some(ObjPixel { priority, behind_bg: spr.get_bg_priority(), shade, color });
+            }
+        }
+
+        // Only the winning sprite is checked against the background, so one hidden behind it
+        // will also hide any lower priority sprites beneath it
+        // On the CGB, clearing LCDC bit 0 puts sprites above the background regardless of any priority flags
+        let bg_master_priority = !self.cgb_mode || self.read_lcd_reg(LCDC).get_bit(LCDC_BG_WNDW_ENABLED_BIT);
+        for x in xs {
+            if let Some(obj) = buffer.obj[x] {
+                // Sprites behind the background only show through its transparent color
+                let bg_pixel = buffer.bg[x];
+                if bg_master_priority && (obj.behind_bg || bg_pixel.priority) && bg_pixel.color != 0 {
+                    continue;
                 }
+                buffer.set_pixel(x, obj.shade, obj.color);
             }
         }
     }
 
+    pub fn read_cgb_reg(&self, addr: u16) -> u8 {
+        if !self.cgb_mode {
+            return 0xFF;
+        }
+
+        match addr {
+            VBK =>  { 0xFE | (self.vram_bank as u8) },
+            BCPS => { self.bg_palettes.read_spec() },
+            BCPD => { self.bg_palettes.read_data() },
+            OCPS => { self.obj_palettes.read_spec() },
+            OCPD => { self.obj_palettes.read_data() },
+            _ => { 0xFF }
+        }
+    }
+
     pub fn read_lcd_reg(&self, addr: u16) -> u8 {
         let relative_addr = addr - LCD_REG_START;
         self.lcd_regs[relative_addr as usize]
@@ -283,29 +707,110 @@ impl Ppu {
     }
 
     pub fn read_vram(&self, addr: u16) -> u8 {
+        self.read_vram_bank(self.vram_bank, addr)
+    }
+
+    fn read_vram_bank(&self, bank: usize, addr: u16) -> u8 {
         match addr {
             TILE_SET_START..=TILE_SET_STOP => {
                 let relative_addr = addr - TILE_SET_START;
                 let tile_idx = relative_addr / BYTES_PER_TILE;
                 let offset = relative_addr % BYTES_PER_TILE;
-                self.tiles[tile_idx as usize].read_u8(offset)
+                self.tiles[bank][tile_idx as usize].read_u8(offset)
             },
             TILE_MAP_START..=TILE_MAP_STOP => {
                 let relative_addr = addr - TILE_MAP_START;
-                self.maps[relative_addr as usize]
+                if bank == 1 {
+                    self.attr_maps[relative_addr as usize]
+                } else {
+                    self.maps[relative_addr as usize]
+                }
             },
             _ => { unreachable!() }
         }
     }
 
-    fn sort_sprites(&self) -> Vec<Sprite> {
-        let mut sprites = self.oam.to_vec();
-        sprites.reverse();
-        sprites.sort_by(|a, b| b.get_coords().0.cmp(&a.get_coords().0));
-        sprites
+    /// Colorizes DMG games using one of the CGB boot ROM palettes, or displays them in grayscale if None
+    pub fn set_compat_palette(&mut self, palette: Option<CompatPalette>) {
+        self.compat_palette = palette;
+    }
+
+    /// Hides or shows one of the layers, on top of whatever LCDC asks for, without affecting timing
+    pub fn set_layer_enabled(&mut self, layer: Layer, enabled: bool) {
+        self.layers_enabled[layer as usize] = enabled;
+    }
+
+    pub fn is_layer_enabled(&self, layer: Layer) -> bool {
+        self.layers_enabled[layer as usize]
+    }
+
+    /// Sets the RGBA colors used for the four DMG shades, from lightest to darkest
+    pub fn set_palette(&mut self, colors: [[u8; 4]; 4]) {
+        self.dmg_colors = colors;
+    }
+
+    /// Recolors DMG output using the palettes set by SGB commands, or None to disable
+    pub fn set_sgb_colors(&mut self, colors: Option<SgbColors>) {
+        self.sgb_colors = colors;
+    }
+
+    /// Emulates the DMG bug where writing STAT can raise a spurious interrupt, which some games rely on
+    pub fn set_stat_write_bug(&mut self, enabled: bool) {
+        self.stat_write_bug = enabled;
+    }
+
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.renderer = renderer;
+    }
+
+    pub fn set_cgb_mode(&mut self, cgb: bool) {
+        self.cgb_mode = cgb;
+    }
+
+    pub fn write_cgb_reg(&mut self, addr: u16, val: u8) {
+        if !self.cgb_mode {
+            return;
+        }
+
+        match addr {
+            VBK =>  { self.vram_bank = (val & 0b1) as usize },
+            BCPS => { self.bg_palettes.write_spec(val) },
+            BCPD => { self.bg_palettes.write_data(val) },
+            OCPS => { self.obj_palettes.write_spec(val) },
+            OCPD => { self.obj_palettes.write_data(val) },
+            _ => {}
+        }
     }
 
     pub fn write_lcd_reg(&mut self, addr: u16, val: u8) {
+        // Finish the part of the line drawn before this write, so it only affects the pixels after
+        if self.renderer == Renderer::Scanline && self.mode.get_mode() == LcdModeType::VRAMReadMode && MID_LINE_REGS.contains(&addr) {
+            let x = self.mode.get_cycles().saturating_sub(MODE3_PIXEL_DELAY).min(SCREEN_WIDTH);
+            self.draw_line_until(self.mode.get_line(), x);
+        }
+
+        // Turning the LCD on or off swaps between the drawn frame and a blank one
+        if addr == LCDC && (val ^ self.read_lcd_reg(LCDC)).get_bit(LCDC_LCD_ENABLED_BIT) {
+            self.frame_changed = true;
+        }
+
+        if addr == STAT {
+            let stat = self.read_lcd_reg(STAT);
+            // On the DMG, STAT briefly acts as if every interrupt source was enabled
+            if self.stat_write_bug && self.is_lcd_enabled() {
+                let mode = self.mode.get_mode();
+                let blanking = mode == LcdModeType::HBLANK || mode == LcdModeType::VBLANK;
+                self.stat_irq_pending |= blanking || stat.get_bit(STAT_LY_EQ_LYC_BIT);
+            }
+            // The mode and LY=LYC bits are read only
+            self.set_lcd_reg(STAT, (val & STAT_WRITABLE_MASK) | (stat & !STAT_WRITABLE_MASK));
+        } else {
+            self.set_lcd_reg(addr, val);
+        }
+    }
+
+    // Sets an LCD register as the hardware does, ignoring which bits the CPU can write
+    fn set_lcd_reg(&mut self, addr: u16, val: u8) {
         let relative_addr = addr - LCD_REG_START;
         self.lcd_regs[relative_addr as usize] = val;
     }
@@ -317,21 +822,114 @@ impl Ppu {
     }
 
     pub fn write_vram(&mut self, addr: u16, val: u8) {
+        self.write_vram_bank(self.vram_bank, addr, val);
+    }
+
+    fn write_vram_bank(&mut self, bank: usize, addr: u16, val: u8) {
         match addr {
             TILE_SET_START..=TILE_SET_STOP => {
                 let relative_addr = addr - TILE_SET_START;
                 let tile_idx = relative_addr / BYTES_PER_TILE;
                 let offset = relative_addr % BYTES_PER_TILE;
-                self.tiles[tile_idx as usize].write_u8(offset, val);
+                self.tiles[bank][tile_idx as usize].write_u8(offset, val);
             },
             TILE_MAP_START..=TILE_MAP_STOP => {
                 let relative_addr = addr - TILE_MAP_START;
-                self.maps[relative_addr as usize] = val;
+                if bank == 1 {
+                    self.attr_maps[relative_addr as usize] = val;
+                } else {
+                    self.maps[relative_addr as usize] = val;
+                }
             },
             _ => { unreachable!() }
         }
     }
 
+    /// Copies in saved VRAM, the first bank followed by the second, with anything past its end ignored
+    pub fn load_vram(&mut self, data: &[u8]) {
+        let addrs = (0..NUM_VRAM_BANKS).flat_map(|bank| (VRAM_START..=VRAM_STOP).map(move |addr| (bank, addr)));
+        for ((bank, addr), &val) in addrs.zip(data) {
+            self.write_vram_bank(bank, addr, val);
+        }
+    }
+
+    pub fn load_oam(&mut self, data: &[u8]) {
+        for (addr, &val) in (OAM_START..=OAM_STOP).zip(data) {
+            self.write_oam(addr, val);
+        }
+    }
+
+    pub fn load_cgb_palettes(&mut self, bg: &[u8], obj: &[u8]) {
+        self.bg_palettes.load_data(bg);
+        self.obj_palettes.load_data(obj);
+    }
+
+    /// Sets the LCD registers directly, for save states from other emulators, picking up from the start of the mode in STAT on line LY
+    pub fn restore_lcd_regs(&mut self, regs: &[u8]) {
+        let len = regs.len().min(LCD_REG_SIZE);
+        self.lcd_regs[..len].copy_from_slice(&regs[..len]);
+        let mode = LcdModeType::from_idx(self.read_lcd_reg(STAT));
+        self.mode.set_position(mode, self.read_lcd_reg(LY));
+        self.wy_triggered = self.read_lcd_reg(LY) >= self.read_lcd_reg(WY);
+        self.window_line = 0;
+        self.window_drawn = false;
+        self.stat_irq_pending = false;
+        self.reset_line();
+    }
+
+    // The line being drawn isn't saved, so it will be started over
+    fn reset_line(&mut self) {
+        self.fifo = PixelFifo::new();
+        self.line_buffer = LineBuffer::new();
+        self.line_x = 0;
+        self.oam_scan_line = None;
+        self.frame_changed = true;
+    }
+
+    // Only what the game can affect is saved, while display settings such as palettes and the renderer are left alone
+    pub fn save_state(&self, state: &mut StateWriter) {
+        let vram: Vec<u8> = (0..NUM_VRAM_BANKS).flat_map(|bank| {
+            (VRAM_START..=VRAM_STOP).map(move |addr| self.read_vram_bank(bank, addr))
+        }).collect();
+        state.write_region(Region::Vram, &vram);
+        let oam: Vec<u8> = (OAM_START..=OAM_STOP).map(|addr| self.read_oam(addr)).collect();
+        state.write_region(Region::Oam, &oam);
+        self.bg_palettes.save_state(state, Region::BgPalettes);
+        self.obj_palettes.save_state(state, Region::ObjPalettes);
+
+        self.mode.save_state(state);
+        state.write_bytes(&self.lcd_regs);
+        state.write_u8(self.vram_bank as u8);
+        state.write_bool(self.wy_triggered);
+        state.write_u8(self.window_line as u8);
+        state.write_bool(self.window_drawn);
+        state.write_bool(self.stat_irq_pending);
+        state.write_bytes(&self.screen_buffer);
+        state.write_bytes(&self.indexed_buffer);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader, version: u16) -> Result<(), StateError> {
+        self.load_vram(state.read_bytes(NUM_VRAM_BANKS * VRAM_BANK_SIZE)?);
+        self.load_oam(state.read_bytes(OAM_SIZE)?);
+        self.bg_palettes.load_state(state)?;
+        self.obj_palettes.load_state(state)?;
+
+        self.mode.load_state(state)?;
+        self.lcd_regs = state.read_array()?;
+        self.vram_bank = (state.read_u8()? & 0b1) as usize;
+        self.wy_triggered = state.read_bool()?;
+        self.window_line = state.read_u8()? as usize;
+        self.window_drawn = state.read_bool()?;
+        self.stat_irq_pending = state.read_bool()?;
+        // Older states didn't keep the picture, which will be drawn again by the end of the frame
+        if version >= 2 {
+            self.screen_buffer = state.read_array()?;
+            self.indexed_buffer = state.read_array()?;
+        }
+        self.reset_line();
+        Ok(())
+    }
+
     fn are_sprites_8x16(&self) -> bool {
         let lcdc = self.read_lcd_reg(LCDC);
         lcdc.get_bit(LCDC_SPR_SIZE_BIT)
@@ -341,6 +939,20 @@ impl Ppu {
         unpack_u8(self.read_lcd_reg(BGP))
     }
 
+    fn get_dmg_bg_color(&self, shade: u8) -> [u8; 4] {
+        match self.compat_palette {
+            Some(compat) => { compat.get_bg_color(shade) },
+            None => { self.dmg_colors[shade as usize] },
+        }
+    }
+
+    fn get_dmg_obj_color(&self, palette1: bool, shade: u8) -> [u8; 4] {
+        match self.compat_palette {
+            Some(compat) => { compat.get_obj_color(palette1, shade) },
+            None => { self.dmg_colors[shade as usize] },
+        }
+    }
+
     fn get_sprite_palette(&self, palette1: bool) -> [u8; 4] {
         if palette1 {
              unpack_u8(self.read_lcd_reg(OBP1))
@@ -365,10 +977,15 @@ impl Ppu {
         Point::new(x, y)
     }
 
-    fn get_window_coords(&self) -> Point {
-        let x = self.read_lcd_reg(WX);
-        let y = self.read_lcd_reg(WY);
-        Point::new(x.saturating_sub(7), y)
+    // Returns the screen column where the window begins on this line, or None if it isn't shown
+    // WX values below 7 push the window's left edge off the screen, while those above 166 hide it
+    fn get_window_left(&self) -> Option<isize> {
+        let wx = self.read_lcd_reg(WX);
+        if self.is_window_layer_displayed() && self.wy_triggered && wx <= WX_MAX {
+            Some(wx as isize - WX_OFFSET)
+        } else {
+            None
+        }
     }
 
     fn get_wndw_tile_map_index(&self) -> u8 {
@@ -382,8 +999,9 @@ impl Ppu {
     }
 
     fn is_bg_layer_displayed(&self) -> bool {
+        // In CGB mode LCDC bit 0 controls priority rather than visibility
         let lcdc = self.read_lcd_reg(LCDC);
-        lcdc.get_bit(LCDC_BG_WNDW_ENABLED_BIT)
+        self.cgb_mode || lcdc.get_bit(LCDC_BG_WNDW_ENABLED_BIT)
     }
 
     fn is_sprite_layer_displayed(&self) -> bool {
@@ -393,6 +1011,139 @@ impl Ppu {
 
     fn is_window_layer_displayed(&self) -> bool {
         let lcdc = self.read_lcd_reg(LCDC);
-        lcdc.get_bit(LCDC_BG_WNDW_ENABLED_BIT) && lcdc.get_bit(LCDC_WNDW_ENABLED_BIT)
+        self.is_bg_layer_displayed() && lcdc.get_bit(LCDC_WNDW_ENABLED_BIT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A PPU on line 0 with sprites enabled, with tile 1 filled with color 1 and tile 2 with color 3
+    fn sprite_ppu() -> Ppu {
+        let mut ppu = Ppu::new();
+        ppu.write_lcd_reg(LCDC, 0x83);
+        ppu.write_lcd_reg(BGP, 0xE4);
+        ppu.write_lcd_reg(OBP0, 0xE4);
+        for row in 0..8 {
+            ppu.write_vram(0x8010 + 2 * row, 0xFF);
+            ppu.write_vram(0x8020 + 2 * row, 0xFF);
+            ppu.write_vram(0x8021 + 2 * row, 0xFF);
+        }
+        ppu
+    }
+
+    fn place_sprite(ppu: &mut Ppu, idx: u16, x: u8, tile: u8, flags: u8) {
+        let addr = OAM_START + idx * BYTES_PER_SPRITE;
+        ppu.write_oam(addr, 16);
+        ppu.write_oam(addr + 1, x);
+        ppu.write_oam(addr + 2, tile);
+        ppu.write_oam(addr + 3, flags);
+    }
+
+    fn pixel(ppu: &Ppu, x: usize) -> [u8; 4] {
+        let screen = ppu.render();
+        screen[(4 * x)..(4 * x + 4)].try_into().unwrap()
+    }
+
+    #[test]
+    fn dmg_sprites_favor_lowest_x() {
+        let mut ppu = sprite_ppu();
+        place_sprite(&mut ppu, 0, 12, 1, 0x00);
+        place_sprite(&mut ppu, 1, 8, 2, 0x00);
+        ppu.render_scanline();
+        assert_eq!(pixel(&ppu, 3), GB_PALETTE[3]);
+        // Past the end of the winning sprite, the other one shows through
+        assert_eq!(pixel(&ppu, 8), GB_PALETTE[1]);
+        assert_eq!(pixel(&ppu, 4), GB_PALETTE[3]);
+    }
+
+    #[test]
+    fn dmg_sprite_ties_go_to_first_in_oam() {
+        let mut ppu = sprite_ppu();
+        place_sprite(&mut ppu, 0, 8, 1, 0x00);
+        place_sprite(&mut ppu, 1, 8, 2, 0x00);
+        ppu.render_scanline();
+        assert_eq!(pixel(&ppu, 0), GB_PALETTE[1]);
+        assert_eq!(pixel(&ppu, 7), GB_PALETTE[1]);
+    }
+
+    #[test]
+    fn sprite_behind_bg_hides_those_beneath_it() {
+        let mut ppu = sprite_ppu();
+        // Fill tile 0, and so the whole background, with color 1
+        for row in 0..8 {
+            ppu.write_vram(0x8000 + 2 * row, 0xFF);
+        }
+        place_sprite(&mut ppu, 0, 8, 1, 0x80);
+        place_sprite(&mut ppu, 1, 9, 2, 0x00);
+        ppu.render_scanline();
+        assert_eq!(pixel(&ppu, 1), GB_PALETTE[1]);
+        assert_eq!(pixel(&ppu, 8), GB_PALETTE[3]);
+    }
+
+    #[test]
+    fn mid_scanline_palette_write_splits_line() {
+        let mut ppu = sprite_ppu();
+        while ppu.mode.get_mode() != LcdModeType::VRAMReadMode {
+            ppu.update(4);
+        }
+        // Change the background palette once the first 100 pixels have been pushed
+        while ppu.mode.get_cycles() < MODE3_PIXEL_DELAY + 100 {
+            ppu.update(4);
+        }
+        ppu.write_lcd_reg(BGP, 0xE7);
+        while ppu.update(4).lcd_result != LcdResults::RenderLine {}
+        ppu.render_scanline();
+
+        let line = ppu.read_lcd_reg(LY) as usize;
+        let screen = ppu.render();
+        let at = |x: usize| -> [u8; 4] { screen[(4 * (line * SCREEN_WIDTH + x))..][..4].try_into().unwrap() };
+        assert_eq!(at(0), GB_PALETTE[0]);
+        assert_eq!(at(99), GB_PALETTE[0]);
+        assert_eq!(at(100), GB_PALETTE[3]);
+        assert_eq!(at(SCREEN_WIDTH - 1), GB_PALETTE[3]);
+    }
+
+    #[test]
+    fn mode3_length_penalties() {
+        let mut ppu = sprite_ppu();
+        assert_eq!(ppu.get_mode3_len(0), 172);
+
+        ppu.write_lcd_reg(SCX, 3);
+        assert_eq!(ppu.get_mode3_len(0), 175);
+        ppu.write_lcd_reg(SCX, 0);
+
+        // OAM is only scanned once per line, so it has to be forgotten after each change here
+        // Waits 5 dots for the background tile at the left edge, then 6 to fetch the sprite
+        place_sprite(&mut ppu, 0, 8, 1, 0x00);
+        ppu.oam_scan_line = None;
+        assert_eq!(ppu.get_mode3_len(0), 183);
+        // A second sprite in the same tile only adds the fetch
+        place_sprite(&mut ppu, 1, 10, 1, 0x00);
+        ppu.oam_scan_line = None;
+        assert_eq!(ppu.get_mode3_len(0), 189);
+        // Sprites hidden off the left edge always take 11 dots
+        place_sprite(&mut ppu, 2, 0, 1, 0x00);
+        ppu.oam_scan_line = None;
+        assert_eq!(ppu.get_mode3_len(0), 200);
+        // Lines without any sprites on them aren't affected
+        assert_eq!(ppu.get_mode3_len(20), 172);
+    }
+
+    #[test]
+    fn frame_length_is_unaffected_by_mode3() {
+        let mut ppu = sprite_ppu();
+        ppu.write_lcd_reg(SCX, 5);
+        for idx in 0..10 {
+            place_sprite(&mut ppu, idx, 8 + 12 * idx as u8, 1, 0x00);
+        }
+        while ppu.update(4).lcd_result != LcdResults::RenderFrame {}
+
+        let mut dots = 4;
+        while ppu.update(4).lcd_result != LcdResults::RenderFrame {
+            dots += 4;
+        }
+        assert_eq!(dots, 70224);
     }
 }
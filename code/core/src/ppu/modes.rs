@@ -1,10 +1,12 @@
-const HBLANK_LEN: usize = 204;
-const VBLANK_LEN: usize = 456;
+use crate::state::{StateError, StateReader, StateWriter};
+
+pub const VRAM_READ_MIN_LEN: usize = 172;
+
+const LINE_LEN: usize = 456;
 const OAM_READ_LEN: usize = 80;
-const VRAM_READ_LEN: usize = 172;
 
-const VBLANK_LINE_START: u8 = 143;
-const VBLANK_LINE_END: u8   = VBLANK_LINE_START + 10;
+const VBLANK_LINE_START: u8 = 144;
+const VBLANK_LINE_END: u8   = 153;
 
 #[derive(PartialEq)]
 pub enum LcdResults {
@@ -30,12 +32,37 @@ impl LcdModeType {
             LcdModeType::VRAMReadMode => { 3 },
         }
     }
+
+    pub fn from_idx(idx: u8) -> Self {
+        match idx & 0b11 {
+            0 => { LcdModeType::HBLANK },
+            1 => { LcdModeType::VBLANK },
+            2 => { LcdModeType::OAMReadMode },
+            _ => { LcdModeType::VRAMReadMode },
+        }
+    }
 }
 
+/*
+ * LCD Timing
+ *
+ * Each line takes 456 dots. Visible lines 0-143 scan OAM for 80 dots, then
+ * draw in mode 3 before waiting out the rest of the line in HBlank. Mode 3
+ * takes at least 172 dots, but is stretched by fine scrolling, the window,
+ * and sprites, which shortens HBlank by the same amount. Lines 144-153 are
+ * spent entirely in VBlank.
+ */
 pub struct Lcd {
     mode: LcdModeType,
     cycles: usize,
     line: u8,
+    vram_len: usize,
+}
+
+impl Default for Lcd {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Lcd {
@@ -44,6 +71,7 @@ impl Lcd {
             mode: LcdModeType::HBLANK,
             cycles: 0,
             line: 0,
+            vram_len: VRAM_READ_MIN_LEN,
         }
     }
 
@@ -55,14 +83,51 @@ impl Lcd {
         self.mode
     }
 
+    /// Returns how many dots have passed in the current mode
+    pub fn get_cycles(&self) -> usize {
+        self.cycles
+    }
+
+    /// Sets how many dots mode 3 lasts on the current line
+    pub fn set_vram_len(&mut self, len: usize) {
+        self.vram_len = len;
+    }
+
+    /// Moves to the start of the given mode on a line, for save states which don't record the exact dot
+    pub fn set_position(&mut self, mode: LcdModeType, line: u8) {
+        self.mode = mode;
+        self.line = line.min(VBLANK_LINE_END);
+        self.cycles = 0;
+        self.vram_len = VRAM_READ_MIN_LEN;
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.mode.get_idx());
+        state.write_u16(self.cycles as u16);
+        state.write_u8(self.line);
+        state.write_u16(self.vram_len as u16);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.mode = LcdModeType::from_idx(state.read_u8()?);
+        self.cycles = state.read_u16()? as usize;
+        self.line = state.read_u8()?;
+        self.vram_len = state.read_u16()? as usize;
+        if self.line > VBLANK_LINE_END || self.cycles > LINE_LEN || !(VRAM_READ_MIN_LEN..=LINE_LEN - OAM_READ_LEN).contains(&self.vram_len) {
+            return Err(StateError::Invalid("LCD timing out of range"));
+        }
+        Ok(())
+    }
+
     pub fn step(&mut self, cycles: u8) -> LcdResults {
         self.cycles += cycles as usize;
         let mut result = LcdResults::NoAction;
 
         match self.mode {
             LcdModeType::HBLANK => {
-                if self.cycles >= HBLANK_LEN {
-                    self.cycles = 0;
+                let hblank_len = LINE_LEN - OAM_READ_LEN - self.vram_len;
+                if self.cycles >= hblank_len {
+                    self.cycles -= hblank_len;
                     self.line += 1;
 
                     // If we've finished line 143, we've finished a frame, time for VBLANK
@@ -75,8 +140,8 @@ impl Lcd {
                 }
             },
             LcdModeType::VBLANK => {
-                if self.cycles >= VBLANK_LEN {
-                    self.cycles = 0;
+                if self.cycles >= LINE_LEN {
+                    self.cycles -= LINE_LEN;
                     self.line += 1;
 
                     if self.line > VBLANK_LINE_END {
@@ -87,19 +152,19 @@ impl Lcd {
             },
             LcdModeType::OAMReadMode => {
                 if self.cycles >= OAM_READ_LEN {
-                    self.cycles = 0;
+                    self.cycles -= OAM_READ_LEN;
                     self.mode = LcdModeType::VRAMReadMode;
                 }
             },
             LcdModeType::VRAMReadMode => {
-                if self.cycles >= VRAM_READ_LEN {
-                    self.cycles = 0;
+                if self.cycles >= self.vram_len {
+                    self.cycles -= self.vram_len;
                     self.mode = LcdModeType::HBLANK;
                     result = LcdResults::RenderLine;
                 }
             }
         }
 
-        return result;
+        result
     }
 }
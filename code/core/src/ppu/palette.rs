@@ -0,0 +1,91 @@
+use crate::state::{Region, StateError, StateReader, StateWriter};
+use crate::utils::{merge_bytes, rgb555_to_rgba, BitOps};
+
+const PALETTE_RAM_SIZE: usize   = 64;
+const BYTES_PER_PALETTE: usize  = 8;
+const BYTES_PER_COLOR: usize    = 2;
+
+const AUTO_INCREMENT_BIT: u8    = 7;
+const INDEX_MASK: u8            = 0x3F;
+
+/*
+ * CGB Palette RAM
+ *
+ * Eight palettes of four colors each, with every color stored as a
+ * little-endian 15-bit value:
+ *
+ * Bit  15  | 14 - 10 | 9 - 5 | 4 - 0
+ *    Unused|  Blue   | Green |  Red
+ *
+ * The RAM isn't directly mapped, instead it's accessed through a
+ * specification register (BCPS/OCPS) which selects the byte index, and a data
+ * register (BCPD/OCPD) which reads or writes that byte.
+ */
+#[derive(Clone, Copy)]
+pub struct CgbPalette {
+    data: [u8; PALETTE_RAM_SIZE],
+    index: u8,
+    auto_increment: bool,
+}
+
+impl Default for CgbPalette {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CgbPalette {
+    pub fn new() -> Self {
+        Self {
+            // Palette RAM powers on as white
+            data: [0xFF; PALETTE_RAM_SIZE],
+            index: 0,
+            auto_increment: false,
+        }
+    }
+
+    pub fn get_color(&self, palette: u8, color: u8) -> [u8; 4] {
+        let idx = (palette as usize) * BYTES_PER_PALETTE + (color as usize) * BYTES_PER_COLOR;
+        let raw = merge_bytes(self.data[idx + 1], self.data[idx]);
+        rgb555_to_rgba(raw)
+    }
+
+    /// Copies in saved palette RAM, with anything past its end ignored
+    pub fn load_data(&mut self, data: &[u8]) {
+        let len = data.len().min(PALETTE_RAM_SIZE);
+        self.data[..len].copy_from_slice(&data[..len]);
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter, region: Region) {
+        state.write_region(region, &self.data);
+        state.write_u8(self.read_spec());
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.load_data(state.read_bytes(PALETTE_RAM_SIZE)?);
+        self.write_spec(state.read_u8()?);
+        Ok(())
+    }
+
+    pub fn read_data(&self) -> u8 {
+        self.data[self.index as usize]
+    }
+
+    pub fn read_spec(&self) -> u8 {
+        let mut ret = self.index | 0b0100_0000;
+        ret.set_bit(AUTO_INCREMENT_BIT, self.auto_increment);
+        ret
+    }
+
+    pub fn write_data(&mut self, val: u8) {
+        self.data[self.index as usize] = val;
+        if self.auto_increment {
+            self.index = (self.index + 1) & INDEX_MASK;
+        }
+    }
+
+    pub fn write_spec(&mut self, val: u8) {
+        self.index = val & INDEX_MASK;
+        self.auto_increment = val.get_bit(AUTO_INCREMENT_BIT);
+    }
+}
@@ -7,7 +7,10 @@ const BG_PRIORITY_BIT: u8   = 7;
 const Y_FLIP_BIT: u8        = 6;
 const X_FLIP_BIT: u8        = 5;
 const PALETTE_BIT: u8       = 4;
+const VRAM_BANK_BIT: u8     = 3;
+const CGB_PALETTE_MASK: u8  = 0b0000_0111;
 
+/// A single entry in OAM
 #[derive(Clone, Copy)]
 pub struct Sprite {
     pos: Point,
@@ -16,6 +19,14 @@ pub struct Sprite {
     x_flip: bool,
     y_flip: bool,
     palette1: bool,
+    vram_bank: usize,
+    cgb_palette: u8,
+}
+
+impl Default for Sprite {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Sprite {
@@ -27,6 +38,8 @@ impl Sprite {
             x_flip: false,
             y_flip: false,
             palette1: false,
+            vram_bank: 0,
+            cgb_palette: 0,
         }
     }
 
@@ -34,6 +47,11 @@ impl Sprite {
         self.bg_priority
     }
 
+    pub fn get_cgb_palette(&self) -> u8 {
+        self.cgb_palette
+    }
+
+    /// Returns the screen position of the sprite's top left corner, which can be off the screen
     pub fn get_coords(&self) -> (isize, isize) {
         (self.pos.x as isize - X_OFFSET, self.pos.y as isize - Y_OFFSET)
     }
@@ -42,6 +60,10 @@ impl Sprite {
         self.tile_num
     }
 
+    pub fn get_vram_bank(&self) -> usize {
+        self.vram_bank
+    }
+
     pub fn is_x_flipped(&self) -> bool {
         self.x_flip
     }
@@ -68,6 +90,8 @@ impl Sprite {
                 ret.set_bit(Y_FLIP_BIT, self.y_flip);
                 ret.set_bit(X_FLIP_BIT, self.x_flip);
                 ret.set_bit(PALETTE_BIT, self.palette1);
+                ret.set_bit(VRAM_BANK_BIT, self.vram_bank == 1);
+                ret |= self.cgb_palette;
                 ret
             }
             _ => { unreachable!() }
@@ -95,6 +119,8 @@ impl Sprite {
                 self.y_flip = val.get_bit(Y_FLIP_BIT);
                 self.x_flip = val.get_bit(X_FLIP_BIT);
                 self.palette1 = val.get_bit(PALETTE_BIT);
+                self.vram_bank = if val.get_bit(VRAM_BANK_BIT) { 1 } else { 0 };
+                self.cgb_palette = val & CGB_PALETTE_MASK;
             },
             _ => { unreachable!(); }
         }
@@ -17,9 +17,7 @@ impl Tile {
     }
 
     pub fn read_u8(&self, offset: u16) -> u8 {
-        if offset > 16 {
-            panic!("Offset too large to fit in this tile");
-        }
+        assert!(offset < 16, "Offset too large to fit in this tile");
         let row = (offset / 2) as usize;
         let bit = (offset % 2) as u8;
         let mut ret = 0;
@@ -31,9 +29,7 @@ impl Tile {
     }
 
     pub fn write_u8(&mut self, offset: u16, val: u8) {
-        if offset > 16 {
-            panic!("Offset too large to fit in this tile");
-        }
+        assert!(offset < 16, "Offset too large to fit in this tile");
         let row = (offset / 2) as usize;
         let bit = (offset % 2) as u8;
         for i in 0..8 {
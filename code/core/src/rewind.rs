@@ -0,0 +1,219 @@
+use crate::cpu::Cpu;
+use crate::state::{StateError, StateReader, StateWriter};
+
+use std::collections::VecDeque;
+
+// By default, a snapshot is taken every 4 frames, keeping about 30 seconds of history
+const DEFAULT_INTERVAL: usize   = 4;
+const DEFAULT_CAPACITY: usize   = 450;
+
+const MAX_RUN: usize            = u16::MAX as usize;
+
+/*
+ * Rewind
+ *
+ * Save states are taken every few frames, but only the newest is kept whole.
+ * Each older one is stored as the difference from the one after it, which
+ * for consecutive frames is mostly unchanged bytes.
+ *
+ * +--------+--------+-----+--------+--------+
+ * | Delta  | Delta  | ... | Delta  | Latest |
+ * +--------+--------+-----+--------+--------+
+ *  oldest                                newest
+ *
+ * A delta is the older state XORed with the newer one, so unchanged bytes
+ * become zero, and then stored as the older state's length, followed by
+ * pairs of runs until it's covered.
+ *
+ * +---------------+------------------+------------------------+
+ * | Zero run (16) | Literal run (16) | Literal run's bytes... |
+ * +---------------+------------------+------------------------+
+ *
+ * Stepping back XORs the newest delta into the latest state, giving the one
+ * before it, and once full the oldest delta is simply dropped.
+ */
+pub struct Rewind {
+    interval: usize,
+    capacity: usize,
+    frames_since: usize,
+    latest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl Default for Rewind {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Rewind {
+    pub fn new() -> Self {
+        Self::with_settings(DEFAULT_INTERVAL, DEFAULT_CAPACITY)
+    }
+
+    /// Takes a snapshot every interval frames, keeping up to capacity of them
+    pub fn with_settings(interval: usize, capacity: usize) -> Self {
+        Self {
+            interval: interval.max(1),
+            capacity: capacity.max(1),
+            frames_since: 0,
+            latest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    /// Forgets all history, such as when a new game is loaded
+    pub fn clear(&mut self) {
+        self.frames_since = 0;
+        self.latest = None;
+        self.deltas.clear();
+    }
+
+    /// Returns how many snapshots are kept
+    pub fn len(&self) -> usize {
+        self.deltas.len() + self.latest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    /// Called once each frame has finished, taking a snapshot if it's time for one
+    pub fn push_frame(&mut self, gb: &Cpu) {
+        self.frames_since += 1;
+        if self.frames_since < self.interval {
+            return;
+        }
+        self.frames_since = 0;
+
+        let state = gb.save_state();
+        if let Some(previous) = self.latest.take() {
+            self.deltas.push_back(encode_delta(&state, &previous));
+        }
+        self.latest = Some(state);
+        while self.len() > self.capacity {
+            self.deltas.pop_front();
+        }
+    }
+
+    /// Puts the emulator back to the previous snapshot, returning false once there's no more history.
+    /// If frames have run since the latest snapshot, that one is returned to first.
+    pub fn step_back(&mut self, gb: &mut Cpu) -> bool {
+        let Some(latest) = &self.latest else {
+            return false;
+        };
+
+        if self.frames_since == 0 {
+            let Some(delta) = self.deltas.pop_back() else {
+                return false;
+            };
+            match decode_delta(latest, &delta) {
+                Ok(previous) => { self.latest = Some(previous) },
+                Err(_) => {
+                    self.clear();
+                    return false;
+                },
+            }
+        }
+
+        self.frames_since = 0;
+        match &self.latest {
+            Some(state) => { gb.load_state(state).is_ok() },
+            None => { false },
+        }
+    }
+}
+
+// Stores target as the difference from base
+fn encode_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let diff = |i: usize| target[i] ^ base.get(i).copied().unwrap_or(0);
+
+    let mut delta = StateWriter::new();
+    delta.write_u32(target.len() as u32);
+    let mut i = 0;
+    while i < target.len() {
+        let zeros_start = i;
+        while i < target.len() && i - zeros_start < MAX_RUN && diff(i) == 0 {
+            i += 1;
+        }
+        let literal_start = i;
+        while i < target.len() && i - literal_start < MAX_RUN && diff(i) != 0 {
+            i += 1;
+        }
+        delta.write_u16((literal_start - zeros_start) as u16);
+        delta.write_u16((i - literal_start) as u16);
+        for j in literal_start..i {
+            delta.write_u8(diff(j));
+        }
+    }
+    delta.into_bytes()
+}
+
+// Rebuilds what encode_delta was given as the target
+fn decode_delta(base: &[u8], delta: &[u8]) -> Result<Vec<u8>, StateError> {
+    let mut reader = StateReader::new(delta);
+    let len = reader.read_u32()? as usize;
+    let mut target: Vec<u8> = (0..len).map(|i| base.get(i).copied().unwrap_or(0)).collect();
+
+    let mut i = 0;
+    while reader.remaining() > 0 {
+        i += reader.read_u16()? as usize;
+        let literal_len = reader.read_u16()? as usize;
+        for &byte in reader.read_bytes(literal_len)? {
+            let Some(val) = target.get_mut(i) else {
+                return Err(StateError::Invalid("rewind delta is out of bounds"));
+            };
+            *val ^= byte;
+            i += 1;
+        }
+    }
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(base: &[u8], target: &[u8]) {
+        let delta = encode_delta(base, target);
+        assert_eq!(decode_delta(base, &delta).unwrap(), target);
+    }
+
+    #[test]
+    fn delta_round_trip() {
+        let base: Vec<u8> = (0..0x1000).map(|i| (i * 7) as u8).collect();
+        let mut target = base.clone();
+        target[0] ^= 0xFF;
+        target[0x800..0x810].fill(0x5A);
+        target[0xFFF] = 0;
+        round_trip(&base, &target);
+        round_trip(&base, &base);
+        round_trip(&base, &[]);
+    }
+
+    #[test]
+    fn delta_between_lengths() {
+        let short = vec![1; 0x10];
+        let long = vec![2; 0x30];
+        round_trip(&short, &long);
+        round_trip(&long, &short);
+    }
+
+    #[test]
+    fn delta_with_runs_longer_than_max() {
+        let base = vec![0; MAX_RUN * 2 + 10];
+        let mut target = vec![0xAA; MAX_RUN + 5];
+        target.extend(vec![0; MAX_RUN + 5]);
+        round_trip(&base, &target);
+    }
+
+    #[test]
+    fn delta_out_of_bounds_is_rejected() {
+        let mut delta = StateWriter::new();
+        delta.write_u32(4);
+        delta.write_u16(3);
+        delta.write_u16(2);
+        delta.write_bytes(&[1, 2]);
+        assert_eq!(decode_delta(&[0; 4], &delta.into_bytes()), Err(StateError::Invalid("rewind delta is out of bounds")));
+    }
+}
@@ -0,0 +1,269 @@
+use crate::state::{StateError, StateReader, StateWriter};
+use crate::utils::BitOps;
+
+use std::collections::VecDeque;
+use std::mem;
+
+pub const SB: u16       = 0xFF01;
+pub const SC: u16       = 0xFF02;
+
+const SC_TRANSFER_BIT: u8       = 7;
+const SC_SPEED_BIT: u8          = 1;
+const SC_CLOCK_BIT: u8          = 0;
+// The unused bits always read as set, which includes the speed bit on the DMG
+const SC_UNUSED_MASK: u8        = 0b0111_1110;
+const SC_CGB_UNUSED_MASK: u8    = 0b0111_1100;
+
+// The internal clock shifts a bit on each falling edge of one of the system counter's bits,
+// every 512 T-cycles (8192 Hz) normally, or every 16 (262144 Hz) in the CGB's fast mode
+const SLOW_CLOCK_BIT: u8        = 8;
+const FAST_CLOCK_BIT: u8        = 3;
+const BITS_PER_TRANSFER: u8     = 8;
+
+// Sent bytes are kept for test harnesses, but only so many in case nobody collects them
+const MAX_OUTPUT_LEN: usize     = 0x10000;
+
+/// Something plugged into the link port, such as another Game Boy or a printer
+pub trait SerialDevice {
+    /// Called as the Game Boy starts a transfer on its own clock, with the byte it is sending,
+    /// returning the byte the device sends back over the following eight clocks
+    fn exchange(&mut self, sent: u8) -> u8;
+
+    /// Polled while the Game Boy waits for the device to clock a transfer, returning the byte
+    /// it sent once a whole one has been swapped for the given byte, or None if still waiting
+    fn external_transfer(&mut self, _sent: u8) -> Option<u8> {
+        None
+    }
+}
+
+/// The link port with no cable in it, where the data line is left pulled high
+pub struct Disconnected;
+
+impl SerialDevice for Disconnected {
+    fn exchange(&mut self, _sent: u8) -> u8 {
+        0xFF
+    }
+}
+
+/*
+ * Serial Port
+ *
+ * +------+-----+---------------------------------------------+
+ * | SB   | R/W | The byte being sent, and then received      |
+ * +------+-----+---------------------------------------------+
+ * | SC 7 | R/W | Set to start a transfer, cleared once done  |
+ * | SC 0 | R/W | Clock source, 1 for internal, 0 for partner |
+ * +------+-----+---------------------------------------------+
+ *
+ * Each clock shifts SB left by one, sending out its top bit and taking in a
+ * bit from the other side. After eight, the whole byte has been swapped,
+ * SC bit 7 is cleared, and the Serial interrupt is requested. Transfers
+ * waiting on an external clock will only finish once the connected device
+ * has clocked them.
+ *
+ * The internal clock is taken from the same counter as DIV, so the first bit
+ * of a transfer can come anywhere up to a full period after it's started,
+ * and resetting DIV can clock an extra bit early.
+ */
+pub struct Serial {
+    sb: u8,
+    sc: u8,
+    clock_level: bool,
+    bits_left: u8,
+    incoming: u8,
+    device: Box<dyn SerialDevice>,
+    cgb_mode: bool,
+    output: VecDeque<u8>,
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Self {
+            sb: 0,
+            sc: 0,
+            clock_level: false,
+            bits_left: 0,
+            incoming: 0xFF,
+            device: Box::new(Disconnected),
+            cgb_mode: false,
+            output: VecDeque::new(),
+        }
+    }
+
+    pub fn set_cgb_mode(&mut self, cgb: bool) {
+        self.cgb_mode = cgb;
+    }
+
+    pub fn set_device(&mut self, device: Box<dyn SerialDevice>) {
+        self.device = device;
+    }
+
+    /// Clears the registers, leaving the device plugged in and any unread output waiting
+    pub fn reset(&mut self) {
+        *self = Self {
+            device: mem::replace(&mut self.device, Box::new(Disconnected)),
+            output: mem::take(&mut self.output),
+            ..Self::new()
+        };
+    }
+
+    /// Returns every byte sent since the last call, oldest first
+    pub fn take_output(&mut self) -> Vec<u8> {
+        mem::take(&mut self.output).into()
+    }
+
+    // The connected device and collected output belong to the frontend, so aren't part of the state
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.sb);
+        state.write_u8(self.sc);
+        state.write_bool(self.clock_level);
+        state.write_u8(self.bits_left);
+        state.write_u8(self.incoming);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.sb = state.read_u8()?;
+        self.sc = state.read_u8()?;
+        self.clock_level = state.read_bool()?;
+        self.bits_left = state.read_u8()?.min(BITS_PER_TRANSFER);
+        self.incoming = state.read_u8()?;
+        // An internally clocked transfer always has a bit left to shift until it finishes
+        if self.sc.get_bit(SC_TRANSFER_BIT) && self.sc.get_bit(SC_CLOCK_BIT) && self.bits_left == 0 {
+            return Err(StateError::Invalid("serial transfer has no bits left"));
+        }
+        Ok(())
+    }
+
+    pub fn read_serial(&self, addr: u16) -> u8 {
+        match addr {
+            SB => { self.sb },
+            SC => {
+                let unused = if self.cgb_mode { SC_CGB_UNUSED_MASK } else { SC_UNUSED_MASK };
+                self.sc | unused
+            },
+            _ => unreachable!("Trying to read a non-serial register")
+        }
+    }
+
+    pub fn write_serial(&mut self, addr: u16, val: u8) {
+        match addr {
+            SB => { self.sb = val },
+            SC => {
+                self.sc = val;
+                if val.get_bit(SC_TRANSFER_BIT) {
+                    if self.output.len() == MAX_OUTPUT_LEN {
+                        self.output.pop_front();
+                    }
+                    self.output.push_back(self.sb);
+                    self.bits_left = BITS_PER_TRANSFER;
+                    if val.get_bit(SC_CLOCK_BIT) {
+                        self.incoming = self.device.exchange(self.sb);
+                    }
+                }
+            },
+            _ => unreachable!("Trying to write to a non-serial register")
+        }
+    }
+
+    // Called once per M-cycle with the timer's system counter, returning true once a transfer has
+    // finished, requesting the Serial interrupt
+    pub fn tick(&mut self, system_counter: u16) -> bool {
+        let fast = self.cgb_mode && self.sc.get_bit(SC_SPEED_BIT);
+        let level = system_counter.get_bit(if fast { FAST_CLOCK_BIT } else { SLOW_CLOCK_BIT });
+        let falling_edge = self.clock_level && !level;
+        self.clock_level = level;
+
+        if !self.sc.get_bit(SC_TRANSFER_BIT) {
+            return false;
+        }
+
+        if !self.sc.get_bit(SC_CLOCK_BIT) {
+            let Some(received) = self.device.external_transfer(self.sb) else {
+                return false;
+            };
+            self.sb = received;
+            self.sc.set_bit(SC_TRANSFER_BIT, false);
+            return true;
+        }
+
+        if !falling_edge {
+            return false;
+        }
+
+        self.sb = (self.sb << 1) | (self.incoming >> 7);
+        self.incoming <<= 1;
+        self.bits_left -= 1;
+        if self.bits_left > 0 {
+            return false;
+        }
+        self.sc.set_bit(SC_TRANSFER_BIT, false);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state_with(sc: u8, bits_left: u8) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        state.write_u8(0x12);
+        state.write_u8(sc);
+        state.write_bool(false);
+        state.write_u8(bits_left);
+        state.write_u8(0xFF);
+        state.into_bytes()
+    }
+
+    #[test]
+    fn load_state_rejects_transfer_without_bits() {
+        let mut serial = Serial::new();
+        let state = state_with(0x81, 0);
+        assert_eq!(serial.load_state(&mut StateReader::new(&state)), Err(StateError::Invalid("serial transfer has no bits left")));
+    }
+
+    #[test]
+    fn load_state_accepts_idle_and_external_transfers() {
+        let mut serial = Serial::new();
+        for sc in [0x00, 0x01, 0x80] {
+            let state = state_with(sc, 0);
+            assert!(serial.load_state(&mut StateReader::new(&state)).is_ok());
+        }
+    }
+
+    #[test]
+    fn loaded_transfer_finishes() {
+        let mut serial = Serial::new();
+        // More bits than a transfer has are clamped to a whole byte
+        let state = state_with(0x81, 0xFF);
+        serial.load_state(&mut StateReader::new(&state)).unwrap();
+
+        let mut finished = 0;
+        for counter in 0..0x4000u16 {
+            if serial.tick(counter) {
+                finished += 1;
+            }
+        }
+        assert_eq!(finished, 1);
+        assert_eq!(serial.read_serial(SB), 0xFF);
+    }
+
+    #[test]
+    fn output_keeps_newest_bytes() {
+        let mut serial = Serial::new();
+        for i in 0..=MAX_OUTPUT_LEN {
+            serial.write_serial(SB, i as u8);
+            serial.write_serial(SC, 0x80);
+        }
+        let output = serial.take_output();
+        assert_eq!(output.len(), MAX_OUTPUT_LEN);
+        assert_eq!(output[0], 1);
+        assert!(serial.take_output().is_empty());
+    }
+}
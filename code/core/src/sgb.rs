@@ -0,0 +1,321 @@
+use crate::state::{StateError, StateReader, StateWriter};
+use crate::utils::*;
+
+const PACKET_SIZE: usize        = 16;
+const PACKET_BITS: usize        = PACKET_SIZE * 8;
+const MAX_PACKETS: usize        = 7;
+
+const NUM_PALETTES: usize       = 4;
+const ATTR_WIDTH: usize         = SCREEN_WIDTH / 8;
+const ATTR_HEIGHT: usize        = SCREEN_HEIGHT / 8;
+const ATTR_BLK_SET_SIZE: usize  = 6;
+
+// Command codes
+const PAL01: u8                 = 0x00;
+const PAL23: u8                 = 0x01;
+const PAL03: u8                 = 0x02;
+const PAL12: u8                 = 0x03;
+const ATTR_BLK: u8              = 0x04;
+const MLT_REQ: u8               = 0x11;
+
+const P14_BIT: u8               = 4;
+const P15_BIT: u8               = 5;
+
+/*
+ * Super Game Boy Packets
+ *
+ * Games talk to the SGB by pulsing the two joypad select lines (P14 and P15).
+ * Pulling both low resets the transfer, after which each pulse of a single line
+ * sends a bit: P14 for a 0, P15 for a 1. Both lines return high between pulses.
+ *
+ * A packet is 16 bytes sent LSB first, followed by a 0 stop bit. The first byte
+ * of the first packet holds the command in its upper five bits and the total
+ * number of packets (1-7) in its lower three.
+ */
+
+/// The palettes and screen regions a game has set up via SGB commands
+#[derive(Clone, Copy)]
+pub struct SgbColors {
+    palettes: [[[u8; 4]; 4]; NUM_PALETTES],
+    attrs: [u8; ATTR_WIDTH * ATTR_HEIGHT],
+}
+
+impl Default for SgbColors {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SgbColors {
+    pub fn new() -> Self {
+        Self {
+            palettes: [GB_PALETTE; NUM_PALETTES],
+            attrs: [0; ATTR_WIDTH * ATTR_HEIGHT],
+        }
+    }
+
+    /// Replaces the RGBA values of a scanline using the palette assigned to each region of the screen
+    pub fn colorize_line(&self, rgba: &mut [u8], shades: &[u8], line: u8) {
+        let attr_row = (line as usize / 8) * ATTR_WIDTH;
+        for (x, shade) in shades.iter().enumerate() {
+            let palette = self.attrs[attr_row + x / 8] as usize;
+            let color = self.palettes[palette][*shade as usize];
+            rgba[(4 * x)..(4 * x + 4)].copy_from_slice(&color);
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        for color in self.palettes.iter().flatten() {
+            state.write_bytes(color);
+        }
+        state.write_bytes(&self.attrs);
+    }
+
+    fn load_state(state: &mut StateReader) -> Result<Self, StateError> {
+        let mut colors = Self::new();
+        for color in colors.palettes.iter_mut().flatten() {
+            *color = state.read_array()?;
+        }
+        colors.attrs = state.read_array()?;
+        // Anything out of range would index past the palettes when drawn
+        if colors.attrs.iter().any(|&palette| palette as usize >= NUM_PALETTES) {
+            return Err(StateError::Invalid("SGB palette out of range"));
+        }
+        Ok(colors)
+    }
+
+    // Sets two palettes at once, which share a color 0 with every other palette
+    fn set_palette_pair(&mut self, first: usize, second: usize, data: &[u8]) {
+        let color = |idx: usize| rgb555_to_rgba(merge_bytes(data[2 * idx + 1], data[2 * idx]));
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color(0);
+        }
+        for i in 1..4 {
+            self.palettes[first][i] = color(i);
+            self.palettes[second][i] = color(i + 3);
+        }
+    }
+
+    fn set_attr_blocks(&mut self, data: &[u8]) {
+        let num_sets = data[1] as usize;
+        for set in data[2..].chunks_exact(ATTR_BLK_SET_SIZE).take(num_sets) {
+            let control = set[0];
+            let palettes = set[1];
+            let inside = control.get_bit(0);
+            let mut border = control.get_bit(1);
+            let outside = control.get_bit(2);
+            let inside_pal = palettes & 0b11;
+            let mut border_pal = (palettes >> 2) & 0b11;
+            let outside_pal = (palettes >> 4) & 0b11;
+
+            // If only one of inside or outside is set, the border takes on its palette
+            if !border && (inside != outside) {
+                border = true;
+                border_pal = if inside { inside_pal } else { outside_pal };
+            }
+
+            let (x1, y1) = (set[2] as usize, set[3] as usize);
+            let (x2, y2) = (set[4] as usize, set[5] as usize);
+            for y in 0..ATTR_HEIGHT {
+                for x in 0..ATTR_WIDTH {
+                    let in_rect = (x1..=x2).contains(&x) && (y1..=y2).contains(&y);
+                    let on_border = in_rect && (x == x1 || x == x2 || y == y1 || y == y2);
+                    let idx = y * ATTR_WIDTH + x;
+                    if on_border {
+                        if border {
+                            self.attrs[idx] = border_pal;
+                        }
+                    } else if in_rect {
+                        if inside {
+                            self.attrs[idx] = inside_pal;
+                        }
+                    } else if outside {
+                        self.attrs[idx] = outside_pal;
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub struct Sgb {
+    enabled: bool,
+    data: [u8; PACKET_SIZE * MAX_PACKETS],
+    bit_idx: usize,
+    packets_received: usize,
+    receiving: bool,
+    ready_for_pulse: bool,
+    players: u8,
+    current_player: u8,
+    player_latched: bool,
+    colors: Option<SgbColors>,
+    colors_dirty: bool,
+}
+
+impl Default for Sgb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sgb {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            data: [0; PACKET_SIZE * MAX_PACKETS],
+            bit_idx: 0,
+            packets_received: 0,
+            receiving: false,
+            ready_for_pulse: false,
+            players: 1,
+            current_player: 0,
+            player_latched: false,
+            colors: None,
+            colors_dirty: false,
+        }
+    }
+
+    /// Returns the ID shown in the lower nibble of the joypad register while neither button group is selected
+    pub fn get_joypad_id(&self) -> Option<u8> {
+        if self.players > 1 {
+            Some(0x0F - self.current_player)
+        } else {
+            None
+        }
+    }
+
+    pub fn get_current_player(&self) -> u8 {
+        self.current_player
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if self.enabled != enabled {
+            *self = Self::new();
+            self.enabled = enabled;
+            self.colors_dirty = true;
+        }
+    }
+
+    /// Returns the updated colors if a command has changed them since the last call
+    pub fn take_colors(&mut self) -> Option<Option<SgbColors>> {
+        if self.colors_dirty {
+            self.colors_dirty = false;
+            Some(self.colors)
+        } else {
+            None
+        }
+    }
+
+    // Whether the SGB is enabled depends on the cartridge, so only the transfer and its results are saved
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.data);
+        state.write_u8(self.bit_idx as u8);
+        state.write_u8(self.packets_received as u8);
+        state.write_bool(self.receiving);
+        state.write_bool(self.ready_for_pulse);
+        state.write_u8(self.players);
+        state.write_u8(self.current_player);
+        state.write_bool(self.player_latched);
+        state.write_bool(self.colors.is_some());
+        if let Some(colors) = &self.colors {
+            colors.save_state(state);
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.data = state.read_array()?;
+        self.bit_idx = (state.read_u8()? as usize).min(PACKET_BITS);
+        self.packets_received = (state.read_u8()? as usize).min(MAX_PACKETS - 1);
+        self.receiving = state.read_bool()?;
+        self.ready_for_pulse = state.read_bool()?;
+        self.players = state.read_u8()?.max(1);
+        self.current_player = state.read_u8()? % self.players;
+        self.player_latched = state.read_bool()?;
+        self.colors = if state.read_bool()? { Some(SgbColors::load_state(state)?) } else { None };
+        self.colors_dirty = true;
+        Ok(())
+    }
+
+    pub fn write_joypad(&mut self, val: u8) {
+        if !self.enabled {
+            return;
+        }
+
+        match (val.get_bit(P15_BIT), val.get_bit(P14_BIT)) {
+            (false, false) => {
+                // Reset pulse, a new packet is starting
+                self.receiving = true;
+                self.ready_for_pulse = false;
+                self.bit_idx = 0;
+            },
+            (true, true) => {
+                self.ready_for_pulse = true;
+                // In multiplayer mode, the next controller is selected when P15 goes high again
+                if self.players > 1 && !self.player_latched {
+                    self.current_player = (self.current_player + 1) % self.players;
+                    self.player_latched = true;
+                }
+            },
+            (p15, _) => {
+                if !p15 {
+                    self.player_latched = false;
+                }
+                if self.receiving && self.ready_for_pulse {
+                    self.ready_for_pulse = false;
+                    // P15 being pulled low sends a 1
+                    self.receive_bit(!p15);
+                }
+            }
+        }
+    }
+
+    fn receive_bit(&mut self, bit: bool) {
+        let packet_start = self.packets_received * PACKET_SIZE;
+        if self.bit_idx < PACKET_BITS {
+            let byte_idx = packet_start + self.bit_idx / 8;
+            self.data[byte_idx].set_bit((self.bit_idx % 8) as u8, bit);
+            self.bit_idx += 1;
+            return;
+        }
+
+        // This is the stop bit, the packet is complete
+        self.receiving = false;
+        self.packets_received += 1;
+        let length = ((self.data[0] & 0b111) as usize).max(1);
+        if self.packets_received >= length {
+            self.run_command();
+            self.packets_received = 0;
+        }
+    }
+
+    fn run_command(&mut self) {
+        let command = self.data[0] >> 3;
+        let data = self.data;
+        match command {
+            PAL01 | PAL23 | PAL03 | PAL12 => {
+                let (first, second) = match command {
+                    PAL01 => { (0, 1) },
+                    PAL23 => { (2, 3) },
+                    PAL03 => { (0, 3) },
+                    _ =>     { (1, 2) },
+                };
+                self.colors.get_or_insert_with(SgbColors::new).set_palette_pair(first, second, &data[1..]);
+                self.colors_dirty = true;
+            },
+            ATTR_BLK => {
+                self.colors.get_or_insert_with(SgbColors::new).set_attr_blocks(&data);
+                self.colors_dirty = true;
+            },
+            MLT_REQ => {
+                self.players = match data[1] & 0b11 {
+                    1 => { 2 },
+                    3 => { 4 },
+                    _ => { 1 },
+                };
+                self.current_player = 0;
+            },
+            // Other commands, such as borders and sound effects, aren't supported
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,314 @@
+use super::{Region, StateError, StateReader, StateWriter};
+use crate::config::Model;
+
+const EMULATOR_NAME: &str       = concat!("gb-book v", env!("CARGO_PKG_VERSION"));
+
+const FOOTER_MAGIC: &[u8; 4]    = b"BESS";
+const FOOTER_SIZE: usize        = 8;
+
+const CORE_MAJOR: u16           = 1;
+const CORE_MINOR: u16           = 1;
+const CORE_SIZE: usize          = 0xD0;
+const MBC_WRITE_SIZE: usize     = 3;
+
+pub const IO_REGS_SIZE: usize   = 0x80;
+pub const INFO_SIZE: usize      = 0x12;
+pub const RTC_SIZE: usize       = 0x30;
+
+// Without color, only the first VRAM bank is stored and there's no palette RAM
+const DMG_VRAM_SIZE: u32        = 0x2000;
+
+const EXEC_RUNNING: u8          = 0;
+const EXEC_HALTED: u8           = 1;
+
+/*
+ * Best Effort Save State (BESS)
+ * https://github.com/LIJI32/SameBoy/blob/master/BESS.md
+ *
+ * A common format understood by SameBoy, BGB, and others. Each emulator
+ * writes its own state first, followed by a series of blocks which describe
+ * the system in a standard way, and ends with a footer pointing back to them.
+ *
+ * +-----------------------+
+ * |  Emulator specific    |
+ * |  data, including the  |
+ * |  raw memory regions   |
+ * +-----------------------+ <-+
+ * | NAME | Emulator name  |   |
+ * | INFO | Title, checksum|   |
+ * | CORE | Registers and  |   |
+ * |      | region offsets |   |
+ * | MBC  | Register writes|   |
+ * | RTC  | MBC3 clock     |   |
+ * | END  |                |   |
+ * +-----------------------+   |
+ * | Offset of first block | --+
+ * +-----------------------+
+ * |        "BESS"         |
+ * +-----------------------+
+ *
+ * Each block is a four character ID and a 32-bit length, followed by that
+ * many bytes. Blocks we don't recognize are skipped. Rather than store each
+ * mapper's internals, the MBC block lists register writes which put the
+ * mapper back into the same state when replayed.
+ */
+
+/// The CPU and hardware register state held in the CORE block
+pub struct BessCore {
+    /// The model as four ASCII characters, such as "GD  " for a DMG or "CC  " for a CGB
+    pub model: [u8; 4],
+    pub pc: u16,
+    pub af: u16,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+    pub sp: u16,
+    pub ime: bool,
+    pub ie: u8,
+    pub halted: bool,
+    /// $FF00-$FF7F, as they would be read by the CPU
+    pub io_regs: [u8; IO_REGS_SIZE],
+}
+
+impl BessCore {
+    /// Whether the state was made on a color model, even if it was running a DMG game
+    pub fn is_cgb(&self) -> bool {
+        self.model[0] == b'C'
+    }
+}
+
+/// Returns the four character model ID stored in the CORE block
+pub fn model_id(model: Model) -> [u8; 4] {
+    match model {
+        Model::Dmg => { *b"GD  " },
+        Model::Mgb => { *b"GM  " },
+        Model::Sgb => { *b"SN  " },
+        Model::Cgb => { *b"CC  " },
+        Model::Agb => { *b"CA  " },
+    }
+}
+
+/// The standard blocks found in a save state
+pub struct BessState<'a> {
+    pub name: Option<&'a [u8]>,
+    /// The game's title bytes from $0134-$0143, then the global checksum from $014E-$014F
+    pub info: Option<&'a [u8]>,
+    pub core: BessCore,
+    /// The contents of each memory region, indexed by Region, empty if it wasn't saved
+    pub regions: [&'a [u8]; Region::ALL.len()],
+    pub mbc_writes: Vec<(u16, u8)>,
+    pub rtc: Option<&'a [u8]>,
+}
+
+impl<'a> BessState<'a> {
+    pub fn get_region(&self, region: Region) -> &'a [u8] {
+        self.regions[region as usize]
+    }
+}
+
+// Appends a block, with its ID and length
+fn write_block(state: &mut StateWriter, id: &[u8; 4], data: &[u8]) {
+    state.write_bytes(id);
+    state.write_u32(data.len() as u32);
+    state.write_bytes(data);
+}
+
+/// Appends the BESS blocks and footer, after the emulator specific data and memory regions have been written
+pub fn write_blocks(state: &mut StateWriter, info: &[u8; INFO_SIZE], core: &BessCore, mbc_writes: &[(u16, u8)], rtc: Option<&[u8]>) {
+    let first_block = state.len() as u32;
+    write_block(state, b"NAME", EMULATOR_NAME.as_bytes());
+    write_block(state, b"INFO", info);
+
+    let mut block = StateWriter::new();
+    block.write_u16(CORE_MAJOR);
+    block.write_u16(CORE_MINOR);
+    block.write_bytes(&core.model);
+    for reg in [core.pc, core.af, core.bc, core.de, core.hl, core.sp] {
+        block.write_u16(reg);
+    }
+    block.write_bool(core.ime);
+    block.write_u8(core.ie);
+    block.write_u8(if core.halted { EXEC_HALTED } else { EXEC_RUNNING });
+    block.write_u8(0);
+    block.write_bytes(&core.io_regs);
+    for region in Region::ALL {
+        let (mut size, offset) = state.get_region(region);
+        if !core.is_cgb() {
+            size = match region {
+                Region::Vram => { size.min(DMG_VRAM_SIZE) },
+                Region::BgPalettes | Region::ObjPalettes => { 0 },
+                _ => { size },
+            };
+        }
+        block.write_u32(size);
+        block.write_u32(offset);
+    }
+    write_block(state, b"CORE", &block.into_bytes());
+
+    if !mbc_writes.is_empty() {
+        let mut block = StateWriter::new();
+        for &(addr, val) in mbc_writes {
+            block.write_u16(addr);
+            block.write_u8(val);
+        }
+        write_block(state, b"MBC ", &block.into_bytes());
+    }
+
+    if let Some(rtc) = rtc {
+        write_block(state, b"RTC ", rtc);
+    }
+    write_block(state, b"END ", &[]);
+
+    state.write_u32(first_block);
+    state.write_bytes(FOOTER_MAGIC);
+}
+
+/// Finds and reads the BESS blocks through the footer at the end of the data
+pub fn parse(data: &[u8]) -> Result<BessState<'_>, StateError> {
+    if data.len() < FOOTER_SIZE || !data.ends_with(FOOTER_MAGIC) {
+        return Err(StateError::Invalid("missing BESS footer"));
+    }
+    let footer = data.len() - FOOTER_SIZE;
+    let first_block = StateReader::new(&data[footer..]).read_u32()? as usize;
+    if first_block > footer {
+        return Err(StateError::Invalid("BESS blocks start past the footer"));
+    }
+
+    let mut name = None;
+    let mut info = None;
+    let mut core = None;
+    let mut mbc_writes = Vec::new();
+    let mut rtc = None;
+
+    let mut reader = StateReader::new(&data[first_block..footer]);
+    loop {
+        let id: [u8; 4] = reader.read_array()?;
+        let len = reader.read_u32()? as usize;
+        let block = reader.read_bytes(len)?;
+        match &id {
+            b"NAME" => { name = Some(block) },
+            b"INFO" if len == INFO_SIZE => { info = Some(block) },
+            b"CORE" => { core = Some(parse_core(block)?) },
+            b"MBC " => {
+                mbc_writes = block.chunks_exact(MBC_WRITE_SIZE).map(|write| {
+                    (u16::from_le_bytes([write[0], write[1]]), write[2])
+                }).collect();
+            },
+            b"RTC " if len == RTC_SIZE => { rtc = Some(block) },
+            b"END " => { break },
+            _ => {},
+        }
+    }
+
+    let (core, region_offsets) = core.ok_or(StateError::Invalid("missing CORE block"))?;
+    let mut regions: [&[u8]; Region::ALL.len()] = [&[]; Region::ALL.len()];
+    for (region, (size, offset)) in regions.iter_mut().zip(region_offsets) {
+        let end = offset.checked_add(size).ok_or(StateError::Invalid("memory region out of bounds"))?;
+        *region = data.get(offset..end).ok_or(StateError::Invalid("memory region out of bounds"))?;
+    }
+
+    Ok(BessState { name, info, core, regions, mbc_writes, rtc })
+}
+
+// The size and offset of each memory region listed in the CORE block
+type RegionOffsets = [(usize, usize); Region::ALL.len()];
+
+fn parse_core(block: &[u8]) -> Result<(BessCore, RegionOffsets), StateError> {
+    if block.len() < CORE_SIZE {
+        return Err(StateError::Invalid("CORE block is too short"));
+    }
+
+    let mut reader = StateReader::new(block);
+    let major = reader.read_u16()?;
+    let _minor = reader.read_u16()?;
+    if major != CORE_MAJOR {
+        return Err(StateError::UnsupportedVersion(major));
+    }
+
+    let model = reader.read_array()?;
+    let pc = reader.read_u16()?;
+    let af = reader.read_u16()?;
+    let bc = reader.read_u16()?;
+    let de = reader.read_u16()?;
+    let hl = reader.read_u16()?;
+    let sp = reader.read_u16()?;
+    let ime = reader.read_bool()?;
+    let ie = reader.read_u8()?;
+    // Stopped is treated the same as halted, as STOP isn't emulated
+    let halted = reader.read_u8()? != EXEC_RUNNING;
+    let _reserved = reader.read_u8()?;
+    let io_regs = reader.read_array()?;
+
+    let mut regions = [(0, 0); Region::ALL.len()];
+    for region in regions.iter_mut() {
+        let size = reader.read_u32()? as usize;
+        let offset = reader.read_u32()? as usize;
+        *region = (size, offset);
+    }
+
+    let core = BessCore { model, pc, af, bc, de, hl, sp, ime, ie, halted, io_regs };
+    Ok((core, regions))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dmg_core() -> BessCore {
+        let mut io_regs = [0; IO_REGS_SIZE];
+        io_regs[0x40] = 0x91;
+        BessCore {
+            model: model_id(Model::Dmg),
+            pc: 0x0150,
+            af: 0x01B0,
+            bc: 0x0013,
+            de: 0x00D8,
+            hl: 0x014D,
+            sp: 0xFFFE,
+            ime: true,
+            ie: 0x05,
+            halted: true,
+            io_regs,
+        }
+    }
+
+    #[test]
+    fn blocks_round_trip() {
+        let mut writer = StateWriter::new();
+        writer.write_bytes(b"emulator specific");
+        writer.write_region(Region::Wram, &[0x11; 0x2000]);
+        writer.write_region(Region::Vram, &[0x22; 0x4000]);
+        writer.write_region(Region::BgPalettes, &[0x33; 0x40]);
+        let info = *b"TETRIS\0\0\0\0\0\0\0\0\0\0\x16\xBF";
+        let rtc = [0x44; RTC_SIZE];
+        write_blocks(&mut writer, &info, &dmg_core(), &[(0x2000, 0x05), (0x0000, 0x0A)], Some(&rtc));
+        let data = writer.into_bytes();
+
+        let state = parse(&data).unwrap();
+        assert_eq!(state.name, Some(EMULATOR_NAME.as_bytes()));
+        assert_eq!(state.info, Some(&info[..]));
+        let core = &state.core;
+        assert_eq!(core.model, *b"GD  ");
+        assert_eq!((core.pc, core.af, core.bc, core.de, core.hl, core.sp), (0x0150, 0x01B0, 0x0013, 0x00D8, 0x014D, 0xFFFE));
+        assert!(core.ime && core.halted);
+        assert_eq!(core.ie, 0x05);
+        assert_eq!(core.io_regs, dmg_core().io_regs);
+        assert_eq!(state.mbc_writes, vec![(0x2000, 0x05), (0x0000, 0x0A)]);
+        assert_eq!(state.rtc, Some(&rtc[..]));
+
+        assert_eq!(state.get_region(Region::Wram), &[0x11; 0x2000]);
+        // Without color, only the first VRAM bank is listed, and no palettes
+        assert_eq!(state.get_region(Region::Vram), &[0x22; 0x2000]);
+        assert!(state.get_region(Region::BgPalettes).is_empty());
+        assert!(state.get_region(Region::CartRam).is_empty());
+    }
+
+    #[test]
+    fn missing_footer_is_refused() {
+        let mut writer = StateWriter::new();
+        write_blocks(&mut writer, &[0; INFO_SIZE], &dmg_core(), &[], None);
+        let mut data = writer.into_bytes();
+        data.pop();
+        assert!(matches!(parse(&data), Err(StateError::Invalid(_))));
+    }
+}
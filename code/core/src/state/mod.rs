@@ -0,0 +1,180 @@
+pub mod bess;
+
+use std::fmt;
+
+// Identifies the emulator specific data at the start of our own save states
+// Version 2 added the last drawn frame
+pub const NATIVE_MAGIC: &[u8; 4]    = b"GBBK";
+pub const NATIVE_VERSION: u16       = 2;
+
+/// Why a save state couldn't be loaded
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StateError {
+    /// The data ended before everything expected had been read
+    Truncated,
+    /// The data isn't a save state, or has been damaged
+    Invalid(&'static str),
+    /// The state was made with a different game than the one loaded
+    WrongGame,
+    /// The state was made on color hardware and the game is running without, or vice versa
+    WrongModel,
+    /// The state was made by a newer version of this emulator
+    UnsupportedVersion(u16),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::Truncated => { write!(f, "save state is truncated") },
+            StateError::Invalid(reason) => { write!(f, "invalid save state: {}", reason) },
+            StateError::WrongGame => { write!(f, "save state is for a different game") },
+            StateError::WrongModel => { write!(f, "save state is for a different Game Boy model") },
+            StateError::UnsupportedVersion(version) => { write!(f, "unsupported save state version {}", version) },
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// The memory regions BESS stores as raw blocks, which other emulators locate through the CORE block
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Region {
+    Wram,
+    Vram,
+    CartRam,
+    Oam,
+    Hram,
+    BgPalettes,
+    ObjPalettes,
+}
+
+impl Region {
+    pub const ALL: [Region; 7] = [
+        Region::Wram,
+        Region::Vram,
+        Region::CartRam,
+        Region::Oam,
+        Region::Hram,
+        Region::BgPalettes,
+        Region::ObjPalettes,
+    ];
+}
+
+/// Builds up a save state, with every value stored little endian
+pub struct StateWriter {
+    data: Vec<u8>,
+    // The size and offset of each region, once written
+    regions: [(u32, u32); Region::ALL.len()],
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self {
+            data: Vec::new(),
+            regions: [(0, 0); Region::ALL.len()],
+        }
+    }
+
+    pub fn write_u8(&mut self, val: u8) {
+        self.data.push(val);
+    }
+
+    pub fn write_u16(&mut self, val: u16) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, val: u32) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, val: u64) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn write_bool(&mut self, val: bool) {
+        self.data.push(val as u8);
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// Writes the raw contents of a memory region, noting where it is so the BESS blocks can point to it
+    pub fn write_region(&mut self, region: Region, bytes: &[u8]) {
+        self.regions[region as usize] = (bytes.len() as u32, self.data.len() as u32);
+        self.write_bytes(bytes);
+    }
+
+    /// Returns the size and offset of a region, which are both 0 if it wasn't written
+    pub fn get_region(&self, region: Region) -> (u32, u32) {
+        self.regions[region as usize]
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Reads values back in the order a StateWriter wrote them
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        let end = self.pos.checked_add(len).ok_or(StateError::Truncated)?;
+        let bytes = self.data.get(self.pos..end).ok_or(StateError::Truncated)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    pub fn read_array<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.read_bytes(N)?);
+        Ok(array)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    /// Returns how many bytes haven't been read yet
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+}
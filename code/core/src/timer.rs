@@ -1,3 +1,4 @@
+use crate::state::{StateError, StateReader, StateWriter};
 use crate::utils::BitOps;
 
 pub const DIV: u16      = 0xFF04;
@@ -18,6 +19,12 @@ pub struct Timer {
     tima_cooldown: u8,
 }
 
+impl Default for Timer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Timer {
     pub fn new() -> Self {
         Self {
@@ -30,6 +37,52 @@ impl Timer {
         }
     }
 
+    /// Returns the full 16-bit counter which DIV is the top half of, and which other clocks are taken from
+    pub fn get_system_counter(&self) -> u16 {
+        ((self.div as u16) << 8) | self.counter as u16
+    }
+
+    /// Sets DIV directly when restoring a save state from another emulator, as the CPU can only ever clear it
+    pub fn set_div(&mut self, div: u8) {
+        self.div = div;
+        self.counter = 0;
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.counter);
+        state.write_u8(self.div);
+        state.write_u8(self.tima);
+        state.write_u8(self.tma);
+        state.write_u8(self.tac);
+        state.write_u8(self.tima_cooldown);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.counter = state.read_u8()?;
+        self.div = state.read_u8()?;
+        self.tima = state.read_u8()?;
+        self.tma = state.read_u8()?;
+        self.tac = state.read_u8()?;
+        self.tima_cooldown = state.read_u8()?;
+        Ok(())
+    }
+
+    // Writing to DIV clears the entire internal counter, which can produce a falling edge
+    // on the bit watched by TIMA, clocking it early
+    fn reset_div(&mut self) {
+        let old_tima_bit = self.tima_status();
+        self.counter = 0;
+        self.div = 0;
+
+        if old_tima_bit && self.tac.get_bit(TAC_ENABLE_BIT) && self.tima_cooldown == 0 {
+            let (new_tima, overflow) = self.tima.overflowing_add(1);
+            self.tima = new_tima;
+            if overflow {
+                self.tima_cooldown = TIMA_COOLDOWN_OVERFLOW;
+            }
+        }
+    }
+
     pub fn tick(&mut self, m_cycles: u8) -> bool {
         let mut interrupt = false;
         let t_cycles = 4 * m_cycles;
@@ -76,7 +129,7 @@ impl Timer {
 
     pub fn write_timer(&mut self, addr: u16, val: u8) {
         match addr {
-            DIV => { self.div = 0 },
+            DIV => { self.reset_div() },
             TIMA => {
                 self.tima = val;
                 self.tima_cooldown = 0;
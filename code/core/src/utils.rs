@@ -1,6 +1,14 @@
 pub const SCREEN_WIDTH: usize = 160;
 pub const SCREEN_HEIGHT: usize = 144;
 pub const DISPLAY_BUFFER: usize = SCREEN_WIDTH * SCREEN_HEIGHT * 4;
+pub const INDEXED_BUFFER: usize = SCREEN_WIDTH * SCREEN_HEIGHT;
+
+/// T-cycles executed per second of emulated time
+pub const CLOCK_SPEED: u64 = 4_194_304;
+/// T-cycles in a single M-cycle
+pub const T_CYCLES_PER_M_CYCLE: u64 = 4;
+/// T-cycles in a full frame, VBlank included, giving about 59.73 frames per second
+pub const T_CYCLES_PER_FRAME: u64 = 70_224;
 
 pub const GB_PALETTE: [[u8; 4]; 4] = [
     [255, 255, 255, 255],
@@ -100,6 +108,16 @@ pub fn check_c_borrow_u16(lhs: u16, rhs: u16) -> bool {
     lhs.checked_sub(rhs).is_none()
 }
 
+// Converts a 15-bit BGR color, as used by the CGB and SGB, into RGBA
+pub fn rgb555_to_rgba(raw: u16) -> [u8; 4] {
+    // Scale each 5-bit channel to the full 8-bit range
+    let expand = |channel: u16| -> u8 {
+        let val = (channel & 0x1F) as u8;
+        (val << 3) | (val >> 2)
+    };
+    [expand(raw), expand(raw >> 5), expand(raw >> 10), 255]
+}
+
 pub fn unpack_u8(val: u8) -> [u8; 4] {
     let mut output = [0; 4];
     output[0] = val & 0b0000_0011;
@@ -1,3 +1,5 @@
+use crate::state::{Region, StateError, StateReader, StateWriter};
+
 pub const WRAM_START: u16       = 0xC000;
 pub const WRAM_STOP: u16        = 0xDFFF;
 pub const ECHO_START: u16       = 0xE000;
@@ -9,6 +11,12 @@ pub struct WRAM {
     wram: [u8; WRAM_SIZE],
 }
 
+impl Default for WRAM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl WRAM {
     pub fn new() -> Self {
         Self {
@@ -16,6 +24,21 @@ impl WRAM {
         }
     }
 
+    /// Copies in saved WRAM, with anything past its end ignored
+    pub fn load_ram(&mut self, data: &[u8]) {
+        let len = data.len().min(WRAM_SIZE);
+        self.wram[..len].copy_from_slice(&data[..len]);
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_region(Region::Wram, &self.wram);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.load_ram(state.read_bytes(WRAM_SIZE)?);
+        Ok(())
+    }
+
     pub fn read_u8(&self, addr: u16) -> u8 {
         match addr {
             WRAM_START..=WRAM_STOP => {
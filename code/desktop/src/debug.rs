@@ -1,95 +1,127 @@
 use std::cmp::min;
+use std::collections::VecDeque;
+use std::fs::{self, File};
 use std::io::*;
+use std::path::{Path, PathBuf};
 
 use gb_core::cpu::*;
+use gb_core::cpu::profile::Profiler;
+use gb_core::debug::disasm::disassemble;
+use gb_core::debug::tracer::TextTracer;
+use gb_core::debug::*;
+use gb_core::ioregs::{find_io_register, IoRegister, IO_REGISTERS};
+use gb_core::ppu::Layer;
 
-const OPCODE_NAMES: [&str; 0x100] = [
-    "NOP",          "LD BC, u16",   "LD (BC), A",   "INC BC",       "INC B",        "DEC B",        "LD B, u8",     "RLCA",         // $00
-    "LD (u16), SP", "ADD HL, BC",   "LD A, (BC)",   "DEC BC",       "INC C",        "DEC C",        "LD C, u8",     "RRCA",         // $08
-    "STOP",         "LD DE, u16",   "LD (DE), A",   "INC DE",       "INC D",        "DEC D",        "LD D, u8",     "RLA",          // $10
-    "JR i8",        "ADD HL, DE",   "LD A, (DE)",   "DEC DE",       "INC E",        "DEC E",        "LD E, u8",     "RRA",          // $18
-    "JR NZ, i8",    "LD HL, u16",   "LD (HL+), A",  "INC HL",       "INC H",        "DEC H",        "LD H, u8",     "DAA",          // $20
-    "JR Z, i8",     "ADD HL, HL",   "LD A, (HL+)",  "DEC HL",       "INC L",        "DEC L",        "LD L, u8",     "CPL",          // $28
-    "JR NC, i8",    "LD SP, u16",   "LD (HL-), A",  "INC SP",       "INC (HL)",     "DEC (HL)",     "LD (HL), u8",  "SCF",          // $30
-    "JR C, i8",     "ADD HL, SP",   "LD A, (HL-)",  "DEC SP",       "INC A",        "DEC A",        "LD A, u8",     "CCF",          // $38
-    "LD B, B",      "LD B, C",      "LD B, D",      "LD B, E",      "LD B, H",      "LD B, L",      "LD B, (HL)",   "LD B, A",      // $40
-    "LD C, B",      "LD C, C",      "LD C, D",      "LD C, E",      "LD C, H",      "LD C, L",      "LD C, (HL)",   "LD C, A",      // $48
-    "LD D, B",      "LD D, C",      "LD D, D",      "LD D, E",      "LD D, H",      "LD D, L",      "LD D, (HL)",   "LD D, A",      // $50
-    "LD E, B",      "LD E, C",      "LD E, D",      "LD E, E",      "LD E, H",      "LD E, L",      "LD E, (HL)",   "LD E, A",      // $58
-    "LD H, B",      "LD H, C",      "LD H, D",      "LD H, E",      "LD H, H",      "LD H, L",      "LD H, (HL)",   "LD H, A",      // $60
-    "LD L, B",      "LD L, C",      "LD L, D",      "LD L, E",      "LD L, H",      "LD L, L",      "LD L, (HL)",   "LD L, A",      // $68
-    "LD (HL), B",   "LD (HL), C",   "LD (HL), D",   "LD (HL), E",   "LD (HL), H",   "LD (HL), L",   "HALT",         "LD (HL), A",   // $70
-    "LD A, B",      "LD A, C",      "LD A, D",      "LD A, E",      "LD A, H",      "LD A, L",      "LD A, (HL)",   "LD A, A",      // $78
-    "ADD A, B",     "ADD A, C",     "ADD A, D",     "ADD A, E",     "ADD A, H",     "ADD A, L",     "ADD A, (HL)",  "ADD A, A",     // $80
-    "ADC A, B",     "ADC A, C",     "ADC A, D",     "ADC A, E",     "ADC A, H",     "ADC A, L",     "ADC A, (HL)",  "ADC A, A",     // $88
-    "SUB B",        "SUB C",        "SUB D",        "SUB E",        "SUB H",        "SUB L",        "SUB (HL)",     "SUB A",        // $90
-    "SBC B",        "SBC C",        "SBC D",        "SBC E",        "SBC H",        "SBC L",        "SBC (HL)",     "SBC A",        // $98
-    "AND B",        "AND C",        "AND D",        "AND E",        "AND H",        "AND L",        "AND (HL)",     "AND A",        // $A0
-    "XOR B",        "XOR C",        "XOR D",        "XOR E",        "XOR H",        "XOR L",        "XOR (HL)",     "XOR A",        // $A8
-    "OR B",         "OR C",         "OR D",         "OR E",         "OR H",         "OR L",         "OR (HL)",      "OR A",         // $B0
-    "CP B",         "CP C",         "CP D",         "CP E",         "CP H",         "CP L",         "CP (HL)",      "CP A",         // $B8
-    "RET NZ",       "POP BC",       "JP NZ, u16",   "JP u16",       "CALL NZ, u16", "PUSH BC",      "AND A, u8",    "RST 00",       // $C0
-    "RET Z",        "RET",          "JP Z, u16",    "PREFIX CB",    "CALL Z, u16",  "CALL u16",     "ADC A, u8",    "RST 08",       // $C8
-    "RET NC",       "POP DE",       "JP NC, u16",   "INVALID",      "CALL NC, u16", "PUSH DE",      "SUB u8",       "RST 10",       // $D0
-    "RET C",        "RETI",         "JP C, u16",    "INVALID",      "CALL C, u16",  "INVALID",      "SBC A, u8",    "RST 18",       // $D8
-    "LDH (a8), A",  "POP HL",       "LD (C), A",    "INVALID",      "INVALID",      "PUSH HL",      "AND u8",       "RST 20",       // $E0
-    "ADD SP, i8",   "JP (HL)",      "LD (u16), A",  "INVALID",      "INVALID",      "INVALID",      "XOR u8",       "RST 28",       // $E8
-    "LDH A, (a8)",  "POP AF",       "LD A, (C)",    "DI",           "INVALID",      "PUSH AF",      "OR u8",        "RST 30",       // $F0
-    "LD HL, SP+i8", "LD SP, HL",    "LD A, (u16)",  "EI",           "INVALID",      "INVALID",      "CP u8",        "RST 38"        // $F8
-];
-
-const OPCODE_LENGTH: [u8; 0x100] = [
-    1, 3, 1, 1, 1, 1, 2, 1, 3, 1, 1, 1, 1, 1, 2, 1, 2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1,
-    2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, 2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1,
-    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
-    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
-    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
-    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
-    1, 1, 3, 3, 3, 1, 2, 1, 1, 1, 3, 1, 3, 3, 2, 1, 1, 1, 3, 1, 3, 1, 2, 1, 1, 1, 3, 1, 3, 1, 2, 1,
-    2, 1, 2, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1, 2, 1, 2, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1,
-];
-
-#[derive(PartialEq, Clone, Copy)]
-enum BreakpointTypes {
-    READ,
-    WRITE,
-    EXEC,
-}
+#[cfg(feature = "scripting")]
+use crate::script::Scripts;
+
+// How many frames back 'rn' can go
+const MAX_CHECKPOINTS: usize = 8;
 
-#[derive(PartialEq)]
-struct Breakpoint {
-    addr: u16,
-    kind: BreakpointTypes,
+// The state at the start of a frame, to replay forward from when stepping backwards
+struct Checkpoint {
+    state: Vec<u8>,
+    // Buttons aren't part of save states, but need to be the same for the replay to be
+    buttons: u8,
+    // How many instructions had run when it was taken
+    executed: u64,
 }
 
-impl Breakpoint {
-    pub fn new(addr: u16, kind: BreakpointTypes) -> Self {
-        Self { addr, kind }
-    }
+// Something to print the value of whenever the game stops
+#[derive(Clone, Copy)]
+enum DisplayExpr {
+    Reg8(Regs),
+    Reg16(Regs16),
+    Pc,
+    Mem(u16),
+}
 
-    pub fn get_addr(&self) -> u16 {
-        self.addr
+impl DisplayExpr {
+    fn parse(expr: &str) -> Option<Self> {
+        if let Some(reg) = name2reg8(expr) {
+            Some(DisplayExpr::Reg8(reg))
+        } else if let Some(reg) = name2reg16(expr) {
+            Some(DisplayExpr::Reg16(reg))
+        } else if expr == "pc" {
+            Some(DisplayExpr::Pc)
+        } else {
+            parse_address(expr).map(DisplayExpr::Mem)
+        }
     }
 
-    pub fn get_type(&self) -> BreakpointTypes {
-        self.kind
+    fn format(&self, gb: &mut Cpu) -> String {
+        match *self {
+            DisplayExpr::Reg8(reg) => { format!("0x{:02x}", gb.get_r8(reg)) },
+            DisplayExpr::Reg16(reg) => { format!("0x{:04x}", gb.get_r16(reg)) },
+            DisplayExpr::Pc => { format!("0x{:04x}", gb.get_pc()) },
+            DisplayExpr::Mem(addr) => { format!("0x{:02x}", gb.read_ram(addr)) },
+        }
     }
 }
 
-pub struct Debugger {
+/*
+ * gbd
+ *
+ * A command prompt on top of the core's debugger, which reads commands from
+ * stdin whenever the game is stopped, and prints why it stopped.
+ *
+ * To step backwards, a save state is taken at the start of each frame, and
+ * every instruction since is counted. Going back one instruction loads the
+ * latest checkpoint from before it, then runs forward to just short of where
+ * the game was. The emulator is deterministic, so this ends up exactly one
+ * instruction earlier.
+ *
+ * Nothing is kept until the prompt first comes up, so games which are run
+ * without ever stopping don't pay for it.
+ */
+pub struct Gbd {
     debugging: bool,
-    breakpoints: Vec<Breakpoint>,
+    debugger: Debugger,
+    // Each display as it was typed, so it can be shown and saved the same way
+    displays: Vec<(String, DisplayExpr)>,
+    profiler: Option<Profiler>,
+    checkpoints: VecDeque<Checkpoint>,
+    // Set once the prompt has been shown, from when checkpoints start being taken
+    keep_history: bool,
+    executed: u64,
+    // Where breakpoints are saved whenever they change, once they've been loaded
+    save_path: Option<PathBuf>,
+    #[cfg(feature = "scripting")]
+    scripts: Scripts,
 }
 
-impl Debugger {
+impl Gbd {
     pub fn new() -> Self {
         Self {
             debugging: false,
-            breakpoints: Vec::new(),
+            debugger: Debugger::new(),
+            displays: Vec::new(),
+            profiler: None,
+            checkpoints: VecDeque::new(),
+            keep_history: false,
+            executed: 0,
+            save_path: None,
+            #[cfg(feature = "scripting")]
+            scripts: Scripts::new(),
         }
     }
 
+    /// Lets breakpoints see the game's reads and writes, which must be done once the game is created
+    pub fn attach(&mut self, gb: &mut Cpu) {
+        self.debugger.attach(gb);
+    }
+
+    /// Stops watching the game's reads and writes, for when the game is run without checking in here
+    #[cfg(feature = "gdb")]
+    pub fn detach(&mut self, gb: &mut Cpu) {
+        self.debugger.detach(gb);
+    }
+
     pub fn debugloop(&mut self, gb: &mut Cpu) -> bool {
+        // Whatever brought us back here, any earlier step command is finished with
+        self.debugger.set_stop_condition(None);
+        self.keep_history = true;
+        self.print_displays(gb);
         loop {
             print!("(gbd) ");
             stdout().flush().unwrap();
@@ -103,7 +135,8 @@ impl Debugger {
             match words[0] {
                 "b" => {
                     let addr = parse_address(words[1]);
-                    self.add_breakpoint(addr, BreakpointTypes::EXEC);
+                    self.add_breakpoint(addr, BreakpointKind::Exec);
+                    self.save_breakpoints();
                 },
                 "c" => {
                     self.debugging = false;
@@ -112,37 +145,128 @@ impl Debugger {
                 "d" => {
                     let addr = parse_address(words[1]);
                     self.remove_breakpoint(addr);
+                    self.save_breakpoints();
                 },
                 "disass" => {
                     self.disassemble(gb);
                 },
+                "display" => {
+                    match words.get(1) {
+                        Some(expr) => {
+                            self.add_display(gb, expr);
+                            self.save_breakpoints();
+                        },
+                        None => { self.print_displays(gb) },
+                    }
+                },
                 "h" => {
                     self.print_help();
                 },
+                "info" => {
+                    println!("{}", gb.cart_info());
+                },
+                "int" => {
+                    self.toggle_irq_breakpoint(words.get(1).copied());
+                    self.save_breakpoints();
+                },
+                "io" => {
+                    self.print_io(gb, words.get(1).copied());
+                },
                 "l" => {
                     self.print_breakpoints();
                 },
+                "layer" => {
+                    self.toggle_layer(gb, words.get(1).copied());
+                },
+                "dump" => {
+                    self.dump_memory(gb, &words[1..]);
+                },
+                "fin" => {
+                    self.debugger.set_stop_condition(Some(StopCondition::finish(gb)));
+                    self.debugging = false;
+                    return false;
+                },
                 "n" => {
-                    gb.tick();
-                    println!("PC: 0x{:04x}", gb.get_pc());
+                    let count = words.get(1).and_then(|count| count.parse::<u32>().ok()).unwrap_or(1);
+                    if count > 1 {
+                        self.debugger.set_stop_condition(Some(StopCondition::Steps(count)));
+                        self.debugging = false;
+                        return false;
+                    }
+                    self.step(gb);
+                },
+                "oam" => {
+                    self.print_sprites(gb);
                 },
                 "p" => {
                     let addr = parse_address(words[1]);
                     self.print_ram(gb, addr);
                 },
+                "prof" => {
+                    self.profile(gb, words.get(1).copied());
+                },
                 "q" => {
+                    // Dropping the tracer flushes anything it hasn't written yet
+                    gb.set_tracer(None);
                     return true;
                 },
                 "r" => {
                     let addr = parse_address(words[1]);
-                    self.add_breakpoint(addr, BreakpointTypes::READ);
+                    self.add_breakpoint(addr, BreakpointKind::Read);
+                    self.save_breakpoints();
                 },
                 "reg" => {
-                    self.print_registers(&gb);
+                    self.print_registers(gb);
+                },
+                "rn" => {
+                    self.reverse_step(gb);
+                },
+                "script" => {
+                    self.run_script(gb, words.get(1).copied());
+                },
+                "set" => {
+                    self.set_value(gb, words.get(1).copied(), words.get(2).copied());
+                },
+                "so" => {
+                    if let Some(stop) = StopCondition::step_over(gb) {
+                        self.debugger.set_stop_condition(Some(stop));
+                        self.debugging = false;
+                        return false;
+                    }
+                    self.step(gb);
+                },
+                "trace" => {
+                    self.set_trace(gb, words.get(1).copied(), words.get(2).copied());
+                },
+                "u" => {
+                    match words.get(1).and_then(|addr| parse_address(addr)) {
+                        Some(addr) => {
+                            self.debugger.set_stop_condition(Some(StopCondition::Address(addr)));
+                            self.debugging = false;
+                            return false;
+                        },
+                        None => { println!("Expected 'u XXXX'") },
+                    }
+                },
+                "undisplay" => {
+                    match words.get(1).and_then(|idx| idx.parse::<usize>().ok()) {
+                        Some(idx) if idx >= 1 && idx <= self.displays.len() => {
+                            self.displays.remove(idx - 1);
+                            self.save_breakpoints();
+                        },
+                        _ => { println!("Expected 'undisplay N', where N is one of the numbers shown by 'display'") },
+                    }
                 },
                 "w" => {
                     let addr = parse_address(words[1]);
-                    self.add_breakpoint(addr, BreakpointTypes::WRITE);
+                    if words.len() <= 2 {
+                        self.add_breakpoint(addr, BreakpointKind::Write);
+                    } else if let Some(condition) = WriteCondition::parse(words[2], words.get(3).unwrap_or(&"")) {
+                        self.add_watchpoint(addr, condition);
+                    } else {
+                        println!("Expected 'w XXXX == XX' or 'w XXXX != XX'");
+                    }
+                    self.save_breakpoints();
                 },
                 _ => {
                     println!("Unknown command");
@@ -151,55 +275,234 @@ impl Debugger {
         }
     }
 
-    fn add_breakpoint(&mut self, bp: Option<u16>, kind: BreakpointTypes) {
+    fn add_display(&mut self, gb: &mut Cpu, expr: &str) {
+        let expr = expr.to_lowercase();
+        let Some(display) = DisplayExpr::parse(&expr) else {
+            println!("Expected a register name or an address");
+            return;
+        };
+        if !self.displays.iter().any(|(existing, _)| *existing == expr) {
+            self.displays.push((expr.clone(), display));
+        }
+        println!("{} = {}", expr, display.format(gb));
+    }
+
+    fn add_breakpoint(&mut self, bp: Option<u16>, kind: BreakpointKind) {
         if let Some(addr) = bp {
-            let breakpoint = Breakpoint::new(addr, kind);
-            if !self.breakpoints.contains(&breakpoint) {
-                self.breakpoints.push(breakpoint);
-            }
+            self.debugger.add_breakpoint(addr, kind);
         }
     }
 
-    pub fn check_exec_breakpoints(&mut self, pc: u16) {
-        for bp in &self.breakpoints {
-            if bp.get_addr() == pc && bp.get_type() == BreakpointTypes::EXEC {
-                self.debugging = true;
-                break;
-            }
+    fn add_watchpoint(&mut self, bp: Option<u16>, condition: WriteCondition) {
+        if let Some(addr) = bp {
+            self.debugger.add_watchpoint(addr, condition);
         }
     }
 
-    pub fn check_read_breakpoints(&mut self, addr: u16) {
-        for bp in &self.breakpoints {
-            if bp.get_addr() == addr && bp.get_type() == BreakpointTypes::READ {
-                self.debugging = true;
-                break;
+    /// Restores the breakpoints and displays saved in the given file, which they'll be saved back to as they change
+    pub fn load_breakpoints(&mut self, path: &Path) {
+        if let Ok(saved) = fs::read_to_string(path) {
+            for line in saved.lines().map(str::trim) {
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                // Saved with the same syntax as the commands which set them
+                let words: Vec<&str> = line.split_whitespace().collect();
+                match words.as_slice() {
+                    ["b", addr] => { self.add_breakpoint(parse_address(addr), BreakpointKind::Exec) },
+                    ["r", addr] => { self.add_breakpoint(parse_address(addr), BreakpointKind::Read) },
+                    ["w", addr] => { self.add_breakpoint(parse_address(addr), BreakpointKind::Write) },
+                    ["w", addr, op, val] => {
+                        if let Some(condition) = WriteCondition::parse(op, val) {
+                            self.add_watchpoint(parse_address(addr), condition);
+                        }
+                    },
+                    ["display", expr] => {
+                        if let Some(display) = DisplayExpr::parse(expr) {
+                            self.displays.push((expr.to_string(), display));
+                        }
+                    },
+                    ["int", name] => {
+                        if let Some(irq) = name2irq(name) {
+                            self.debugger.add_irq_breakpoint(irq);
+                        }
+                    },
+                    _ => { println!("Ignoring unknown breakpoint in {}: {}", path.display(), line) },
+                }
             }
+            let count = self.debugger.get_breakpoints().len() + self.debugger.get_irq_breakpoints().len();
+            println!("Loaded {} breakpoints from {}", count, path.display());
         }
+        self.save_path = Some(path.to_path_buf());
     }
 
-    pub fn check_write_breakpoints(&mut self, addr: u16) {
-        for bp in &self.breakpoints {
-            if bp.get_addr() == addr && bp.get_type() == BreakpointTypes::WRITE {
-                self.debugging = true;
-                break;
+    fn save_breakpoints(&self) {
+        let Some(path) = &self.save_path else {
+            return;
+        };
+        let mut saved = String::new();
+        for bp in self.debugger.get_breakpoints() {
+            let cmd = match bp.get_type() {
+                BreakpointKind::Exec => { "b" },
+                BreakpointKind::Read => { "r" },
+                BreakpointKind::Write => { "w" },
+            };
+            saved.push_str(&format!("{} {:04x}", cmd, bp.get_addr()));
+            match bp.get_condition() {
+                Some(WriteCondition::Equal(val)) => { saved.push_str(&format!(" == {:02x}", val)) },
+                Some(WriteCondition::NotEqual(val)) => { saved.push_str(&format!(" != {:02x}", val)) },
+                None => {},
             }
+            saved.push('\n');
+        }
+        for irq in self.debugger.get_irq_breakpoints() {
+            let name = format!("{:?}", irq).to_lowercase();
+            saved.push_str(&format!("int {}\n", name));
+        }
+        for (expr, _) in &self.displays {
+            saved.push_str(&format!("display {}\n", expr));
+        }
+
+        // Don't leave an empty file behind once every breakpoint is gone
+        let result = if saved.is_empty() {
+            fs::remove_file(path).or_else(|err| if err.kind() == ErrorKind::NotFound { Ok(()) } else { Err(err) })
+        } else {
+            fs::write(path, saved)
+        };
+        if let Err(err) = result {
+            println!("Unable to save breakpoints to {}: {}", path.display(), err);
+        }
+    }
+
+    /// Called after every instruction, stopping at any breakpoints, as well as profiling and running script hooks
+    pub fn check(&mut self, gb: &mut Cpu, info: &StepInfo) {
+        self.executed += 1;
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(gb, info);
+        }
+        #[cfg(feature = "scripting")]
+        self.check_script_hooks(gb, info);
+
+        let Some(reason) = self.debugger.check(gb, info) else {
+            return;
+        };
+        match reason {
+            BreakReason::Stopped(pc) => { println!("PC: 0x{:04x}", pc) },
+            BreakReason::Interrupt(irq) => { println!("Jumped to {:?} interrupt", irq) },
+            BreakReason::Write(write) => {
+                println!("0x{:04x} changed from 0x{:02x} to 0x{:02x}", write.addr, write.old, write.val);
+            },
+            BreakReason::Exec(_) | BreakReason::Read(_) => {},
+        }
+        self.debugging = true;
+    }
+
+    #[cfg(feature = "scripting")]
+    fn check_script_hooks(&mut self, gb: &mut Cpu, info: &StepInfo) {
+        let pc = gb.get_pc();
+        if self.scripts.has_break_hook(pc) && self.scripts.run_break_hooks(gb, pc) {
+            self.debugging = true;
+        }
+        if info.frame_ready && self.scripts.run_frame_hooks(gb) {
+            self.debugging = true;
         }
     }
 
     fn disassemble(&self, gb: &mut Cpu) {
         let mut pc = gb.get_pc();
         for _ in 0..5 {
-            let op = gb.read_ram(pc) as usize;
-            let name = OPCODE_NAMES[op];
-            let len = OPCODE_LENGTH[op] as u16;
-            let mut printout = format!("0x{:04x} | {} |", pc, name);
-            for i in 0..len {
+            let instr = disassemble(gb, pc);
+            let mut printout = format!("0x{:04x} | {} |", pc, instr.name);
+            for i in 0..instr.len {
                 let arg = gb.read_ram(pc + i);
                 printout = format!("{} {:02x}", printout, arg);
             }
             println!("{}", printout);
-            pc += len;
+            pc += instr.len;
+        }
+    }
+
+    fn step(&mut self, gb: &mut Cpu) {
+        let start = gb.elapsed_cycles();
+        let info = gb.step();
+        self.executed += 1;
+        if let Some(irq) = info.interrupt {
+            println!("Jumped to {:?} interrupt", irq);
+        }
+        println!("PC: 0x{:04x} ({} cycles)", info.pc_after, gb.cycles_since(start));
+        self.print_displays(gb);
+    }
+
+    /// Called at the start of every frame, so it can be stepped back into once the debugger has been used
+    pub fn checkpoint(&mut self, gb: &Cpu) {
+        if !self.keep_history {
+            return;
+        }
+        if self.checkpoints.len() == MAX_CHECKPOINTS {
+            self.checkpoints.pop_front();
+        }
+        self.checkpoints.push_back(Checkpoint {
+            state: gb.save_state(),
+            buttons: gb.get_buttons(),
+            executed: self.executed,
+        });
+    }
+
+    /// Called whenever a state is loaded from elsewhere, which the history no longer leads up to
+    pub fn forget_history(&mut self) {
+        self.checkpoints.clear();
+    }
+
+    fn reverse_step(&mut self, gb: &mut Cpu) {
+        let checkpoint = self.executed.checked_sub(1).and_then(|target| {
+            self.checkpoints.iter().rev().find(|cp| cp.executed <= target)
+        });
+        let Some(checkpoint) = checkpoint else {
+            println!("Can't step back any further");
+            return;
+        };
+
+        // As with movies, the buttons go first so the state can undo any interrupt they request
+        gb.set_buttons(checkpoint.buttons);
+        if let Err(err) = gb.load_state(&checkpoint.state) {
+            println!("Unable to step back: {}", err);
+            return;
+        }
+        let target = self.executed - 1;
+        for _ in checkpoint.executed..target {
+            gb.step();
+        }
+        self.executed = target;
+        // Anything taken after this point is in a future which may no longer happen
+        self.checkpoints.retain(|cp| cp.executed <= target);
+        println!("PC: 0x{:04x}", gb.get_pc());
+        self.print_displays(gb);
+    }
+
+    // Writes an inclusive address range, or a named region, to a file as it's currently mapped
+    fn dump_memory(&self, gb: &Cpu, args: &[&str]) {
+        let (range, filename) = match *args {
+            [region, filename] => { (region2range(region), filename) },
+            [start, end, filename] => {
+                match (parse_address(start), parse_address(end)) {
+                    (Some(start), Some(end)) if start <= end => { (Some((start, end)), filename) },
+                    _ => { (None, filename) },
+                }
+            },
+            _ => {
+                println!("Expected 'dump XXXX YYYY <file>' or 'dump vram|wram|oam|hram|sram <file>'");
+                return;
+            }
+        };
+        let Some((start, end)) = range else {
+            println!("Expected a region name, or a start address no later than the end");
+            return;
+        };
+
+        let data: Vec<u8> = (start..=end).map(|addr| gb.read_ram(addr)).collect();
+        match File::create(filename).and_then(|mut file| file.write_all(&data)) {
+            Ok(()) => { println!("Wrote 0x{:04x}-0x{:04x} to {}", start, end, filename) },
+            Err(err) => { println!("Unable to write {}: {}", filename, err) },
         }
     }
 
@@ -208,28 +511,63 @@ impl Debugger {
     }
 
     fn print_breakpoints(&self) {
-        if self.breakpoints.is_empty() {
+        let irq_breakpoints = self.debugger.get_irq_breakpoints();
+        if !irq_breakpoints.is_empty() {
+            let names: Vec<String> = irq_breakpoints.iter().map(|irq| format!("{:?}", irq)).collect();
+            println!("Interrupts: {}", names.join(", "));
+        }
+        let breakpoints = self.debugger.get_breakpoints();
+        if breakpoints.is_empty() {
             println!("There are no set breakpoints");
             return;
         }
         let mut output = "Breakpoints:".to_string();
-        for bp in &self.breakpoints {
-            output = format!("{} 0x{:04x}", output, bp.get_addr());
+        for bp in breakpoints {
+            output = match bp.get_condition() {
+                Some(WriteCondition::Equal(val)) => { format!("{} 0x{:04x}==0x{:02x}", output, bp.get_addr(), val) },
+                Some(WriteCondition::NotEqual(val)) => { format!("{} 0x{:04x}!=0x{:02x}", output, bp.get_addr(), val) },
+                None => { format!("{} 0x{:04x}", output, bp.get_addr()) },
+            };
         }
         println!("{}", output);
     }
 
+    fn print_displays(&self, gb: &mut Cpu) {
+        for (i, (expr, display)) in self.displays.iter().enumerate() {
+            println!("{}: {} = {}", i + 1, expr, display.format(gb));
+        }
+    }
+
     fn print_help(&self) {
         let help = "'b XXXX' to add a breakpoint at that address\n\
                     'c' to continue execution\n\
                     'd XXXX' to delete breakpoint at that address\n\
                     'disass' to show disassembly of next 5 instructions\n\
+                    'display <register>|XXXX' to print that value every time the game stops, or 'display' to print them all now\n\
+                    'dump XXXX YYYY <file>' or 'dump vram|wram|oam|hram|sram <file>' to save memory to a file\n\
+                    'fin' to run until the current routine returns\n\
                     'h' to print this message\n\
+                    'info' to print the cartridge header details\n\
+                    'int vblank|stat|timer|serial|joypad' to break whenever that interrupt is dispatched, or stop if already set\n\
+                    'io' to print every IO register decoded into its fields, or 'io XXXX' for just one\n\
                     'l' to print list of breakpoints\n\
-                    'n' to execute the next instruction\n\
+                    'layer bg|win|obj' to hide or show a layer of the screen\n\
+                    'n' to execute the next instruction, or 'n N' to execute the next N\n\
+                    'oam' to list the sprites in OAM\n\
                     'p XXXX' to print 16 bytes at that address\n\
+                    'prof on|off' to start or stop profiling, or 'prof [N]' to print the N instructions taking the most cycles\n\
                     'q' to quit debugging\n\
-                    'reg' to print register contents\n";
+                    'r XXXX' to break when that address is read\n\
+                    'reg' to print register contents\n\
+                    'rn' to step back to before the previous instruction\n\
+                    'script <file>' to run a Rhai script, or 'script clear' to remove the hooks scripts have added\n\
+                    'set XXXX XX' to write a byte to memory, or 'set <register> XXXX' to change a register\n\
+                    'so' to step over the next instruction, running any routine it calls\n\
+                    'trace on <file>' to log every instruction to a file, until 'trace off'\n\
+                    'u XXXX' to run until that address, without adding a breakpoint\n\
+                    'undisplay N' to stop printing that display\n\
+                    'w XXXX' to break when that address is written\n\
+                    'w XXXX == XX' or 'w XXXX != XX' to only break on writes of, or other than, that value\n";
         println!("{}", help);
     }
 
@@ -251,41 +589,222 @@ impl Debugger {
 
     }
 
+    fn print_sprites(&self, gb: &Cpu) {
+        for (i, spr) in gb.sprites().iter().enumerate() {
+            let (x, y) = spr.get_coords();
+            let mut flags = String::new();
+            if spr.get_bg_priority() { flags.push_str(" behind"); }
+            if spr.is_x_flipped() { flags.push_str(" xflip"); }
+            if spr.is_y_flipped() { flags.push_str(" yflip"); }
+            if spr.use_palette1() { flags.push_str(" obp1"); }
+            println!("{:2}: ({:4}, {:4}) tile 0x{:02x}{}", i, x, y, spr.get_tile_num(), flags);
+        }
+    }
+
+    fn print_io(&self, gb: &Cpu, addr: Option<&str>) {
+        let regs: Vec<&IoRegister> = match addr {
+            Some(addr) => {
+                let Some(addr) = parse_address(addr) else {
+                    println!("Expected 'io' or 'io XXXX'");
+                    return;
+                };
+                let Some(reg) = find_io_register(addr) else {
+                    println!("There's no IO register at 0x{:04x}", addr);
+                    return;
+                };
+                vec![reg]
+            },
+            None => { IO_REGISTERS.iter().collect() },
+        };
+        for reg in regs {
+            let val = gb.read_ram(reg.addr);
+            println!("0x{:04x} {:<5} 0x{:02x} {}", reg.addr, reg.name, val, reg.decode(val));
+        }
+    }
+
+    fn print_profile(&self, gb: &Cpu, count: usize) {
+        let Some(profiler) = &self.profiler else {
+            println!("Not profiling, start with 'prof on'");
+            return;
+        };
+        let total = profiler.total_cycles().max(1) as f64;
+        let percent = |cycles: u64| 100.0 * cycles as f64 / total;
+        println!("{} M-cycles profiled, {:.1}% halted", profiler.total_cycles(), percent(profiler.halted_cycles()));
+        for spot in profiler.hot_spots(count) {
+            // The instruction can only be named if its bank is still the one mapped in
+            let name = if spot.pc > 0x7FFF || gb.get_rom_bank(spot.pc) == spot.bank {
+                disassemble(gb, spot.pc).name
+            } else {
+                ""
+            };
+            println!("{:02x}:{:04x} {:>12} {:5.1}% {}", spot.bank, spot.pc, spot.cycles, percent(spot.cycles), name);
+        }
+        for (irq, cycles) in profiler.irq_cycles() {
+            if cycles > 0 {
+                println!("{:?} handler: {} ({:.1}%)", irq, cycles, percent(cycles));
+            }
+        }
+    }
+
     fn print_registers(&self, gb: &Cpu) {
-        let mut output = format!("PC: 0x{:04x}\n", gb.get_pc());
-        output = format!("{}SP: 0x{:04x}\n", output, gb.get_r16(Regs16::SP));
-        output = format!("{}AF: 0x{:04x}\n", output, gb.get_r16(Regs16::AF));
-        output = format!("{}BC: 0x{:04x}\n", output, gb.get_r16(Regs16::BC));
-        output = format!("{}DE: 0x{:04x}\n", output, gb.get_r16(Regs16::DE));
-        output = format!("{}HL: 0x{:04x}\n", output, gb.get_r16(Regs16::HL));
+        let regs = gb.registers();
+        let mut output = format!("PC: 0x{:04x}\n", regs.pc);
+        output = format!("{}SP: 0x{:04x}\n", output, regs.sp);
+        output = format!("{}AF: 0x{:04x}\n", output, regs.af);
+        output = format!("{}BC: 0x{:04x}\n", output, regs.bc);
+        output = format!("{}DE: 0x{:04x}\n", output, regs.de);
+        output = format!("{}HL: 0x{:04x}\n", output, regs.hl);
+        output = format!("{}IME: {}, halted: {}\n", output, regs.ime, regs.halted);
         println!("{}", output);
     }
 
+    fn profile(&mut self, gb: &Cpu, arg: Option<&str>) {
+        match arg {
+            Some("on") => {
+                self.profiler = Some(Profiler::new());
+                println!("Profiling started");
+            },
+            Some("off") => {
+                self.profiler = None;
+                println!("Profiling stopped");
+            },
+            _ => {
+                match arg.map(|count| count.parse::<usize>()) {
+                    None => { self.print_profile(gb, 10) },
+                    Some(Ok(count)) => { self.print_profile(gb, count) },
+                    Some(Err(_)) => { println!("Expected 'prof on', 'prof off', or 'prof N'") },
+                }
+            },
+        }
+    }
+
     fn remove_breakpoint(&mut self, bp: Option<u16>) {
         if let Some(addr) = bp {
-            for i in 0..self.breakpoints.len() {
-                if self.breakpoints[i].get_addr() == addr {
-                    self.breakpoints.remove(i);
-                    break;
-                }
+            self.debugger.remove_breakpoint(addr);
+        }
+    }
+
+    // Sets a register by name, or otherwise writes a byte to memory as the CPU would
+    fn set_value(&self, gb: &mut Cpu, target: Option<&str>, val: Option<&str>) {
+        let (Some(target), Some(val)) = (target, val) else {
+            println!("Expected 'set XXXX XX' or 'set <register> XXXX'");
+            return;
+        };
+        let target = target.to_lowercase();
+        let reg8 = name2reg8(&target);
+        let reg16 = name2reg16(&target);
+
+        if let Some(reg) = reg8 {
+            match u8::from_str_radix(val, 16) {
+                Ok(val) => { gb.set_r8(reg, val) },
+                Err(_) => { println!("Expected an 8-bit hex value") },
             }
+        } else if reg16.is_some() || target == "pc" {
+            let Some(val) = parse_address(val) else {
+                println!("Expected a 16-bit hex value");
+                return;
+            };
+            match reg16 {
+                Some(reg) => { gb.set_r16(reg, val) },
+                None => { gb.set_pc(val) },
+            }
+        } else if let Some(addr) = parse_address(&target) {
+            match u8::from_str_radix(val, 16) {
+                Ok(val) => { gb.write_ram(addr, val) },
+                Err(_) => { println!("Expected an 8-bit hex value") },
+            }
+        } else {
+            println!("Unknown register or address {}", target);
+        }
+    }
+
+    #[cfg(feature = "scripting")]
+    fn run_script(&mut self, gb: &mut Cpu, filename: Option<&str>) {
+        match filename {
+            Some("clear") => {
+                self.scripts.clear_hooks();
+                println!("Removed all script hooks");
+            },
+            Some(filename) => {
+                if let Err(err) = self.scripts.run_file(gb, filename) {
+                    println!("Script error: {}", err);
+                }
+            },
+            None => { println!("Expected 'script <file>' or 'script clear'") },
         }
     }
 
+    #[cfg(not(feature = "scripting"))]
+    fn run_script(&mut self, _gb: &mut Cpu, _filename: Option<&str>) {
+        println!("Scripting isn't available, rebuild with '--features scripting'");
+    }
+
+    fn set_trace(&self, gb: &mut Cpu, mode: Option<&str>, filename: Option<&str>) {
+        match (mode, filename) {
+            (Some("on"), Some(filename)) => {
+                match File::create(filename) {
+                    Ok(file) => {
+                        gb.set_tracer(Some(Box::new(TextTracer::new(BufWriter::new(file)))));
+                        println!("Tracing instructions to {}", filename);
+                    },
+                    Err(err) => { println!("Unable to open {}: {}", filename, err) },
+                }
+            },
+            (Some("off"), _) => {
+                gb.set_tracer(None);
+                println!("Tracing stopped");
+            },
+            _ => { println!("Expected 'trace on <file>' or 'trace off'") },
+        }
+    }
+
+    fn toggle_irq_breakpoint(&mut self, name: Option<&str>) {
+        let Some(irq) = name.and_then(name2irq) else {
+            println!("Expected one of vblank, stat, timer, serial, or joypad");
+            return;
+        };
+        if self.debugger.toggle_irq_breakpoint(irq) {
+            println!("Breaking whenever the {:?} interrupt is dispatched", irq);
+        } else {
+            println!("No longer breaking on the {:?} interrupt", irq);
+        }
+    }
+
+    fn toggle_layer(&self, gb: &mut Cpu, name: Option<&str>) {
+        let layer = match name {
+            Some("bg") => { Layer::Background },
+            Some("win") => { Layer::Window },
+            Some("obj") => { Layer::Sprites },
+            _ => {
+                println!("Expected one of bg, win, or obj");
+                return;
+            }
+        };
+        let enabled = !gb.is_layer_enabled(layer);
+        gb.set_layer_enabled(layer, enabled);
+        println!("The {} layer is now {}", layer.get_name(), if enabled { "shown" } else { "hidden" });
+    }
+
     pub fn set_debugging(&mut self, debug: bool) {
         self.debugging = debug;
     }
 }
 
-fn parse_address(input: &str) -> Option<u16> {
-    let hex = u16::from_str_radix(input, 16);
-    if let Ok(addr) = hex {
-        Some(addr)
-    } else {
-        None
+fn region2range(name: &str) -> Option<(u16, u16)> {
+    match name {
+        "vram" => { Some((0x8000, 0x9FFF)) },
+        "sram" => { Some((0xA000, 0xBFFF)) },
+        "wram" => { Some((0xC000, 0xDFFF)) },
+        "oam" =>  { Some((0xFE00, 0xFE9F)) },
+        "hram" => { Some((0xFF80, 0xFFFE)) },
+        _ =>      { None                   }
     }
 }
 
+fn parse_address(input: &str) -> Option<u16> {
+    u16::from_str_radix(input, 16).ok()
+}
+
 fn trim_newline(s: &mut String) {
     if s.ends_with('\n') {
         s.pop();
@@ -0,0 +1,81 @@
+use gb_core::utils::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::{BlendMode, Canvas};
+use sdl2::video::Window;
+
+/*
+ * Display Filters
+ *
+ * Drawn over the scaled up screen to mimic how it looked on real hardware.
+ * Rather than shaders, each is a set of translucent black lines along the
+ * edges of the Game Boy's pixels, which SDL can draw in one go:
+ *
+ * Scanlines    A line under each row, like a CRT television
+ * LCD Grid     Lines between every row and column, like the gaps between
+ *              the dots of the Game Boy's own screen
+ *
+ * The lines need the screen to be at least twice its size, otherwise they'd
+ * cover up the pixels entirely.
+ */
+
+// How dark the lines are drawn, from 0 for invisible to 255 for solid black
+const SCANLINE_ALPHA: u8 = 96;
+const GRID_ALPHA: u8     = 64;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Filter {
+    None,
+    Scanlines,
+    LcdGrid,
+}
+
+impl Filter {
+    pub const ALL: [Filter; 3] = [Filter::None, Filter::Scanlines, Filter::LcdGrid];
+
+    pub fn get_name(&self) -> &'static str {
+        match *self {
+            Filter::None =>      { "No" },
+            Filter::Scanlines => { "Scanline" },
+            Filter::LcdGrid =>   { "LCD grid" },
+        }
+    }
+
+    /// Returns the filter after this one, wrapping back around to none
+    pub fn next(&self) -> Filter {
+        let idx = Filter::ALL.iter().position(|filter| filter == self).unwrap();
+        Filter::ALL[(idx + 1) % Filter::ALL.len()]
+    }
+
+    /// Draws the filter over the screen, which has already been drawn to the given area
+    pub fn apply(&self, canvas: &mut Canvas<Window>, screen: Rect) {
+        let scale = screen.height() as f32 / SCREEN_HEIGHT as f32;
+        if *self == Filter::None || scale < 2.0 {
+            return;
+        }
+        // Lines grow with the screen, staying about a third of a pixel wide
+        let thickness = (scale / 3.0).max(1.0) as u32;
+        let mut lines = Vec::new();
+        for row in 1..=SCREEN_HEIGHT {
+            let bottom = screen.y() + (row as f32 * scale) as i32;
+            lines.push(Rect::new(screen.x(), bottom - thickness as i32, screen.width(), thickness));
+        }
+        let alpha = if *self == Filter::LcdGrid {
+            for col in 1..=SCREEN_WIDTH {
+                let right = screen.x() + (col as f32 * scale) as i32;
+                lines.push(Rect::new(right - thickness as i32, screen.y(), thickness, screen.height()));
+            }
+            GRID_ALPHA
+        } else {
+            SCANLINE_ALPHA
+        };
+
+        canvas.set_blend_mode(BlendMode::Blend);
+        canvas.set_draw_color(Color::RGBA(0, 0, 0, alpha));
+        canvas.fill_rects(&lines).unwrap();
+        // Clearing the canvas uses the draw color too, which needs to go back to opaque black for the borders
+        canvas.set_blend_mode(BlendMode::None);
+        canvas.set_draw_color(Color::BLACK);
+    }
+}
@@ -0,0 +1,411 @@
+use crate::draw_screen;
+use crate::filter::Filter;
+use crate::keymap::{Hotkey, KeyMap};
+use crate::limiter::FrameLimiter;
+use crate::signals::stop_requested;
+
+use gb_core::cpu::Cpu;
+use gb_core::hooks::{Access, AccessLog, MemAccess};
+
+use gdbstub::arch::{Arch, Registers};
+use gdbstub::common::Signal;
+use gdbstub::conn::{Connection, ConnectionExt};
+use gdbstub::stub::run_blocking::{self, BlockingEventLoop};
+use gdbstub::stub::{DisconnectReason, GdbStub, SingleThreadStopReason};
+use gdbstub::target::ext::base::singlethread::{SingleThreadBase, SingleThreadResume, SingleThreadSingleStep};
+use gdbstub::target::ext::base::BaseOps;
+use gdbstub::target::ext::breakpoints::{Breakpoints, HwWatchpoint, SwBreakpoint, WatchKind};
+use gdbstub::target::{Target, TargetResult};
+
+use sdl2::event::Event;
+use sdl2::render::{Canvas, Texture};
+use sdl2::video::Window;
+use sdl2::EventPump;
+
+use std::marker::PhantomData;
+use std::net::{TcpListener, TcpStream};
+
+// GDB has no SM83 of its own, but knows the Z80 it was derived from
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>z80</architecture>
+  <feature name="org.gnu.gdb.z80.cpu">
+    <reg name="af" bitsize="16" type="int"/>
+    <reg name="bc" bitsize="16" type="int"/>
+    <reg name="de" bitsize="16" type="int"/>
+    <reg name="hl" bitsize="16" type="int"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>"#;
+
+const NUM_REGS: usize = 6;
+
+/*
+ * GDB Remote Serial Protocol
+ *
+ * Lets GDB, LLDB, or an IDE debugger attach over TCP, as an alternative to
+ * the built in debugger. Once attached, the debugger is in charge, with the
+ * game running a frame at a time while it's resumed so the screen and
+ * buttons keep working, and the connection checked between frames in case
+ * the user wants to interrupt it.
+ *
+ * The registers are sent as the six 16-bit pairs, little endian, in the
+ * order AF, BC, DE, HL, SP, PC.
+ */
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct Sm83Regs {
+    af: u16,
+    bc: u16,
+    de: u16,
+    hl: u16,
+    sp: u16,
+    pc: u16,
+}
+
+impl Registers for Sm83Regs {
+    type ProgramCounter = u16;
+
+    fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    fn gdb_serialize(&self, mut write_byte: impl FnMut(Option<u8>)) {
+        for reg in [self.af, self.bc, self.de, self.hl, self.sp, self.pc] {
+            for byte in reg.to_le_bytes() {
+                write_byte(Some(byte));
+            }
+        }
+    }
+
+    fn gdb_deserialize(&mut self, bytes: &[u8]) -> Result<(), ()> {
+        if bytes.len() < NUM_REGS * 2 {
+            return Err(());
+        }
+        let mut regs = bytes.chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]]));
+        for reg in [&mut self.af, &mut self.bc, &mut self.de, &mut self.hl, &mut self.sp, &mut self.pc] {
+            *reg = regs.next().ok_or(())?;
+        }
+        Ok(())
+    }
+}
+
+pub enum Sm83 {}
+
+impl Arch for Sm83 {
+    type Usize = u16;
+    type Registers = Sm83Regs;
+    type BreakpointKind = ();
+    type RegId = ();
+
+    fn target_description_xml() -> Option<&'static str> {
+        Some(TARGET_XML)
+    }
+}
+
+#[derive(PartialEq)]
+enum ExecMode {
+    Step,
+    Continue,
+}
+
+// Why the game stopped running on its own
+enum StopEvent {
+    DoneStep,
+    Breakpoint,
+    Watch(WatchKind, u16),
+    Locked,
+    Quit,
+}
+
+struct GbTarget<'a, 't> {
+    gb: &'a mut Cpu,
+    canvas: &'a mut Canvas<Window>,
+    texture: &'a mut Texture<'t>,
+    events: &'a mut EventPump,
+    keys: &'a KeyMap,
+    limiter: FrameLimiter,
+    mode: ExecMode,
+    breakpoints: Vec<u16>,
+    // The start and length of each watched range, and what kind of access it watches for
+    watchpoints: Vec<(u16, u16, WatchKind)>,
+    // Every access the game makes, gathered into step_accesses after each step
+    log: AccessLog,
+    step_accesses: Vec<MemAccess>,
+}
+
+impl GbTarget<'_, '_> {
+    fn check_watchpoints(&mut self) -> Option<StopEvent> {
+        self.log.take_into(&mut self.step_accesses);
+        for &(start, len, kind) in &self.watchpoints {
+            let watched = |access: &&MemAccess| {
+                let matches = match kind {
+                    WatchKind::Read => { access.kind == Access::Read },
+                    WatchKind::Write => { access.kind == Access::Write },
+                    WatchKind::ReadWrite => { access.kind != Access::Execute },
+                };
+                matches && access.addr.wrapping_sub(start) < len
+            };
+            if let Some(access) = self.step_accesses.iter().find(watched) {
+                return Some(StopEvent::Watch(kind, access.addr));
+            }
+        }
+        None
+    }
+
+    // Runs a single instruction, or until the end of the frame when continuing
+    fn run(&mut self) -> Option<StopEvent> {
+        loop {
+            let info = self.gb.step();
+            if self.gb.is_locked() {
+                return Some(StopEvent::Locked);
+            }
+            if let Some(event) = self.check_watchpoints() {
+                return Some(event);
+            }
+            if self.mode == ExecMode::Step {
+                return Some(StopEvent::DoneStep);
+            }
+            if self.breakpoints.contains(&self.gb.get_pc()) {
+                return Some(StopEvent::Breakpoint);
+            }
+            if info.frame_ready {
+                draw_screen(self.gb, self.canvas, self.texture, Filter::None);
+                self.limiter.wait();
+                return self.poll_input();
+            }
+        }
+    }
+
+    fn poll_input(&mut self) -> Option<StopEvent> {
+        if stop_requested() {
+            return Some(StopEvent::Quit);
+        }
+        for event in self.events.poll_iter() {
+            match event {
+                Event::Quit{..} => {
+                    return Some(StopEvent::Quit);
+                },
+                Event::KeyDown{keycode: Some(keycode), ..} => {
+                    if self.keys.key2hotkey(keycode) == Some(Hotkey::Quit) {
+                        return Some(StopEvent::Quit);
+                    }
+                    if let Some(button) = self.keys.key2btn(keycode) {
+                        self.gb.press_button(button, true);
+                    }
+                },
+                Event::KeyUp{keycode: Some(keycode), ..} => {
+                    if let Some(button) = self.keys.key2btn(keycode) {
+                        self.gb.press_button(button, false);
+                    }
+                },
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+impl Target for GbTarget<'_, '_> {
+    type Arch = Sm83;
+    type Error = &'static str;
+
+    #[inline(always)]
+    fn base_ops(&mut self) -> BaseOps<'_, Self::Arch, Self::Error> {
+        BaseOps::SingleThread(self)
+    }
+
+    #[inline(always)]
+    fn support_breakpoints(&mut self) -> Option<gdbstub::target::ext::breakpoints::BreakpointsOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl SingleThreadBase for GbTarget<'_, '_> {
+    fn read_registers(&mut self, regs: &mut Sm83Regs) -> TargetResult<(), Self> {
+        let file = self.gb.registers();
+        *regs = Sm83Regs { af: file.af, bc: file.bc, de: file.de, hl: file.hl, sp: file.sp, pc: file.pc };
+        Ok(())
+    }
+
+    fn write_registers(&mut self, regs: &Sm83Regs) -> TargetResult<(), Self> {
+        use gb_core::cpu::Regs16;
+        self.gb.set_r16(Regs16::AF, regs.af);
+        self.gb.set_r16(Regs16::BC, regs.bc);
+        self.gb.set_r16(Regs16::DE, regs.de);
+        self.gb.set_r16(Regs16::HL, regs.hl);
+        self.gb.set_r16(Regs16::SP, regs.sp);
+        self.gb.set_pc(regs.pc);
+        Ok(())
+    }
+
+    fn read_addrs(&mut self, start_addr: u16, data: &mut [u8]) -> TargetResult<usize, Self> {
+        for (i, val) in data.iter_mut().enumerate() {
+            *val = self.gb.read_ram(start_addr.wrapping_add(i as u16));
+        }
+        Ok(data.len())
+    }
+
+    fn write_addrs(&mut self, start_addr: u16, data: &[u8]) -> TargetResult<(), Self> {
+        for (i, &val) in data.iter().enumerate() {
+            self.gb.write_ram(start_addr.wrapping_add(i as u16), val);
+        }
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn support_resume(&mut self) -> Option<gdbstub::target::ext::base::singlethread::SingleThreadResumeOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl SingleThreadResume for GbTarget<'_, '_> {
+    fn resume(&mut self, _signal: Option<Signal>) -> Result<(), Self::Error> {
+        self.mode = ExecMode::Continue;
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn support_single_step(&mut self) -> Option<gdbstub::target::ext::base::singlethread::SingleThreadSingleStepOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl SingleThreadSingleStep for GbTarget<'_, '_> {
+    fn step(&mut self, _signal: Option<Signal>) -> Result<(), Self::Error> {
+        self.mode = ExecMode::Step;
+        Ok(())
+    }
+}
+
+impl Breakpoints for GbTarget<'_, '_> {
+    #[inline(always)]
+    fn support_sw_breakpoint(&mut self) -> Option<gdbstub::target::ext::breakpoints::SwBreakpointOps<'_, Self>> {
+        Some(self)
+    }
+
+    #[inline(always)]
+    fn support_hw_watchpoint(&mut self) -> Option<gdbstub::target::ext::breakpoints::HwWatchpointOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl SwBreakpoint for GbTarget<'_, '_> {
+    fn add_sw_breakpoint(&mut self, addr: u16, _kind: ()) -> TargetResult<bool, Self> {
+        if !self.breakpoints.contains(&addr) {
+            self.breakpoints.push(addr);
+        }
+        Ok(true)
+    }
+
+    fn remove_sw_breakpoint(&mut self, addr: u16, _kind: ()) -> TargetResult<bool, Self> {
+        let Some(idx) = self.breakpoints.iter().position(|&bp| bp == addr) else {
+            return Ok(false);
+        };
+        self.breakpoints.remove(idx);
+        Ok(true)
+    }
+}
+
+impl HwWatchpoint for GbTarget<'_, '_> {
+    fn add_hw_watchpoint(&mut self, addr: u16, len: u16, kind: WatchKind) -> TargetResult<bool, Self> {
+        self.watchpoints.push((addr, len, kind));
+        Ok(true)
+    }
+
+    fn remove_hw_watchpoint(&mut self, addr: u16, len: u16, kind: WatchKind) -> TargetResult<bool, Self> {
+        let Some(idx) = self.watchpoints.iter().position(|&wp| wp == (addr, len, kind)) else {
+            return Ok(false);
+        };
+        self.watchpoints.remove(idx);
+        Ok(true)
+    }
+}
+
+struct GbEventLoop<'a, 't>(PhantomData<(&'a (), &'t ())>);
+
+impl<'a, 't: 'a> BlockingEventLoop for GbEventLoop<'a, 't> {
+    type Target = GbTarget<'a, 't>;
+    type Connection = TcpStream;
+    type StopReason = SingleThreadStopReason<u16>;
+
+    #[allow(clippy::type_complexity)]
+    fn wait_for_stop_reason(
+        target: &mut GbTarget<'a, 't>,
+        conn: &mut TcpStream,
+    ) -> Result<
+        run_blocking::Event<SingleThreadStopReason<u16>>,
+        run_blocking::WaitForStopReasonError<&'static str, <TcpStream as Connection>::Error>,
+    > {
+        loop {
+            // Anything sent while running is most likely a request to stop
+            if conn.peek().map(|byte| byte.is_some()).unwrap_or(true) {
+                let byte = conn.read().map_err(run_blocking::WaitForStopReasonError::Connection)?;
+                return Ok(run_blocking::Event::IncomingData(byte));
+            }
+
+            let Some(event) = target.run() else {
+                continue;
+            };
+            let reason = match event {
+                StopEvent::DoneStep => { SingleThreadStopReason::DoneStep },
+                StopEvent::Breakpoint => { SingleThreadStopReason::SwBreak(()) },
+                StopEvent::Watch(kind, addr) => { SingleThreadStopReason::Watch { tid: (), kind, addr } },
+                StopEvent::Locked => { SingleThreadStopReason::Signal(Signal::SIGILL) },
+                StopEvent::Quit => { SingleThreadStopReason::Exited(0) },
+            };
+            return Ok(run_blocking::Event::TargetStopped(reason));
+        }
+    }
+
+    fn on_interrupt(_target: &mut GbTarget<'a, 't>) -> Result<Option<SingleThreadStopReason<u16>>, &'static str> {
+        // The game only runs inside wait_for_stop_reason, so it's already stopped by the time this is called
+        Ok(Some(SingleThreadStopReason::Signal(Signal::SIGINT)))
+    }
+}
+
+/// Waits for a debugger to attach on the given port, then lets it control the game until it detaches
+pub fn run(gb: &mut Cpu, port: u16, canvas: &mut Canvas<Window>, texture: &mut Texture, events: &mut EventPump, keys: &KeyMap) {
+    let listener = match TcpListener::bind(("127.0.0.1", port)) {
+        Ok(listener) => listener,
+        Err(err) => {
+            println!("Unable to listen for GDB on port {}: {}", port, err);
+            return;
+        },
+    };
+    println!("Waiting for GDB to connect on port {}...", port);
+    let stream = match listener.accept() {
+        Ok((stream, addr)) => {
+            println!("GDB connected from {}", addr);
+            stream
+        },
+        Err(err) => {
+            println!("Unable to accept the GDB connection: {}", err);
+            return;
+        },
+    };
+
+    let log = AccessLog::new();
+    let hook = gb.add_memory_hook(Box::new(log.clone()));
+    let mut target = GbTarget {
+        gb,
+        canvas,
+        texture,
+        events,
+        keys,
+        limiter: FrameLimiter::new(),
+        mode: ExecMode::Continue,
+        breakpoints: Vec::new(),
+        watchpoints: Vec::new(),
+        log,
+        step_accesses: Vec::new(),
+    };
+    match GdbStub::new(stream).run_blocking::<GbEventLoop>(&mut target) {
+        Ok(DisconnectReason::Disconnect) => { println!("GDB disconnected") },
+        Ok(DisconnectReason::Kill) => { println!("GDB ended the session") },
+        Ok(_) => { println!("The game was closed") },
+        Err(err) => { println!("GDB connection failed: {}", err) },
+    }
+    target.gb.remove_memory_hook(hook);
+}
@@ -0,0 +1,69 @@
+use crate::debug::Gbd;
+use crate::signals::stop_requested;
+use crate::tick_until_draw;
+
+use gb_core::cpu::Cpu;
+use gb_core::movie::Movie;
+
+use std::io::{stdout, Write};
+use std::path::Path;
+
+/*
+ * Headless Mode
+ *
+ * Runs the game without a window or any speed limit, for test ROMs run by
+ * scripts rather than people. It stops once the game reports over the serial
+ * port whether it passed or failed, or after the given number of frames, and
+ * the process exits with a status saying which happened:
+ *
+ * 0    The test passed
+ * 1    The test failed
+ * 2    Neither was reported, as the frames ran out or the CPU locked up
+ *
+ * Blargg's tests print "Passed" or "Failed", while Mooneye's send the
+ * Fibonacci numbers 3, 5, 8, 13, 21, 34 on passing and six $42 bytes on
+ * failing. Everything sent over the serial port is echoed to stdout.
+ */
+
+const EXIT_PASSED: i32      = 0;
+const EXIT_FAILED: i32      = 1;
+const EXIT_UNFINISHED: i32  = 2;
+
+const PASSED_MARKERS: [&str; 2] = ["Passed", "\x03\x05\x08\x0d\x15\x22"];
+const FAILED_MARKERS: [&str; 2] = ["Failed", "BBBBBB"];
+
+/// Runs until the game reports a result or the frames run out, returning the exit status
+pub fn run(gb: &mut Cpu, gbd: &mut Gbd, save_path: &Path, frames: Option<u64>, mut playback: Option<Movie>) -> i32 {
+    let mut output = String::new();
+    let mut frame = 0;
+    while frames.is_none_or(|frames| frame < frames) && !stop_requested() {
+        if let Some(movie) = &mut playback {
+            if !movie.play_frame(gb) {
+                playback = None;
+            }
+        }
+        tick_until_draw(gb, gbd, save_path);
+        frame += 1;
+
+        let sent = gb.take_serial_output();
+        if !sent.is_empty() {
+            print!("{}", sent);
+            stdout().flush().unwrap();
+            output.push_str(&sent);
+        }
+        if PASSED_MARKERS.iter().any(|marker| output.contains(marker)) {
+            println!("\nPassed after {} frames", frame);
+            return EXIT_PASSED;
+        }
+        if FAILED_MARKERS.iter().any(|marker| output.contains(marker)) {
+            println!("\nFailed after {} frames", frame);
+            return EXIT_FAILED;
+        }
+        if let Some(opcode) = gb.get_locked_opcode() {
+            println!("\nThe CPU locked up after running invalid opcode ${:02X}", opcode);
+            return EXIT_UNFINISHED;
+        }
+    }
+    println!("\nNo result after {} frames", frame);
+    EXIT_UNFINISHED
+}
@@ -0,0 +1,211 @@
+use gb_core::io::Buttons;
+
+use sdl2::controller::Button;
+use sdl2::keyboard::Keycode;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/*
+ * Key Mapping
+ *
+ * Which keys and controller buttons press which Game Boy buttons, and which
+ * keys control the emulator itself, can be changed with an INI file. It's
+ * read from keys.ini in the working directory, or wherever --keys=path says.
+ * Each section of the file lists "input = action" pairs:
+ *
+ * [keys]           Keyboard keys to Game Boy buttons, such as "X = A"
+ * [turbo]          Keyboard keys which mash Game Boy buttons while held, such as "S = A"
+ * [controller]     Controller buttons to Game Boy buttons, such as "dpup = Up"
+ * [hotkeys]        Keyboard keys to emulator actions, such as "Escape = quit"
+ *
+ * Keys are named as SDL names them, such as "Return" or "Left Shift", and
+ * controller buttons use SDL's names too, such as "a", "back", or "dpleft".
+ * A section which is given replaces all of its defaults, while sections
+ * which are left out keep them.
+ */
+
+/// Things the emulator can be told to do from the keyboard
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Hotkey {
+    Quit,
+    Debug,
+    Rewind,
+    Colorize,
+    Palette,
+    Filter,
+    Record,
+    Screenshot,
+    Reset,
+    SoftReset,
+    Pause,
+    FrameAdvance,
+}
+
+impl Hotkey {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "quit" =>       { Some(Hotkey::Quit)         },
+            "debug" =>      { Some(Hotkey::Debug)        },
+            "rewind" =>     { Some(Hotkey::Rewind)       },
+            "colorize" =>   { Some(Hotkey::Colorize)     },
+            "palette" =>    { Some(Hotkey::Palette)      },
+            "filter" =>     { Some(Hotkey::Filter)       },
+            "record" =>     { Some(Hotkey::Record)       },
+            "screenshot" => { Some(Hotkey::Screenshot)   },
+            "reset" =>      { Some(Hotkey::Reset)        },
+            "softreset" =>  { Some(Hotkey::SoftReset)    },
+            "pause" =>      { Some(Hotkey::Pause)        },
+            "advance" =>    { Some(Hotkey::FrameAdvance) },
+            _ =>            { None                       }
+        }
+    }
+}
+
+pub struct KeyMap {
+    keys: HashMap<Keycode, Buttons>,
+    controller: HashMap<Button, Buttons>,
+    turbo: HashMap<Keycode, Buttons>,
+    hotkeys: HashMap<Keycode, Hotkey>,
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KeyMap {
+    pub fn new() -> Self {
+        let keys = HashMap::from([
+            (Keycode::Down,         Buttons::Down),
+            (Keycode::Up,           Buttons::Up),
+            (Keycode::Left,         Buttons::Left),
+            (Keycode::Right,        Buttons::Right),
+            (Keycode::Return,       Buttons::Start),
+            (Keycode::Backspace,    Buttons::Select),
+            (Keycode::X,            Buttons::A),
+            (Keycode::Z,            Buttons::B),
+        ]);
+        // The face buttons follow the Game Boy's layout rather than their labels, with B to the lower left of A
+        let controller = HashMap::from([
+            (Button::DPadDown,      Buttons::Down),
+            (Button::DPadUp,        Buttons::Up),
+            (Button::DPadLeft,      Buttons::Left),
+            (Button::DPadRight,     Buttons::Right),
+            (Button::Start,         Buttons::Start),
+            (Button::Back,          Buttons::Select),
+            (Button::B,             Buttons::A),
+            (Button::Y,             Buttons::A),
+            (Button::A,             Buttons::B),
+            (Button::X,             Buttons::B),
+        ]);
+        let turbo = HashMap::from([
+            (Keycode::S,            Buttons::A),
+            (Keycode::A,            Buttons::B),
+        ]);
+        let hotkeys = HashMap::from([
+            (Keycode::Escape,       Hotkey::Quit),
+            (Keycode::Space,        Hotkey::Debug),
+            (Keycode::R,            Hotkey::Rewind),
+            (Keycode::C,            Hotkey::Colorize),
+            (Keycode::P,            Hotkey::Palette),
+            (Keycode::F,            Hotkey::Filter),
+            (Keycode::V,            Hotkey::Record),
+            (Keycode::F12,          Hotkey::Screenshot),
+            (Keycode::F5,           Hotkey::Reset),
+            (Keycode::F6,           Hotkey::SoftReset),
+            (Keycode::F7,           Hotkey::Pause),
+            (Keycode::F8,           Hotkey::FrameAdvance),
+        ]);
+        Self { keys, controller, turbo, hotkeys }
+    }
+
+    /// Replaces the default bindings with those in the file, if there is one
+    pub fn load(&mut self, path: &Path) {
+        let Ok(config) = fs::read_to_string(path) else {
+            return;
+        };
+        let mut section = "";
+        for line in config.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+                section = name.trim();
+                match section {
+                    "keys" =>       { self.keys.clear()       },
+                    "controller" => { self.controller.clear() },
+                    "turbo" =>      { self.turbo.clear()      },
+                    "hotkeys" =>    { self.hotkeys.clear()    },
+                    _ => { println!("Ignoring unknown section in {}: {}", path.display(), line) },
+                }
+                continue;
+            }
+
+            let Some((input, action)) = line.split_once('=').map(|(input, action)| (input.trim(), action.trim())) else {
+                println!("Ignoring invalid line in {}: {}", path.display(), line);
+                continue;
+            };
+            let bound = match section {
+                "keys" => {
+                    Keycode::from_name(input).zip(name2btn(action)).map(|(key, btn)| {
+                        self.keys.insert(key, btn);
+                    })
+                },
+                "controller" => {
+                    Button::from_string(input).zip(name2btn(action)).map(|(pad, btn)| {
+                        self.controller.insert(pad, btn);
+                    })
+                },
+                "turbo" => {
+                    Keycode::from_name(input).zip(name2btn(action)).map(|(key, btn)| {
+                        self.turbo.insert(key, btn);
+                    })
+                },
+                "hotkeys" => {
+                    Keycode::from_name(input).zip(Hotkey::from_name(action)).map(|(key, hotkey)| {
+                        self.hotkeys.insert(key, hotkey);
+                    })
+                },
+                // Already warned about when the section started
+                _ => { Some(()) },
+            };
+            if bound.is_none() {
+                println!("Ignoring invalid binding in {}: {}", path.display(), line);
+            }
+        }
+        println!("Loaded key bindings from {}", path.display());
+    }
+
+    pub fn key2btn(&self, key: Keycode) -> Option<Buttons> {
+        self.keys.get(&key).copied()
+    }
+
+    pub fn pad2btn(&self, button: Button) -> Option<Buttons> {
+        self.controller.get(&button).copied()
+    }
+
+    pub fn key2turbo(&self, key: Keycode) -> Option<Buttons> {
+        self.turbo.get(&key).copied()
+    }
+
+    pub fn key2hotkey(&self, key: Keycode) -> Option<Hotkey> {
+        self.hotkeys.get(&key).copied()
+    }
+}
+
+fn name2btn(name: &str) -> Option<Buttons> {
+    match name {
+        "Down" =>   { Some(Buttons::Down)   },
+        "Up" =>     { Some(Buttons::Up)     },
+        "Left" =>   { Some(Buttons::Left)   },
+        "Right" =>  { Some(Buttons::Right)  },
+        "Start" =>  { Some(Buttons::Start)  },
+        "Select" => { Some(Buttons::Select) },
+        "A" =>      { Some(Buttons::A)      },
+        "B" =>      { Some(Buttons::B)      },
+        _ =>        { None                  }
+    }
+}
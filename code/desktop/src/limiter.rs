@@ -0,0 +1,38 @@
+use gb_core::utils::{CLOCK_SPEED, T_CYCLES_PER_FRAME};
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Holds the game to the Game Boy's own frame rate, however often the display refreshes
+pub struct FrameLimiter {
+    frame_time: Duration,
+    next_frame: Instant,
+}
+
+impl Default for FrameLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameLimiter {
+    pub fn new() -> Self {
+        Self {
+            frame_time: Duration::from_nanos(T_CYCLES_PER_FRAME * 1_000_000_000 / CLOCK_SPEED),
+            next_frame: Instant::now(),
+        }
+    }
+
+    /// Sleeps until it's time for the next frame
+    pub fn wait(&mut self) {
+        let now = Instant::now();
+        if let Some(remaining) = self.next_frame.checked_duration_since(now) {
+            thread::sleep(remaining);
+        }
+        self.next_frame += self.frame_time;
+        // After falling more than a frame behind, such as while stopped in the debugger, start again from now rather than rushing to catch up
+        if self.next_frame < now {
+            self.next_frame = now + self.frame_time;
+        }
+    }
+}
@@ -1,124 +1,755 @@
 mod debug;
+mod filter;
+#[cfg(feature = "gdb")]
+mod gdb;
+mod headless;
+mod keymap;
+mod limiter;
+mod recorder;
+#[cfg(feature = "scripting")]
+mod script;
+mod signals;
+mod stats;
 
-use crate::debug::Debugger;
+use crate::debug::Gbd;
+use crate::filter::Filter;
+use crate::keymap::{Hotkey, KeyMap};
+use crate::limiter::FrameLimiter;
+use crate::recorder::GifRecorder;
+use crate::signals::{catch_signals, stop_requested};
+use crate::stats::SpeedStats;
 
+use gb_core::cart::HardwareMode;
+use gb_core::config::{CpuConfig, RamInit};
 use gb_core::cpu::Cpu;
 use gb_core::io::Buttons;
-use gb_core::utils::{SCREEN_HEIGHT, SCREEN_WIDTH, DISPLAY_BUFFER};
+use gb_core::movie::Movie;
+use gb_core::ppu::compat::CompatPalette;
+use gb_core::ppu::display::DisplayPalette;
+use gb_core::ppu::{Layer, Renderer};
+use gb_core::rewind::Rewind;
+use gb_core::utils::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+use clap::Parser;
 
 use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use sdl2::pixels::Color;
+use sdl2::keyboard::{Keycode, Mod};
+use sdl2::pixels::PixelFormatEnum;
 use sdl2::rect::Rect;
-use sdl2::render::Canvas;
-use sdl2::video::Window;
+use sdl2::render::{Canvas, Texture};
+use sdl2::video::{FullscreenType, Window};
 
-use std::env;
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::io::prelude::*;
-use std::io::Read;
+use std::io::{BufWriter, Read};
+use std::mem;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// How long the game must stop writing to its save before it's written out, about a second
+const BATTERY_FLUSH_FRAMES: u32 = 60;
+// How long the soft reset combo is held, long enough for games which only check the buttons now and then
+const SOFT_RESET_FRAMES: u32 = 10;
+const SOFT_RESET_BUTTONS: [Buttons; 4] = [Buttons::A, Buttons::B, Buttons::Start, Buttons::Select];
 
-const SCALE: u32 = 3;
-const WINDOW_WIDTH: u32 = (SCREEN_WIDTH as u32) * SCALE;
-const WINDOW_HEIGHT: u32 = (SCREEN_HEIGHT as u32) * SCALE;
+/// A Game Boy and Game Boy Color emulator
+#[derive(Parser)]
+#[command(version, about)]
+struct Args {
+    /// The game to play, which is picked from a file dialog if not given
+    #[arg(required_if_eq("headless", "true"))]
+    rom: Option<String>,
+    /// Runs color games as the original Game Boy would
+    #[arg(long)]
+    dmg: bool,
+    /// Fills RAM with what real hardware powers on with, rather than zeroes
+    #[arg(long)]
+    random_ram: bool,
+    /// Draws with the slower but more accurate pixel FIFO renderer
+    #[arg(long)]
+    pixel_fifo: bool,
+    /// How many times larger than the Game Boy's screen the window starts
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    scale: u32,
+    /// The display palette to start with, such as "pocket" or "dmg-green"
+    #[arg(long, value_parser = parse_palette)]
+    palette: Option<DisplayPalette>,
+    /// Runs a boot ROM before the game, rather than skipping straight to it
+    #[arg(long, value_name = "PATH")]
+    bootrom: Option<PathBuf>,
+    /// Where battery saves are kept, rather than the user's data directory
+    #[arg(long, value_name = "DIR")]
+    save_dir: Option<PathBuf>,
+    /// Where save state slots are kept, rather than next to the ROM
+    #[arg(long, value_name = "DIR")]
+    state_dir: Option<PathBuf>,
+    /// Loads a save state before starting
+    #[arg(long, value_name = "PATH")]
+    load_state: Option<PathBuf>,
+    /// Records the buttons pressed to a movie, written out on quitting
+    #[arg(long, value_name = "PATH")]
+    record: Option<PathBuf>,
+    /// Plays back a movie
+    #[arg(long, value_name = "PATH")]
+    play: Option<PathBuf>,
+    /// The key bindings file
+    #[arg(long, value_name = "PATH", default_value = "keys.ini")]
+    keys: PathBuf,
+    /// Waits for the display's vertical sync before drawing each frame
+    #[arg(long)]
+    vsync: bool,
+    /// Starts in fullscreen
+    #[arg(long)]
+    fullscreen: bool,
+    /// Starts stopped in the debugger
+    #[arg(long)]
+    debug: bool,
+    /// Runs without a window until a test ROM passes or fails, exiting with 0, 1, or 2 if it did neither
+    #[arg(long, conflicts_with = "record")]
+    headless: bool,
+    /// How many frames to run before giving up in headless mode, rather than carrying on until there's a result
+    #[arg(long, value_name = "N", requires = "headless")]
+    frames: Option<u64>,
+    /// Waits for GDB to attach on this port, and lets it control the game
+    #[cfg(feature = "gdb")]
+    #[arg(long, value_name = "PORT")]
+    gdb: Option<u16>,
+}
 
 fn main() {
-    let args: Vec<_> = env::args().collect();
-    if args.len() == 1 {
-        println!("Please specify a ROM location: cargo run path/to/game");
+    let args = Args::parse();
+    catch_signals();
+    // Without a game, such as when launched by double-clicking, one is picked from a file dialog instead
+    let Some(rom_path) = args.rom.clone().or_else(pick_rom) else {
+        println!("No game was chosen, see --help for how to give one");
         return;
+    };
+    let filename = rom_path.as_str();
+    let save_path = battery_path(filename, args.save_dir.as_deref());
+    if let Some(dir) = save_path.parent() {
+        if let Err(err) = fs::create_dir_all(dir) {
+            println!("Unable to create the save directory {}: {}", dir.display(), err);
+        }
     }
+    migrate_battery_save(filename, &save_path);
 
-    let mut gbd = Debugger::new();
-    let mut gb = Cpu::new();
-    let filename = &args[1];
+    let mut gbd = Gbd::new();
+    // Breakpoints are kept next to the ROM, so they're still there next time
+    gbd.load_breakpoints(Path::new(&format!("{}.gbd", filename)));
+    gbd.set_debugging(args.debug);
+    // Bindings are read from keys.ini in the working directory unless another file is given
+    let mut keys = KeyMap::new();
+    keys.load(&args.keys);
+    let mut config = CpuConfig::default();
+    if args.random_ram {
+        config.ram_init = RamInit::Hardware;
+    }
+    if args.pixel_fifo {
+        config.renderer = Renderer::PixelFifo;
+    }
+    let mut gb = Cpu::with_config(config);
+    gbd.attach(&mut gb);
     let rom = load_rom(filename);
-    gb.load_rom(&rom);
-    load_battery_save(&mut gb, filename);
-    let title = gb.get_title();
+    if let Err(err) = gb.load_rom(&rom) {
+        eprintln!("Unable to load {}: {}", filename, err);
+        exit(1);
+    }
+    if !gb.verify_header_checksum() {
+        println!("Warning: the header checksum doesn't match, this ROM may be corrupt");
+    }
+    if args.dmg {
+        gb.set_hardware_mode(Some(HardwareMode::Dmg));
+    }
+    if let Some(path) = &args.bootrom {
+        let boot_rom = match fs::read(path) {
+            Ok(data) => { data },
+            Err(err) => {
+                eprintln!("Unable to read {}: {}", path.display(), err);
+                exit(1);
+            },
+        };
+        if let Err(err) = gb.load_boot_rom(&boot_rom) {
+            eprintln!("Skipping {}: {}", path.display(), err);
+        }
+    }
+    load_battery_save(&mut gb, &save_path);
+    if let Some(path) = &args.load_state {
+        load_save_state(&mut gb, path);
+    }
+    // Movies play back from the state they were recorded from, or record starting from here
+    let mut playback = args.play.as_deref().and_then(|path| load_movie(&mut gb, path));
+    let mut recording = args.record.as_ref().map(|_| Movie::record(&gb));
+    if args.headless {
+        let status = headless::run(&mut gb, &mut gbd, &save_path, args.frames, playback);
+        if gb.is_battery_dirty() {
+            write_battery_save(&mut gb, &save_path);
+        }
+        exit(status);
+    }
+    let title = gb.get_title().to_string();
 
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
-    let window = video_subsystem.window(title, WINDOW_WIDTH, WINDOW_HEIGHT)
-        .position_centered().opengl().build().unwrap();
-    let mut canvas = window.into_canvas().present_vsync().build().unwrap();
+    let window_width = SCREEN_WIDTH as u32 * args.scale;
+    let window_height = SCREEN_HEIGHT as u32 * args.scale;
+    let mut window_builder = video_subsystem.window(&title, window_width, window_height);
+    window_builder.position_centered().resizable().opengl();
+    if args.fullscreen {
+        window_builder.fullscreen_desktop();
+    }
+    let mut window = window_builder.build().unwrap();
+    window.set_minimum_size(SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32).unwrap();
+    // The frame limiter keeps the game at the right speed, so vsync only needs to be asked for to avoid tearing
+    let mut canvas = if args.vsync {
+        window.into_canvas().present_vsync().build().unwrap()
+    } else {
+        window.into_canvas().build().unwrap()
+    };
     canvas.clear();
     canvas.present();
+    // The screen is drawn into a texture the size of the Game Boy's, which SDL then scales up to the window
+    let texture_creator = canvas.texture_creator();
+    let mut texture = texture_creator.create_texture_streaming(
+        PixelFormatEnum::RGBA32, SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32
+    ).unwrap();
 
+    let mut compat_palette = None;
+    let mut filter = Filter::None;
+    let mut gif: Option<GifRecorder> = None;
+    let mut display_palette = args.palette.unwrap_or(DisplayPalette::ALL[0]);
+    gb.set_palette(display_palette.get_colors());
+    let mut tilt = (0.0, 0.0);
+    let mut rewind = Rewind::new();
+    let mut rewinding = false;
+    let mut soft_reset_frames = 0;
+    let mut paused = false;
+    let mut frame_advance = false;
+    let mut turbo: Vec<Buttons> = Vec::new();
+    let mut turbo_pressed = false;
+    let mut limiter = FrameLimiter::new();
+    let mut stats = SpeedStats::new(gb.elapsed_cycles());
     let mut events = sdl_context.event_pump().unwrap();
+    // Controllers are opened as SDL reports them, including those already plugged in at startup
+    let controller_subsystem = sdl_context.game_controller().unwrap();
+    let mut controllers = Vec::new();
+    // With a GDB port given, the game is left to whichever debugger attaches to it
+    #[cfg(feature = "gdb")]
+    if let Some(port) = args.gdb {
+        // GDB has its own watchpoints, and never lets gbd check a step to empty its log
+        gbd.detach(&mut gb);
+        gdb::run(&mut gb, port, &mut canvas, &mut texture, &mut events, &keys);
+        if gb.is_battery_dirty() {
+            write_battery_save(&mut gb, &save_path);
+        }
+        return;
+    }
     'gameloop: loop {
+        // Quitting through the loop, rather than where the signal arrived, gets everything written out
+        if stop_requested() {
+            break 'gameloop;
+        }
         for event in events.poll_iter() {
             match event {
-                Event::Quit{..} |
-                Event::KeyDown{keycode: Some(Keycode::Escape), ..} => {
+                Event::Quit{..} => {
                     break 'gameloop;
                 },
-                Event::KeyDown{keycode: Some(Keycode::Space), ..} => {
-                    gbd.set_debugging(true);
+                Event::KeyDown{keycode: Some(Keycode::Return), keymod, ..} if keymod.intersects(Mod::LALTMOD | Mod::RALTMOD) => {
+                    toggle_fullscreen(canvas.window_mut());
                 },
-                Event::KeyDown{keycode: Some(keycode), ..} => {
-                    if let Some(button) = key2btn(keycode) {
-                        gb.press_button(button, true);
+                Event::KeyDown{keycode: Some(keycode), keymod, ..} => {
+                    if let Some(hotkey) = keys.key2hotkey(keycode) {
+                        match hotkey {
+                            Hotkey::Quit => {
+                                break 'gameloop;
+                            },
+                            Hotkey::Debug => {
+                                gbd.set_debugging(true);
+                            },
+                            Hotkey::Rewind => {
+                                // Going back would leave the movie's frames out of step, as with a reset
+                                if playback.is_some() || recording.is_some() {
+                                    println!("Unable to rewind while a movie is playing or recording");
+                                } else {
+                                    rewinding = true;
+                                }
+                            },
+                            Hotkey::Colorize => {
+                                compat_palette = next_compat_palette(compat_palette);
+                                gb.set_compat_palette(compat_palette);
+                                match compat_palette {
+                                    Some(palette) => println!("Using {} colorization palette", palette.get_name()),
+                                    None => println!("Colorization disabled"),
+                                }
+                            },
+                            Hotkey::Palette => {
+                                display_palette = next_display_palette(display_palette);
+                                gb.set_palette(display_palette.get_colors());
+                                println!("Using {} display palette", display_palette.get_name());
+                            },
+                            Hotkey::Filter => {
+                                filter = filter.next();
+                                println!("{} filter", filter.get_name());
+                            },
+                            Hotkey::Record => {
+                                match gif.take() {
+                                    Some(recorder) => { finish_gif(recorder) },
+                                    None => { gif = start_gif(filename) },
+                                }
+                            },
+                            Hotkey::Screenshot => {
+                                // Holding Shift saves it at the window's size instead of the Game Boy's
+                                let scale = if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) { args.scale } else { 1 };
+                                write_screenshot(&gb, filename, scale as usize);
+                            },
+                            Hotkey::Reset => {
+                                // Movies only hold the buttons, so they'd fall out of step after a reset
+                                if playback.is_some() || recording.is_some() {
+                                    println!("Unable to reset while a movie is playing or recording");
+                                } else {
+                                    gb.reset();
+                                    gbd.forget_history();
+                                    println!("Reset the game");
+                                }
+                            },
+                            Hotkey::SoftReset => {
+                                if playback.is_none() {
+                                    for button in SOFT_RESET_BUTTONS {
+                                        gb.press_button(button, true);
+                                    }
+                                    soft_reset_frames = SOFT_RESET_FRAMES;
+                                }
+                            },
+                            Hotkey::Pause => {
+                                paused = !paused;
+                                println!("{}", if paused { "Paused" } else { "Resumed" });
+                            },
+                            Hotkey::FrameAdvance => {
+                                // Advancing while running pauses first, so the next press runs a single frame
+                                if paused {
+                                    frame_advance = true;
+                                } else {
+                                    paused = true;
+                                    println!("Paused");
+                                }
+                            },
+                        }
+                    } else if let Some(slot) = key2slot(keycode) {
+                        let path = state_path(filename, args.state_dir.as_deref(), slot);
+                        if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                            if playback.is_some() || recording.is_some() {
+                                println!("Unable to load a state while a movie is playing or recording");
+                            } else {
+                                load_save_state(&mut gb, &path);
+                                gbd.forget_history();
+                            }
+                        } else {
+                            write_save_state(&gb, &path);
+                        }
+                    } else if let Some(layer) = key2layer(keycode) {
+                        let enabled = !gb.is_layer_enabled(layer);
+                        gb.set_layer_enabled(layer, enabled);
+                        println!("{} the {} layer", if enabled { "Showing" } else { "Hiding" }, layer.get_name());
+                    } else if let Some(button) = keys.key2btn(keycode) {
+                        // The movie being played holds the buttons instead
+                        if playback.is_none() {
+                            gb.press_button(button, true);
+                        }
+                    } else if let Some(button) = keys.key2turbo(keycode) {
+                        if !turbo.contains(&button) {
+                            turbo.push(button);
+                        }
+                    } else if let Some(new_tilt) = key2tilt(keycode, tilt, true) {
+                        tilt = new_tilt;
+                        gb.set_tilt(tilt.0, tilt.1);
                     }
                 },
                 Event::KeyUp{keycode: Some(keycode), ..} => {
-                    if let Some(button) = key2btn(keycode) {
-                        gb.press_button(button, false);
+                    if keys.key2hotkey(keycode) == Some(Hotkey::Rewind) {
+                        rewinding = false;
+                    } else if let Some(button) = keys.key2btn(keycode) {
+                        if playback.is_none() {
+                            gb.press_button(button, false);
+                        }
+                    } else if let Some(button) = keys.key2turbo(keycode) {
+                        turbo.retain(|&held| held != button);
+                        if playback.is_none() {
+                            gb.press_button(button, false);
+                        }
+                    } else if let Some(new_tilt) = key2tilt(keycode, tilt, false) {
+                        tilt = new_tilt;
+                        gb.set_tilt(tilt.0, tilt.1);
+                    }
+                },
+                Event::ControllerDeviceAdded{which, ..} => {
+                    match controller_subsystem.open(which) {
+                        Ok(controller) => {
+                            println!("Connected {}", controller.name());
+                            controllers.push(controller);
+                        },
+                        Err(err) => { println!("Unable to open controller: {}", err) },
+                    }
+                },
+                Event::ControllerDeviceRemoved{which, ..} => {
+                    // Closing the controller happens when it's dropped
+                    if let Some(idx) = controllers.iter().position(|c| c.instance_id() == which) {
+                        println!("Disconnected {}", controllers.remove(idx).name());
+                    }
+                },
+                Event::ControllerButtonDown{button, ..} => {
+                    if let Some(button) = keys.pad2btn(button) {
+                        if playback.is_none() {
+                            gb.press_button(button, true);
+                        }
+                    }
+                },
+                Event::ControllerButtonUp{button, ..} => {
+                    if let Some(button) = keys.pad2btn(button) {
+                        if playback.is_none() {
+                            gb.press_button(button, false);
+                        }
                     }
                 },
                 _ => {}
             }
         }
 
-        // Keep ticking until told to stop
-        tick_until_draw(&mut gb, &mut gbd, filename);
-        let frame = gb.render();
-        draw_screen(&frame, &mut canvas);
+        // While paused, the game only runs one frame each time it's advanced
+        let frozen = paused && !mem::take(&mut frame_advance);
+        // Step back through history while R is held, otherwise keep ticking until told to stop
+        if rewinding {
+            rewind.step_back(&mut gb);
+            gbd.forget_history();
+        } else if !frozen {
+            // Turbo buttons flip between pressed and released each frame they're held
+            if playback.is_none() && !turbo.is_empty() {
+                turbo_pressed = !turbo_pressed;
+                for &button in &turbo {
+                    gb.press_button(button, turbo_pressed);
+                }
+            }
+            if let Some(movie) = &mut playback {
+                if !movie.play_frame(&mut gb) {
+                    println!("Movie finished after {} frames", movie.len());
+                    playback = None;
+                }
+            }
+            if let Some(movie) = &mut recording {
+                movie.record_frame(&gb);
+            }
+            tick_until_draw(&mut gb, &mut gbd, &save_path);
+            rewind.push_frame(&gb);
+            if soft_reset_frames > 0 {
+                soft_reset_frames -= 1;
+                if soft_reset_frames == 0 {
+                    for button in SOFT_RESET_BUTTONS {
+                        gb.press_button(button, false);
+                    }
+                }
+            }
+        }
+        if let Some(opcode) = gb.get_locked_opcode() {
+            println!("The game has crashed, the CPU locked up after running invalid opcode ${:02X}", opcode);
+            break 'gameloop;
+        }
+        draw_screen(&mut gb, &mut canvas, &mut texture, filter);
+        if let Some(recorder) = gif.as_mut().filter(|_| !frozen) {
+            if let Err(err) = recorder.add_frame(gb.render()) {
+                println!("Unable to record to {}: {}", recorder.get_path().display(), err);
+                gif = None;
+            }
+        }
+        // Shown in the title bar, so it's clear when the emulator can't keep up
+        if let Some((fps, speed)) = stats.frame_drawn(gb.elapsed_cycles()) {
+            let status = format!("{} - {:.1} FPS ({:.0}%)", title, fps, speed);
+            canvas.window_mut().set_title(&status).unwrap();
+        }
+        limiter.wait();
     }
-}
 
-fn draw_screen(data: &[u8], canvas: &mut Canvas<Window>) {
-    for i in (0..DISPLAY_BUFFER).step_by(4) {
-        canvas.set_draw_color(Color::RGB(data[i], data[i + 1], data[i + 2]));
-        let pixel = i / 4;
-        let x = (pixel % SCREEN_WIDTH) as u32;
-        let y = (pixel / SCREEN_WIDTH) as u32;
+    if gb.is_battery_dirty() {
+        write_battery_save(&mut gb, &save_path);
+    }
+    if let Some(recorder) = gif {
+        finish_gif(recorder);
+    }
+    if let (Some(movie), Some(path)) = (recording, &args.record) {
+        write_movie(&movie, path);
+    }
+}
 
-        let rect = Rect::new((x * SCALE) as i32, (y * SCALE) as i32, SCALE, SCALE);
-        canvas.fill_rect(rect).unwrap();
+fn draw_screen(gb: &mut Cpu, canvas: &mut Canvas<Window>, texture: &mut Texture, filter: Filter) {
+    // Games often leave the screen as it is for several frames, which the texture already holds
+    if gb.take_frame_changed() {
+        texture.update(None, gb.render(), SCREEN_WIDTH * 4).unwrap();
     }
+    canvas.clear();
+    let (width, height) = canvas.output_size().unwrap();
+    // Fullscreen sticks to whole multiples to keep the pixels crisp, while a window can be sized however it's wanted
+    let integer_scale = canvas.window().fullscreen_state() != FullscreenType::Off;
+    let screen = screen_rect(width, height, integer_scale);
+    canvas.copy(texture, None, screen).unwrap();
+    filter.apply(canvas, screen);
     canvas.present();
 }
 
-fn key2btn(key: Keycode) -> Option<Buttons> {
+// The largest size of the Game Boy's screen which fits, keeping its shape and centered with black bars around the rest
+fn screen_rect(width: u32, height: u32, integer_scale: bool) -> Rect {
+    let mut scale = (width as f32 / SCREEN_WIDTH as f32).min(height as f32 / SCREEN_HEIGHT as f32);
+    if integer_scale {
+        scale = scale.floor().max(1.0);
+    }
+    let screen_width = (SCREEN_WIDTH as f32 * scale) as u32;
+    let screen_height = (SCREEN_HEIGHT as f32 * scale) as u32;
+    let x = (width as i32 - screen_width as i32) / 2;
+    let y = (height as i32 - screen_height as i32) / 2;
+    Rect::new(x, y, screen_width, screen_height)
+}
+
+// Switches between the window and filling the whole display, which Alt+Enter does
+fn toggle_fullscreen(window: &mut Window) {
+    let fullscreen = match window.fullscreen_state() {
+        FullscreenType::Off => { FullscreenType::Desktop },
+        _ => { FullscreenType::Off },
+    };
+    if let Err(err) = window.set_fullscreen(fullscreen) {
+        println!("Unable to change fullscreen mode: {}", err);
+    }
+}
+
+// Cycles through each of the colorization palettes, then back to grayscale
+fn next_compat_palette(current: Option<CompatPalette>) -> Option<CompatPalette> {
+    match current {
+        Some(palette) => {
+            let idx = CompatPalette::ALL.iter().position(|p| *p == palette).unwrap();
+            CompatPalette::ALL.get(idx + 1).copied()
+        },
+        None => Some(CompatPalette::ALL[0]),
+    }
+}
+
+// Cycles through each of the built in display palettes
+fn next_display_palette(current: DisplayPalette) -> DisplayPalette {
+    let idx = DisplayPalette::ALL.iter().position(|p| *p == current).unwrap();
+    DisplayPalette::ALL[(idx + 1) % DisplayPalette::ALL.len()]
+}
+
+// Tilts the cartridge a full 1g along an axis while I/J/K/L is held, for MBC7 games
+fn key2tilt(key: Keycode, tilt: (f32, f32), pressed: bool) -> Option<(f32, f32)> {
+    let amount = if pressed { 1.0 } else { 0.0 };
+    match key {
+        Keycode::I => { Some((tilt.0, -amount)) },
+        Keycode::K => { Some((tilt.0, amount))  },
+        Keycode::J => { Some((-amount, tilt.1)) },
+        Keycode::L => { Some((amount, tilt.1))  },
+        _ =>          { None                    }
+    }
+}
+
+// Toggles the background, window, and sprite layers with 1, 2, and 3
+fn key2layer(key: Keycode) -> Option<Layer> {
+    match key {
+        Keycode::Num1 => { Some(Layer::Background) },
+        Keycode::Num2 => { Some(Layer::Window)     },
+        Keycode::Num3 => { Some(Layer::Sprites)    },
+        _ =>             { None                    }
+    }
+}
+
+// Saves to slots 1-4 with F1-F4, or loads from them while holding Shift
+fn key2slot(key: Keycode) -> Option<usize> {
     match key {
-        Keycode::Down =>        { Some(Buttons::Down)   },
-        Keycode::Up =>          { Some(Buttons::Up)     },
-        Keycode::Left =>        { Some(Buttons::Left)   },
-        Keycode::Right =>       { Some(Buttons::Right)  },
-        Keycode::Return =>      { Some(Buttons::Start)  },
-        Keycode::Backspace =>   { Some(Buttons::Select) },
-        Keycode::X =>           { Some(Buttons::A)      },
-        Keycode::Z =>           { Some(Buttons::B)      },
-        _ =>                    { None                  }
+        Keycode::F1 => { Some(1) },
+        Keycode::F2 => { Some(2) },
+        Keycode::F3 => { Some(3) },
+        Keycode::F4 => { Some(4) },
+        _ =>           { None    }
     }
 }
 
-fn load_battery_save(gb: &mut Cpu, gamename: &str) {
+fn load_battery_save(gb: &mut Cpu, path: &Path) {
     if gb.has_battery() {
         let mut battery_data: Vec<u8> = Vec::new();
-        let mut filename = gamename.to_owned();
-        filename.push_str(".sav");
-
-        let f = OpenOptions::new().read(true).open(filename);
-        if f.is_ok() {
-            f.unwrap().read_to_end(&mut battery_data).expect("Error reading save file");
+        let f = OpenOptions::new().read(true).open(path);
+        if let Ok(mut file) = f {
+            file.read_to_end(&mut battery_data).expect("Error reading save file");
             gb.set_battery_data(&battery_data);
         }
     }
 }
 
+/*
+ * Battery saves are named after the ROM without its extension, such as
+ * Tetris.sav for Tetris.gb, and are kept in the user's data directory:
+ *
+ * Linux    ~/.local/share/gb-book/saves
+ * macOS    ~/Library/Application Support/gb-book/saves
+ * Windows  %APPDATA%\gb-book\saves
+ *
+ * Unless a directory is given, or the system doesn't have a data directory,
+ * in which case they're left next to the ROM as game.gb.sav as they used to be.
+ */
+fn battery_path(gamename: &str, save_dir: Option<&Path>) -> PathBuf {
+    let save_dir = save_dir.map(Path::to_path_buf).or_else(|| {
+        dirs::data_dir().map(|dir| dir.join("gb-book").join("saves"))
+    });
+    match save_dir {
+        Some(dir) => {
+            let stem = Path::new(gamename).file_stem().unwrap_or_default();
+            dir.join(format!("{}.sav", stem.to_string_lossy()))
+        },
+        None => { PathBuf::from(format!("{}.sav", gamename)) },
+    }
+}
+
+// Moves a save left next to the ROM by older versions to where it's now kept
+fn migrate_battery_save(gamename: &str, save_path: &Path) {
+    let old_path = PathBuf::from(format!("{}.sav", gamename));
+    if old_path == save_path || save_path.exists() || !old_path.exists() {
+        return;
+    }
+    match fs::copy(&old_path, save_path) {
+        Ok(_) => {
+            println!("Moved save from {} to {}", old_path.display(), save_path.display());
+            // The ROM's directory may well be read only, in which case the old save just stays behind
+            let _ = fs::remove_file(&old_path);
+        },
+        Err(err) => { println!("Unable to move save from {} to {}: {}", old_path.display(), save_path.display(), err) },
+    }
+}
+
+// Slots are named after the ROM, such as game.gb.state1
+fn state_path(gamename: &str, state_dir: Option<&Path>, slot: usize) -> PathBuf {
+    let filename = format!("{}.state{}", gamename, slot);
+    match state_dir {
+        Some(dir) => {
+            let name = Path::new(&filename).file_name().unwrap();
+            dir.join(name)
+        },
+        None => { PathBuf::from(filename) },
+    }
+}
+
+fn load_save_state(gb: &mut Cpu, path: &Path) {
+    let mut state_data: Vec<u8> = Vec::new();
+    let f = OpenOptions::new().read(true).open(path);
+    let Ok(mut file) = f else {
+        println!("No save state found at {}", path.display());
+        return;
+    };
+    file.read_to_end(&mut state_data).expect("Error reading save state");
+    match gb.load_state(&state_data) {
+        Ok(()) => println!("Loaded state from {}", path.display()),
+        Err(err) => println!("Unable to load {}: {}", path.display(), err),
+    }
+}
+
+fn write_save_state(gb: &Cpu, path: &Path) {
+    let state_data = gb.save_state();
+    let f = OpenOptions::new().write(true).create(true).truncate(true).open(path);
+    match f.and_then(|mut file| file.write_all(&state_data)) {
+        Ok(()) => println!("Saved state to {}", path.display()),
+        Err(err) => println!("Unable to save state to {}: {}", path.display(), err),
+    }
+}
+
+// Named after the ROM and the time, such as game.gb.1700000000000.png, so they don't overwrite each other
+fn timestamped_path(gamename: &str, extension: &str) -> String {
+    let millis = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_millis()).unwrap_or(0);
+    format!("{}.{}.{}", gamename, millis, extension)
+}
+
+fn write_screenshot(gb: &Cpu, gamename: &str, scale: usize) {
+    let path = timestamped_path(gamename, "png");
+    let (width, height) = (SCREEN_WIDTH * scale, SCREEN_HEIGHT * scale);
+    let mut pixels = Vec::with_capacity(width * height * 4);
+    for row in gb.render().chunks_exact(SCREEN_WIDTH * 4) {
+        let line: Vec<u8> = row.chunks_exact(4).flat_map(|pixel| pixel.repeat(scale)).collect();
+        pixels.extend(line.repeat(scale));
+    }
+
+    let written = File::create(&path).map_err(png::EncodingError::from).and_then(|file| {
+        let mut encoder = png::Encoder::new(BufWriter::new(file), width as u32, height as u32);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&pixels)?;
+        writer.finish()
+    });
+    match written {
+        Ok(()) => println!("Saved screenshot to {}", path),
+        Err(err) => println!("Unable to save screenshot to {}: {}", path, err),
+    }
+}
+
+fn start_gif(gamename: &str) -> Option<GifRecorder> {
+    let path = timestamped_path(gamename, "gif");
+    match GifRecorder::start(Path::new(&path)) {
+        Ok(recorder) => {
+            println!("Recording to {}", path);
+            Some(recorder)
+        },
+        Err(err) => {
+            println!("Unable to record to {}: {}", path, err);
+            None
+        },
+    }
+}
+
+fn finish_gif(recorder: GifRecorder) {
+    let path = recorder.get_path().to_path_buf();
+    let frames = recorder.get_frames();
+    match recorder.finish() {
+        Ok(()) => println!("Recorded {} frames to {}", frames, path.display()),
+        Err(err) => println!("Unable to finish recording {}: {}", path.display(), err),
+    }
+}
+
+fn load_movie(gb: &mut Cpu, path: &Path) -> Option<Movie> {
+    let mut movie_data: Vec<u8> = Vec::new();
+    let mut file = File::open(path).expect("Error opening movie file");
+    file.read_to_end(&mut movie_data).expect("Error reading movie file");
+    let started = Movie::from_bytes(&movie_data).and_then(|mut movie| {
+        movie.start_playback(gb)?;
+        Ok(movie)
+    });
+    match started {
+        Ok(movie) => {
+            println!("Playing {} frames from {}", movie.len(), path.display());
+            Some(movie)
+        },
+        Err(err) => {
+            println!("Unable to play {}: {}", path.display(), err);
+            None
+        },
+    }
+}
+
+fn write_movie(movie: &Movie, path: &Path) {
+    let f = OpenOptions::new().write(true).create(true).truncate(true).open(path);
+    match f.and_then(|mut file| file.write_all(&movie.to_bytes())) {
+        Ok(()) => println!("Recorded {} frames to {}", movie.len(), path.display()),
+        Err(err) => println!("Unable to write movie to {}: {}", path.display(), err),
+    }
+}
+
+// Matches names like "pocket" or "dmg-green" to the display palettes
+fn parse_palette(name: &str) -> Result<DisplayPalette, String> {
+    let simplify = |name: &str| name.to_lowercase().replace([' ', '-', '_'], "");
+    DisplayPalette::ALL.iter().copied().find(|palette| simplify(palette.get_name()) == simplify(name)).ok_or_else(|| {
+        let names: Vec<_> = DisplayPalette::ALL.iter().map(|palette| palette.get_name()).collect();
+        format!("unknown palette, expected one of: {}", names.join(", "))
+    })
+}
+
+fn pick_rom() -> Option<String> {
+    rfd::FileDialog::new()
+        .set_title("Open a Game Boy game")
+        .add_filter("Game Boy ROMs", &["gb", "gbc"])
+        .pick_file()
+        .map(|path| path.to_string_lossy().into_owned())
+}
+
 fn load_rom(path: &str) -> Vec<u8> {
     let mut buffer: Vec<u8> = Vec::new();
 
@@ -127,43 +758,44 @@ fn load_rom(path: &str) -> Vec<u8> {
     buffer
 }
 
-fn tick_until_draw(gb: &mut Cpu, gbd: &mut Debugger, gamename: &str) {
+fn tick_until_draw(gb: &mut Cpu, gbd: &mut Gbd, save_path: &Path) {
+    gbd.checkpoint(gb);
     loop {
-        let render = gb.tick();
+        let info = gb.step();
 
-        gbd.check_exec_breakpoints(gb.get_pc());
-        if let Some(addr) = gb.get_read() {
-            gbd.check_read_breakpoints(addr);
-        }
-        if let Some(addr) = gb.get_write() {
-            gbd.check_write_breakpoints(addr);
-        }
+        gbd.check(gb, &info);
         if gbd.is_debugging() {
+            // Ctrl-C at the prompt can only exit on the spot, so nothing is left unsaved while waiting there
+            if gb.is_battery_dirty() {
+                write_battery_save(gb, save_path);
+            }
             gbd.print_info();
             let quit = gbd.debugloop(gb);
             if quit {
+                if gb.is_battery_dirty() {
+                    write_battery_save(gb, save_path);
+                }
                 exit(0);
             }
         }
 
-        if render {
+        // A locked CPU may never render another frame, as the screen could be off
+        if info.frame_ready || gb.is_locked() {
             break;
         }
     }
 
-    if gb.is_battery_dirty() {
-        write_battery_save(gb, &gamename);
+    // Games often write their save a little at a time, so wait until they've finished rather than after every frame
+    if gb.battery_dirty_since(BATTERY_FLUSH_FRAMES) {
+        write_battery_save(gb, save_path);
     }
 }
 
-fn write_battery_save(gb: &mut Cpu, gamename: &str) {
+fn write_battery_save(gb: &mut Cpu, path: &Path) {
     if gb.has_battery() {
         let battery_data = gb.get_battery_data();
-        let mut filename = gamename.to_owned();
-        filename.push_str(".sav");
-
-        let mut file = OpenOptions::new().write(true).create(true).open(filename).expect("Error opening save file");
-        file.write(battery_data).unwrap();
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(path).expect("Error opening save file");
+        file.write_all(&battery_data).unwrap();
         gb.clean_battery();
     }
 }
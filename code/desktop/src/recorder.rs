@@ -0,0 +1,105 @@
+use gb_core::utils::{CLOCK_SPEED, SCREEN_HEIGHT, SCREEN_WIDTH, T_CYCLES_PER_FRAME};
+
+use gif::{Encoder, EncodingError, Frame, Repeat};
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/*
+ * GIF Recording
+ *
+ * GIFs time their frames in hundredths of a second, and most viewers slow
+ * down anything shorter than two of them, so only every other frame is kept.
+ * Each is given however long it takes for the GIF to catch back up with the
+ * game, which alternates between 3 and 4 hundredths.
+ *
+ * No Game Boy frame can have more than 56 colors, so each one gets its own
+ * exact palette, rather than having its colors approximated. Only frames
+ * with more than a GIF's 256 colors, which the game can't draw itself, fall
+ * back to approximating them.
+ */
+
+// Frames are kept once every this many
+const FRAME_SKIP: u32 = 2;
+
+pub struct GifRecorder {
+    encoder: Encoder<BufWriter<File>>,
+    path: PathBuf,
+    frames: u32,
+    // How long the frames written so far last in total, in hundredths of a second
+    written_time: u64,
+}
+
+impl GifRecorder {
+    pub fn start(path: &Path) -> Result<Self, EncodingError> {
+        let file = BufWriter::new(File::create(path)?);
+        let mut encoder = Encoder::new(file, SCREEN_WIDTH as u16, SCREEN_HEIGHT as u16, &[])?;
+        encoder.set_repeat(Repeat::Infinite)?;
+        Ok(Self {
+            encoder,
+            path: path.to_path_buf(),
+            frames: 0,
+            written_time: 0,
+        })
+    }
+
+    /// Adds a frame of RGBA pixels, which is called once per frame the game runs
+    pub fn add_frame(&mut self, pixels: &[u8]) -> Result<(), EncodingError> {
+        self.frames += 1;
+        if !self.frames.is_multiple_of(FRAME_SKIP) {
+            return Ok(());
+        }
+        let game_time = self.frames as u64 * T_CYCLES_PER_FRAME * 100 / CLOCK_SPEED;
+        let delay = game_time - self.written_time;
+        self.written_time = game_time;
+
+        let mut frame = index_colors(pixels);
+        frame.delay = delay as u16;
+        self.encoder.write_frame(&frame)
+    }
+
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get_frames(&self) -> u32 {
+        self.frames
+    }
+
+    /// Finishes the file off, which is needed for it to be a valid GIF
+    pub fn finish(self) -> Result<(), EncodingError> {
+        self.encoder.into_inner()?.flush()?;
+        Ok(())
+    }
+}
+
+// Converts the RGBA pixels into a palette of the colors used and the index of each pixel's color
+fn index_colors(pixels: &[u8]) -> Frame<'static> {
+    const MAX_COLORS: usize = 256;
+
+    let mut palette = Vec::new();
+    let mut indices = HashMap::new();
+    let mut buffer = Vec::with_capacity(SCREEN_WIDTH * SCREEN_HEIGHT);
+    for pixel in pixels.chunks_exact(4) {
+        let color = [pixel[0], pixel[1], pixel[2]];
+        let idx = *indices.entry(color).or_insert_with(|| {
+            palette.extend_from_slice(&color);
+            palette.len() / 3 - 1
+        });
+        if idx >= MAX_COLORS {
+            let mut pixels = pixels.to_vec();
+            return Frame::from_rgba_speed(SCREEN_WIDTH as u16, SCREEN_HEIGHT as u16, &mut pixels, 10);
+        }
+        buffer.push(idx as u8);
+    }
+    Frame {
+        width: SCREEN_WIDTH as u16,
+        height: SCREEN_HEIGHT as u16,
+        palette: Some(palette),
+        buffer: Cow::Owned(buffer),
+        ..Frame::default()
+    }
+}
@@ -0,0 +1,198 @@
+use gb_core::cpu::Cpu;
+use gb_core::debug::{name2reg16, name2reg8};
+
+use rhai::{Dynamic, Engine, EvalAltResult, FnPtr, AST};
+
+use std::cell::RefCell;
+use std::mem;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+/*
+ * Debugger Scripts
+ *
+ * Scripts are written in Rhai, and are given these functions on top of the
+ * language's own:
+ *
+ * read(addr)           Returns the byte at that address
+ * write(addr, val)     Writes a byte to that address, as the CPU would
+ * reg(name)            Returns a register, named as for the 'set' command
+ * set_reg(name, val)   Changes a register
+ * on_break(addr, f)    Calls f whenever the PC reaches that address
+ * on_frame(f)          Calls f after every frame
+ *
+ * A hook which returns true stops in the debugger, otherwise the game keeps
+ * running, so a hook can act as a condition for a breakpoint, log values as
+ * they change, or hold a value in place as a cheat would.
+ *
+ * The engine can only reach the game while a script is running, so it's
+ * lent out for each run, and handed back once the script has finished.
+ */
+
+// The hooks which scripts have added
+#[derive(Default)]
+struct Hooks {
+    breakpoints: Vec<(u16, FnPtr)>,
+    frame: Vec<FnPtr>,
+}
+
+pub struct Scripts {
+    engine: Engine,
+    gb: Rc<RefCell<Option<Cpu>>>,
+    hooks: Rc<RefCell<Hooks>>,
+    // Every function scripts have defined, so their hooks can be called later
+    functions: AST,
+}
+
+impl Default for Scripts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scripts {
+    pub fn new() -> Self {
+        let gb = Rc::new(RefCell::new(None));
+        let hooks = Rc::new(RefCell::new(Hooks::default()));
+        let mut engine = Engine::new();
+
+        let lent = gb.clone();
+        engine.register_fn("read", move |addr: i64| -> ScriptResult<i64> {
+            let addr = to_u16(addr)?;
+            with_gb(&lent, |gb| Ok(gb.read_ram(addr) as i64))
+        });
+
+        let lent = gb.clone();
+        engine.register_fn("write", move |addr: i64, val: i64| -> ScriptResult<()> {
+            let addr = to_u16(addr)?;
+            let val = u8::try_from(val).map_err(|_| format!("0x{:x} doesn't fit in a byte", val))?;
+            with_gb(&lent, |gb| {
+                gb.write_ram(addr, val);
+                Ok(())
+            })
+        });
+
+        let lent = gb.clone();
+        engine.register_fn("reg", move |name: &str| -> ScriptResult<i64> {
+            let name = name.to_lowercase();
+            with_gb(&lent, |gb| {
+                if let Some(reg) = name2reg8(&name) {
+                    Ok(gb.get_r8(reg) as i64)
+                } else if let Some(reg) = name2reg16(&name) {
+                    Ok(gb.get_r16(reg) as i64)
+                } else if name == "pc" {
+                    Ok(gb.get_pc() as i64)
+                } else {
+                    Err(format!("Unknown register {}", name).into())
+                }
+            })
+        });
+
+        let lent = gb.clone();
+        engine.register_fn("set_reg", move |name: &str, val: i64| -> ScriptResult<()> {
+            let name = name.to_lowercase();
+            with_gb(&lent, |gb| {
+                if let Some(reg) = name2reg8(&name) {
+                    let val = u8::try_from(val).map_err(|_| format!("0x{:x} doesn't fit in a byte", val))?;
+                    gb.set_r8(reg, val);
+                } else if let Some(reg) = name2reg16(&name) {
+                    gb.set_r16(reg, to_u16(val)?);
+                } else if name == "pc" {
+                    gb.set_pc(to_u16(val)?);
+                } else {
+                    return Err(format!("Unknown register {}", name).into());
+                }
+                Ok(())
+            })
+        });
+
+        let added = hooks.clone();
+        engine.register_fn("on_break", move |addr: i64, f: FnPtr| -> ScriptResult<()> {
+            added.borrow_mut().breakpoints.push((to_u16(addr)?, f));
+            Ok(())
+        });
+
+        let added = hooks.clone();
+        engine.register_fn("on_frame", move |f: FnPtr| {
+            added.borrow_mut().frame.push(f);
+        });
+
+        Self {
+            engine,
+            gb,
+            hooks,
+            functions: AST::empty(),
+        }
+    }
+
+    /// Runs a script file, keeping any hooks it adds
+    pub fn run_file(&mut self, gb: &mut Cpu, filename: &str) -> Result<(), String> {
+        let ast = self.engine.compile_file(PathBuf::from(filename)).map_err(|err| err.to_string())?;
+        self.functions += ast.clone_functions_only();
+        self.lend(gb, |engine, _| engine.run_ast(&ast)).map_err(|err| err.to_string())
+    }
+
+    /// Removes every hook scripts have added
+    pub fn clear_hooks(&mut self) {
+        *self.hooks.borrow_mut() = Hooks::default();
+    }
+
+    pub fn has_break_hook(&self, addr: u16) -> bool {
+        self.hooks.borrow().breakpoints.iter().any(|(bp, _)| *bp == addr)
+    }
+
+    /// Runs the hooks on the given address, returning true if any of them want to stop
+    pub fn run_break_hooks(&mut self, gb: &mut Cpu, addr: u16) -> bool {
+        let hooks: Vec<FnPtr> = self.hooks.borrow().breakpoints.iter()
+            .filter(|(bp, _)| *bp == addr)
+            .map(|(_, f)| f.clone())
+            .collect();
+        self.run_hooks(gb, &hooks)
+    }
+
+    /// Runs the hooks for the end of a frame, returning true if any of them want to stop
+    pub fn run_frame_hooks(&mut self, gb: &mut Cpu) -> bool {
+        let hooks = self.hooks.borrow().frame.clone();
+        self.run_hooks(gb, &hooks)
+    }
+
+    fn run_hooks(&mut self, gb: &mut Cpu, hooks: &[FnPtr]) -> bool {
+        if hooks.is_empty() {
+            return false;
+        }
+        self.lend(gb, |engine, functions| {
+            let mut stop = false;
+            for hook in hooks {
+                match hook.call::<Dynamic>(engine, functions, ()) {
+                    Ok(result) => { stop |= result.as_bool().unwrap_or(false) },
+                    Err(err) => {
+                        println!("Script error in {}: {}", hook.fn_name(), err);
+                        stop = true;
+                    },
+                }
+            }
+            stop
+        })
+    }
+
+    // Hands the game to the script functions for as long as f runs
+    fn lend<T>(&mut self, gb: &mut Cpu, f: impl FnOnce(&Engine, &AST) -> T) -> T {
+        *self.gb.borrow_mut() = Some(mem::take(gb));
+        let result = f(&self.engine, &self.functions);
+        *gb = self.gb.borrow_mut().take().expect("The game wasn't handed back by the script");
+        result
+    }
+}
+
+fn with_gb<T>(gb: &RefCell<Option<Cpu>>, f: impl FnOnce(&mut Cpu) -> ScriptResult<T>) -> ScriptResult<T> {
+    match gb.borrow_mut().as_mut() {
+        Some(gb) => { f(gb) },
+        None => { Err("No game is running".into()) },
+    }
+}
+
+fn to_u16(val: i64) -> ScriptResult<u16> {
+    u16::try_from(val).map_err(|_| format!("0x{:x} isn't a valid address", val).into())
+}
@@ -0,0 +1,31 @@
+use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/*
+ * Signals
+ *
+ * Ctrl-C, closing the terminal, or being killed would normally end the
+ * process on the spot, losing any battery save which hadn't been written out
+ * yet. Instead they're noted here, and the game loop quits as it would from
+ * the Quit hotkey once it sees one. A second signal before then, such as
+ * while waiting at the debugger's prompt, exits straight away.
+ */
+
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Catches the signals which ask the process to stop, which must be done before SDL starts so it doesn't take them
+pub fn catch_signals() {
+    let result = ctrlc::set_handler(|| {
+        if STOP_REQUESTED.swap(true, Ordering::SeqCst) {
+            exit(1);
+        }
+    });
+    if let Err(err) = result {
+        println!("Unable to catch signals, so Ctrl-C may lose unsaved progress: {}", err);
+    }
+}
+
+/// Returns true once a signal has asked the emulator to quit
+pub fn stop_requested() -> bool {
+    STOP_REQUESTED.load(Ordering::SeqCst)
+}
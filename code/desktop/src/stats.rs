@@ -0,0 +1,39 @@
+use gb_core::utils::CLOCK_SPEED;
+
+use std::time::{Duration, Instant};
+
+// How often the statistics are worked out, which is long enough for them to be readable
+const UPDATE_PERIOD: Duration = Duration::from_secs(1);
+
+/// Measures how many frames are drawn each second, and how fast the game runs compared to a real Game Boy
+pub struct SpeedStats {
+    start: Instant,
+    start_cycles: u64,
+    frames: u32,
+}
+
+impl SpeedStats {
+    pub fn new(cycles: u64) -> Self {
+        Self {
+            start: Instant::now(),
+            start_cycles: cycles,
+            frames: 0,
+        }
+    }
+
+    /// Counts a drawn frame, returning the frame rate and the speed as a percentage each time a period has passed
+    pub fn frame_drawn(&mut self, cycles: u64) -> Option<(f32, f32)> {
+        self.frames += 1;
+        let elapsed = self.start.elapsed();
+        if elapsed < UPDATE_PERIOD {
+            return None;
+        }
+        let seconds = elapsed.as_secs_f32();
+        let fps = self.frames as f32 / seconds;
+        // Rewinding goes back in emulated time, which counts as no progress at all
+        let emulated = cycles.saturating_sub(self.start_cycles) as f32 / CLOCK_SPEED as f32;
+        let speed = 100.0 * emulated / seconds;
+        *self = Self::new(cycles);
+        Some((fps, speed))
+    }
+}
@@ -1,22 +1,163 @@
 import init, * as wasm from "./wasm.js"
 
 const SCALE = 3
-const WIDTH = 160
-const HEIGHT = 144
+const CAMERA_WIDTH = 128
+const CAMERA_HEIGHT = 112
 
 let canvas = document.getElementById("canvas")
-canvas.width = WIDTH * SCALE
-canvas.height = HEIGHT * SCALE
 
 let anim_frame = 0
-
-let ctx = canvas.getContext("2d")
-ctx.fillStyle = "#FFFFFF"
-ctx.fillRect(0, 0, canvas.width, canvas.height)
+let webcam = null
+let rom_name = "game"
 
 async function run() {
     await init()
-    let gb = new wasm.GB()
+    let gb = new wasm.GB(canvas)
+    gb.set_scale(SCALE)
+    gb.on_title_loaded(function(title) {
+        document.title = title
+    })
+
+    let ctx = canvas.getContext("2d")
+    ctx.fillStyle = "#FFFFFF"
+    ctx.fillRect(0, 0, canvas.width, canvas.height)
+
+    document.getElementById("smoothing").addEventListener("change", function(e) {
+        gb.set_smoothing(e.target.checked)
+    })
+
+    document.getElementById("speed").addEventListener("change", function(e) {
+        gb.set_speed(Number(e.target.value))
+    })
+
+    let palette_select = document.getElementById("palette")
+    for (let i = 0; gb.get_compat_palette_name(i) !== undefined; i++) {
+        let option = document.createElement("option")
+        option.value = i
+        option.text = gb.get_compat_palette_name(i)
+        palette_select.add(option)
+    }
+    palette_select.addEventListener("change", function() {
+        gb.set_compat_palette(Number(palette_select.value))
+    })
+
+    let display_select = document.getElementById("display")
+    for (let i = 0; gb.get_display_palette_name(i) !== undefined; i++) {
+        let option = document.createElement("option")
+        option.value = i
+        option.text = gb.get_display_palette_name(i)
+        display_select.add(option)
+    }
+    display_select.addEventListener("change", function() {
+        gb.set_display_palette(Number(display_select.value))
+    })
+
+    let layers = document.getElementById("layers")
+    for (let i = 0; gb.get_layer_name(i) !== undefined; i++) {
+        let checkbox = document.createElement("input")
+        checkbox.type = "checkbox"
+        checkbox.id = "layer" + i
+        checkbox.checked = true
+        checkbox.autocomplete = "off"
+        checkbox.addEventListener("change", function() {
+            gb.set_layer_enabled(i, checkbox.checked)
+        })
+        let label = document.createElement("label")
+        label.htmlFor = checkbox.id
+        label.textContent = "Show " + gb.get_layer_name(i)
+        layers.append(checkbox, label)
+    }
+
+    // Tabs link up with any other tab which uses the same channel name
+    let link_mode = document.getElementById("linkmode")
+    let link_url = document.getElementById("linkurl")
+    link_mode.addEventListener("change", function() {
+        link_url.placeholder = link_mode.value == "tab" ? "gb-link" : "ws://localhost:8080"
+    })
+
+    let link_button = document.getElementById("link")
+    link_button.addEventListener("click", function() {
+        if (link_button.textContent == "Disconnect") {
+            gb.disconnect_link()
+            link_button.textContent = "Connect"
+            return
+        }
+        try {
+            if (link_mode.value == "tab") {
+                gb.connect_local_link(link_url.value || link_url.placeholder)
+            } else {
+                gb.connect_link(link_url.value)
+            }
+            link_button.textContent = "Disconnect"
+        } catch (err) {
+            alert("Unable to connect the link cable: " + err)
+        }
+    })
+
+    // States are kept in the browser, so a game can be picked back up after the page is closed
+    let state_slot = document.getElementById("stateslot")
+    document.getElementById("savestate").addEventListener("click", function() {
+        gb.save_state_to_storage(Number(state_slot.value)).catch(function(err) {
+            alert("Unable to save state: " + err)
+        })
+    })
+    document.getElementById("loadstate").addEventListener("click", function() {
+        gb.load_state_from_storage(Number(state_slot.value)).then(function(found) {
+            if (!found) {
+                alert("Slot " + state_slot.value + " is empty")
+            }
+        }).catch(function(err) {
+            alert("Unable to load state: " + err)
+        })
+    })
+
+    // Files are named as the desktop frontend names its slots, so they can be put next to the ROM there
+    document.getElementById("exportstate").addEventListener("click", function() {
+        let blob = new Blob([gb.export_state()], { type: "application/octet-stream" })
+        let link = document.createElement("a")
+        link.href = URL.createObjectURL(blob)
+        link.download = rom_name + ".state" + state_slot.value
+        link.click()
+        // Released once the download has had a chance to start
+        setTimeout(() => URL.revokeObjectURL(link.href), 0)
+    })
+    document.getElementById("importstate").addEventListener("change", async function(e) {
+        let file = e.target.files[0]
+        if (!file) {
+            return
+        }
+        try {
+            gb.import_state(new Uint8Array(await file.arrayBuffer()))
+        } catch (err) {
+            alert("Unable to load state: " + err)
+        }
+        // Cleared so the same file can be picked again
+        e.target.value = ""
+    })
+
+    document.getElementById("webcam").addEventListener("change", async function(e) {
+        if (e.target.checked) {
+            webcam = await start_webcam().catch(function(err) {
+                alert("Unable to access webcam: " + err)
+                e.target.checked = false
+                return null
+            })
+        } else if (webcam) {
+            webcam.srcObject.getTracks().forEach(track => track.stop())
+            webcam = null
+        }
+    })
+
+    setup_touch_controls(gb)
+
+    // Saves are stored a second after the game stops writing them, but the page may be closed before then
+    document.addEventListener("visibilitychange", function() {
+        if (document.visibilityState == "hidden") {
+            gb.flush_battery().catch(function(err) {
+                alert("Unable to store the battery save: " + err)
+            })
+        }
+    })
 
     document.addEventListener("keydown", function(e) {
         gb.press_button(e, true)
@@ -38,38 +179,147 @@ async function run() {
             return
         }
 
+        rom_name = file.name
         let fr = new FileReader()
-        fr.onload = function () {
+        fr.onload = async function () {
             let buffer = fr.result
             const rom = new Uint8Array(buffer)
-            gb.load_rom(rom)
-            let title = gb.get_title()
-            document.title = title
-
-            mainloop(gb)
+            // Anything the previous game saved since it was last stored is kept before it's replaced
+            gb.flush_battery().catch(function(err) {
+                alert("Unable to store the battery save: " + err)
+            })
+            let loading
+            try {
+                loading = gb.load_rom(rom)
+            } catch (err) {
+                alert("Unable to load " + file.name + ": " + err)
+                // The game from before is still loaded, so carry on with it
+                if (anim_frame != 0) {
+                    mainloop(gb)
+                }
+                return
+            }
+            await loading.catch(function(err) {
+                alert("Unable to restore the battery save: " + err)
+            })
+            start_game(gb)
         }
 
         fr.readAsArrayBuffer(file)
     }, false)
+
+    // A page linked as index.html?rom=path/to/game.gb starts that game straight away
+    let rom_url = new URLSearchParams(window.location.search).get("rom")
+    if (rom_url) {
+        rom_name = rom_url.split("/").pop()
+        gb.load_rom_from_url(rom_url).then(function() {
+            start_game(gb)
+        }, function(err) {
+            alert("Unable to load " + rom_url + ": " + err)
+        })
+    }
 }
 
-function mainloop(gb) {
-    while (true) {
-        let draw_time = gb.tick()
-        if (draw_time) {
-            gb.draw_screen()
-            if (SCALE != 1) {
-                let ctx = canvas.getContext('2d')
-                ctx.imageSmoothingEnabled = false
-                ctx.drawImage(canvas, 0, 0, WIDTH, HEIGHT, 0, 0, canvas.width, canvas.height)
-            }
+function start_game(gb) {
+    if (!gb.verify_header_checksum()) {
+        console.warn("The header checksum doesn't match, this ROM may be corrupt")
+    }
+    gb.force_dmg(document.getElementById("dmgmode").checked)
+    document.getElementById("cartinfo").textContent = gb.get_cart_info()
 
-            anim_frame = window.requestAnimationFrame(() => {
-                mainloop(gb)
-            })
-            return
+    mainloop(gb)
+}
+
+// Each finger presses whichever button it's over, so sliding one across the d-pad changes direction
+function setup_touch_controls(gb) {
+    let touch = document.getElementById("touch")
+    let checkbox = document.getElementById("touchcontrols")
+    let held = new Set()
+
+    let press_touched = function(touches) {
+        let touched = new Set()
+        let touched_elements = new Set()
+        for (let t of touches) {
+            let element = document.elementFromPoint(t.clientX, t.clientY)
+            let button = element && element.closest("#touch [data-button]")
+            if (button) {
+                touched_elements.add(button)
+                button.dataset.button.split(" ").forEach(name => touched.add(name))
+            }
+        }
+        for (let name of held) {
+            if (!touched.has(name)) {
+                gb.press_named_button(name, false)
+            }
         }
+        for (let name of touched) {
+            if (!held.has(name)) {
+                gb.press_named_button(name, true)
+            }
+        }
+        held = touched
+        touch.querySelectorAll("[data-button]").forEach(function(button) {
+            button.classList.toggle("held", touched_elements.has(button))
+        })
+    }
+
+    for (let type of ["touchstart", "touchmove", "touchend", "touchcancel"]) {
+        touch.addEventListener(type, function(e) {
+            e.preventDefault()
+            press_touched(e.touches)
+        }, { passive: false })
+    }
+
+    // Shown by default on devices which are mainly used by touch
+    checkbox.checked = window.matchMedia("(pointer: coarse)").matches
+    touch.hidden = !checkbox.checked
+    checkbox.addEventListener("change", function() {
+        touch.hidden = !checkbox.checked
+        press_touched([])
+    })
+}
+
+async function start_webcam() {
+    let video = document.createElement("video")
+    video.srcObject = await navigator.mediaDevices.getUserMedia({ video: true })
+    await video.play()
+    return video
+}
+
+// Passes the current webcam frame to the Game Boy Camera as grayscale
+function send_webcam_frame(gb) {
+    let cam_canvas = document.createElement("canvas")
+    cam_canvas.width = CAMERA_WIDTH
+    cam_canvas.height = CAMERA_HEIGHT
+    let cam_ctx = cam_canvas.getContext("2d")
+    cam_ctx.drawImage(webcam, 0, 0, CAMERA_WIDTH, CAMERA_HEIGHT)
+
+    let rgba = cam_ctx.getImageData(0, 0, CAMERA_WIDTH, CAMERA_HEIGHT).data
+    let gray = new Uint8Array(CAMERA_WIDTH * CAMERA_HEIGHT)
+    for (let i = 0; i < gray.length; i++) {
+        gray[i] = (rgba[4 * i] + rgba[4 * i + 1] + rgba[4 * i + 2]) / 3
+    }
+    gb.set_camera_image(gray)
+}
+
+function mainloop(gb) {
+    if (webcam) {
+        send_webcam_frame(gb)
+    }
+
+    gb.run_frame()
+    let locked_opcode = gb.get_locked_opcode()
+    if (locked_opcode !== undefined) {
+        let opcode = locked_opcode.toString(16).toUpperCase().padStart(2, "0")
+        alert("The game has crashed, the CPU locked up after running invalid opcode $" + opcode)
+        return
     }
+    // Unchanged frames are skipped, leaving the picture as it was
+    gb.draw_screen()
+
+    anim_frame = window.requestAnimationFrame(() => {
+        mainloop(gb)
+    })
 }
 
 run().catch(console.error)
@@ -0,0 +1,45 @@
+// The page side of worker.js, which only passes input along and shows what comes back
+const SCALE = 3
+
+let canvas = document.getElementById("canvas")
+let offscreen = canvas.transferControlToOffscreen()
+let worker = new Worker("worker.js", { type: "module" })
+worker.postMessage({ type: "init", canvas: offscreen, scale: SCALE }, [offscreen])
+
+worker.onmessage = function(e) {
+    let msg = e.data
+    if (msg.type == "loaded") {
+        document.title = msg.title
+    } else if (msg.type == "error") {
+        alert("Unable to load the game: " + msg.message)
+    } else if (msg.type == "save_failed") {
+        alert("Unable to store the battery save: " + msg.message)
+    } else if (msg.type == "crashed") {
+        let opcode = msg.opcode.toString(16).toUpperCase().padStart(2, "0")
+        alert("The game has crashed, the CPU locked up after running invalid opcode $" + opcode)
+    }
+}
+
+document.getElementById("fileinput").addEventListener("change", async function(e) {
+    let file = e.target.files[0]
+    if (!file) {
+        alert("Failed to read file")
+        return
+    }
+    let data = await file.arrayBuffer()
+    worker.postMessage({ type: "rom", data: data }, [data])
+})
+
+document.addEventListener("keydown", function(e) {
+    worker.postMessage({ type: "key", key: e.key, pressed: true })
+})
+
+document.addEventListener("keyup", function(e) {
+    worker.postMessage({ type: "key", key: e.key, pressed: false })
+})
+
+document.addEventListener("visibilitychange", function() {
+    if (document.visibilityState == "hidden") {
+        worker.postMessage({ type: "flush" })
+    }
+})
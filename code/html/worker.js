@@ -0,0 +1,82 @@
+import init, * as wasm from "./wasm.js"
+
+/*
+ * Runs the emulator inside a Web Worker, drawing to a canvas the page has
+ * handed over with transferControlToOffscreen, so a slow frame never holds
+ * up the page itself. The page talks to it with these messages:
+ *
+ * { type: "init", canvas, scale }      The OffscreenCanvas to draw on, sent first
+ * { type: "rom", data }                An ArrayBuffer of the game to start
+ * { type: "key", key, pressed }        A KeyboardEvent.key going down or up
+ * { type: "flush" }                    Stores the battery save, such as when the page is hidden
+ *
+ * It replies with { type: "loaded", title } once a game has started,
+ * { type: "error", message } if a game can't be loaded,
+ * { type: "save_failed", message } if the battery save can't be stored, and
+ * { type: "crashed", opcode } if the CPU locks up.
+ */
+
+// Workers don't always have requestAnimationFrame, in which case a timer is close enough
+const next_frame = self.requestAnimationFrame
+    ? (f) => self.requestAnimationFrame(f)
+    : (f) => setTimeout(f, 1000 / 60)
+
+let gb_ready = null
+let running = false
+
+self.onmessage = async function(e) {
+    let msg = e.data
+    if (msg.type == "init") {
+        gb_ready = init().then(function() {
+            let gb = wasm.GB.with_offscreen_canvas(msg.canvas)
+            gb.set_scale(msg.scale)
+            gb.on_title_loaded(function(title) {
+                self.postMessage({ type: "loaded", title: title })
+            })
+            return gb
+        })
+        return
+    }
+
+    let gb = await gb_ready
+    switch (msg.type) {
+        case "rom":
+            // Anything the previous game saved since it was last stored is kept before it's replaced
+            flush_battery(gb)
+            try {
+                await gb.load_rom(new Uint8Array(msg.data))
+            } catch (err) {
+                self.postMessage({ type: "error", message: String(err) })
+                break
+            }
+            if (!running) {
+                running = true
+                mainloop(gb)
+            }
+            break
+        case "key":
+            gb.press_key(msg.key, msg.pressed)
+            break
+        case "flush":
+            flush_battery(gb)
+            break
+    }
+}
+
+function flush_battery(gb) {
+    gb.flush_battery().catch(function(err) {
+        self.postMessage({ type: "save_failed", message: String(err) })
+    })
+}
+
+function mainloop(gb) {
+    gb.run_frame()
+    let locked_opcode = gb.get_locked_opcode()
+    if (locked_opcode !== undefined) {
+        self.postMessage({ type: "crashed", opcode: locked_opcode })
+        running = false
+        return
+    }
+    gb.draw_screen()
+    next_frame(() => mainloop(gb))
+}
@@ -0,0 +1,98 @@
+use gb_core::utils::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::Clamped;
+use web_sys::{CanvasRenderingContext2d, HtmlCanvasElement, ImageData, OffscreenCanvas, OffscreenCanvasRenderingContext2d};
+
+/*
+ * Display
+ *
+ * Each frame is drawn at the Game Boy's size to an OffscreenCanvas, then
+ * scaled up onto the canvas being shown. That's either a canvas on the page,
+ * or an OffscreenCanvas handed over by transferControlToOffscreen, in which
+ * case nothing here needs the window or document, and the emulator can run
+ * entirely inside a Web Worker.
+ */
+
+// The canvas being shown
+enum Target {
+    Page(HtmlCanvasElement, CanvasRenderingContext2d),
+    Offscreen(OffscreenCanvas, OffscreenCanvasRenderingContext2d),
+}
+
+pub struct Display {
+    target: Target,
+    screen: OffscreenCanvas,
+    screen_ctx: OffscreenCanvasRenderingContext2d,
+    smoothing: bool,
+}
+
+impl Display {
+    pub fn for_page(canvas: HtmlCanvasElement) -> Result<Self, JsValue> {
+        let ctx = canvas.get_context("2d")?.ok_or("The canvas doesn't support 2D drawing")?;
+        let ctx = ctx.dyn_into::<CanvasRenderingContext2d>()?;
+        Self::with_target(Target::Page(canvas, ctx))
+    }
+
+    pub fn for_offscreen(canvas: OffscreenCanvas) -> Result<Self, JsValue> {
+        let ctx = offscreen_context_2d(&canvas)?;
+        Self::with_target(Target::Offscreen(canvas, ctx))
+    }
+
+    fn with_target(target: Target) -> Result<Self, JsValue> {
+        let screen = OffscreenCanvas::new(SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32)?;
+        let screen_ctx = offscreen_context_2d(&screen)?;
+        Ok(Self { target, screen, screen_ctx, smoothing: false })
+    }
+
+    /// Resizes the canvas to this many times the Game Boy's screen
+    pub fn set_scale(&mut self, scale: u32) {
+        let scale = scale.max(1);
+        let (width, height) = (SCREEN_WIDTH as u32 * scale, SCREEN_HEIGHT as u32 * scale);
+        match &self.target {
+            Target::Page(canvas, _) => {
+                canvas.set_width(width);
+                canvas.set_height(height);
+            },
+            Target::Offscreen(canvas, _) => {
+                canvas.set_width(width);
+                canvas.set_height(height);
+            },
+        }
+        // Resizing clears the canvas, and the frame may not change again for a while
+        self.present();
+    }
+
+    pub fn set_smoothing(&mut self, smoothing: bool) {
+        self.smoothing = smoothing;
+        self.present();
+    }
+
+    /// Draws a frame of RGBA pixels the size of the Game Boy's screen
+    pub fn draw_frame(&self, rgba: &[u8]) {
+        let img_data = ImageData::new_with_u8_clamped_array_and_sh(Clamped(rgba), SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32).unwrap();
+        self.screen_ctx.put_image_data(&img_data, 0.0, 0.0).unwrap();
+        self.present();
+    }
+
+    // Scales the last frame drawn up to fill the canvas being shown
+    fn present(&self) {
+        match &self.target {
+            Target::Page(canvas, ctx) => {
+                ctx.set_image_smoothing_enabled(self.smoothing);
+                let (width, height) = (canvas.width() as f64, canvas.height() as f64);
+                ctx.draw_image_with_offscreen_canvas_and_dw_and_dh(&self.screen, 0.0, 0.0, width, height).unwrap();
+            },
+            Target::Offscreen(canvas, ctx) => {
+                ctx.set_image_smoothing_enabled(self.smoothing);
+                let (width, height) = (canvas.width() as f64, canvas.height() as f64);
+                ctx.draw_image_with_offscreen_canvas_and_dw_and_dh(&self.screen, 0.0, 0.0, width, height).unwrap();
+            },
+        }
+    }
+}
+
+fn offscreen_context_2d(canvas: &OffscreenCanvas) -> Result<OffscreenCanvasRenderingContext2d, JsValue> {
+    let ctx = canvas.get_context("2d")?.ok_or("The canvas doesn't support 2D drawing")?;
+    ctx.dyn_into::<OffscreenCanvasRenderingContext2d>().map_err(JsValue::from)
+}
@@ -0,0 +1,88 @@
+use js_sys::Function;
+use wasm_bindgen::prelude::*;
+
+/*
+ * Events
+ *
+ * Rather than polling the emulator after every frame, a page can register
+ * callbacks to hear when something happens:
+ *
+ * on_frame_ready       A frame has finished and can be drawn
+ * on_battery_dirty     The game has written to its battery save since it was last stored
+ * on_serial_byte       The game has sent a byte over the serial port
+ * on_title_loaded      A game has been loaded, with its title
+ *
+ * Events happen in the middle of tick or run_frame, while the GB object is
+ * busy, so calling back into it then would fail. Instead each callback is
+ * queued as a microtask, which runs once the current call has returned.
+ */
+
+#[wasm_bindgen(typescript_custom_section)]
+const CALLBACK_TYPES: &str = r#"
+export type FrameReadyCallback = () => void;
+export type BatteryDirtyCallback = () => void;
+export type SerialByteCallback = (byte: number) => void;
+export type TitleLoadedCallback = (title: string) => void;
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "FrameReadyCallback")]
+    pub type FrameReadyCallback;
+
+    #[wasm_bindgen(typescript_type = "BatteryDirtyCallback")]
+    pub type BatteryDirtyCallback;
+
+    #[wasm_bindgen(typescript_type = "SerialByteCallback")]
+    pub type SerialByteCallback;
+
+    #[wasm_bindgen(typescript_type = "TitleLoadedCallback")]
+    pub type TitleLoadedCallback;
+
+    // Global in both the page and workers
+    #[wasm_bindgen(js_name = queueMicrotask)]
+    fn queue_microtask(callback: &Function);
+}
+
+#[derive(Default)]
+pub struct Events {
+    pub frame_ready: Option<Function>,
+    pub battery_dirty: Option<Function>,
+    pub serial_byte: Option<Function>,
+    pub title_loaded: Option<Function>,
+}
+
+impl Events {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn frame_ready(&self) {
+        dispatch(&self.frame_ready, None);
+    }
+
+    pub fn battery_dirty(&self) {
+        dispatch(&self.battery_dirty, None);
+    }
+
+    pub fn serial_byte(&self, byte: u8) {
+        dispatch(&self.serial_byte, Some(JsValue::from(byte)));
+    }
+
+    pub fn title_loaded(&self, title: &str) {
+        dispatch(&self.title_loaded, Some(JsValue::from_str(title)));
+    }
+
+    pub fn wants_serial(&self) -> bool {
+        self.serial_byte.is_some()
+    }
+}
+
+fn dispatch(callback: &Option<Function>, arg: Option<JsValue>) {
+    if let Some(callback) = callback {
+        match arg {
+            Some(arg) => { queue_microtask(&callback.bind1(&JsValue::NULL, &arg).unchecked_into()) },
+            None => { queue_microtask(callback) },
+        }
+    }
+}
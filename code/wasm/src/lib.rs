@@ -1,73 +1,513 @@
+mod display;
+mod events;
+mod link;
+mod storage;
+
+use crate::display::Display;
+use crate::events::{BatteryDirtyCallback, Events, FrameReadyCallback, SerialByteCallback, TitleLoadedCallback};
+use crate::link::LinkCable;
+use crate::storage::{BATTERY_STORE, STATE_STORE};
+
+use gb_core::cart::camera::{CameraSource, CAMERA_HEIGHT, CAMERA_WIDTH};
+use gb_core::cart::HardwareMode;
 use gb_core::cpu::Cpu;
 use gb_core::io::Buttons;
-use gb_core::utils::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use gb_core::ppu::compat::CompatPalette;
+use gb_core::ppu::display::DisplayPalette;
+use gb_core::ppu::Layer;
+use gb_core::rewind::Rewind;
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
 
-use js_sys::Uint8Array;
+use js_sys::{Function, Promise, Uint8Array};
 use wasm_bindgen::prelude::*;
-use wasm_bindgen::Clamped;
-use web_sys::{CanvasRenderingContext2d, HtmlCanvasElement, ImageData, KeyboardEvent};
+use wasm_bindgen_futures::{future_to_promise, JsFuture};
+use web_sys::{HtmlCanvasElement, KeyboardEvent, OffscreenCanvas, Response};
 
+// The global fetch, which both the page and workers have
+#[wasm_bindgen]
+extern "C" {
+    fn fetch(url: &str) -> Promise;
+}
+
+// Even the smallest cartridges have two 16 KiB banks
+const MIN_ROM_SIZE: usize = 0x8000;
+
+// The fastest run_frame will go, as each call runs this many frames before returning
+const MAX_SPEED: f32 = 8.0;
+
+// How long the game must stop writing to its save before it's stored, about a second
+const BATTERY_FLUSH_FRAMES: u32 = 60;
+
+// A fixed picture for the Game Boy Camera to see
+struct StillImage([u8; CAMERA_WIDTH * CAMERA_HEIGHT]);
+
+impl CameraSource for StillImage {
+    fn capture(&mut self) -> [u8; CAMERA_WIDTH * CAMERA_HEIGHT] {
+        self.0
+    }
+}
+
+// A state fetched from storage, waiting to be loaded between instructions
+struct PendingState {
+    data: Vec<u8>,
+    resolve: Function,
+    reject: Function,
+}
+
+// The game and its history are shared with callbacks, such as a finished fetch, which may load a new game
 #[wasm_bindgen]
 pub struct GB {
-    cpu: Cpu,
-    ctx: CanvasRenderingContext2d,
+    cpu: Rc<RefCell<Cpu>>,
+    display: Display,
+    tilt: (f32, f32),
+    rewind: Rc<RefCell<Rewind>>,
+    rewinding: bool,
+    pending_state: Rc<RefCell<Option<PendingState>>>,
+    events: Rc<RefCell<Events>>,
+    battery_dirty: bool,
+    // Set while a battery save is being written, so the next frame doesn't start writing it again
+    battery_flushing: Rc<Cell<bool>>,
+    speed: f32,
+    frame_credit: f32,
 }
 
 #[wasm_bindgen]
 impl GB {
+    /// Draws the game onto the given canvas, stretched to whatever size it is until set_scale is called
     #[wasm_bindgen(constructor)]
-    pub fn new() -> Result<GB, JsValue> {
-        let cpu = Cpu::new();
+    pub fn new(canvas: HtmlCanvasElement) -> Result<GB, JsValue> {
+        Ok(GB::with_display(Display::for_page(canvas)?))
+    }
+
+    /// Draws the game onto a canvas which has been handed to a Web Worker, so the whole emulator can
+    /// run inside the worker without touching the page
+    #[wasm_bindgen]
+    pub fn with_offscreen_canvas(canvas: OffscreenCanvas) -> Result<GB, JsValue> {
+        Ok(GB::with_display(Display::for_offscreen(canvas)?))
+    }
 
-        let document = web_sys::window().unwrap().document().unwrap();
-        let canvas = document.get_element_by_id("canvas").unwrap();
-        let canvas: HtmlCanvasElement = canvas.dyn_into::<HtmlCanvasElement>()
-            .map_err(|_| ())
-            .unwrap();
+    fn with_display(display: Display) -> GB {
+        GB {
+            cpu: Rc::new(RefCell::new(Cpu::new())),
+            display,
+            tilt: (0.0, 0.0),
+            rewind: Rc::new(RefCell::new(Rewind::new())),
+            rewinding: false,
+            pending_state: Rc::new(RefCell::new(None)),
+            events: Rc::new(RefCell::new(Events::new())),
+            battery_dirty: false,
+            battery_flushing: Rc::new(Cell::new(false)),
+            speed: 1.0,
+            frame_credit: 0.0,
+        }
+    }
+
+    /// Plugs a link cable into the serial port which talks to another player through a WebSocket relay
+    #[wasm_bindgen]
+    pub fn connect_link(&mut self, url: &str) -> Result<(), JsValue> {
+        let link = LinkCable::connect_websocket(url)?;
+        self.cpu.borrow_mut().set_serial_device(Some(Box::new(link)));
+        Ok(())
+    }
 
-        let ctx = canvas.get_context("2d")
-            .unwrap()
-            .unwrap()
-            .dyn_into::<CanvasRenderingContext2d>()
-            .unwrap();
+    /// Plugs a link cable into the serial port which talks to another tab in this browser that has
+    /// connected with the same name, without needing a server
+    #[wasm_bindgen]
+    pub fn connect_local_link(&mut self, name: &str) -> Result<(), JsValue> {
+        let link = LinkCable::connect_broadcast(name)?;
+        self.cpu.borrow_mut().set_serial_device(Some(Box::new(link)));
+        Ok(())
+    }
 
-        let gb = GB { cpu, ctx };
-        Ok(gb)
+    #[wasm_bindgen]
+    pub fn disconnect_link(&mut self) {
+        self.cpu.borrow_mut().set_serial_device(None);
+    }
+
+    /// Returns the current state as bytes, in the same format as the desktop frontend's state files
+    #[wasm_bindgen]
+    pub fn export_state(&self) -> Uint8Array {
+        Uint8Array::from(self.cpu.borrow().save_state().as_slice())
+    }
+
+    /// Loads a state from bytes, such as one exported earlier or saved by the desktop frontend
+    #[wasm_bindgen]
+    pub fn import_state(&mut self, data: Uint8Array) -> Result<(), JsValue> {
+        self.cpu.borrow_mut().load_state(&data.to_vec()).map_err(|err| JsValue::from_str(&err.to_string()))
+    }
+
+    #[wasm_bindgen]
+    pub fn force_dmg(&mut self, forced: bool) {
+        let mode = if forced { Some(HardwareMode::Dmg) } else { None };
+        self.cpu.borrow_mut().set_hardware_mode(mode);
+    }
+
+    /// Describes the cartridge header, one field per line
+    #[wasm_bindgen]
+    pub fn get_cart_info(&self) -> String {
+        self.cpu.borrow().cart_info().to_string()
+    }
+
+    #[wasm_bindgen]
+    pub fn get_compat_palette_name(&self, index: usize) -> Option<String> {
+        CompatPalette::ALL.get(index).map(|palette| palette.get_name().to_string())
+    }
+
+    #[wasm_bindgen]
+    pub fn get_display_palette_name(&self, index: usize) -> Option<String> {
+        DisplayPalette::ALL.get(index).map(|palette| palette.get_name().to_string())
+    }
+
+    #[wasm_bindgen]
+    pub fn get_layer_name(&self, index: usize) -> Option<String> {
+        Layer::ALL.get(index).map(|layer| layer.get_name().to_string())
+    }
+
+    /// Returns the screen as one palette index per pixel, a quarter of the size of the RGBA frame
+    #[wasm_bindgen]
+    pub fn get_indexed_frame(&self) -> Vec<u8> {
+        self.cpu.borrow().render_indexed().to_vec()
+    }
+
+    /// Returns the tile data in VRAM as an RGBA image, 128 pixels wide and 192 tall
+    #[wasm_bindgen]
+    pub fn get_tileset(&self) -> Vec<u8> {
+        self.cpu.borrow().render_tileset()
+    }
+
+    /// Returns background map 0 or 1 as a 256x256 RGBA image
+    #[wasm_bindgen]
+    pub fn get_tilemap(&self, map_index: u8) -> Vec<u8> {
+        self.cpu.borrow().render_tilemap(map_index).to_vec()
+    }
+
+    /// Returns the screen's position within the background map, as [SCX, SCY]
+    #[wasm_bindgen]
+    pub fn get_viewport(&self) -> Vec<u8> {
+        let viewport = self.cpu.borrow().get_viewport();
+        vec![viewport.x, viewport.y]
     }
 
     #[wasm_bindgen]
     pub fn get_title(&self) -> String {
-        self.cpu.get_title().to_string()
+        self.cpu.borrow().get_title().to_string()
     }
 
+    /// Returns the invalid opcode which crashed the game, if any
     #[wasm_bindgen]
-    pub fn load_rom(&mut self, data: Uint8Array) {
+    pub fn get_locked_opcode(&self) -> Option<u8> {
+        self.cpu.borrow().get_locked_opcode()
+    }
+
+    /// Loads a game, returning a Promise which resolves once its battery save has been fetched from the
+    /// browser's storage, to whether there was one. The game shouldn't be run until then.
+    /// Throws if the game can't be run, keeping whatever was loaded before.
+    #[wasm_bindgen]
+    pub fn load_rom(&mut self, data: Uint8Array) -> Result<Promise, JsValue> {
         let mut rom: Vec<u8> = Vec::new();
 
         for i in 0..data.byte_length() {
             rom.push(data.get_index(i));
         }
-        self.cpu.load_rom(&rom);
+        start_game(&self.cpu, &self.rewind, &self.events, &rom)
+    }
+
+    /// Fetches a game and loads it, returning a Promise which resolves as load_rom's does, or rejects if
+    /// the game couldn't be fetched or run
+    #[wasm_bindgen]
+    pub fn load_rom_from_url(&self, url: &str) -> Promise {
+        let cpu = self.cpu.clone();
+        let rewind = self.rewind.clone();
+        let events = self.events.clone();
+        let url = url.to_string();
+        future_to_promise(async move {
+            let response: Response = JsFuture::from(fetch(&url)).await?.dyn_into()?;
+            if !response.ok() {
+                return Err(JsValue::from_str(&format!("Unable to fetch {}: {} {}", url, response.status(), response.status_text())));
+            }
+            let buffer = JsFuture::from(response.array_buffer()?).await?;
+            let rom = Uint8Array::new(&buffer).to_vec();
+            if rom.len() < MIN_ROM_SIZE {
+                return Err(JsValue::from_str(&format!("{} is too small to be a Game Boy ROM", url)));
+            }
+            JsFuture::from(start_game(&cpu, &rewind, &events, &rom)?).await
+        })
+    }
+
+    /// Stores the battery save in the browser if the game has written to it since it was last stored,
+    /// returning a Promise which resolves once it's written, or straight away if there was nothing new.
+    /// If the write fails, the save is still counted as unstored and will be tried again.
+    #[wasm_bindgen]
+    pub fn flush_battery(&mut self) -> Promise {
+        if !self.cpu.borrow().is_battery_dirty() || !self.cpu.borrow().has_battery() {
+            return Promise::resolve(&JsValue::UNDEFINED);
+        }
+        let data = self.cpu.borrow().get_battery_data();
+        let write = storage::put(BATTERY_STORE, &game_key(&self.cpu.borrow()), &data);
+        let cpu = self.cpu.clone();
+        let flushing = self.battery_flushing.clone();
+        flushing.set(true);
+        future_to_promise(async move {
+            let written = JsFuture::from(write).await;
+            flushing.set(false);
+            written?;
+            // The game may have written again while this was being stored, which still needs storing in turn
+            let mut cpu = cpu.borrow_mut();
+            if cpu.get_battery_data() == data {
+                cpu.clean_battery();
+            }
+            Ok(JsValue::UNDEFINED)
+        })
+    }
+
+    /// Calls back whenever a frame is ready to draw, or stops calling back if given undefined
+    #[wasm_bindgen]
+    pub fn on_frame_ready(&mut self, callback: Option<FrameReadyCallback>) {
+        self.events.borrow_mut().frame_ready = callback.map(JsCast::unchecked_into);
+    }
+
+    /// Calls back when the game first writes to its battery save after it was last stored
+    #[wasm_bindgen]
+    pub fn on_battery_dirty(&mut self, callback: Option<BatteryDirtyCallback>) {
+        self.events.borrow_mut().battery_dirty = callback.map(JsCast::unchecked_into);
+    }
+
+    /// Calls back with each byte the game sends over the serial port
+    #[wasm_bindgen]
+    pub fn on_serial_byte(&mut self, callback: Option<SerialByteCallback>) {
+        self.events.borrow_mut().serial_byte = callback.map(JsCast::unchecked_into);
+    }
+
+    /// Calls back with the game's title whenever one is loaded
+    #[wasm_bindgen]
+    pub fn on_title_loaded(&mut self, callback: Option<TitleLoadedCallback>) {
+        self.events.borrow_mut().title_loaded = callback.map(JsCast::unchecked_into);
     }
 
     #[wasm_bindgen]
     pub fn press_button(&mut self, event: KeyboardEvent, pressed: bool) {
-        let key = event.key();
-        if let Some(button) = key2btn(&key) {
-            self.cpu.press_button(button, pressed);
+        self.press_key(&event.key(), pressed);
+    }
+
+    /// Handles a key by its KeyboardEvent.key name, for callers such as workers which only have the name
+    #[wasm_bindgen]
+    pub fn press_key(&mut self, key: &str, pressed: bool) {
+        if key == "r" {
+            self.rewinding = pressed;
+        } else if let Some(button) = key2btn(key) {
+            self.cpu.borrow_mut().press_button(button, pressed);
+        } else if let Some(tilt) = key2tilt(key, self.tilt, pressed) {
+            self.tilt = tilt;
+            self.cpu.borrow_mut().set_tilt(tilt.0, tilt.1);
+        }
+    }
+
+    /// Presses or releases a button by its name, such as "A" or "Up", for the on-screen controls
+    #[wasm_bindgen]
+    pub fn press_named_button(&mut self, name: &str, pressed: bool) {
+        if let Some(button) = name2btn(name) {
+            self.cpu.borrow_mut().press_button(button, pressed);
+        }
+    }
+
+    /// Saves the current state to a slot in the browser's storage, returning a Promise which resolves once it's written
+    #[wasm_bindgen]
+    pub fn save_state_to_storage(&self, slot: u32) -> Promise {
+        storage::put(STATE_STORE, &self.storage_key(slot), &self.cpu.borrow().save_state())
+    }
+
+    /// Loads the state in a slot from the browser's storage, returning a Promise which resolves once it has been
+    /// loaded, to false if the slot was empty
+    #[wasm_bindgen]
+    pub fn load_state_from_storage(&self, slot: u32) -> Promise {
+        let key = self.storage_key(slot);
+        let pending = self.pending_state.clone();
+        Promise::new(&mut |resolve, reject| {
+            let pending = pending.clone();
+            let found_reject = reject.clone();
+            storage::get(STATE_STORE, &key, reject, move |data| {
+                match data {
+                    Some(data) => { *pending.borrow_mut() = Some(PendingState { data, resolve, reject: found_reject }) },
+                    None => { let _ = resolve.call1(&JsValue::NULL, &JsValue::FALSE); },
+                }
+            });
+        })
+    }
+
+    #[wasm_bindgen]
+    pub fn verify_header_checksum(&self) -> bool {
+        self.cpu.borrow().verify_header_checksum()
+    }
+
+    /// Sets the picture seen by the Game Boy Camera, as 128x112 grayscale bytes
+    #[wasm_bindgen]
+    pub fn set_camera_image(&mut self, data: Uint8Array) {
+        let mut image = [0; CAMERA_WIDTH * CAMERA_HEIGHT];
+        let len = image.len().min(data.length() as usize);
+        data.subarray(0, len as u32).copy_to(&mut image[..len]);
+        self.cpu.borrow_mut().set_camera_source(Some(Box::new(StillImage(image))));
+    }
+
+    /// Colorizes DMG games with the palette at the given index, or shows them in grayscale if out of range
+    #[wasm_bindgen]
+    pub fn set_compat_palette(&mut self, index: usize) {
+        self.cpu.borrow_mut().set_compat_palette(CompatPalette::ALL.get(index).copied());
+    }
+
+    /// Shows the DMG shades using the built in palette at the given index
+    #[wasm_bindgen]
+    pub fn set_display_palette(&mut self, index: usize) {
+        if let Some(palette) = DisplayPalette::ALL.get(index) {
+            self.cpu.borrow_mut().set_palette(palette.get_colors());
         }
     }
 
+    /// Sets how many frames each run_frame call runs, such as 4.0 to fast forward or 0.5 to run a frame every
+    /// other call, between 0 (paused) and 8
+    #[wasm_bindgen]
+    pub fn set_speed(&mut self, speed: f32) {
+        self.speed = if speed.is_nan() { 1.0 } else { speed.clamp(0.0, MAX_SPEED) };
+        // A leftover part of a frame from a faster speed shouldn't be run all at once
+        self.frame_credit = self.frame_credit.min(1.0);
+    }
+
+    /// Resizes the canvas to this many times the Game Boy's screen
+    #[wasm_bindgen]
+    pub fn set_scale(&mut self, scale: u32) {
+        self.display.set_scale(scale);
+    }
+
+    /// Blends neighbouring pixels as the screen is scaled up, rather than keeping them sharp
+    #[wasm_bindgen]
+    pub fn set_smoothing(&mut self, smoothing: bool) {
+        self.display.set_smoothing(smoothing);
+    }
+
+    /// Hides or shows the background, window, or sprites, by their index in the layer list
+    #[wasm_bindgen]
+    pub fn set_layer_enabled(&mut self, index: usize, enabled: bool) {
+        if let Some(&layer) = Layer::ALL.get(index) {
+            self.cpu.borrow_mut().set_layer_enabled(layer, enabled);
+        }
+    }
+
+    /// Runs one instruction, returning true once a frame is ready to draw.
+    /// While R is held, it instead steps back to the previous snapshot, which is always ready to draw.
     #[wasm_bindgen]
     pub fn tick(&mut self) -> bool {
-        self.cpu.tick()
+        let pending = self.pending_state.borrow_mut().take();
+        if let Some(pending) = pending {
+            match self.cpu.borrow_mut().load_state(&pending.data) {
+                Ok(()) => { let _ = pending.resolve.call1(&JsValue::NULL, &JsValue::TRUE); },
+                Err(err) => { let _ = pending.reject.call1(&JsValue::NULL, &JsValue::from_str(&err.to_string())); },
+            }
+        }
+        if self.rewinding {
+            self.rewind.borrow_mut().step_back(&mut self.cpu.borrow_mut());
+            self.events.borrow().frame_ready();
+            return true;
+        }
+        let draw_time = self.cpu.borrow_mut().tick();
+        if draw_time {
+            self.rewind.borrow_mut().push_frame(&self.cpu.borrow());
+            self.dispatch_frame_events();
+            // Games often write their save a little at a time, so wait until they've finished rather than after every frame
+            if self.cpu.borrow().battery_dirty_since(BATTERY_FLUSH_FRAMES) && !self.battery_flushing.get() {
+                let _ = self.flush_battery();
+            }
+        }
+        draw_time
     }
 
+    /// Runs until the next frame is ready to draw, so JS only has to cross into the module once a frame.
+    /// The speed changes how many frames that is, with fractions carried over to later calls.
+    /// A CPU which has locked up may never finish another frame, so this returns early once it has.
     #[wasm_bindgen]
-    pub fn draw_screen(&mut self) {
-        let mut framebuffer = self.cpu.render();
-        let img_data = ImageData::new_with_u8_clamped_array_and_sh(Clamped(&mut framebuffer), SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32).unwrap();
-        self.ctx.put_image_data(&img_data, 0.0, 0.0).unwrap();
+    pub fn run_frame(&mut self) {
+        self.frame_credit += self.speed;
+        while self.frame_credit >= 1.0 {
+            self.frame_credit -= 1.0;
+            while !self.tick() {
+                if self.cpu.borrow().is_locked() {
+                    self.frame_credit = 0.0;
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Draws the frame to the canvas, returning false if it was skipped as nothing had changed
+    #[wasm_bindgen]
+    pub fn draw_screen(&mut self) -> bool {
+        if !self.cpu.borrow_mut().take_frame_changed() {
+            return false;
+        }
+        self.display.draw_frame(self.cpu.borrow().render().as_slice());
+        true
+    }
+}
+
+impl GB {
+    // Checked once a frame, as none of these need to be heard about any sooner
+    fn dispatch_frame_events(&mut self) {
+        let events = self.events.borrow();
+        events.frame_ready();
+        if events.wants_serial() {
+            for byte in self.cpu.borrow_mut().take_serial_bytes() {
+                events.serial_byte(byte);
+            }
+        }
+        let dirty = self.cpu.borrow().is_battery_dirty();
+        if dirty && !self.battery_dirty {
+            events.battery_dirty();
+        }
+        self.battery_dirty = dirty;
+    }
+
+    fn storage_key(&self, slot: u32) -> String {
+        format!("{}-{}", game_key(&self.cpu.borrow()), slot)
+    }
+}
+
+// Saves are kept per game, with the checksum telling apart games which share a title
+fn game_key(cpu: &Cpu) -> String {
+    format!("{}-{:04X}", cpu.get_title(), cpu.get_global_checksum().computed)
+}
+
+// Loads a game, then fetches its battery save from the browser's storage, resolving to whether there was one
+fn start_game(cpu: &Rc<RefCell<Cpu>>, rewind: &Rc<RefCell<Rewind>>, events: &Rc<RefCell<Events>>, rom: &[u8]) -> Result<Promise, JsValue> {
+    cpu.borrow_mut().load_rom(rom).map_err(|err| JsValue::from_str(&err.to_string()))?;
+    rewind.borrow_mut().clear();
+    events.borrow().title_loaded(cpu.borrow().get_title());
+
+    if !cpu.borrow().has_battery() {
+        return Ok(Promise::resolve(&JsValue::FALSE));
+    }
+    let key = game_key(&cpu.borrow());
+    let cpu = cpu.clone();
+    Ok(Promise::new(&mut |resolve, reject| {
+        let cpu = cpu.clone();
+        storage::get(BATTERY_STORE, &key, reject, move |data| {
+            let found = data.is_some();
+            if let Some(data) = data {
+                cpu.borrow_mut().set_battery_data(&data);
+            }
+            let _ = resolve.call1(&JsValue::NULL, &JsValue::from_bool(found));
+        });
+    }))
+}
+
+// Tilts the cartridge a full 1g along an axis while I/J/K/L is held, for MBC7 games
+fn key2tilt(key: &str, tilt: (f32, f32), pressed: bool) -> Option<(f32, f32)> {
+    let amount = if pressed { 1.0 } else { 0.0 };
+    match key {
+        "i" => { Some((tilt.0, -amount)) },
+        "k" => { Some((tilt.0, amount))  },
+        "j" => { Some((-amount, tilt.1)) },
+        "l" => { Some((amount, tilt.1))  },
+        _ =>   { None                    }
     }
 }
 
@@ -84,3 +524,17 @@ fn key2btn(key: &str) -> Option<Buttons> {
         _ =>              { None                  }
     }
 }
+
+fn name2btn(name: &str) -> Option<Buttons> {
+    match name {
+        "Down" =>   { Some(Buttons::Down)   },
+        "Up" =>     { Some(Buttons::Up)     },
+        "Right" =>  { Some(Buttons::Right)  },
+        "Left" =>   { Some(Buttons::Left)   },
+        "Start" =>  { Some(Buttons::Start)  },
+        "Select" => { Some(Buttons::Select) },
+        "A" =>      { Some(Buttons::A)      },
+        "B" =>      { Some(Buttons::B)      },
+        _ =>        { None                  }
+    }
+}
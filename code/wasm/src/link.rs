@@ -0,0 +1,135 @@
+use gb_core::serial::SerialDevice;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use js_sys::{ArrayBuffer, Uint8Array};
+use wasm_bindgen::prelude::*;
+use web_sys::{BinaryType, BroadcastChannel, MessageEvent, WebSocket};
+
+// Sent by the side clocking a transfer, along with the byte it sent
+const MSG_CLOCKED: u8   = 0;
+// Sent by a side waiting on its partner's clock, along with the byte it will send back
+const MSG_READY: u8     = 1;
+
+// What has arrived from the other Game Boy, filled in as messages come in
+#[derive(Default)]
+struct LinkState {
+    clocked: Option<u8>,
+    ready: Option<u8>,
+}
+
+/*
+ * Link Cable
+ *
+ * Each message is two bytes, a type followed by a serial byte, sent either
+ * through a WebSocket relay server, which only needs to forward every message
+ * on to the other player, or through a BroadcastChannel, which passes it to
+ * every other tab on the same site which opened a channel of the same name.
+ * The latter needs no server at all, but only works between tabs of the same
+ * browser, and only two tabs should share a channel at a time.
+ *
+ * A real cable swaps both bytes at once, which neither can do, so the side
+ * waiting on an external clock announces its byte up front. The side with the
+ * clock sends its byte and immediately takes whichever byte was announced
+ * last, or 0xFF if its partner isn't ready, while the waiting side finishes
+ * its transfer once the clocked byte arrives.
+ */
+
+type MessageHandler = Closure<dyn FnMut(MessageEvent)>;
+
+// How messages reach the other Game Boy
+enum Channel {
+    Socket(WebSocket),
+    Broadcast(BroadcastChannel),
+}
+
+pub struct LinkCable {
+    channel: Channel,
+    state: Rc<RefCell<LinkState>>,
+    announced: bool,
+    _on_message: MessageHandler,
+}
+
+impl LinkCable {
+    /// Links to another player through a relay server
+    pub fn connect_websocket(url: &str) -> Result<Self, JsValue> {
+        let socket = WebSocket::new(url)?;
+        socket.set_binary_type(BinaryType::Arraybuffer);
+        let (state, on_message) = listen();
+        socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+        Ok(Self { channel: Channel::Socket(socket), state, announced: false, _on_message: on_message })
+    }
+
+    /// Links to another tab which has connected to the same name
+    pub fn connect_broadcast(name: &str) -> Result<Self, JsValue> {
+        let broadcast = BroadcastChannel::new(name)?;
+        let (state, on_message) = listen();
+        broadcast.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+        Ok(Self { channel: Channel::Broadcast(broadcast), state, announced: false, _on_message: on_message })
+    }
+
+    fn send(&self, kind: u8, byte: u8) {
+        match &self.channel {
+            // Anything sent before the connection opens is dropped, the game will simply see no partner
+            Channel::Socket(socket) => { let _ = socket.send_with_u8_array(&[kind, byte]); },
+            Channel::Broadcast(broadcast) => { let _ = broadcast.post_message(&Uint8Array::from(&[kind, byte][..])); },
+        }
+    }
+}
+
+// Fills in the link state as messages arrive, from WebSockets as an ArrayBuffer or from BroadcastChannels as a Uint8Array
+fn listen() -> (Rc<RefCell<LinkState>>, MessageHandler) {
+    let state = Rc::new(RefCell::new(LinkState::default()));
+    let msg_state = state.clone();
+    let on_message = Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+        let data = event.data();
+        if !data.is_instance_of::<ArrayBuffer>() && !data.is_instance_of::<Uint8Array>() {
+            return;
+        }
+        let msg = Uint8Array::new(&data).to_vec();
+        if let [kind, byte] = msg[..] {
+            let mut state = msg_state.borrow_mut();
+            match kind {
+                MSG_CLOCKED => { state.clocked = Some(byte) },
+                MSG_READY => { state.ready = Some(byte) },
+                _ => {},
+            }
+        }
+    });
+    (state, on_message)
+}
+
+impl SerialDevice for LinkCable {
+    fn exchange(&mut self, sent: u8) -> u8 {
+        self.send(MSG_CLOCKED, sent);
+        self.state.borrow_mut().ready.take().unwrap_or(0xFF)
+    }
+
+    fn external_transfer(&mut self, sent: u8) -> Option<u8> {
+        if !self.announced {
+            self.send(MSG_READY, sent);
+            self.announced = true;
+        }
+        let received = self.state.borrow_mut().clocked.take();
+        if received.is_some() {
+            self.announced = false;
+        }
+        received
+    }
+}
+
+impl Drop for LinkCable {
+    fn drop(&mut self) {
+        match &self.channel {
+            Channel::Socket(socket) => {
+                socket.set_onmessage(None);
+                let _ = socket.close();
+            },
+            Channel::Broadcast(broadcast) => {
+                broadcast.set_onmessage(None);
+                broadcast.close();
+            },
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use js_sys::{Function, Promise, Reflect, Uint8Array};
+use wasm_bindgen::prelude::*;
+use web_sys::{IdbDatabase, IdbFactory, IdbRequest, IdbTransactionMode};
+
+const DB_NAME: &str             = "gb-book";
+const DB_VERSION: u32           = 2;
+pub const STATE_STORE: &str     = "save_states";
+pub const BATTERY_STORE: &str   = "battery_saves";
+
+/*
+ * Browser Storage
+ *
+ * Save states and battery saves are kept as raw bytes in IndexedDB object
+ * stores, keyed by the game (and slot, for states), so they survive the page
+ * being closed. IndexedDB only works through callbacks, so each operation
+ * opens the database, makes a single request, and reports back once it has
+ * finished.
+ */
+
+// Rejects with the request's error if it fails
+fn reject_on_error(request: &IdbRequest, reject: Function) {
+    let failed = request.clone();
+    let on_error = Closure::once_into_js(move || {
+        let err = failed.error().ok().flatten().map(JsValue::from).unwrap_or(JsValue::UNDEFINED);
+        let _ = reject.call1(&JsValue::NULL, &err);
+    });
+    request.set_onerror(Some(on_error.unchecked_ref()));
+}
+
+// Opens the database, creating the store the first time, and hands it to on_open
+fn open_db<F>(reject: Function, on_open: F) -> Result<(), JsValue>
+where F: FnOnce(IdbDatabase) -> Result<(), JsValue> + 'static {
+    // Found through the global scope rather than the window, which workers don't have
+    let factory = Reflect::get(&js_sys::global(), &JsValue::from_str("indexedDB"))?
+        .dyn_into::<IdbFactory>()
+        .map_err(|_| "IndexedDB isn't available")?;
+    let request = factory.open_with_u32(DB_NAME, DB_VERSION)?;
+
+    let upgrade_request = request.clone();
+    let on_upgrade = Closure::once_into_js(move || {
+        if let Ok(db) = upgrade_request.result().and_then(|db| db.dyn_into::<IdbDatabase>()) {
+            // Stores which already exist from an older version fail to be created, and are left as they are
+            let _ = db.create_object_store(STATE_STORE);
+            let _ = db.create_object_store(BATTERY_STORE);
+        }
+    });
+    request.set_onupgradeneeded(Some(on_upgrade.unchecked_ref()));
+
+    let success_request = request.clone();
+    let success_reject = reject.clone();
+    let on_success = Closure::once_into_js(move || {
+        let opened = success_request.result().and_then(|db| db.dyn_into::<IdbDatabase>());
+        if let Err(err) = opened.and_then(on_open) {
+            let _ = success_reject.call1(&JsValue::NULL, &err);
+        }
+    });
+    request.set_onsuccess(Some(on_success.unchecked_ref()));
+    reject_on_error(&request, reject);
+    Ok(())
+}
+
+/// Stores data in one of the stores under the given key, returning a Promise which resolves once it has been written
+pub fn put(store: &'static str, key: &str, data: &[u8]) -> Promise {
+    Promise::new(&mut |resolve, reject| {
+        let key = JsValue::from_str(key);
+        let data = Uint8Array::from(data);
+        let request_reject = reject.clone();
+        let opened = open_db(reject.clone(), move |db| {
+            let transaction = db.transaction_with_str_and_mode(store, IdbTransactionMode::Readwrite)?;
+            let request = transaction.object_store(store)?.put_with_key(&data, &key)?;
+            reject_on_error(&request, request_reject);
+            let on_complete = Closure::once_into_js(move || {
+                let _ = resolve.call0(&JsValue::NULL);
+            });
+            transaction.set_oncomplete(Some(on_complete.unchecked_ref()));
+            Ok(())
+        });
+        if let Err(err) = opened {
+            let _ = reject.call1(&JsValue::NULL, &err);
+        }
+    })
+}
+
+/// Looks up what one of the stores holds under the given key, passing its bytes to on_found, or None if there isn't anything
+pub fn get<F>(store: &'static str, key: &str, reject: Function, on_found: F)
+where F: FnOnce(Option<Vec<u8>>) + 'static {
+    let key = JsValue::from_str(key);
+    let request_reject = reject.clone();
+    let opened = open_db(reject.clone(), move |db| {
+        let transaction = db.transaction_with_str(store)?;
+        let request = transaction.object_store(store)?.get(&key)?;
+        reject_on_error(&request, request_reject);
+        let success_request = request.clone();
+        let on_success = Closure::once_into_js(move || {
+            let data = success_request.result().ok().filter(|data| !data.is_undefined());
+            on_found(data.map(|data| Uint8Array::new(&data).to_vec()));
+        });
+        request.set_onsuccess(Some(on_success.unchecked_ref()));
+        Ok(())
+    });
+    if let Err(err) = opened {
+        let _ = reject.call1(&JsValue::NULL, &err);
+    }
+}